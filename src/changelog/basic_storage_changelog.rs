@@ -1,12 +1,24 @@
 use anyhow::Result;
+use std::collections::BTreeMap;
 use rayon::iter::{IntoParallelIterator, ParallelIterator as _};
 use uuid::Uuid;
 
 use crate::{changelog::ChangelogChangeWithFields, storage::SyncStorage};
-use super::changelog::Changelog;
+use super::changelog::{validate_change_ids, Changelog};
 
 
-/// Basic remote changelog backed by storage with one file per change (no batching)
+/// Returned by BasicStorageChangelog::compact().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Change files and previously compacted files that were read.
+    pub files_merged: usize,
+    pub files_deleted: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Basic remote changelog backed by storage with one file per change (no batching).
+/// compact() merges the change files into /compacted/[highest change_id].msgpack.
 pub struct BasicStorageChangelog<'a> {
     storage: &'a dyn SyncStorage,
     prefix: String,
@@ -17,19 +29,74 @@ impl<'a> BasicStorageChangelog<'a> {
         Self { storage, prefix }
     }
     
-    fn prefixed_path(&self, path: &str) -> String {
-        if self.prefix.is_empty() {
-            path.to_string()
-        } else {
-            format!("{}/{}", self.prefix, path)
+    /// Merge every change file, and any earlier compacted file, into a single
+    /// compacted file named for the highest change id, then delete the
+    /// originals. Files appended while compacting are left for the next
+    /// call. Calling it again with nothing new to merge does nothing.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        let change_files: Vec<String> = self.change_file_ids()?
+            .into_iter()
+            .map(|change_id| self.prefixed_path(&format!("changes/{}.msgpack", change_id)))
+            .collect();
+        let compacted_files = self.compacted_files()?;
+        if change_files.is_empty() && compacted_files.len() <= 1 {
+            return Ok(CompactionStats::default());
+        }
+
+        let mut stats = CompactionStats::default();
+        let mut changes = BTreeMap::new();
+        for path in &compacted_files {
+            let data = self.storage.get(path)?;
+            stats.files_merged += 1;
+            stats.bytes_before += data.len() as u64;
+            for change in rmp_serde::from_slice::<Vec<ChangelogChangeWithFields>>(&data)? {
+                changes.insert(change.change.id.clone(), change);
+            }
         }
+        for path in &change_files {
+            let data = self.storage.get(path)?;
+            stats.files_merged += 1;
+            stats.bytes_before += data.len() as u64;
+            let change = rmp_serde::from_slice::<ChangelogChangeWithFields>(&data)?;
+            changes.insert(change.change.id.clone(), change);
+        }
+        let Some(highest_id) = changes.keys().next_back().cloned() else {
+            return Ok(stats);
+        };
+
+        let compacted_path = self.prefixed_path(&format!("compacted/{}.msgpack", highest_id));
+        let data = rmp_serde::to_vec(&changes.into_values().collect::<Vec<_>>())?;
+        self.storage.put(&compacted_path, &data)?;
+        stats.bytes_after = data.len() as u64;
+
+        for path in compacted_files.iter().chain(&change_files) {
+            if *path != compacted_path {
+                self.storage.delete(path)?;
+                stats.files_deleted += 1;
+            }
+        }
+        Ok(stats)
     }
-}
 
-impl<'a> Changelog for BasicStorageChangelog<'a> {
-    fn get_all_change_ids(&self) -> Result<Vec<String>> {
+    fn compacted_files(&self) -> Result<Vec<String>> {
+        Ok(self.storage.list_paths_only(&self.prefixed_path("compacted/"))?
+            .into_iter()
+            .filter(|path| path.ends_with(".msgpack"))
+            .collect())
+    }
+
+    fn compacted_changes(&self) -> Result<Vec<ChangelogChangeWithFields>> {
+        let mut changes = Vec::new();
+        for path in self.compacted_files()? {
+            let data = self.storage.get(&path)?;
+            changes.extend(rmp_serde::from_slice::<Vec<ChangelogChangeWithFields>>(&data)?);
+        }
+        Ok(changes)
+    }
+
+    fn change_file_ids(&self) -> Result<Vec<String>> {
         let prefix = self.prefixed_path("changes/");
-        let files = self.storage.list(&prefix)?;
+        let files = self.storage.list_paths_only(&prefix)?;
         
         let mut change_ids = Vec::new();
         for file in files {
@@ -39,17 +106,43 @@ impl<'a> Changelog for BasicStorageChangelog<'a> {
                 }
             }
         }
-        
+        Ok(change_ids)
+    }
+
+    fn prefixed_path(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+}
+
+impl<'a> Changelog for BasicStorageChangelog<'a> {
+    fn get_all_change_ids(&self) -> Result<Vec<String>> {
+        let mut change_ids = self.change_file_ids()?;
+        change_ids.extend(self.compacted_changes()?.into_iter().map(|change| change.change.id));
+        // A change can be re-appended after it was compacted
         change_ids.sort();
+        change_ids.dedup();
         Ok(change_ids)
     }
     
     fn get_changes(&self, from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
         let from_id = from_id.map(|s| s.to_string()).unwrap_or_else(|| Uuid::nil().to_string());
         let to_id = to_id.map(|s| s.to_string()).unwrap_or_else(|| Uuid::max().to_string());
-        let results = self.get_all_change_ids()?
+        let in_range = |change_id: &String| change_id >= &from_id && change_id <= &to_id;
+        let mut changes: BTreeMap<String, ChangelogChangeWithFields> = self.compacted_changes()?
+            .into_iter()
+            .filter(|change| in_range(&change.change.id))
+            .map(|change| (change.change.id.clone(), change))
+            .collect();
+        let file_ids: Vec<String> = self.change_file_ids()?
+            .into_iter()
+            .filter(|change_id| in_range(change_id) && !changes.contains_key(change_id))
+            .collect();
+        let results: Result<Vec<ChangelogChangeWithFields>> = file_ids
             .into_par_iter()
-            .filter(|change_id| change_id >= &from_id && change_id <= &to_id)
             .map(|change_id| {
                 let path = self.prefixed_path(&format!("changes/{}.msgpack", change_id));
                 let data = self.storage.get(&path)?;
@@ -57,14 +150,20 @@ impl<'a> Changelog for BasicStorageChangelog<'a> {
                 Ok(change)
             })
             .collect();
-        results
+        for change in results? {
+            changes.insert(change.change.id.clone(), change);
+        }
+        Ok(changes.into_values().collect())
     }
 
     fn append_changes(&self, changes: Vec<ChangelogChangeWithFields>) -> Result<()> {
+        validate_change_ids(&changes)?;
         for change in changes {
             let path = self.prefixed_path(&format!("changes/{}.msgpack", change.change.id));
             let data = rmp_serde::to_vec(&change)?;
-            self.storage.put(&path, &data)?;
+            // Changes are immutable, so one already pushed by another
+            // replica can be left as is
+            self.storage.put_if_not_exists(&path, &data)?;
         }
         Ok(())
     }    
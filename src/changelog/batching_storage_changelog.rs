@@ -1,18 +1,80 @@
 use anyhow::Result;
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, time::Duration};
 use uuid::Uuid;
 
 use crate::{changelog::ChangelogChangeWithFields, storage::SyncStorage};
-use super::changelog::Changelog;
+use super::changelog::{validate_change_ids, Changelog};
+
+/// Default limit on the serialized size of a batch.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 1024 * 1024;
 
 pub struct BatchingStorageChangelog<'a> {
     storage: &'a dyn SyncStorage,
     prefix: String,
+    max_batch_bytes: usize,
+    time_window: Option<Duration>,
+}
+
+/// Builder for a BatchingStorageChangelog. See BatchingStorageChangelog::builder().
+pub struct BatchingStorageChangelogBuilder<'a> {
+    changelog: BatchingStorageChangelog<'a>,
+}
+
+impl<'a> BatchingStorageChangelogBuilder<'a> {
+    /// Start a new batch when the serialized changes would exceed this many
+    /// bytes. A single change larger than the limit gets a batch of its own.
+    pub fn max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.changelog.max_batch_bytes = max_batch_bytes;
+        self
+    }
+
+    /// Start a new batch when a change was made more than this long after
+    /// the first change in the batch, based on the change's UUIDv7 id.
+    pub fn time_window(mut self, time_window: Duration) -> Self {
+        self.changelog.time_window = Some(time_window);
+        self
+    }
+
+    pub fn build(self) -> BatchingStorageChangelog<'a> {
+        self.changelog
+    }
 }
 
 impl<'a> BatchingStorageChangelog<'a> {
     pub fn new(storage: &'a dyn SyncStorage, prefix: String) -> Self {
-        Self { storage, prefix }
+        Self::builder(storage, prefix).build()
+    }
+
+    /// Appended changes are split into batches when either the size limit or
+    /// the time window is hit first. Defaults to DEFAULT_MAX_BATCH_BYTES and
+    /// no time window.
+    pub fn builder(storage: &'a dyn SyncStorage, prefix: String) -> BatchingStorageChangelogBuilder<'a> {
+        BatchingStorageChangelogBuilder {
+            changelog: Self {
+                storage,
+                prefix,
+                max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+                time_window: None,
+            },
+        }
+    }
+
+    /// True if the change falls outside the time window that started with
+    /// the batch's first change. Changes without a UUIDv7 id never do.
+    fn outside_time_window(&self, first: &ChangelogChangeWithFields, change: &ChangelogChangeWithFields) -> bool {
+        let Some(time_window) = self.time_window else {
+            return false;
+        };
+        let timestamp = |change: &ChangelogChangeWithFields| Uuid::parse_str(&change.change.id).ok()
+            .and_then(|uuid| uuid.get_timestamp())
+            .map(|ts| {
+                let (secs, nanos) = ts.to_unix();
+                Duration::new(secs, nanos)
+            });
+        match (timestamp(first), timestamp(change)) {
+            (Some(start), Some(end)) => end.saturating_sub(start) > time_window,
+            _ => false,
+        }
     }
 
     fn prefixed_path(&self, path: &str) -> String {
@@ -28,7 +90,7 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
     /// Read the manifests, return the change_ids. 
     fn get_all_change_ids(&self) -> Result<Vec<String>> {
         let manifest_prefix = self.prefixed_path("manifests/");
-        let manifest_files = self.storage.list(&manifest_prefix)?;
+        let manifest_files = self.storage.list_paths_only(&manifest_prefix)?;
         
         let mut all_change_ids = HashSet::new();
         
@@ -60,7 +122,7 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
         
         // First, read all manifests to find which batches we need
         let manifest_prefix = self.prefixed_path("manifests/");
-        let manifest_files = self.storage.list(&manifest_prefix)?;
+        let manifest_files = self.storage.list_paths_only(&manifest_prefix)?;
         
         let mut batch_ids_to_fetch = HashSet::new();
         let mut change_id_to_batch: HashMap<String, String> = HashMap::new();
@@ -113,6 +175,7 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
         if changes.is_empty() {
             return Ok(());
         }
+        validate_change_ids(&changes)?;
         
         // Get all existing change_ids to filter out duplicates
         let existing_ids: HashSet<String> = self.get_all_change_ids()?.into_iter().collect();
@@ -127,8 +190,7 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
             return Ok(());
         }
         
-        // Split changes into batches of approximately 100MB
-        const MAX_BATCH_SIZE: usize = 100 * 1024 * 1024; // 100MB
+        // Split changes into batches by size and time window
         let mut batches = Vec::new();
         let mut current_batch = Vec::new();
         let mut current_batch_size = 0;
@@ -137,11 +199,14 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
             // Estimate the size of this change when serialized
             let change_size = rmp_serde::to_vec(&change)?.len();
             
-            // If adding this change would exceed the limit, start a new batch
-            if !current_batch.is_empty() && current_batch_size + change_size > MAX_BATCH_SIZE {
-                batches.push(current_batch);
-                current_batch = Vec::new();
-                current_batch_size = 0;
+            // If adding this change would exceed either limit, start a new batch
+            if let Some(first) = current_batch.first() {
+                if current_batch_size + change_size > self.max_batch_bytes
+                    || self.outside_time_window(first, &change) {
+                    batches.push(current_batch);
+                    current_batch = Vec::new();
+                    current_batch_size = 0;
+                }
             }
             
             current_batch_size += change_size;
@@ -191,7 +256,7 @@ impl<'a> Changelog for BatchingStorageChangelog<'a> {
             for change in batch_changes {
                 let manifest = author_manifests
                     .entry(change.change.author_id.clone())
-                    .or_insert_with(HashMap::new);
+                    .or_default();
                 manifest.insert(change.change.id.clone(), batch_id.clone());
             }
         }
@@ -217,18 +282,18 @@ mod tests {
         let storage = InMemoryStorage::new();
         let changelog = BatchingStorageChangelog::new(&storage, String::new());
         
-        // Create a large set of changes that will exceed 100MB when serialized
+        // Create a large set of changes that will exceed the default 1MB
+        // batch size when serialized
         let mut changes = Vec::new();
         
-        // Create a large field value (1MB)
-        let large_value = vec![0u8; 1024 * 1024];
-        let large_msgpack_value = rmpv::Value::Binary(large_value);
+        // Create a large field value (256KB)
+        let large_value = serde_json::Value::String("x".repeat(256 * 1024));
         
-        // Create 150 changes, each with a 1MB field - total ~150MB
-        for i in 0..150 {
+        // Create 15 changes, each with a 256KB field - total ~3.75MB
+        for i in 0..15 {
             let change = ChangelogChangeWithFields {
                 change: ChangelogChange {
-                    id: format!("change-{:03}", i),
+                    id: change_id(i),
                     author_id: "author-1".to_string(),
                     entity_type: "TestEntity".to_string(),
                     entity_id: format!("entity-{:03}", i),
@@ -236,8 +301,10 @@ mod tests {
                 },
                 fields: vec![RemoteFieldRecord {
                     field_name: "large_field".to_string(),
-                    field_value: large_msgpack_value.clone(),
+                    field_value: large_value.clone(),
                 }],
+                clock: None,
+                author: None,
             };
             changes.push(change);
         }
@@ -246,19 +313,76 @@ mod tests {
         changelog.append_changes(changes)?;
         
         // Verify that multiple batches were created
-        let batch_files = storage.list("batches/")?;
+        let batch_files = storage.list_paths_only("batches/")?;
         assert!(batch_files.len() > 1, "Expected multiple batches but got {}", batch_files.len());
         
         // Verify all changes can be retrieved
         let all_change_ids = changelog.get_all_change_ids()?;
-        assert_eq!(all_change_ids.len(), 150);
+        assert_eq!(all_change_ids.len(), 15);
         
         // Verify we can retrieve all changes
         let retrieved_changes = changelog.get_changes(None, None)?;
-        assert_eq!(retrieved_changes.len(), 150);
+        assert_eq!(retrieved_changes.len(), 15);
         
         Ok(())
     }
+
+    fn change_id(i: u64) -> String {
+        let mut bytes = [0u8; 10];
+        bytes[2..].copy_from_slice(&i.to_be_bytes());
+        uuid::Builder::from_unix_timestamp_millis(0, &bytes).into_uuid().to_string()
+    }
+
+    fn test_change(id: String, value: &str) -> ChangelogChangeWithFields {
+        ChangelogChangeWithFields {
+            change: ChangelogChange {
+                id,
+                author_id: "author-1".to_string(),
+                entity_type: "TestEntity".to_string(),
+                entity_id: "entity-1".to_string(),
+                merged: false,
+            },
+            fields: vec![RemoteFieldRecord {
+                field_name: "name".to_string(),
+                field_value: serde_json::Value::String(value.to_string()),
+            }],
+            clock: None,
+            author: None,
+        }
+    }
+
+    #[test]
+    fn test_max_batch_bytes() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let changelog = BatchingStorageChangelog::builder(&storage, String::new())
+            .max_batch_bytes(250)
+            .build();
+        let value = "x".repeat(100);
+        let changes = (0..6).map(|i| test_change(change_id(i), &value)).collect();
+        changelog.append_changes(changes)?;
+        // Two ~160 byte changes don't fit in 250 bytes, so each gets a batch
+        assert_eq!(storage.list_paths_only("batches/")?.len(), 6);
+        assert_eq!(changelog.get_changes(None, None)?.len(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_window() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let changelog = BatchingStorageChangelog::builder(&storage, String::new())
+            .time_window(Duration::from_secs(60))
+            .build();
+        let id_at = |secs: u64| uuid::Builder::from_unix_timestamp_millis(secs * 1000, &[secs as u8; 10])
+            .into_uuid().to_string();
+        let changes = [0, 30, 60, 61, 90, 200].into_iter()
+            .map(|secs| test_change(id_at(secs), "Test"))
+            .collect();
+        changelog.append_changes(changes)?;
+        // [0, 30, 60], [61, 90], [200]
+        assert_eq!(storage.list_paths_only("batches/")?.len(), 3);
+        assert_eq!(changelog.get_changes(None, None)?.len(), 6);
+        Ok(())
+    }
     
     #[test]
     fn test_small_batch_not_split() -> Result<()> {
@@ -270,7 +394,7 @@ mod tests {
         for i in 0..10 {
             let change = ChangelogChangeWithFields {
                 change: ChangelogChange {
-                    id: format!("change-{:02}", i),
+                    id: change_id(i),
                     author_id: "author-1".to_string(),
                     entity_type: "TestEntity".to_string(),
                     entity_id: format!("entity-{:02}", i),
@@ -278,8 +402,10 @@ mod tests {
                 },
                 fields: vec![RemoteFieldRecord {
                     field_name: "name".to_string(),
-                    field_value: rmpv::Value::String(format!("Test {}", i).into()),
+                    field_value: serde_json::Value::String(format!("Test {}", i)),
                 }],
+                clock: None,
+                author: None,
             };
             changes.push(change);
         }
@@ -288,7 +414,7 @@ mod tests {
         changelog.append_changes(changes)?;
         
         // Verify that only one batch was created
-        let batch_files = storage.list("batches/")?;
+        let batch_files = storage.list_paths_only("batches/")?;
         assert_eq!(batch_files.len(), 1, "Expected single batch but got {}", batch_files.len());
         
         Ok(())
@@ -1,15 +1,113 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+
 use crate::changelog::{ChangelogChangeWithFields};
 
-/// Trait representing a changelog that can be synced between devices
+/// Trait representing a changelog that can be synced between devices.
+/// 
+/// Change ids are UUIDv7s, which sort in creation order as strings.
+/// Implementors must accept any UUIDv7 id and should reject others with
+/// validate_change_ids().
 pub trait Changelog: Send + Sync {
     /// Get all change IDs in the changelog
     fn get_all_change_ids(&self) -> Result<Vec<String>>;
     
-    /// Get all changes between the two change_ids, inclusive. If either is
-    /// None the range will be extended to the beginning or end repectively.
+    /// Get all changes between the two change_ids, inclusive, compared in
+    /// string sort order. If either is None the range will be extended to
+    /// the beginning or end repectively.
     fn get_changes(&self, from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>>;
+
+    /// Like get_all_change_ids(), but only changes to the given entity types.
+    /// The default implementation reads every change to check its type.
+    fn get_change_ids_for_types(&self, entity_types: &[&str]) -> Result<Vec<String>> {
+        Ok(self.get_changes_for_types(entity_types, None, None)?
+            .into_iter()
+            .map(|change| change.change.id)
+            .collect())
+    }
+
+    /// Like get_changes(), but only changes to the given entity types. The
+    /// default implementation filters the result of get_changes(), so
+    /// implementors that can filter more cheaply should override it.
+    fn get_changes_for_types(&self, entity_types: &[&str], from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
+        Ok(self.get_changes(from_id, to_id)?
+            .into_iter()
+            .filter(|change| entity_types.contains(&change.change.entity_type.as_str()))
+            .collect())
+    }
     
     /// Append new changes to the changelog
     fn append_changes(&self, changes: Vec<ChangelogChangeWithFields>) -> Result<()>;
 }
+
+/// Returns an error if any change id is not a UUIDv7 in the standard
+/// hyphenated lowercase form, since the sync range queries rely on the
+/// string order of ids matching their creation order.
+pub fn validate_change_ids(changes: &[ChangelogChangeWithFields]) -> Result<()> {
+    for change in changes {
+        let id = &change.change.id;
+        let valid = Uuid::parse_str(id)
+            .is_ok_and(|uuid| uuid.get_version_num() == 7 && uuid.hyphenated().to_string() == *id);
+        if !valid {
+            return Err(anyhow!("Change id {} is not a UUIDv7", id));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{changelog::{BasicStorageChangelog, BatchingStorageChangelog, ChangelogChange, ChangelogChangeWithFields}, storage::InMemoryStorage};
+
+    use super::{validate_change_ids, Changelog};
+
+    fn change(id: &str) -> ChangelogChangeWithFields {
+        typed_change(id, "Artist")
+    }
+
+    fn typed_change(id: &str, entity_type: &str) -> ChangelogChangeWithFields {
+        ChangelogChangeWithFields {
+            change: ChangelogChange {
+                id: id.to_string(),
+                author_id: "author-1".to_string(),
+                entity_type: entity_type.to_string(),
+                entity_id: "artist-1".to_string(),
+                merged: false,
+            },
+            fields: vec![],
+            clock: None,
+            author: None,
+        }
+    }
+
+    #[test]
+    fn only_uuidv7_change_ids_are_valid() {
+        assert!(validate_change_ids(&[change(&uuid::Uuid::now_v7().to_string())]).is_ok());
+        assert!(validate_change_ids(&[change("0198e3b2-7c1a-7d2e-9f00-0123456789ab")]).is_ok());
+        assert!(validate_change_ids(&[change("change-1")]).is_err());
+        assert!(validate_change_ids(&[change("0198e3b2-7c1a-4d2e-9f00-0123456789ab")]).is_err());
+        assert!(validate_change_ids(&[change("0198E3B2-7C1A-7D2E-9F00-0123456789AB")]).is_err());
+    }
+
+    #[test]
+    fn storage_changelogs_filter_by_type() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let basic = BasicStorageChangelog::new(&storage, "basic".to_string());
+        let batching = BatchingStorageChangelog::new(&storage, "batching".to_string());
+        let ids: Vec<String> = (0..6).map(|_| uuid::Uuid::now_v7().to_string()).collect();
+        let changes: Vec<_> = ids.iter().zip(["Artist", "Album", "Track", "Artist", "Album", "Track"])
+            .map(|(id, entity_type)| typed_change(id, entity_type))
+            .collect();
+        for changelog in [&basic as &dyn Changelog, &batching] {
+            changelog.append_changes(changes.clone())?;
+            assert_eq!(changelog.get_change_ids_for_types(&["Artist", "Track"])?,
+                vec![ids[0].clone(), ids[2].clone(), ids[3].clone(), ids[5].clone()]);
+            assert!(changelog.get_change_ids_for_types(&["Label"])?.is_empty());
+            let albums = changelog.get_changes_for_types(&["Album"], Some(&ids[1]), Some(&ids[3]))?;
+            assert_eq!(albums, vec![changes[1].clone()]);
+        }
+        Ok(())
+    }
+}
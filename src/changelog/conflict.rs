@@ -0,0 +1,91 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use rusqlite::{types::Value, OptionalExtension as _};
+
+use crate::{changelog::{vector_clock::change_clock, SYNCED_THROUGH_KEY}, db::transaction::DbTransaction, sync::sync_engine::{json_to_sql_value, sql_value_to_json}};
+
+/// A field changed both locally and by another author, passed to the
+/// resolver given to SyncEngineBuilder::on_conflict(). Change ids are
+/// UUIDv7s, so they also tell when each change was made.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConflictContext {
+    pub entity_type: String,
+    pub entity_key: String,
+    pub field_name: String,
+    pub local_field_value: serde_json::Value,
+    pub remote_field_value: serde_json::Value,
+    pub local_change_id: String,
+    pub remote_change_id: String,
+}
+
+/// How to resolve a ConflictContext.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConflictResolution {
+    TakeLocal,
+    TakeRemote,
+    Custom(serde_json::Value),
+}
+
+pub type ConflictResolver = dyn Fn(ConflictContext) -> ConflictResolution + Send + Sync;
+
+/// If this database has changed the field concurrently with the remote
+/// change, to a different value, asks the resolver which value to keep.
+/// When both changes have a VectorClock the clocks decide. The changes are
+/// concurrent if neither clock is before the other, otherwise the causally
+/// later value is kept without asking, whatever the change ids say. For
+/// changes from replicas that don't send clocks, local changes made since
+/// the last full sync can't have been seen by the remote author, so they
+/// are taken to be concurrent with the remote change.
+pub(crate) fn resolve_conflict(txn: &DbTransaction, resolver: &ConflictResolver, entity_type: &str,
+        entity_id: &str, field_name: &str, remote_change_id: &str, remote_value: &Value) -> Result<Option<Value>> {
+    let local: Option<(String, Value)> = txn.txn().query_row(
+        "SELECT c.id, cf.field_value FROM ZV_CHANGE c
+            JOIN ZV_CHANGE_FIELD cf ON c.id = cf.change_id
+            WHERE c.entity_type = ? AND c.entity_id = ? AND cf.field_name = ?
+            AND c.author_id = ? AND c.merged = true
+            ORDER BY c.id DESC
+            LIMIT 1",
+        rusqlite::params![entity_type, entity_id, field_name, txn.db().author_id()],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+    let Some((local_change_id, local_value)) = local else {
+        return Ok(None);
+    };
+    let local_clock = change_clock(txn.txn(), &local_change_id)?;
+    let remote_clock = change_clock(txn.txn(), remote_change_id)?;
+    if let (Some(local_clock), Some(remote_clock)) = (local_clock, remote_clock) {
+        match local_clock.causal_cmp(&remote_clock) {
+            None => {},
+            Some(Ordering::Less) => return Ok(Some(remote_value.clone())),
+            Some(Ordering::Greater) => return Ok(Some(local_value)),
+            Some(Ordering::Equal) => return Ok(None),
+        }
+    } else {
+        let synced_through_id: String = txn.txn().query_row(
+            "SELECT value FROM ZV_METADATA WHERE key = ?", [SYNCED_THROUGH_KEY], |row| row.get(0))
+            .optional()?
+            .unwrap_or_default();
+        if local_change_id <= synced_through_id {
+            return Ok(None);
+        }
+    }
+    if &local_value == remote_value {
+        return Ok(None);
+    }
+
+    let resolution = resolver(ConflictContext {
+        entity_type: entity_type.to_string(),
+        entity_key: entity_id.to_string(),
+        field_name: field_name.to_string(),
+        local_field_value: sql_value_to_json(&local_value),
+        remote_field_value: sql_value_to_json(remote_value),
+        local_change_id,
+        remote_change_id: remote_change_id.to_string(),
+    });
+    Ok(Some(match resolution {
+        ConflictResolution::TakeLocal => local_value,
+        ConflictResolution::TakeRemote => remote_value.clone(),
+        ConflictResolution::Custom(value) => json_to_sql_value(&value),
+    }))
+}
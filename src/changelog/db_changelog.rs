@@ -1,49 +1,61 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::{changelog::{Changelog, ChangelogChange, ChangelogChangeWithFields, RemoteFieldRecord}, sync::sync_engine, Db};
+use crate::{changelog::{conflict::resolve_conflict, validate_change_ids, vector_clock::{load_clock, store_clock}, Changelog, ConflictResolver, ChangelogChange, ChangelogChangeWithFields, RemoteFieldRecord, VectorClock}, sync::sync_engine, Db};
 
 use rusqlite::{Connection, OptionalExtension as _};
 use uuid::Uuid;
-use std::collections::{BTreeMap, HashMap};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::Write, sync::Arc};
 
-use crate::{db::{transaction::{DbTransaction, DbValue}, DbEvent}};
+use crate::{db::{transaction::{row_to_json, DbTransaction, DbValue}, DbEvent}};
 
 pub struct DbChangelog {
     db: Db,
+    conflict_resolver: Option<Arc<ConflictResolver>>,
+    vector_clocks: bool,
 }
 
 impl DbChangelog {
     pub fn new(db: Db) -> Self {
-        Self { db }
+        Self { db, conflict_resolver: None, vector_clocks: false }
     }
-}
 
-impl Changelog for DbChangelog {
-    fn get_all_change_ids(&self) -> Result<Vec<String>> {
-        let changes = self.db.query::<ChangelogChange, _>(
-            "SELECT id, author_id, entity_type, entity_id, merged FROM ZV_CHANGE ORDER BY id ASC", 
-            ()
-        )?;
-        Ok(changes.into_iter().map(|c| c.id).collect())
+    /// Include each change's VectorClock in the changes returned by
+    /// get_changes(), so they reach other replicas. Off by default, since
+    /// replicas from before vector clocks can't read changes that have them.
+    pub fn with_vector_clocks(mut self) -> Self {
+        self.vector_clocks = true;
+        self
     }
 
-    fn get_changes(&self, from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
+    /// Resolve fields changed both locally and by appended changes with the
+    /// resolver, instead of taking the newest change. See ConflictContext.
+    pub fn with_conflict_resolver(mut self, resolver: Arc<ConflictResolver>) -> Self {
+        self.conflict_resolver = Some(resolver);
+        self
+    }
+
+    fn query_changes(&self, entity_types: Option<&[&str]>, from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
         let from_id = from_id.map(|s| s.to_string()).unwrap_or_else(|| Uuid::nil().to_string());
         let to_id = to_id.map(|s| s.to_string()).unwrap_or_else(|| Uuid::max().to_string());
+        let mut params = vec![from_id, to_id];
+        let mut sql = "SELECT ZV_CHANGE.id, author_id, entity_type, entity_id, merged, field_name, field_value, clock, author
+                 FROM ZV_CHANGE 
+                 JOIN ZV_CHANGE_FIELD ON (ZV_CHANGE.id = ZV_CHANGE_FIELD.change_id)
+                 LEFT JOIN ZV_CLOCK ON (ZV_CHANGE.clock_id = ZV_CLOCK.id)
+                 WHERE ZV_CHANGE.id >= ? AND ZV_CHANGE.id <= ?".to_string();
+        if let Some(entity_types) = entity_types {
+            sql.push_str(&format!(" AND ZV_CHANGE.entity_type IN ({})", vec!["?"; entity_types.len()].join(", ")));
+            params.extend(entity_types.iter().map(|t| t.to_string()));
+        }
+        sql.push_str(" ORDER BY ZV_CHANGE.id ASC");
         
         let mut changes = Vec::new();
         
         // Use transaction to access raw connection
         self.db.transaction(|txn| {
-            let mut stmt = txn.txn().prepare(
-                "SELECT id, author_id, entity_type, entity_id, merged, field_name, field_value
-                 FROM ZV_CHANGE 
-                 JOIN ZV_CHANGE_FIELD ON (ZV_CHANGE.id = ZV_CHANGE_FIELD.change_id)
-                 WHERE ZV_CHANGE.id >= ? AND ZV_CHANGE.id <= ?
-                 ORDER BY ZV_CHANGE.id ASC"
-            )?;
+            let mut stmt = txn.txn().prepare(&sql)?;
             
-            let rows = stmt.query_map(rusqlite::params![from_id, to_id], |row| {
+            let rows = stmt.query_map(rusqlite::params_from_iter(&params), |row| {
                 Ok((
                     row.get::<_, String>(0)?,     // id
                     row.get::<_, String>(1)?,     // author_id
@@ -52,13 +64,19 @@ impl Changelog for DbChangelog {
                     row.get::<_, bool>(4)?,       // merged
                     row.get::<_, String>(5)?,     // field_name
                     row.get::<_, rusqlite::types::Value>(6)?, // field_value
+                    row.get::<_, Option<String>>(7)?, // clock
+                    row.get::<_, Option<String>>(8)?, // author
                 ))
             })?;
             
             let mut grouped: BTreeMap<String, ChangelogChangeWithFields> = BTreeMap::new();
             
             for row in rows {
-                let (id, author_id, entity_type, entity_id, merged, field_name, field_value) = row?;
+                let (id, author_id, entity_type, entity_id, merged, field_name, field_value, clock, author) = row?;
+                let clock = match clock {
+                    Some(clock) if self.vector_clocks => Some(serde_json::from_str::<VectorClock>(&clock)?),
+                    _ => None,
+                };
                 
                 let entry = grouped.entry(id.clone()).or_insert_with(|| {
                     ChangelogChangeWithFields {
@@ -70,12 +88,14 @@ impl Changelog for DbChangelog {
                             merged,
                         },
                         fields: Vec::new(),
+                        clock,
+                        author,
                     }
                 });
                 
                 entry.fields.push(RemoteFieldRecord {
                     field_name,
-                    field_value: sync_engine::sql_value_to_msgpack(&field_value),
+                    field_value: sync_engine::sql_value_to_json(&field_value),
                 });
             }
             
@@ -85,27 +105,84 @@ impl Changelog for DbChangelog {
         
         Ok(changes)
     }
+}
+
+impl Changelog for DbChangelog {
+    fn get_all_change_ids(&self) -> Result<Vec<String>> {
+        let changes = self.db.query::<ChangelogChange, _>(
+            "SELECT id, author_id, entity_type, entity_id, merged FROM ZV_CHANGE ORDER BY id ASC", 
+            ()
+        )?;
+        Ok(changes.into_iter().map(|c| c.id).collect())
+    }
+
+    fn get_changes(&self, from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
+        self.query_changes(None, from_id, to_id)
+    }
+
+    fn get_change_ids_for_types(&self, entity_types: &[&str]) -> Result<Vec<String>> {
+        let sql = format!("SELECT id FROM ZV_CHANGE WHERE entity_type IN ({}) ORDER BY id ASC",
+            vec!["?"; entity_types.len()].join(", "));
+        let ids = self.db.read_transaction(|txn| {
+            let mut stmt = txn.prepare(&sql)?;
+            let ids = stmt.query_map(rusqlite::params_from_iter(entity_types), |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(ids)
+        })?;
+        Ok(ids)
+    }
+
+    fn get_changes_for_types(&self, entity_types: &[&str], from_id: Option<&str>, to_id: Option<&str>) -> Result<Vec<ChangelogChangeWithFields>> {
+        self.query_changes(Some(entity_types), from_id, to_id)
+    }
     
+    /// Stores the changes and merges them into the entity tables. Subscribers
+    /// to the Db receive a DbEvent for each entity the merge changes, once
+    /// the merge commits. The changes can be in any order, each field takes
+    /// the value from the change with the greatest id. The clocks of the
+    /// changes are merged into this database's VectorClock, since any
+    /// change made after this has seen them.
     fn append_changes(&self, changes: Vec<ChangelogChangeWithFields>) -> Result<()> {
+        validate_change_ids(&changes)?;
         self.db.transaction(|txn| {
+            // Remote clocks are only kept once this database keeps its own
+            let mut clock = if self.vector_clocks { load_clock(txn.txn())? } else { None };
+            let mut clock_ids: HashMap<String, String> = HashMap::new();
             for remote_change in changes {
                 let change = &remote_change.change;
                 
+                // Changes from one transaction share a clock
+                let clock_json = match (&mut clock, &remote_change.clock) {
+                    (Some(clock), Some(remote_clock)) => {
+                        clock.merge(remote_clock);
+                        Some(serde_json::to_string(remote_clock)?)
+                    },
+                    _ => None,
+                };
+                let clock_id = clock_json.as_ref()
+                    .map(|clock_json| clock_ids.entry(clock_json.clone()).or_insert_with(|| change.id.clone()).clone());
+
                 // Insert the change record
-                txn.txn().execute(
-                    "INSERT OR IGNORE INTO ZV_CHANGE (id, author_id, entity_type, entity_id, merged) 
-                     VALUES (?, ?, ?, ?, false)",
+                let inserted = txn.txn().execute(
+                    "INSERT OR IGNORE INTO ZV_CHANGE (id, author_id, entity_type, entity_id, merged, author, clock_id) 
+                     VALUES (?, ?, ?, ?, false, ?, ?)",
                     rusqlite::params![
                         &change.id,
                         &change.author_id,
                         &change.entity_type,
                         &change.entity_id,
+                        &remote_change.author,
+                        &clock_id,
                     ]
-                )?;
+                )? > 0;
+                if let (true, Some(clock_id), Some(clock_json)) = (inserted, &clock_id, &clock_json) {
+                    txn.txn().execute("INSERT OR IGNORE INTO ZV_CLOCK (id, clock) VALUES (?, ?)",
+                        rusqlite::params![clock_id, clock_json])?;
+                }
                 
                 // Insert the field records
                 for field in &remote_change.fields {
-                    let sql_value = crate::sync::sync_engine::msgpack_to_sql_value(&field.field_value);
+                    let sql_value = crate::sync::sync_engine::json_to_sql_value(&field.field_value);
                     txn.txn().execute(
                         "INSERT OR IGNORE INTO ZV_CHANGE_FIELD (change_id, field_name, field_value) VALUES (?, ?, ?)",
                         rusqlite::params![
@@ -116,11 +193,16 @@ impl Changelog for DbChangelog {
                     )?;
                 }
             }
+            if let Some(clock) = &clock {
+                if !clock_ids.is_empty() {
+                    store_clock(txn.txn(), clock)?;
+                }
+            }
             Ok(())
         })?;
         
         // Process unmerged changes
-        merge_unmerged_changes(&self.db)
+        merge_unmerged_changes(&self.db, self.conflict_resolver.as_deref())
     }
 }
 
@@ -153,7 +235,9 @@ pub (crate) fn init_change_tracking_tables(conn: &Connection) -> Result<()> {
             author_id TEXT NOT NULL,
             entity_type TEXT NOT NULL,
             entity_id TEXT NOT NULL,
-            merged BOOL NOT NULL DEFAULT FALSE
+            merged BOOL NOT NULL DEFAULT FALSE,
+            author TEXT,
+            clock_id TEXT
         );
 
         CREATE TABLE IF NOT EXISTS ZV_CHANGE_FIELD (
@@ -163,51 +247,442 @@ pub (crate) fn init_change_tracking_tables(conn: &Connection) -> Result<()> {
             PRIMARY KEY (change_id, field_name),
             FOREIGN KEY (change_id) REFERENCES ZV_CHANGE(id)
         );
+
+        CREATE TABLE IF NOT EXISTS ZV_TRANSACTION (
+            id TEXT NOT NULL PRIMARY KEY
+        );
+
+        CREATE TABLE IF NOT EXISTS ZV_CLOCK (
+            id TEXT NOT NULL PRIMARY KEY,
+            clock TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS ZV_CHANGE_BUNDLE (
+            change_id TEXT NOT NULL PRIMARY KEY,
+            bundle_id TEXT NOT NULL,
+            inserted BOOL NOT NULL DEFAULT FALSE,
+            FOREIGN KEY (change_id) REFERENCES ZV_CHANGE(id)
+        );
+
+        CREATE INDEX IF NOT EXISTS ZV_CHANGE_BUNDLE_BUNDLE_ID ON ZV_CHANGE_BUNDLE (bundle_id);
     ")?;
+
+    // Databases created before authors and clocks were recorded
+    for column in ["author", "clock_id"] {
+        let has_column: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('ZV_CHANGE') WHERE name = ?", [column], |row| row.get(0))?;
+        if !has_column {
+            conn.execute_batch(&format!("ALTER TABLE ZV_CHANGE ADD COLUMN {} TEXT", column))?;
+        }
+    }
     Ok(())
 }
 
 pub (crate) fn track_changes(txn: &DbTransaction, table_name: &str, entity_id: &str, 
         old_entity: Option<&DbValue>, 
         new_entity: &DbValue,
-        column_names: &[String]) -> Result<()> {
+        column_names: &[String],
+        id_column: &str) -> Result<()> {
     
-    let author_id = txn.db().get_database_uuid()?;
+    let author_id = txn.db().author_id().to_string();
     
     // Compute the diff between old and new entities
-    let field_changes = compute_entity_changes(old_entity, new_entity, column_names);
+    let field_changes = compute_entity_changes(old_entity, new_entity, column_names, id_column);
     
-    // Only create a change record if there are actual changes
+    // Only create a change record if there are actual changes. The records
+    // are written in bulk by write_changes() when the transaction commits.
     if !field_changes.is_empty() {
-        let change_id = Uuid::now_v7().to_string();
-        
-        // Insert the change record
-        txn.txn().execute(
-            "INSERT INTO ZV_CHANGE (id, author_id, entity_type, entity_id, merged) VALUES (?, ?, ?, ?, true)",
-            rusqlite::params![
-                &change_id,
-                &author_id,
-                table_name,
-                entity_id,
-            ]
-        )?;
-        
-        // Insert individual field changes
-        for (field_name, sql_value) in field_changes {
-            txn.txn().execute(
-                "INSERT INTO ZV_CHANGE_FIELD (change_id, field_name, field_value) VALUES (?, ?, ?)",
-                rusqlite::params![
-                    &change_id,
-                    &field_name,
-                    &sql_value,
-                ]
-            )?;
-        }
+        txn.add_pending_change(PendingChange {
+            id: txn.db().next_uuid(),
+            author_id,
+            entity_type: table_name.to_string(),
+            entity_id: entity_id.to_string(),
+            fields: field_changes.into_iter().collect(),
+            bundle_id: txn.db().bundle_id().map(str::to_string),
+            inserted: old_entity.is_none(),
+            author: txn.db().author().map(str::to_string),
+        });
     }
     
     Ok(())
 }
 
+/// Field name used to record that an entity was deleted. The ZV prefix is
+/// reserved for internal use so it can't collide with a column name.
+pub const DELETED_FIELD_NAME: &str = "ZV_DELETED";
+
+/// Entity id of a bulk delete change, which deletes every entity of its type
+/// that has no newer change.
+pub const ALL_ENTITIES_ID: &str = "*";
+
+pub (crate) fn track_delete(txn: &DbTransaction, table_name: &str, entity_id: &str) -> Result<()> {
+    txn.add_pending_change(PendingChange {
+        id: txn.db().next_uuid(),
+        author_id: txn.db().author_id().to_string(),
+        entity_type: table_name.to_string(),
+        entity_id: entity_id.to_string(),
+        fields: vec![(DELETED_FIELD_NAME.to_string(), rusqlite::types::Value::Integer(1))],
+        bundle_id: txn.db().bundle_id().map(str::to_string),
+        inserted: false,
+        author: txn.db().author().map(str::to_string),
+    });
+    Ok(())
+}
+
+/// A locally tracked change waiting to be written to ZV_CHANGE and
+/// ZV_CHANGE_FIELD.
+#[derive(Debug)]
+pub (crate) struct PendingChange {
+    pub id: String,
+    pub author_id: String,
+    pub entity_type: String,
+    pub entity_id: String,
+    pub fields: Vec<(String, rusqlite::types::Value)>,
+    pub bundle_id: Option<String>,
+    /// True if the change created the entity. Recorded for bundled changes
+    /// so undo knows the entity didn't exist before.
+    pub inserted: bool,
+    pub author: Option<String>,
+}
+
+/// Write the pending changes using multi-row INSERTs, chunked to stay under
+/// SQLite's bound parameter limit.
+pub (crate) fn write_changes(txn: &DbTransaction, changes: &[PendingChange]) -> Result<()> {
+    const ROWS_PER_STATEMENT: usize = 200;
+
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    // Every change in the transaction gets the same clock, stored once
+    let clock_id = match load_clock(txn.txn())? {
+        Some(mut clock) => {
+            clock.increment(txn.db().author_id());
+            store_clock(txn.txn(), &clock)?;
+            txn.txn().execute("INSERT INTO ZV_CLOCK (id, clock) VALUES (?, ?)",
+                rusqlite::params![&changes[0].id, serde_json::to_string(&clock)?])?;
+            Some(changes[0].id.clone())
+        },
+        None => None,
+    };
+
+    for chunk in changes.chunks(ROWS_PER_STATEMENT) {
+        let sql = format!(
+            "INSERT INTO ZV_CHANGE (id, author_id, entity_type, entity_id, merged, author, clock_id) VALUES {}",
+            vec!["(?, ?, ?, ?, true, ?, ?)"; chunk.len()].join(", ")
+        );
+        let params = chunk.iter()
+            .flat_map(|c| [Some(&c.id), Some(&c.author_id), Some(&c.entity_type), Some(&c.entity_id), c.author.as_ref(), clock_id.as_ref()])
+            .map(|s| s.map_or(rusqlite::types::Value::Null, |s| rusqlite::types::Value::Text(s.clone())));
+        txn.txn().execute(&sql, rusqlite::params_from_iter(params))?;
+    }
+
+    let fields = changes.iter()
+        .flat_map(|c| c.fields.iter().map(move |(name, value)| (&c.id, name, value)))
+        .collect::<Vec<_>>();
+    for chunk in fields.chunks(ROWS_PER_STATEMENT) {
+        let sql = format!(
+            "INSERT INTO ZV_CHANGE_FIELD (change_id, field_name, field_value) VALUES {}",
+            vec!["(?, ?, ?)"; chunk.len()].join(", ")
+        );
+        let params = chunk.iter()
+            .flat_map(|(change_id, field_name, value)| [
+                rusqlite::types::Value::Text(change_id.to_string()),
+                rusqlite::types::Value::Text(field_name.to_string()),
+                (*value).clone(),
+            ]);
+        txn.txn().execute(&sql, rusqlite::params_from_iter(params))?;
+    }
+
+    let bundled = changes.iter()
+        .filter_map(|c| c.bundle_id.as_ref().map(|bundle_id| (&c.id, bundle_id, c.inserted)))
+        .collect::<Vec<_>>();
+    for chunk in bundled.chunks(ROWS_PER_STATEMENT) {
+        let sql = format!(
+            "INSERT INTO ZV_CHANGE_BUNDLE (change_id, bundle_id, inserted) VALUES {}",
+            vec!["(?, ?, ?)"; chunk.len()].join(", ")
+        );
+        let params = chunk.iter()
+            .flat_map(|(change_id, bundle_id, inserted)| [
+                rusqlite::types::Value::Text(change_id.to_string()),
+                rusqlite::types::Value::Text(bundle_id.to_string()),
+                rusqlite::types::Value::Integer(*inserted as i64),
+            ]);
+        txn.txn().execute(&sql, rusqlite::params_from_iter(params))?;
+    }
+
+    Ok(())
+}
+
+/// Revert the changes in a bundle, recording the reverting changes in the
+/// bundle undo:<bundle_id>. Each entity the bundle changed is restored to its
+/// state before the bundle's first change to it, rebuilt from the earlier
+/// changes, or deleted if it didn't exist. Fails without changing anything if
+/// an entity was changed after the bundle, its history was pruned, or it
+/// existed before the bundle without any tracked changes, such as a row
+/// written by save_untracked() or execute(), since its earlier state is
+/// unknown.
+pub (crate) fn undo_bundle(db: &Db, bundle_id: &str) -> Result<()> {
+    let changes = db.get_changes_for_bundle(bundle_id)?;
+    if changes.is_empty() {
+        return Err(anyhow!("bundle {} has no changes", bundle_id));
+    }
+    let bundle_change_ids = changes.iter().map(|c| c.id.as_str()).collect::<HashSet<_>>();
+
+    // The first change to each entity, in change order
+    let mut entities: Vec<&ChangelogChange> = Vec::new();
+    for change in &changes {
+        if change.entity_id == ALL_ENTITIES_ID {
+            return Err(anyhow!("bundle {} deletes every {}, which can't be undone", bundle_id, change.entity_type));
+        }
+        if !entities.iter().any(|e| e.entity_type == change.entity_type && e.entity_id == change.entity_id) {
+            entities.push(change);
+        }
+    }
+
+    db.with_bundle_id(&format!("undo:{}", bundle_id), |db| db.transaction(|txn| {
+        // In reverse, so that entities referenced by others are restored first
+        for change in entities.iter().rev() {
+            let later_change_ids = txn.txn().prepare_cached(
+                "SELECT id FROM ZV_CHANGE 
+                    WHERE entity_type = ? AND (entity_id = ? OR entity_id = ?) AND id > ?
+                    ORDER BY id"
+            )?
+                .query_map(rusqlite::params![change.entity_type, change.entity_id, ALL_ENTITIES_ID, change.id],
+                    |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(later_change_id) = later_change_ids.iter().find(|id| !bundle_change_ids.contains(id.as_str())) {
+                return Err(anyhow!("can't undo bundle {}: {} {} was changed after it by change {}",
+                    bundle_id, change.entity_type, change.entity_id, later_change_id));
+            }
+
+            let inserted = txn.txn().query_row(
+                "SELECT inserted FROM ZV_CHANGE_BUNDLE WHERE change_id = ?", [&change.id], |row| row.get(0))?;
+            match entity_state_before(txn, &change.entity_type, &change.entity_id, &change.id, inserted)? {
+                Some(values) => txn.save_row(&change.entity_type, &change.entity_id, &values)?,
+                None => {
+                    txn.delete_internal(&change.entity_type, &change.entity_id, true)?;
+                },
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// Rebuild an entity's column values just before the change with
+/// before_id by replaying its earlier changes, or None if it didn't exist.
+/// With no earlier changes the entity only didn't exist if the change
+/// inserted it, otherwise it was written untracked and this fails.
+fn entity_state_before(txn: &DbTransaction, entity_type: &str, entity_id: &str, before_id: &str, inserted: bool)
+        -> Result<Option<BTreeMap<String, rusqlite::types::Value>>> {
+    let mut stmt = txn.txn().prepare_cached(
+        "SELECT c.id, f.field_name, f.field_value FROM ZV_CHANGE c
+            LEFT JOIN ZV_CHANGE_FIELD f ON f.change_id = c.id
+            WHERE c.entity_type = ? AND (c.entity_id = ? OR c.entity_id = ?) AND c.id < ?
+            ORDER BY c.id"
+    )?;
+    let rows = stmt.query_map(rusqlite::params![entity_type, entity_id, ALL_ENTITIES_ID, before_id], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, rusqlite::types::Value>(2)?))
+    })?.collect::<Result<Vec<_>, _>>()?;
+    if rows.is_empty() && !inserted {
+        return Err(anyhow!("can't undo change {}: {} {} has no tracked state before it",
+            before_id, entity_type, entity_id));
+    }
+
+    let mut state: Option<BTreeMap<String, rusqlite::types::Value>> = None;
+    for (change_id, field_name, field_value) in rows {
+        let Some(field_name) = field_name else {
+            return Err(anyhow!("history of {} {} was pruned at change {}", entity_type, entity_id, change_id));
+        };
+        if field_name == DELETED_FIELD_NAME {
+            state = None;
+        } else {
+            state.get_or_insert_with(BTreeMap::new).insert(field_name, field_value);
+        }
+    }
+    Ok(state)
+}
+
+/// Metadata key for the greatest local change id known to be in remote
+/// storage, recorded by SyncEngine::sync().
+pub const SYNCED_THROUGH_KEY: &str = "synced_through_change_id";
+
+/// Prune the field values of local changes that are no longer needed,
+/// returning the number of changes pruned. A change is only pruned if it has
+/// been merged, has been synced, every one of its fields has a newer change
+/// to the same entity, and it is older than older_than_id and not among the
+/// keep_last_n newest changes to its entity, where given.
+///
+/// The ZV_CHANGE row is kept so that sync doesn't pull the change from remote
+/// storage again, but its clock is dropped along with its fields. Since a
+/// newer change wins every field, a pruned change would lose any merge, so
+/// it can be safely dropped.
+pub (crate) fn prune_changes(db: &Db, older_than_id: Option<&str>, keep_last_n: Option<usize>) -> Result<usize> {
+    let Some(synced_through_id) = db.get_metadata(SYNCED_THROUGH_KEY)? else {
+        return Ok(0);
+    };
+    let older_than_id = older_than_id.map(str::to_string).unwrap_or_else(|| Uuid::max().to_string());
+    let keep_last_n = keep_last_n.map(|n| n as i64).unwrap_or(0);
+    db.transaction(|txn| {
+        let change_ids = txn.txn().prepare(
+            "SELECT id FROM (
+                SELECT c.id, c.entity_type, c.entity_id, c.merged, ROW_NUMBER() OVER (
+                    PARTITION BY c.entity_type, c.entity_id ORDER BY c.id DESC) AS newness
+                FROM ZV_CHANGE c
+                WHERE EXISTS (SELECT 1 FROM ZV_CHANGE_FIELD f WHERE f.change_id = c.id)
+            ) c
+            WHERE c.merged = true AND c.id <= ?1 AND c.id < ?2 AND c.newness > ?3
+            AND NOT EXISTS (
+                SELECT 1 FROM ZV_CHANGE_FIELD f
+                WHERE f.change_id = c.id AND NOT EXISTS (
+                    SELECT 1 FROM ZV_CHANGE c2
+                    JOIN ZV_CHANGE_FIELD f2 ON f2.change_id = c2.id
+                    WHERE c2.entity_type = c.entity_type AND c2.entity_id = c.entity_id
+                    AND f2.field_name = f.field_name AND c2.id > c.id))"
+        )?
+            .query_map(rusqlite::params![synced_through_id, older_than_id, keep_last_n], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut delete_fields = txn.txn().prepare("DELETE FROM ZV_CHANGE_FIELD WHERE change_id = ?")?;
+        let mut clear_clock = txn.txn().prepare("UPDATE ZV_CHANGE SET clock_id = NULL WHERE id = ?")?;
+        for change_id in &change_ids {
+            delete_fields.execute([change_id])?;
+            clear_clock.execute([change_id])?;
+        }
+        txn.txn().execute("DELETE FROM ZV_CLOCK WHERE id NOT IN (
+            SELECT clock_id FROM ZV_CHANGE WHERE clock_id IS NOT NULL)", [])?;
+        Ok(change_ids.len())
+    })
+}
+
+/// Write the changes with ids at or after from_id, or all of them, as a
+/// SQLite script that reproduces their effect on the entity tables, in change
+/// id order. Each change becomes an UPDATE of the fields it set, followed by
+/// an INSERT of the entity if it doesn't exist yet, or a DELETE. Pruned
+/// changes have no fields and are skipped.
+///
+/// A change that doesn't set every NOT NULL column without a default can't
+/// insert its entity, so it only gets the UPDATE if the entity was inserted
+/// earlier in the script, or if the export is partial, where from_id is
+/// given and the target is expected to have the earlier entities. Otherwise
+/// the script would fail when run, so an error is returned instead.
+pub (crate) fn export_changes_as_sql(db: &Db, writer: &mut dyn Write, from_id: Option<&str>) -> Result<()> {
+    let exported_at: String = db.query_scalar("SELECT strftime('%Y-%m-%dT%H:%M:%fZ', 'now')", [])?;
+    writeln!(writer, "-- dimple_db change export")?;
+    writeln!(writer, "-- database_uuid: {}", db.get_database_uuid())?;
+    writeln!(writer, "-- exported_at: {}", exported_at)?;
+    writeln!(writer, "BEGIN;")?;
+    let mut export = SqlExport {
+        partial: from_id.is_some(),
+        required_columns: HashMap::new(),
+        inserted: HashSet::new(),
+    };
+    db.read_transaction(|txn| {
+        let mut stmt = txn.prepare(
+            "SELECT c.id, c.entity_type, c.entity_id, f.field_name, f.field_value
+                FROM ZV_CHANGE c
+                JOIN ZV_CHANGE_FIELD f ON f.change_id = c.id
+                WHERE c.id >= ?
+                ORDER BY c.id, f.field_name"
+        )?;
+        let mut rows = stmt.query([from_id.map(str::to_string).unwrap_or_else(|| Uuid::nil().to_string())])?;
+        let mut current: Option<(String, String, String)> = None;
+        let mut fields: Vec<(String, rusqlite::types::Value)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let change = (row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?);
+            if current.as_ref().is_some_and(|current| current.0 != change.0) {
+                let (change_id, entity_type, entity_id) = current.take().unwrap_or_default();
+                export.write_change(db, txn, writer, &change_id, &entity_type, &entity_id, &std::mem::take(&mut fields))?;
+            }
+            current = Some(change);
+            fields.push((row.get(3)?, row.get(4)?));
+        }
+        if let Some((change_id, entity_type, entity_id)) = current {
+            export.write_change(db, txn, writer, &change_id, &entity_type, &entity_id, &fields)?;
+        }
+        Ok(())
+    })?;
+    writeln!(writer, "COMMIT;")?;
+    Ok(())
+}
+
+/// State kept while writing export_changes_as_sql().
+struct SqlExport {
+    partial: bool,
+    /// NOT NULL columns without a default, other than the id, by table.
+    required_columns: HashMap<String, Vec<String>>,
+    /// The entities inserted so far, as (entity_type, entity_id).
+    inserted: HashSet<(String, String)>,
+}
+
+impl SqlExport {
+    #[allow(clippy::too_many_arguments)]
+    fn write_change(&mut self, db: &Db, conn: &Connection, writer: &mut dyn Write, change_id: &str,
+            entity_type: &str, entity_id: &str, fields: &[(String, rusqlite::types::Value)]) -> Result<()> {
+        let table = sql_identifier(entity_type);
+        let id_column_name = db.table_id_column(conn, entity_type)?;
+        let id_column = sql_identifier(&id_column_name);
+        let id = sql_literal(&entity_id.to_string().into())?;
+        let key = (entity_type.to_string(), entity_id.to_string());
+        if fields.iter().any(|(field_name, _)| field_name == DELETED_FIELD_NAME) {
+            if entity_id == ALL_ENTITIES_ID {
+                writeln!(writer, "DELETE FROM {};", table)?;
+                self.inserted.retain(|(inserted_type, _)| inserted_type != entity_type);
+            } else {
+                writeln!(writer, "DELETE FROM {} WHERE {} = {};", table, id_column, id)?;
+                self.inserted.remove(&key);
+            }
+            return Ok(());
+        }
+
+        let columns = fields.iter().map(|(field_name, _)| sql_identifier(field_name)).collect::<Vec<_>>();
+        let values = fields.iter().map(|(_, value)| sql_literal(value)).collect::<Result<Vec<_>>>()?;
+        let assignments = columns.iter().zip(&values)
+            .map(|(column, value)| format!("{} = {}", column, value))
+            .collect::<Vec<_>>();
+        writeln!(writer, "UPDATE {} SET {} WHERE {} = {};", table, assignments.join(", "), id_column, id)?;
+
+        if !self.required_columns.contains_key(entity_type) {
+            let required = conn.prepare("SELECT name FROM pragma_table_info(?)
+                    WHERE \"notnull\" = 1 AND dflt_value IS NULL AND name != ?")?
+                .query_map([entity_type, &id_column_name], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            self.required_columns.insert(entity_type.to_string(), required);
+        }
+        let missing = self.required_columns[entity_type].iter()
+            .find(|column| !fields.iter().any(|(field_name, _)| field_name == *column));
+        match missing {
+            None => {
+                writeln!(writer, "INSERT INTO {} ({}, {}) SELECT {}, {} WHERE NOT EXISTS (SELECT 1 FROM {} WHERE {} = {});",
+                    table, id_column, columns.join(", "), id, values.join(", "), table, id_column, id)?;
+                self.inserted.insert(key);
+            },
+            Some(_) if self.partial || self.inserted.contains(&key) => {},
+            Some(column) => return Err(anyhow!(
+                "change {} to {} {} can't insert it without NOT NULL column {}, and no earlier change does",
+                change_id, entity_type, entity_id, column)),
+        }
+        Ok(())
+    }
+}
+
+fn sql_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_literal(value: &rusqlite::types::Value) -> Result<String> {
+    use rusqlite::types::Value;
+
+    Ok(match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) if f.is_nan() => return Err(anyhow!("NaN has no SQL literal")),
+        Value::Real(f) if f.is_infinite() => if *f > 0.0 { "9e999" } else { "-9e999" }.to_string(),
+        Value::Real(f) => format!("{:?}", f),
+        Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Blob(b) => format!("X'{}'", b.iter().map(|b| format!("{:02X}", b)).collect::<String>()),
+    })
+}
+
 /// Convert DbValue to a map for easier access
 fn dbvalue_to_map(db_value: &DbValue) -> BTreeMap<String, rusqlite::types::Value> {
     let mut map = BTreeMap::new();
@@ -229,14 +704,15 @@ fn dbvalue_to_map(db_value: &DbValue) -> BTreeMap<String, rusqlite::types::Value
 /// Compute the changes to track, returning only changed/new fields
 fn compute_entity_changes(old_entity: Option<&DbValue>, 
                           new_entity: &DbValue,
-                          column_names: &[String]) -> BTreeMap<String, rusqlite::types::Value> {
+                          column_names: &[String],
+                          id_column: &str) -> BTreeMap<String, rusqlite::types::Value> {
     let mut field_changes = BTreeMap::new();
     
     let old_map = old_entity.map(dbvalue_to_map);
     let new_map = dbvalue_to_map(new_entity);
     
     for column_name in column_names {
-        if column_name == "id" {
+        if column_name == id_column {
             continue;
         }
         
@@ -272,7 +748,11 @@ fn compute_entity_changes(old_entity: Option<&DbValue>,
 ///     1. Read the entity
 ///     2. Update the entity fields from the values in the group
 ///     3. Save the entity
-pub (crate) fn merge_unmerged_changes(db: &Db) -> Result<()> {
+///
+/// If a resolver is given, fields that conflict are resolved with it. A
+/// resolution that differs from the merged value is saved as a new local
+/// change, so it syncs to every other replica.
+pub (crate) fn merge_unmerged_changes(db: &Db, resolver: Option<&ConflictResolver>) -> Result<()> {
     db.transaction(|txn| {
         // Get unmerged changes
         // Vec<ChangeRecord>
@@ -294,6 +774,23 @@ pub (crate) fn merge_unmerged_changes(db: &Db) -> Result<()> {
         // HashMap<(entity_type, entity_id, attribute), AttributeChange>
         let newest_changes = reduce_to_newest_changes(attribute_changes);
 
+        // Resolve conflicts before the merge, while the local values are
+        // still the newest local changes
+        let mut resolutions: BTreeMap<(String, String), BTreeMap<String, rusqlite::types::Value>> = BTreeMap::new();
+        if let Some(resolver) = resolver {
+            for change in newest_changes.values() {
+                if change.entity_id == ALL_ENTITIES_ID || change.attribute == DELETED_FIELD_NAME {
+                    continue;
+                }
+                if let Some(value) = resolve_conflict(txn, resolver, &change.entity_type, &change.entity_id,
+                        &change.attribute, &change.change_id, &change.new_value)? {
+                    resolutions.entry((change.entity_type.clone(), change.entity_id.clone()))
+                        .or_default()
+                        .insert(change.attribute.clone(), value);
+                }
+            }
+        }
+
         // Group by entity and apply updates
         // HashMap<(entity_type, entity_id), Vec<AttributeChange>>
         let entity_updates = group_changes_by_entity(newest_changes);
@@ -310,6 +807,17 @@ pub (crate) fn merge_unmerged_changes(db: &Db) -> Result<()> {
 
         // Apply all entity updates in sorted order
         for ((entity_type, entity_id), changes) in sorted_updates {
+            if entity_id == ALL_ENTITIES_ID {
+                // Bulk delete, every existing entity decides for itself
+                let id_column = txn.db().table_id_column(txn.txn(), &entity_type)?;
+                let entity_ids = txn.txn().prepare(&format!("SELECT {} FROM {}", id_column, entity_type))?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                for entity_id in entity_ids {
+                    apply_entity_updates(txn, &entity_type, &entity_id, Vec::new())?;
+                }
+                continue;
+            }
             apply_entity_updates(txn, &entity_type, &entity_id, changes)?;
         }
 
@@ -319,6 +827,23 @@ pub (crate) fn merge_unmerged_changes(db: &Db) -> Result<()> {
             []
         )?;
 
+        for ((entity_type, entity_id), fields) in resolutions {
+            // Nothing to resolve if the entity was deleted
+            let Some(mut values) = txn.get_row_values(&entity_type, &entity_id)? else {
+                continue;
+            };
+            let mut changed = false;
+            for (field_name, value) in fields {
+                if values.get(&field_name) != Some(&value) {
+                    values.insert(field_name, value);
+                    changed = true;
+                }
+            }
+            if changed {
+                txn.save_row(&entity_type, &entity_id, &values)?;
+            }
+        }
+
         Ok(())
     })
 }
@@ -385,10 +910,44 @@ fn group_changes_by_entity(newest_changes: HashMap<(String, String, String), Att
 }
 
 fn apply_entity_updates(txn: &DbTransaction, entity_type: &str, entity_id: &str, changes: Vec<AttributeChange>) -> Result<()> {
-    let exists = entity_exists(txn, entity_type, entity_id)?;
+    // Loaded once, for the existence check and the event's old_data
+    let old_data = txn.get_row_json(entity_type, entity_id)?;
+    let exists = old_data.is_some();
+
+    // A delete, of the entity or a bulk delete of its type, wins over every
+    // older change to the entity. If it is the newest change the entity is
+    // deleted, otherwise the entity was saved again after the delete and
+    // only the newer changes apply.
+    let latest_delete_id: Option<String> = txn.txn().query_row(
+        "SELECT c.id FROM ZV_CHANGE c 
+            JOIN ZV_CHANGE_FIELD cf ON c.id = cf.change_id 
+            WHERE c.entity_type = ? AND (c.entity_id = ? OR c.entity_id = ?)
+            AND cf.field_name = ?
+            ORDER BY c.id DESC 
+            LIMIT 1",
+        rusqlite::params![entity_type, entity_id, ALL_ENTITIES_ID, DELETED_FIELD_NAME],
+        |row| row.get(0)
+    ).optional()?;
+    if let Some(delete_id) = &latest_delete_id {
+        let latest_change_id: String = txn.txn().query_row(
+            "SELECT MAX(id) FROM ZV_CHANGE WHERE entity_type = ? AND (entity_id = ? OR entity_id = ?)",
+            rusqlite::params![entity_type, entity_id, ALL_ENTITIES_ID],
+            |row| row.get(0)
+        )?;
+        if &latest_change_id == delete_id {
+            if exists {
+                txn.delete_internal(entity_type, entity_id, false)?;
+            }
+            return Ok(());
+        }
+    }
+    let changes = changes.into_iter()
+        .filter(|c| latest_delete_id.as_ref().map_or(true, |delete_id| &c.change_id > delete_id))
+        .collect::<Vec<_>>();
     
     // Get table columns
     let column_names = txn.db().table_column_names(txn.txn(), entity_type)?;
+    let id_column = txn.db().table_id_column(txn.txn(), entity_type)?;
     
     // Build a map of column -> value for the changes we need to apply
     let mut updates: HashMap<String, rusqlite::types::Value> = HashMap::new();
@@ -437,7 +996,7 @@ fn apply_entity_updates(txn: &DbTransaction, entity_type: &str, entity_id: &str,
             return Ok(());
         }
         
-        let sql = format!("UPDATE {} SET {} WHERE id = ?", entity_type, set_clauses.join(", "));
+        let sql = format!("UPDATE {} SET {} WHERE {} = ? RETURNING *", entity_type, set_clauses.join(", "), id_column);
         
         // Build parameters
         let mut params: Vec<rusqlite::types::Value> = updates.iter()
@@ -446,18 +1005,23 @@ fn apply_entity_updates(txn: &DbTransaction, entity_type: &str, entity_id: &str,
             .collect();
         params.push(rusqlite::types::Value::Text(entity_id.to_string()));
         
-        txn.txn().execute(&sql, rusqlite::params_from_iter(params))?;
+        let data = execute_returning_json(txn, &sql, params)?;
         
         // Queue update event for notification
-        txn.add_pending_event(DbEvent::Update(entity_type.to_string(), entity_id.to_string()));
+        txn.add_pending_event(DbEvent::Update {
+            entity_type: entity_type.to_string(),
+            entity_id: entity_id.to_string(),
+            data: Arc::new(data),
+            old_data: old_data.map(Arc::new),
+        });
     } else {
         // Build INSERT statement
-        let mut insert_columns = vec!["id"];
+        let mut insert_columns = vec![id_column.as_str()];
         let mut placeholders = vec!["?"];
         let mut params = vec![rusqlite::types::Value::Text(entity_id.to_string())];
         
         for col in &column_names {
-            if col != "id" && updates.contains_key(col) {
+            if *col != id_column && updates.contains_key(col) {
                 insert_columns.push(col);
                 placeholders.push("?");
                 params.push(updates.get(col).unwrap().clone());
@@ -465,27 +1029,31 @@ fn apply_entity_updates(txn: &DbTransaction, entity_type: &str, entity_id: &str,
         }
         
         let sql = format!(
-            "INSERT INTO {} ({}) VALUES ({})", 
+            "INSERT INTO {} ({}) VALUES ({}) RETURNING *", 
             entity_type, 
             insert_columns.join(", "),
             placeholders.join(", ")
         );
         
-        txn.txn().execute(&sql, rusqlite::params_from_iter(params))?;
+        let data = execute_returning_json(txn, &sql, params)?;
         
         // Queue insert event for notification
-        txn.add_pending_event(DbEvent::Insert(entity_type.to_string(), entity_id.to_string()));
+        txn.add_pending_event(DbEvent::Insert {
+            entity_type: entity_type.to_string(),
+            entity_id: entity_id.to_string(),
+            data: Arc::new(data),
+        });
     }
 
     Ok(())
 }
 
-fn entity_exists(txn: &DbTransaction, entity_type: &str, entity_id: &str) -> Result<bool> {
-    Ok(txn.txn().query_row(
-        &format!("SELECT 1 FROM {} WHERE id = ?", entity_type),
-        rusqlite::params![entity_id],
-        |_| Ok(())
-    ).is_ok())
+/// Runs an INSERT or UPDATE of a single row with a RETURNING * clause,
+/// returning the row as written, so events don't need to read it back.
+fn execute_returning_json(txn: &DbTransaction, sql: &str, params: Vec<rusqlite::types::Value>) -> Result<serde_json::Value> {
+    let mut stmt = txn.txn().prepare(sql)?;
+    let column_names = stmt.column_names().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    Ok(stmt.query_row(rusqlite::params_from_iter(params), |row| row_to_json(row, &column_names))?)
 }
 
 #[cfg(test)]
@@ -513,11 +1081,11 @@ mod tests {
     }
 
     fn get_changes(db: &Db, entity_id: &str) -> Result<Vec<ChangelogChange>> {
-        db.query(
+        Ok(db.query(
             "SELECT id, author_id, entity_type, entity_id, merged 
              FROM ZV_CHANGE WHERE entity_id = ? ORDER BY id",
             [entity_id]
-        )
+        )?)
     }
     
     struct TestFieldRecord {
@@ -576,6 +1144,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn db_changelog_filters_by_type() -> Result<()> {
+        #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+        struct Album {
+            pub id: String,
+            pub title: String,
+        }
+
+        let db = setup_db()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (name TEXT NOT NULL, summary TEXT, id TEXT NOT NULL PRIMARY KEY);"),
+            M::up("CREATE TABLE Album (title TEXT NOT NULL, id TEXT NOT NULL PRIMARY KEY);"),
+        ]))?;
+        let changelog = DbChangelog::new(db.clone());
+        db.save(&Artist { name: "The Beatles".to_string(), ..Default::default() })?;
+        let album = db.save(&Album { title: "Abbey Road".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Pink Floyd".to_string(), ..Default::default() })?;
+
+        let album_ids = changelog.get_change_ids_for_types(&["Album"])?;
+        assert_eq!(album_ids.len(), 1);
+        assert_eq!(changelog.get_change_ids_for_types(&["Artist"])?.len(), 2);
+        assert_eq!(changelog.get_change_ids_for_types(&["Artist", "Album"])?, changelog.get_all_change_ids()?);
+        let albums = changelog.get_changes_for_types(&["Album"], None, None)?;
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].change.id, album_ids[0]);
+        assert_eq!(albums[0].change.entity_id, album.id);
+        Ok(())
+    }
+
     #[test]
     fn db_changelog_append_changes() -> Result<()> {
         let db = setup_db()?;
@@ -584,7 +1181,7 @@ mod tests {
         // Create a change to append
         let change = ChangelogChangeWithFields {
             change: ChangelogChange {
-                id: "01234567-1234-1234-1234-123456789012".to_string(),
+                id: "01234567-1234-7234-8234-123456789012".to_string(),
                 author_id: "author1".to_string(),
                 entity_type: "Artist".to_string(),
                 entity_id: "artist1".to_string(),
@@ -593,9 +1190,11 @@ mod tests {
             fields: vec![
                 RemoteFieldRecord {
                     field_name: "name".to_string(),
-                    field_value: rmpv::Value::String("Test Artist".to_string().into()),
+                    field_value: serde_json::Value::String("Test Artist".to_string()),
                 },
             ],
+            clock: None,
+            author: None,
         };
         
         // Append the change
@@ -604,19 +1203,89 @@ mod tests {
         // Verify it was inserted
         let all_changes = changelog.get_all_change_ids()?;
         assert_eq!(all_changes.len(), 1);
-        assert_eq!(all_changes[0], "01234567-1234-1234-1234-123456789012");
+        assert_eq!(all_changes[0], "01234567-1234-7234-8234-123456789012");
         
         // Verify the change has the correct merged status
         let changes = db.query::<ChangelogChange, _>(
             "SELECT id, author_id, entity_type, entity_id, merged FROM ZV_CHANGE WHERE id = ?",
-            ["01234567-1234-1234-1234-123456789012"]
+            ["01234567-1234-7234-8234-123456789012"]
         )?;
         assert_eq!(changes.len(), 1);
-        assert_eq!(changes[0].merged, true);
+        assert!(changes[0].merged);
         
         Ok(())
     }
 
+    #[test]
+    fn append_changes_notifies_subscribers() -> Result<()> {
+        let db = setup_db()?;
+        let changelog = DbChangelog::new(db.clone());
+        let receiver = db.subscribe();
+        changelog.append_changes(vec![ChangelogChangeWithFields {
+            change: ChangelogChange {
+                id: "01234567-1234-7234-8234-123456789012".to_string(),
+                author_id: "author1".to_string(),
+                entity_type: "Artist".to_string(),
+                entity_id: "artist1".to_string(),
+                merged: false,
+            },
+            fields: vec![RemoteFieldRecord {
+                field_name: "name".to_string(),
+                field_value: serde_json::Value::String("Test Artist".to_string()),
+            }],
+            clock: None,
+            author: None,
+        }])?;
+
+        match receiver.try_recv()? {
+            crate::db::DbEvent::Insert { entity_type, entity_id, data } => {
+                assert_eq!(entity_type, "Artist");
+                assert_eq!(entity_id, "artist1");
+                assert_eq!(data["name"], "Test Artist");
+            }
+            event => panic!("Expected Insert event, got {:?}", event),
+        }
+        assert!(receiver.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn append_changes_applies_newest_change_regardless_of_order() -> Result<()> {
+        let name_change = |id: &str, name: &str| ChangelogChangeWithFields {
+            change: ChangelogChange {
+                id: id.to_string(),
+                author_id: "author1".to_string(),
+                entity_type: "Artist".to_string(),
+                entity_id: "artist1".to_string(),
+                merged: false,
+            },
+            fields: vec![RemoteFieldRecord {
+                field_name: "name".to_string(),
+                field_value: serde_json::Value::String(name.to_string()),
+            }],
+            clock: None,
+            author: None,
+        };
+        let older = "01234567-1234-7234-8234-123456789012";
+        let newer = "01234567-1234-7234-8234-123456789013";
+
+        // Newest first in a single append
+        let db = setup_db()?;
+        DbChangelog::new(db.clone()).append_changes(vec![
+            name_change(newer, "Newer"),
+            name_change(older, "Older"),
+        ])?;
+        assert_eq!(db.get::<Artist>("artist1")?.unwrap().name, "Newer");
+
+        // Newest first in separate appends
+        let db = setup_db()?;
+        let changelog = DbChangelog::new(db.clone());
+        changelog.append_changes(vec![name_change(newer, "Newer")])?;
+        changelog.append_changes(vec![name_change(older, "Older")])?;
+        assert_eq!(db.get::<Artist>("artist1")?.unwrap().name, "Newer");
+        Ok(())
+    }
+
     #[test]
     fn insert_creates_change_records() -> Result<()> {
         let db = setup_db()?;
@@ -634,6 +1303,38 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn transaction_writes_all_changes_on_commit() -> Result<()> {
+        let db = setup_db()?;
+        let artists = db.transaction(|txn| {
+            (0..250)
+                .map(|i| txn.save(&Artist { name: format!("Artist {}", i), ..Default::default() }))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        
+        let changelog = DbChangelog::new(db.clone());
+        assert_eq!(changelog.get_all_change_ids()?.len(), 250);
+        let changes = get_changes(&db, &artists[249].id)?;
+        assert_eq!(changes.len(), 1);
+        let fields = get_change_fields(&db, &changes[0].id)?;
+        assert_eq!(fields.len(), 2);
+        let name_field = fields.iter().find(|f| f.field_name == "name").unwrap();
+        assert_eq!(get_field_value_as_string(name_field), "Artist 249");
+        Ok(())
+    }
+
+    #[test]
+    fn rolled_back_transaction_writes_no_changes() -> Result<()> {
+        let db = setup_db()?;
+        let result = db.transaction(|txn| -> Result<()> {
+            txn.save(&Artist { name: "Rolled Back".to_string(), ..Default::default() })?;
+            anyhow::bail!("rollback");
+        });
+        assert!(result.is_err());
+        assert!(DbChangelog::new(db).get_all_change_ids()?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn update_only_tracks_modified_fields() -> Result<()> {
         let db = setup_db()?;
@@ -667,7 +1368,7 @@ mod tests {
         
         assert_eq!(changes.len(), 1);
         assert_eq!(changes[0].entity_type, "Artist");
-        assert_eq!(changes[0].author_id, db.get_database_uuid()?);
+        assert_eq!(changes[0].author_id, db.author_id());
         Ok(())
     }
 
@@ -730,4 +1431,13 @@ mod tests {
         
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sql_literal_rejects_nan() -> Result<()> {
+        use rusqlite::types::Value;
+        assert_eq!(super::sql_literal(&Value::Real(1.5))?, "1.5");
+        assert_eq!(super::sql_literal(&Value::Real(f64::INFINITY))?, "9e999");
+        assert!(super::sql_literal(&Value::Real(f64::NAN)).is_err());
+        Ok(())
+    }
+}
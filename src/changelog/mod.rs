@@ -1,16 +1,26 @@
+#[allow(clippy::module_inception)]
 pub mod changelog;
 pub mod basic_storage_changelog;
 pub mod batching_storage_changelog;
+pub mod conflict;
 pub mod db_changelog;
+pub mod statistics;
+pub mod vector_clock;
+
+use std::hash::{Hash, Hasher};
 
 pub use changelog::*;
 use serde::{Deserialize, Serialize};
-pub use basic_storage_changelog::BasicStorageChangelog;
+pub use basic_storage_changelog::{BasicStorageChangelog, CompactionStats};
 pub use batching_storage_changelog::BatchingStorageChangelog;
+pub use conflict::{ConflictContext, ConflictResolution, ConflictResolver};
 pub use db_changelog::*;
+pub use statistics::{AuthorStats, ChangeTableStats};
+pub use vector_clock::VectorClock;
 
-/// Represents a change record in the ZV_CHANGE table
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Represents a change record in the ZV_CHANGE table. Equality compares all
+/// fields, hashing uses only the id, which is unique.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChangelogChange {
     pub id: String,
     pub author_id: String,
@@ -19,23 +29,80 @@ pub struct ChangelogChange {
     pub merged: bool,
 }
 
+impl Hash for ChangelogChange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
 /// Represents a field change record in the ZV_CHANGE_FIELD table
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ChangelogField {
     pub change_id: String,
     pub field_name: String,
     pub field_value: Vec<u8>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChangelogChangeWithFields {
     pub change: ChangelogChange,
     pub fields: Vec<RemoteFieldRecord>,
+    /// The author's VectorClock when the change was made. None for changes
+    /// from replicas that don't send clocks, which are ordered by id alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<VectorClock>,
+    /// The author set with Db::with_author() when the change was made, for
+    /// attribution only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
 }
 
 /// Simplified field record for remote storage (no change_id since it's in the parent)
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RemoteFieldRecord {
     pub field_name: String,
-    pub field_value: rmpv::Value,
+    /// The column value. Blobs are arrays of bytes. Encoded as a MessagePack
+    /// value on the wire.
+    #[serde(with = "msgpack_field_value")]
+    pub field_value: serde_json::Value,
+}
+
+mod msgpack_field_value {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::sync::sync_engine::{field_value_from_json, field_value_to_json};
+
+    pub fn serialize<S: Serializer>(value: &serde_json::Value, serializer: S) -> Result<S::Ok, S::Error> {
+        field_value_from_json(value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<serde_json::Value, D::Error> {
+        rmpv::Value::deserialize(deserializer).map(|value| field_value_to_json(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::ChangelogChange;
+
+    #[test]
+    fn changes_in_hash_set() {
+        let change = ChangelogChange {
+            id: "change-1".to_string(),
+            author_id: "author-1".to_string(),
+            entity_type: "Artist".to_string(),
+            entity_id: "artist-1".to_string(),
+            merged: false,
+        };
+        let merged = ChangelogChange { merged: true, ..change.clone() };
+        assert_ne!(change, merged);
+
+        let mut changes = HashSet::new();
+        assert!(changes.insert(change.clone()));
+        assert!(!changes.insert(change.clone()));
+        assert!(changes.insert(merged));
+        assert_eq!(changes.len(), 2);
+    }
 }
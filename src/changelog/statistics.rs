@@ -0,0 +1,145 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{changelog::{ALL_ENTITIES_ID, DELETED_FIELD_NAME}, Db};
+
+/// Change counts for one entity type. Returned by Db::get_change_statistics().
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeTableStats {
+    pub entity_type: String,
+    pub total_changes: i64,
+    /// Changes that created an entity, either its first change or the first
+    /// after it was deleted.
+    pub inserts: i64,
+    pub updates: i64,
+    /// Delete changes, including bulk deletes.
+    pub deletes: i64,
+    /// When the newest change was made, in milliseconds since the Unix
+    /// epoch, or 0 if its id isn't a UUIDv7.
+    pub last_change_at: i64,
+}
+
+/// Change counts for one author. Returned by Db::get_author_statistics().
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorStats {
+    pub author_id: String,
+    pub total_changes: i64,
+    pub first_change_id: String,
+    pub last_change_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChangeTableRow {
+    entity_type: String,
+    total_changes: i64,
+    inserts: i64,
+    updates: i64,
+    deletes: i64,
+    last_change_id: String,
+}
+
+pub (crate) fn change_statistics(db: &Db) -> Result<Vec<ChangeTableStats>> {
+    let rows = db.query::<ChangeTableRow, _>(
+        "WITH changes AS (
+            SELECT c.id, c.entity_type, c.entity_id,
+                EXISTS (SELECT 1 FROM ZV_CHANGE_FIELD f
+                    WHERE f.change_id = c.id AND f.field_name = ?1) AS is_delete
+            FROM ZV_CHANGE c
+        ), ordered AS (
+            SELECT *, LAG(is_delete) OVER (
+                PARTITION BY entity_type, entity_id ORDER BY id) AS previous_is_delete
+            FROM changes
+        )
+        SELECT entity_type,
+            COUNT(*) AS total_changes,
+            SUM(CASE WHEN NOT is_delete AND entity_id != ?2
+                AND COALESCE(previous_is_delete, 1) THEN 1 ELSE 0 END) AS inserts,
+            SUM(CASE WHEN NOT is_delete AND previous_is_delete = 0 THEN 1 ELSE 0 END) AS updates,
+            SUM(is_delete) AS deletes,
+            MAX(id) AS last_change_id
+        FROM ordered
+        GROUP BY entity_type
+        ORDER BY entity_type",
+        rusqlite::params![DELETED_FIELD_NAME, ALL_ENTITIES_ID],
+    )?;
+    Ok(rows.into_iter().map(|row| ChangeTableStats {
+        entity_type: row.entity_type,
+        total_changes: row.total_changes,
+        inserts: row.inserts,
+        updates: row.updates,
+        deletes: row.deletes,
+        last_change_at: change_id_millis(&row.last_change_id).unwrap_or(0),
+    }).collect())
+}
+
+pub (crate) fn author_statistics(db: &Db) -> Result<Vec<AuthorStats>> {
+    Ok(db.query(
+        "SELECT author_id,
+            COUNT(*) AS total_changes,
+            MIN(id) AS first_change_id,
+            MAX(id) AS last_change_id
+        FROM ZV_CHANGE
+        GROUP BY author_id
+        ORDER BY author_id",
+        (),
+    )?)
+}
+
+/// The creation time of a UUIDv7 change id in milliseconds since the Unix
+/// epoch.
+fn change_id_millis(change_id: &str) -> Option<i64> {
+    let (secs, nanos) = Uuid::parse_str(change_id).ok()?.get_timestamp()?.to_unix();
+    Some(secs as i64 * 1000 + nanos as i64 / 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::Db;
+
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct Album {
+        id: String,
+        title: String,
+    }
+
+    #[test]
+    fn change_and_author_statistics() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT NOT NULL);"),
+        ]))?;
+        let start = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
+        let artist = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let artist = db.save(&Artist { name: "Metallica!".to_string(), ..artist })?;
+        db.delete(&artist)?;
+        db.save(&Artist { name: "Metallica".to_string(), ..artist })?;
+        db.save(&Album { title: "Master of Puppets".to_string(), ..Default::default() })?;
+
+        let stats = db.get_change_statistics()?;
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].entity_type, "Album");
+        assert_eq!((stats[0].total_changes, stats[0].inserts, stats[0].updates, stats[0].deletes), (1, 1, 0, 0));
+        assert_eq!(stats[1].entity_type, "Artist");
+        assert_eq!((stats[1].total_changes, stats[1].inserts, stats[1].updates, stats[1].deletes), (4, 2, 1, 1));
+        assert!(stats[1].last_change_at >= start);
+
+        let authors = db.get_author_statistics()?;
+        assert_eq!(authors.len(), 1);
+        assert_eq!(authors[0].author_id, db.author_id());
+        assert_eq!(authors[0].total_changes, 5);
+        assert!(authors[0].first_change_id < authors[0].last_change_id);
+        Ok(())
+    }
+}
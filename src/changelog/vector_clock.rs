@@ -0,0 +1,131 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension as _};
+use serde::{Deserialize, Serialize};
+
+/// Metadata key for this database's vector clock, as JSON.
+pub const VECTOR_CLOCK_KEY: &str = "vector_clock";
+
+/// Number of changes, or rather transactions, each author had made when a
+/// change was written. If every entry of one clock is less than or equal to
+/// the other's, the first change happened before the second, and its author
+/// had seen it. If neither is, the changes are concurrent. Serialized as a
+/// map from author id to sequence number.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(pub BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn get(&self, author_id: &str) -> u64 {
+        self.0.get(author_id).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, author_id: &str) {
+        *self.0.entry(author_id.to_string()).or_insert(0) += 1;
+    }
+
+    /// Take the greater sequence number of each author.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (author_id, sequence) in &other.0 {
+            let entry = self.0.entry(author_id.clone()).or_insert(0);
+            *entry = (*entry).max(*sequence);
+        }
+    }
+
+    /// Less if self happened before other, Greater if after, Equal if the
+    /// clocks are the same and None if they are concurrent.
+    pub fn causal_cmp(&self, other: &VectorClock) -> Option<Ordering> {
+        let (mut less, mut greater) = (false, false);
+        for author_id in self.0.keys().chain(other.0.keys()) {
+            match self.get(author_id).cmp(&other.get(author_id)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {},
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+
+    pub fn is_concurrent(&self, other: &VectorClock) -> bool {
+        self.causal_cmp(other).is_none()
+    }
+}
+
+/// This database's clock, or None if vector clocks have not been enabled.
+pub (crate) fn load_clock(conn: &Connection) -> Result<Option<VectorClock>> {
+    let json: Option<String> = conn.query_row(
+        "SELECT value FROM ZV_METADATA WHERE key = ?", [VECTOR_CLOCK_KEY], |row| row.get(0))
+        .optional()?;
+    Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+}
+
+pub (crate) fn store_clock(conn: &Connection, clock: &VectorClock) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO ZV_METADATA (key, value) VALUES (?, ?)",
+        [VECTOR_CLOCK_KEY, &serde_json::to_string(clock)?])?;
+    Ok(())
+}
+
+/// Start recording a clock with each transaction's changes. The local
+/// changes made before this are given one clock between them, as if they
+/// had been made in a single transaction. Does nothing if clocks are
+/// already enabled.
+pub (crate) fn enable_clocks(conn: &Connection, author_id: &str) -> Result<()> {
+    if load_clock(conn)?.is_some() {
+        return Ok(());
+    }
+    let first_change_id: Option<String> = conn.query_row(
+        "SELECT MIN(id) FROM ZV_CHANGE WHERE author_id = ? AND clock_id IS NULL", [author_id], |row| row.get(0))?;
+    let mut clock = VectorClock::default();
+    if let Some(first_change_id) = first_change_id {
+        clock.increment(author_id);
+        conn.execute("INSERT INTO ZV_CLOCK (id, clock) VALUES (?, ?)",
+            [&first_change_id, &serde_json::to_string(&clock)?])?;
+        conn.execute("UPDATE ZV_CHANGE SET clock_id = ? WHERE author_id = ? AND clock_id IS NULL",
+            [&first_change_id, author_id])?;
+    }
+    store_clock(conn, &clock)
+}
+
+/// The clock recorded with a change, if the change had one.
+pub (crate) fn change_clock(conn: &Connection, change_id: &str) -> Result<Option<VectorClock>> {
+    let json: Option<String> = conn.query_row(
+        "SELECT ZV_CLOCK.clock FROM ZV_CHANGE JOIN ZV_CLOCK ON (ZV_CHANGE.clock_id = ZV_CLOCK.id)
+            WHERE ZV_CHANGE.id = ?", [change_id], |row| row.get(0))
+        .optional()?;
+    Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::VectorClock;
+
+    fn clock(entries: &[(&str, u64)]) -> VectorClock {
+        VectorClock(entries.iter().map(|(author_id, sequence)| (author_id.to_string(), *sequence)).collect())
+    }
+
+    #[test]
+    fn causal_order() {
+        let a1 = clock(&[("a", 1)]);
+        let a1_b1 = clock(&[("a", 1), ("b", 1)]);
+        let a2 = clock(&[("a", 2)]);
+        assert_eq!(a1.causal_cmp(&a1_b1), Some(Ordering::Less));
+        assert_eq!(a1_b1.causal_cmp(&a1), Some(Ordering::Greater));
+        assert_eq!(a1.causal_cmp(&a1.clone()), Some(Ordering::Equal));
+        assert!(a2.is_concurrent(&a1_b1));
+        assert!(!VectorClock::default().is_concurrent(&a1));
+
+        let mut merged = a2.clone();
+        merged.merge(&a1_b1);
+        merged.increment("b");
+        assert_eq!(merged, clock(&[("a", 2), ("b", 2)]));
+        assert_eq!(serde_json::to_string(&merged).unwrap(), r#"{"a":2,"b":2}"#);
+    }
+}
@@ -0,0 +1,230 @@
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, Result};
+use rusqlite::{types::FromSql, Params};
+
+use crate::db::Db;
+
+/// SQL aggregate functions supported by Db::aggregate() and
+/// Db::aggregate_scalar().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateFunc {
+    Sum,
+    Count,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunc {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            AggregateFunc::Sum => "SUM",
+            AggregateFunc::Count => "COUNT",
+            AggregateFunc::Avg => "AVG",
+            AggregateFunc::Min => "MIN",
+            AggregateFunc::Max => "MAX",
+        }
+    }
+}
+
+/// Fluent builder for single value aggregate queries. Created with
+/// Db::aggregate().
+///
+/// ```ignore
+/// let total: f64 = db.aggregate::<f64>("Transaction")
+///     .sum("amount")
+///     .where_clause("category = ?", ["food"])
+///     .run()?;
+/// ```
+///
+/// Note that SQLite returns NULL for SUM, AVG, MIN, and MAX over zero rows,
+/// so use an Option<T> result type if the query may not match any rows.
+pub struct Aggregate<'a, T, P = ()> {
+    db: &'a Db,
+    table_name: String,
+    func: Option<(AggregateFunc, String)>,
+    where_sql: Option<String>,
+    params: P,
+    _result: PhantomData<T>,
+}
+
+impl<'a, T: FromSql> Aggregate<'a, T, ()> {
+    pub(crate) fn new(db: &'a Db, table_name: &str) -> Self {
+        Self {
+            db,
+            table_name: table_name.to_string(),
+            func: None,
+            where_sql: None,
+            params: (),
+            _result: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: FromSql, P: Params> Aggregate<'a, T, P> {
+    pub fn sum(self, column: &str) -> Self {
+        self.func(AggregateFunc::Sum, column)
+    }
+
+    pub fn count(self, column: &str) -> Self {
+        self.func(AggregateFunc::Count, column)
+    }
+
+    pub fn avg(self, column: &str) -> Self {
+        self.func(AggregateFunc::Avg, column)
+    }
+
+    pub fn min(self, column: &str) -> Self {
+        self.func(AggregateFunc::Min, column)
+    }
+
+    pub fn max(self, column: &str) -> Self {
+        self.func(AggregateFunc::Max, column)
+    }
+
+    pub fn func(mut self, func: AggregateFunc, column: &str) -> Self {
+        self.func = Some((func, column.to_string()));
+        self
+    }
+
+    /// Restrict the rows being aggregated. The SQL is used as is after WHERE
+    /// and the params are bound to it.
+    pub fn where_clause<P2: Params>(self, where_sql: &str, params: P2) -> Aggregate<'a, T, P2> {
+        Aggregate {
+            db: self.db,
+            table_name: self.table_name,
+            func: self.func,
+            where_sql: Some(where_sql.to_string()),
+            params,
+            _result: PhantomData,
+        }
+    }
+
+    pub fn sql(&self) -> Result<String> {
+        let (func, column) = self.func.as_ref()
+            .ok_or_else(|| anyhow!("no aggregate function specified for {}", self.table_name))?;
+        let mut sql = format!("SELECT {}({}) FROM {}", func.as_sql(), column, self.table_name);
+        if let Some(where_sql) = &self.where_sql {
+            sql.push_str(" WHERE ");
+            sql.push_str(where_sql);
+        }
+        Ok(sql)
+    }
+
+    pub fn run(self) -> Result<T> {
+        let sql = self.sql()?;
+        self.db.query_scalar(&sql, self.params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::{AggregateFunc, Db};
+
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct Purchase {
+        id: String,
+        category: String,
+        amount: f64,
+    }
+
+    fn setup_db() -> Result<Db> {
+        let db = Db::open_memory()?;
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Purchase (id TEXT PRIMARY KEY, category TEXT NOT NULL, amount REAL NOT NULL);"),
+        ]);
+        db.migrate(&migrations)?;
+        for (category, amount) in [("food", 10.0), ("food", 20.0), ("rent", 500.0)] {
+            db.save(&Purchase { category: category.to_string(), amount, ..Default::default() })?;
+        }
+        Ok(db)
+    }
+
+    #[test]
+    fn aggregate_builder_functions() -> Result<()> {
+        let db = setup_db()?;
+        assert_eq!(db.aggregate::<f64>("Purchase").sum("amount").run()?, 530.0);
+        assert_eq!(db.aggregate::<i64>("Purchase").count("*").run()?, 3);
+        assert_eq!(db.aggregate::<f64>("Purchase").min("amount").run()?, 10.0);
+        assert_eq!(db.aggregate::<f64>("Purchase").max("amount").run()?, 500.0);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_builder_where_clause() -> Result<()> {
+        let db = setup_db()?;
+        let food: f64 = db.aggregate("Purchase")
+            .sum("amount")
+            .where_clause("category = ?", ["food"])
+            .run()?;
+        assert_eq!(food, 30.0);
+        let avg: f64 = db.aggregate("Purchase")
+            .avg("amount")
+            .where_clause("category = ?", ["food"])
+            .run()?;
+        assert_eq!(avg, 15.0);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_no_rows_is_null() -> Result<()> {
+        let db = setup_db()?;
+        let total: Option<f64> = db.aggregate("Purchase")
+            .sum("amount")
+            .where_clause("category = ?", ["travel"])
+            .run()?;
+        assert_eq!(total, None);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_requires_function() -> Result<()> {
+        let db = setup_db()?;
+        assert!(db.aggregate::<f64>("Purchase").run().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_scalar() -> Result<()> {
+        let db = setup_db()?;
+        let max: f64 = db.aggregate_scalar("Purchase", AggregateFunc::Max, "amount")?;
+        assert_eq!(max, 500.0);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_subscribe() -> Result<()> {
+        let db = setup_db()?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let count_tx = tx.clone();
+        let _count = db.aggregate_subscribe("SELECT COUNT(*) FROM Purchase", (), move |count| {
+            let _ = count_tx.send(("count", count));
+        })?;
+        let _sum = db.aggregate_subscribe(
+            "SELECT CAST(SUM(amount) AS INTEGER) FROM (SELECT amount FROM Purchase WHERE category = ?)", ["toys"],
+            move |sum| {
+                let _ = tx.send(("sum", sum));
+            })?;
+        let recv = || rx.recv_timeout(std::time::Duration::from_secs(1));
+        assert_eq!(recv()?, ("count", 3));
+        // No toys yet, so the SUM is NULL
+        assert_eq!(recv()?, ("sum", 0));
+
+        db.save(&Purchase { category: "toys".to_string(), amount: 5.0, ..Default::default() })?;
+        let mut values = vec![recv()?, recv()?];
+        values.sort();
+        assert_eq!(values, vec![("count", 4), ("sum", 5)]);
+
+        db.save(&Purchase { category: "fuel".to_string(), amount: 40.0, ..Default::default() })?;
+        let mut values = vec![recv()?, recv()?];
+        values.sort();
+        assert_eq!(values, vec![("count", 5), ("sum", 5)]);
+        Ok(())
+    }
+
+}
@@ -0,0 +1,311 @@
+use std::{collections::VecDeque, future::Future, panic::{self, AssertUnwindSafe}, pin::Pin, sync::{mpsc::{self, Sender}, Arc, Mutex}, task::{Context, Poll, Waker}, thread};
+
+use anyhow::Result;
+use rusqlite::Params;
+use rusqlite_migration::Migrations;
+
+use crate::db::{transaction::DbTransaction, Db, DbEvent, DimpleResult, Entity};
+
+type Job = Box<dyn FnOnce(Db) + Send>;
+
+/// Async wrapper around a Db, for async applications. Calls run the
+/// blocking Db method on a worker thread, one at a time, and return a
+/// future that completes when it's done, so it works with any runtime and
+/// never blocks the executor. Clones share the worker, which exits when the
+/// last clone is dropped.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Db,
+    jobs: Sender<Job>,
+}
+
+impl AsyncDb {
+    pub fn new(db: Db) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let worker_db = db.clone();
+        thread::Builder::new()
+            .name("dimple_db async".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job(worker_db.clone());
+                }
+            })
+            .expect("failed to spawn the AsyncDb worker thread");
+        Self { db, jobs }
+    }
+
+    /// The wrapped Db, for calls that are fine to block on.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// See Db::save()
+    pub async fn save<T: Entity + Send>(&self, entity: T) -> DimpleResult<T> {
+        self.spawn(move |db| db.save(&entity)).await
+    }
+
+    /// See Db::query()
+    pub async fn query<T: Entity + Send, P: Params + Send + 'static>(&self, sql: &str, params: P) -> DimpleResult<Vec<T>> {
+        let sql = sql.to_string();
+        self.spawn(move |db| db.query(&sql, params)).await
+    }
+
+    /// See Db::get()
+    pub async fn get<T: Entity + Send>(&self, id: &str) -> DimpleResult<Option<T>> {
+        let id = id.to_string();
+        self.spawn(move |db| db.get(&id)).await
+    }
+
+    /// See Db::delete()
+    pub async fn delete<T: Entity + Send>(&self, entity: T) -> DimpleResult<bool> {
+        self.spawn(move |db| db.delete(&entity)).await
+    }
+
+    /// See Db::migrate()
+    pub async fn migrate(&self, migrations: Migrations<'static>) -> DimpleResult<()> {
+        self.spawn(move |db| db.migrate(&migrations)).await
+    }
+
+    /// Runs the closure in a transaction, as Db::transaction() does. The
+    /// closure itself is synchronous, see Db::transaction_async() for
+    /// awaiting inside a transaction.
+    pub async fn transaction<F, R>(&self, f: F) -> Result<R>
+        where F: FnOnce(&DbTransaction) -> Result<R> + Send + 'static, R: Send + 'static {
+        self.spawn(move |db| db.transaction(f)).await
+    }
+
+    /// Subscribe to events from every transaction, as Db::subscribe() does,
+    /// received with AsyncDbEvents::recv().
+    pub fn subscribe(&self) -> AsyncDbEvents {
+        AsyncDbEvents::new(self.db.subscribe())
+    }
+
+    /// Run f with the Db on the worker thread, completing with its result.
+    pub(crate) fn spawn<F, R>(&self, f: F) -> impl Future<Output = R>
+        where F: FnOnce(Db) -> R + Send + 'static, R: Send + 'static {
+        let state = Arc::new(Mutex::new(Slot::default()));
+        let job_state = state.clone();
+        let job: Job = Box::new(move |db| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(db)));
+            let mut slot = job_state.lock().unwrap_or_else(|e| e.into_inner());
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        // The worker catches panics, so it outlives every sender
+        self.jobs.send(job).expect("the AsyncDb worker thread exited");
+        Blocking { state }
+    }
+}
+
+struct Slot<R> {
+    result: Option<thread::Result<R>>,
+    waker: Option<Waker>,
+}
+
+impl<R> Default for Slot<R> {
+    fn default() -> Self {
+        Self { result: None, waker: None }
+    }
+}
+
+/// Completes with the job's result. A panic in the job is resumed in the
+/// task that awaits it.
+struct Blocking<R> {
+    state: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for Blocking<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let mut slot = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => {
+                drop(slot);
+                panic::resume_unwind(panic)
+            },
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<DbEvent>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// DbEvents from AsyncDb::subscribe(). A thread blocks on the Db
+/// subscription and forwards its events, stopping at the first event after
+/// this is dropped, or when the Db is dropped.
+pub struct AsyncDbEvents {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+impl AsyncDbEvents {
+    fn new(subscription: crate::db::DbEventSubscription) -> Self {
+        let queue = Arc::new(Mutex::new(EventQueue::default()));
+        let forward_queue = Arc::downgrade(&queue);
+        thread::spawn(move || loop {
+            let event = subscription.recv();
+            let Some(queue) = forward_queue.upgrade() else {
+                return;
+            };
+            let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+            match event {
+                Ok(event) => queue.events.push_back(event),
+                Err(_) => queue.closed = true,
+            }
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+            if queue.closed {
+                return;
+            }
+        });
+        Self { queue }
+    }
+
+    /// The next event, or None once the Db has been dropped.
+    pub async fn recv(&mut self) -> Option<DbEvent> {
+        std::future::poll_fn(|cx| {
+            let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(event) = queue.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            if queue.closed {
+                return Poll::Ready(None);
+            }
+            queue.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }).await
+    }
+
+    /// The next event if one is waiting, without waiting for one.
+    pub fn try_recv(&mut self) -> Option<DbEvent> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).events.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::Arc, task::{Context, Poll, Wake, Waker}, thread};
+
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::{AsyncDb, Db, DbEvent};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn async_db_round_trip() -> Result<()> {
+        block_on(async {
+            let db = AsyncDb::new(Db::open_memory()?);
+            db.migrate(Migrations::new(vec![
+                M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            ])).await?;
+            let mut events = db.subscribe();
+
+            let artist = db.save(Artist { name: "Metallica".to_string(), ..Default::default() }).await?;
+            assert!(matches!(events.recv().await, Some(DbEvent::Insert { entity_id, .. }) if entity_id == artist.id));
+            assert_eq!(db.get::<Artist>(&artist.id).await?, Some(artist.clone()));
+
+            db.transaction(|txn| {
+                txn.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+                txn.save(&Artist { name: "Anthrax".to_string(), ..Default::default() })?;
+                Ok(())
+            }).await?;
+            let artists = db.query::<Artist, _>("SELECT * FROM Artist WHERE name LIKE ?", ["M%"]).await?;
+            assert_eq!(artists.len(), 2);
+
+            assert!(db.delete(artist.clone()).await?);
+            assert_eq!(db.get::<Artist>(&artist.id).await?, None);
+            assert!(db.transaction(|_| -> Result<()> { Err(anyhow::anyhow!("rollback")) }).await.is_err());
+            assert!(db.query::<Artist, _>("SELECT * FROM Label", ()).await.is_err());
+
+            for _ in 0..2 {
+                assert!(matches!(events.recv().await, Some(DbEvent::Insert { .. })));
+            }
+            assert!(matches!(events.recv().await, Some(DbEvent::Delete { .. })));
+            assert!(events.try_recv().is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn calls_share_one_worker_thread() -> Result<()> {
+        block_on(async {
+            let db = AsyncDb::new(Db::open_memory()?);
+            let first = db.spawn(|_| thread::current().id()).await;
+            let second = db.clone().spawn(|_| thread::current().id()).await;
+            assert_eq!(first, second);
+            assert_ne!(first, thread::current().id());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn panics_are_resumed_and_the_worker_survives() -> Result<()> {
+        let db = AsyncDb::new(Db::open_memory()?);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block_on(db.spawn(|_| -> () { panic!("job failed") }))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(block_on(db.spawn(|_| 42)), 42);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_async() -> Result<()> {
+        use crate::sync::SyncEngine;
+
+        block_on(async {
+            let migrations = || Migrations::new(vec![
+                M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            ]);
+            let db1 = AsyncDb::new(Db::open_memory()?);
+            let db2 = AsyncDb::new(Db::open_memory()?);
+            db1.migrate(migrations()).await?;
+            db2.migrate(migrations()).await?;
+            let engine = Arc::new(SyncEngine::builder().in_memory().build()?);
+
+            db1.save(Artist { name: "Metallica".to_string(), ..Default::default() }).await?;
+            engine.sync_async(&db1).await?;
+            engine.sync_async(&db2).await?;
+            assert_eq!(db2.query::<Artist, _>("SELECT * FROM Artist", ()).await?.len(), 1);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,126 @@
+use rusqlite::Connection;
+
+/// SQLite's journal_mode. See https://sqlite.org/pragma.html#pragma_journal_mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// SQLite's synchronous setting. See https://sqlite.org/pragma.html#pragma_synchronous
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SynchronousMode {
+    Off,
+    /// Safe from corruption in WAL mode, but a power loss may roll back the
+    /// most recent transactions.
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl SynchronousMode {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SynchronousMode::Off => "OFF",
+            SynchronousMode::Normal => "NORMAL",
+            SynchronousMode::Full => "FULL",
+            SynchronousMode::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Where SQLite keeps temporary tables and indices. See
+/// https://sqlite.org/pragma.html#pragma_temp_store
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TempStore {
+    /// As SQLite was compiled, which is usually File.
+    #[default]
+    Default,
+    File,
+    Memory,
+}
+
+impl TempStore {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        }
+    }
+}
+
+/// SQLite settings for Db::open_with_config() and
+/// Db::open_memory_with_config(), applied to every connection. The default
+/// is what Db::open() uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbConfig {
+    /// DimpleDb is designed for WAL, and other modes may cause SQLITE_BUSY
+    /// errors under concurrent access. In memory databases always use
+    /// Memory.
+    pub journal_mode: JournalMode,
+    pub synchronous: SynchronousMode,
+    /// The page cache size of each connection.
+    pub cache_size_kb: i32,
+    /// The size of the connection pool. Db runs each transaction on a
+    /// single connection, so more connections allow reads to run alongside
+    /// a write, but writes still wait for each other, for up to
+    /// busy_timeout_ms. In memory databases always use 1, since each
+    /// connection would have its own database.
+    pub max_connections: u32,
+    /// How long to wait for another connection's lock before failing with
+    /// SQLITE_BUSY.
+    pub busy_timeout_ms: u32,
+    pub temp_store: TempStore,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::Wal,
+            synchronous: SynchronousMode::Full,
+            cache_size_kb: 2000,
+            max_connections: 1,
+            busy_timeout_ms: 5000,
+            temp_store: TempStore::Default,
+        }
+    }
+}
+
+impl DbConfig {
+    /// Set the PRAGMAs for the config on the connection.
+    pub(crate) fn apply(&self, conn: &Connection) -> rusqlite::Result<()> {
+        let journal_mode: String = conn.pragma_update_and_check(None, "journal_mode", self.journal_mode.as_sql(),
+            |row| row.get(0))?;
+        // In memory databases can't use WAL and report "memory" instead
+        if !journal_mode.eq_ignore_ascii_case(self.journal_mode.as_sql()) && !journal_mode.eq_ignore_ascii_case("memory") {
+            log::warn!("Unable to set journal_mode to {}, database is using '{}'. \
+                Non-WAL mode is unsupported and may cause SQLITE_BUSY errors.", self.journal_mode.as_sql(), journal_mode);
+        }
+        conn.pragma_update(None, "synchronous", self.synchronous.as_sql())?;
+        // Negative sizes are in KiB rather than pages
+        conn.pragma_update(None, "cache_size", -self.cache_size_kb.abs())?;
+        conn.busy_timeout(std::time::Duration::from_millis(self.busy_timeout_ms.into()))?;
+        conn.pragma_update(None, "temp_store", self.temp_store.as_sql())?;
+        Ok(())
+    }
+}
@@ -1,26 +1,114 @@
-use std::{sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex}};
+use std::{collections::{HashMap, HashSet}, ops::Deref, sync::{atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, Sender}, Arc, Mutex, Weak}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
 use anyhow::Result;
 use r2d2::{CustomizeConnection, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{functions::FunctionFlags, Params, Transaction};
+use rusqlite::{types::FromSql, Connection, OptionalExtension, Params, ToSql, TransactionBehavior};
 use rusqlite_migration::{Migrations};
 use uuid::Uuid;
 
-use crate::db::{query::QuerySubscription, transaction::DbTransaction, DbEvent, Entity};
+use crate::changelog::{AuthorStats, ChangeTableStats, ChangelogChange};
+use crate::db::{aggregate::{Aggregate, AggregateFunc}, config::DbConfig, functions, page::{Page, PageQuery}, query::QuerySubscription, query_plan::{self, IndexSuggestion, QueryPlan}, schema::{DbSchema, SchemaDiff}, transaction::{self, DbTransaction}, uuid_source::{UuidSource, UuidV7Source}, DbEvent, DimpleError, DimpleResult, Entity, EventFilter, Validate};
+
+/// Convert a panic payload caught in a transaction into an error with the
+/// panic's message.
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> anyhow::Error {
+    let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic payload".to_string());
+    anyhow::anyhow!("transaction panicked: {}", message)
+}
+
+/// Event filters and senders by subscriber id.
+type Subscribers = Vec<(u64, EventFilter, Sender<DbEvent>)>;
+
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Receives DbEvents until dropped. Returned by Db::subscribe() and
+/// Db::subscribe_filtered().
+pub struct DbEventSubscription {
+    id: u64,
+    subscribers: Weak<Mutex<Subscribers>>,
+    receiver: Receiver<DbEvent>,
+}
+
+impl DbEventSubscription {
+    pub fn receiver(&self) -> &Receiver<DbEvent> {
+        &self.receiver
+    }
+}
+
+impl Deref for DbEventSubscription {
+    type Target = Receiver<DbEvent>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+impl Drop for DbEventSubscription {
+    fn drop(&mut self) {
+        if let Some(subscribers) = self.subscribers.upgrade() {
+            if let Ok(mut subscribers) = subscribers.lock() {
+                subscribers.retain(|(id, _, _)| *id != self.id);
+            }
+        }
+    }
+}
+
+/// True if a column declared with the type stores text, by SQLite's rules.
+/// See https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+fn has_text_affinity(decl_type: &str) -> bool {
+    let decl_type = decl_type.to_uppercase();
+    !decl_type.contains("INT") && ["CHAR", "CLOB", "TEXT"].iter().any(|text| decl_type.contains(text))
+}
+
+/// Prefixes of tables that belong to SQLite or to change tracking.
+const INTERNAL_TABLE_PREFIXES: &[&str] = &["sqlite_", "ZV_"];
 
 #[derive(Clone)]
 pub struct Db {
     pool: Pool<SqliteConnectionManager>,
-    subscribers: Arc<Mutex<Vec<Sender<DbEvent>>>>,
+    subscribers: Arc<Mutex<Subscribers>>,
     database_uuid: String,
+    author: Option<String>,
+    bundle_id: Option<String>,
+    uuid_source: Arc<Mutex<Arc<dyn UuidSource>>>,
+    analyze_after_writes: Arc<AtomicU64>,
+    writes_since_analyze: Arc<AtomicU64>,
+    bulk_delete_threshold: Arc<AtomicU64>,
+    table_schemas: Arc<Mutex<HashMap<String, CachedTableSchema>>>,
+}
+
+/// The parts of a table's schema that saving and merging need.
+#[derive(Clone, Debug)]
+struct CachedTableSchema {
+    columns: Vec<String>,
+    id_column: String,
 }
 
+/// Number of pages Db::backup() copies in each step.
+pub const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 100;
+
+/// Number of times Db::backup() retries a step while the destination is
+/// busy or locked, 100 ms apart, before giving up.
+pub const BACKUP_BUSY_RETRIES: u32 = 20;
+
+/// Default number of rows above which delete_all() records a single bulk
+/// delete change.
+pub const DEFAULT_BULK_DELETE_THRESHOLD: u64 = 10_000;
+
 impl Db {
     pub fn open_memory() -> Result<Self> {
+        Self::open_memory_with_config(DbConfig::default())
+    }
+
+    /// Like open_memory(), with the given SQLite settings. The database
+    /// has a single connection whatever config.max_connections is.
+    pub fn open_memory_with_config(config: DbConfig) -> Result<Self> {
         let manager = r2d2_sqlite::SqliteConnectionManager::memory();
         let pool = r2d2::Pool::builder()
-            .connection_customizer(Box::new(DbConnectionCustomizer{}))
+            .connection_customizer(Box::new(DbConnectionCustomizer { config }))
             // https://beets.io/blog/sqlite-nightmare.html
             // https://sqlite.org/wal.html
             // > 9. Sometimes Queries Return SQLITE_BUSY In WAL Mode
@@ -29,310 +117,2672 @@ impl Db {
         Self::from_pool(pool)
     }
 
+    /// Open a named in-memory database. Every Db opened with the same name in
+    /// this process shares the same data, for instance to simulate multiple
+    /// connections in tests. The data is discarded when the last Db using the
+    /// name is dropped. Each Db has its own subscribers, so changes made
+    /// through one Db are visible to the others but don't notify their
+    /// subscribers. Shared cache databases use table level locking, so
+    /// concurrent writes from different Dbs may fail with SQLITE_LOCKED.
+    pub fn open_memory_named(name: &str) -> Result<Self> {
+        let manager = r2d2_sqlite::SqliteConnectionManager::file(
+            format!("file:{}?mode=memory&cache=shared", name));
+        let pool = r2d2::Pool::builder()
+            .connection_customizer(Box::new(DbConnectionCustomizer { config: DbConfig::default() }))
+            .max_size(1)
+            .build(manager)?;
+        Self::from_pool(pool)
+    }
+
+    /// Open or create a database file. DimpleDb requires SQLite's WAL journal
+    /// mode and sets it on every connection. If the mode can't be changed,
+    /// for instance because another process holds an exclusive lock, a
+    /// warning is logged. Non-WAL mode is unsupported and concurrent access
+    /// may fail with SQLITE_BUSY.
     pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::open_with_config(path, DbConfig::default())
+    }
+
+    /// Like open(), with the given SQLite settings.
+    pub fn open_with_config<P: AsRef<std::path::Path>>(path: P, config: DbConfig) -> Result<Self> {
         let manager = r2d2_sqlite::SqliteConnectionManager::file(path);
+        let max_connections = config.max_connections.max(1);
         let pool = r2d2::Pool::builder()
-            .connection_customizer(Box::new(DbConnectionCustomizer{}))
+            .connection_customizer(Box::new(DbConnectionCustomizer { config }))
             // https://beets.io/blog/sqlite-nightmare.html
-            .max_size(1)
+            .max_size(max_connections)
             .build(manager)?;
         Self::from_pool(pool)
     }
 
-    pub fn migrate(&self, migrations: &Migrations) -> Result<()> {
+    /// Apply any migrations that haven't been applied yet. The database's
+    /// user_version records how many migrations have been applied, so
+    /// migrations must only ever be appended: never reordered or removed.
+    /// Downgrades aren't supported, so if the database has more migrations
+    /// applied than are given, for instance because it was created by a
+    /// newer app version, this fails without changing anything.
+    pub fn migrate(&self, migrations: &Migrations) -> DimpleResult<()> {
         let mut conn = self.pool.get()?;
 
+        let (user_version, migration_count) = Self::migration_versions(&conn, migrations)?;
+        if user_version > migration_count {
+            return Err(DimpleError::Other(anyhow::anyhow!("Database schema version {} is newer than the {} migrations given. \
+                Migrations may have been removed, or the database was created by a newer version.",
+                user_version, migration_count)));
+        }
         migrations.to_latest(&mut conn)?;
+        self.clear_schema_cache();
+
+        Ok(())
+    }
+
+    /// Like migrate(), but migrates up or down to the given number of
+    /// applied migrations. Migrating down runs each migration's down() SQL,
+    /// and fails if any migration to be reverted has none. Rows and change
+    /// history for dropped tables and columns are not restored by migrating
+    /// back up, so this is mostly useful for testing migrations.
+    pub fn migrate_to(&self, target_version: usize, migrations: &Migrations) -> DimpleResult<()> {
+        let mut conn = self.pool.get()?;
+        migrations.to_version(&mut conn, target_version)?;
+        self.clear_schema_cache();
+        Ok(())
+    }
+
+    /// The number of migrations applied to the database, which
+    /// rusqlite_migration stores as the user_version.
+    pub fn current_migration_version(&self) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let user_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(usize::try_from(user_version)?)
+    }
+
+    /// Check that the migrations are consistent with the database's schema
+    /// version. Warns if the database has more migrations applied than are
+    /// given, which suggests migrations were removed. Errors if the schema
+    /// version is negative, which migrations never set.
+    pub fn check_migration_integrity(&self, migrations: &Migrations) -> Result<()> {
+        let conn = self.pool.get()?;
+        let (user_version, migration_count) = Self::migration_versions(&conn, migrations)?;
+        if user_version > migration_count {
+            log::warn!("Database schema version {} is newer than the {} migrations given.",
+                user_version, migration_count);
+        }
+        Ok(())
+    }
+
+    /// Returns the database's user_version and the number of migrations.
+    fn migration_versions(conn: &Connection, migrations: &Migrations) -> Result<(i64, i64)> {
+        let user_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if user_version < 0 {
+            return Err(anyhow::anyhow!("Database schema version {} is invalid", user_version));
+        }
+        let pending = migrations.pending_migrations(conn)? as i64;
+        Ok((user_version, user_version + pending))
+    }
+
+    /// Copies the database to a file, creating or replacing it, using
+    /// SQLite's online backup API. The copy includes the change tracking
+    /// tables and the database uuid, so it is for restoring this database,
+    /// and shouldn't be synced alongside it.
+    pub fn backup<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.backup_progress(path, |_| {})
+    }
+
+    /// Like backup(), calling the closure with the fraction copied, from 0.0
+    /// to 1.0, after each step of BACKUP_PAGES_PER_STEP pages. The backup
+    /// holds one of the Db's connections until it finishes, so with a single
+    /// connection, as in memory databases have, other calls on this Db wait
+    /// for it. Other connections and processes may write between steps, and
+    /// if they change pages already copied, SQLite restarts the backup. If
+    /// the destination stays busy or locked for BACKUP_BUSY_RETRIES steps
+    /// in a row, the backup fails.
+    pub fn backup_progress<P: AsRef<std::path::Path>, F: FnMut(f64)>(&self, path: P, mut f: F) -> Result<()> {
+        let conn = self.pool.get()?;
+        let mut dest = Connection::open(path)?;
+        let backup = rusqlite::backup::Backup::new(&conn, &mut dest)
+            .map_err(|e| match e {
+                rusqlite::Error::SqliteSingleThreadedMode => anyhow::anyhow!(
+                    "Backup requires SQLite to be built thread safe, but it is in single threaded mode"),
+                e => e.into(),
+            })?;
+        f(0.0);
+        let mut busy_retries = 0;
+        loop {
+            let step = backup.step(BACKUP_PAGES_PER_STEP)?;
+            let progress = backup.progress();
+            if progress.pagecount > 0 {
+                f((progress.pagecount - progress.remaining) as f64 / progress.pagecount as f64);
+            }
+            match step {
+                rusqlite::backup::StepResult::Done => break,
+                rusqlite::backup::StepResult::More => busy_retries = 0,
+                // The destination is locked by another connection, so wait
+                // like Backup::run_to_completion() does
+                _ if busy_retries < BACKUP_BUSY_RETRIES => {
+                    busy_retries += 1;
+                    std::thread::sleep(Duration::from_millis(100));
+                },
+                _ => return Err(anyhow::anyhow!("Backup destination was busy or locked for {} retries", busy_retries)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a single statement, such as CREATE INDEX or DROP TABLE, and
+    /// returns the number of rows changed. This is meant for structural
+    /// changes outside of migrations: nothing is recorded in the change
+    /// tracking tables, so rows changed this way won't sync, and no DbEvents
+    /// are sent.
+    pub fn execute<P: Params>(&self, sql: &str, params: P) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let changed = conn.execute(sql, params)?;
+        self.clear_schema_cache();
+        Ok(changed)
+    }
+
+    /// Runs one or more statements separated by semicolons, with no params.
+    /// Like execute(), nothing is recorded in the change tracking tables.
+    pub fn execute_batch(&self, sql: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(sql)?;
+        self.clear_schema_cache();
+        Ok(())
+    }
+
+    /// Forget cached table columns and id columns. This happens
+    /// automatically in migrate(), execute() and execute_batch(), call it
+    /// after changing the schema any other way.
+    pub fn clear_schema_cache(&self) {
+        if let Ok(mut table_schemas) = self.table_schemas.lock() {
+            table_schemas.clear();
+        }
+    }
+
+    /// Reclaim space used by change records older than age. Returns the
+    /// number of changes pruned. Only changes that have been merged, synced
+    /// to remote storage by SyncEngine::sync(), and entirely superseded by
+    /// newer changes to the same fields are pruned, so pruning never changes
+    /// the result of a merge. The changes' field values are deleted, while
+    /// their ids are kept so they aren't pulled again on the next sync.
+    pub fn prune_changes_older_than(&self, age: Duration) -> Result<usize> {
+        let cutoff = SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH);
+        let cutoff_ms = cutoff.duration_since(UNIX_EPOCH)?.as_millis();
+        // The smallest UUIDv7 created at the cutoff
+        let older_than_id = Uuid::from_u128(cutoff_ms << 80).to_string();
+        crate::changelog::prune_changes(self, Some(&older_than_id), None)
+    }
+
+    /// Like prune_changes_older_than(), but prunes every eligible change
+    /// except the newest n for each entity.
+    pub fn prune_changes_keep_last_n(&self, n: usize) -> Result<usize> {
+        crate::changelog::prune_changes(self, None, Some(n))
+    }
+
+    /// Write the changelog as a SQLite script that reproduces the current
+    /// entities when run against a database with the same schema and no
+    /// entities, for debugging and migration tooling. If since_ms
+    /// (milliseconds since the Unix epoch) is given only changes made at or
+    /// after it are included, and the script is meant for a database that
+    /// already has the earlier entities. Changes are written in the order
+    /// they were made, so this reflects the result of a merge. Returns an
+    /// error if the script could not be run, such as when a REAL is NaN or
+    /// an entity's first exported change doesn't set its NOT NULL columns.
+    pub fn export_changes_as_sql(&self, writer: &mut dyn std::io::Write, since_ms: Option<i64>) -> Result<()> {
+        let from_id = since_ms.map(|since_ms| uuid::Builder::from_unix_timestamp_millis(since_ms.max(0) as u64, &[0; 10])
+            .into_uuid()
+            .to_string());
+        crate::changelog::export_changes_as_sql(self, writer, from_id.as_deref())
+    }
+
+    /// Import a JSON array of objects into the table, saving each as with
+    /// save_raw() in a single transaction, and return the number imported.
+    /// The array is read an element at a time, so it needn't fit in memory.
+    /// If any element fails to save nothing is imported. The table's full
+    /// text index, if any, is rebuilt afterwards.
+    pub fn import_json(&self, table_name: &str, reader: &mut dyn std::io::Read) -> Result<usize> {
+        use serde::Deserializer as _;
+
+        let count = self.transaction(|txn| {
+            let mut visitor = ImportVisitor { txn, table_name, error: None };
+            let mut deserializer = serde_json::Deserializer::from_reader(std::io::BufReader::new(reader));
+            let result = (&mut deserializer).deserialize_seq(&mut visitor);
+            if let Some(e) = visitor.error {
+                return Err(e);
+            }
+            let count = result?;
+            deserializer.end()?;
+            Ok(count)
+        })?;
+        if self.has_fts_index(table_name)? {
+            self.rebuild_fts_index(table_name)?;
+        }
+        Ok(count)
+    }
+
+    /// Write the results of the query as a JSON array, an entity at a time,
+    /// and return the number written. The output can be read back with
+    /// import_json().
+    pub fn export_json<E: Entity, P: Params>(&self, sql: &str, params: P, writer: &mut dyn std::io::Write) -> Result<usize> {
+        self.read_transaction(|txn| {
+            let mut stmt = txn.prepare(sql)?;
+            let mut count = 0;
+            writer.write_all(b"[")?;
+            for entity in serde_rusqlite::from_rows::<E>(stmt.query(params)?) {
+                if count > 0 {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut *writer, &entity?)?;
+                count += 1;
+            }
+            writer.write_all(b"]")?;
+            Ok(count)
+        })
+    }
+
+    /// Calls f with a clone of this Db that records every change made
+    /// through it under bundle_id, such as the saves for one user action.
+    /// This covers transactions and saves made with the Db passed to f,
+    /// including clones of it on other threads, and calls can be nested. Use
+    /// get_changes_for_bundle() to read the changes back. Bundles are local
+    /// to this database and aren't synced.
+    pub fn with_bundle_id<F, R>(&self, bundle_id: &str, f: F) -> Result<R>
+        where F: FnOnce(&Db) -> Result<R> {
+        f(&Db {
+            bundle_id: Some(bundle_id.to_string()),
+            ..self.clone()
+        })
+    }
+
+    /// The bundle id set by with_bundle_id(), if any.
+    pub(crate) fn bundle_id(&self) -> Option<&str> {
+        self.bundle_id.as_deref()
+    }
+
+    /// The changes recorded under bundle_id by with_bundle_id(), oldest
+    /// first.
+    pub fn get_changes_for_bundle(&self, bundle_id: &str) -> Result<Vec<ChangelogChange>> {
+        Ok(self.query("SELECT c.id, c.author_id, c.entity_type, c.entity_id, c.merged
+            FROM ZV_CHANGE c
+            JOIN ZV_CHANGE_BUNDLE b ON b.change_id = c.id
+            WHERE b.bundle_id = ?
+            ORDER BY c.id", [bundle_id])?)
+    }
+
+    /// Revert every change recorded in the bundle by with_bundle_id(),
+    /// restoring the entities it changed to their state before it. The undo
+    /// is recorded as new changes in the bundle `undo:<bundle_id>`, so it
+    /// syncs like any other change and can itself be undone to redo. Returns
+    /// an error without changing anything if any of the entities were changed
+    /// after the bundle, or were written without tracking before it, such as
+    /// by save_untracked() or execute(), since their earlier state is unknown.
+    pub fn undo(&self, bundle_id: &str) -> Result<()> {
+        crate::changelog::undo_bundle(self, bundle_id)
+    }
+
+    /// Change counts for each entity type, for dashboards and debugging.
+    /// Read only.
+    pub fn get_change_statistics(&self) -> Result<Vec<ChangeTableStats>> {
+        crate::changelog::statistics::change_statistics(self)
+    }
+
+    /// Change counts and the range of change ids for each author. Read only.
+    pub fn get_author_statistics(&self) -> Result<Vec<AuthorStats>> {
+        crate::changelog::statistics::author_statistics(self)
+    }
+
+    /// The tables, columns and indexes of the database, leaving out the
+    /// change tracking tables.
+    pub fn introspect(&self) -> Result<DbSchema> {
+        let conn = self.pool.get()?;
+        crate::db::schema::introspect(&conn)
+    }
+
+    /// Compares this database's schema to other's, for instance to find out
+    /// why changes from another device don't merge. See DbSchema::diff().
+    pub fn diff_schemas(&self, other: &Db) -> Result<SchemaDiff> {
+        Ok(self.introspect()?.diff(&other.introspect()?))
+    }
+
+    /// Update the statistics SQLite's query planner uses to choose query
+    /// plans. Call this after bulk imports or deletes, such as after
+    /// importing exported changes, so the planner doesn't use stale
+    /// statistics.
+    pub fn analyze(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        let start = Instant::now();
+        conn.execute_batch("ANALYZE")?;
+        log::info!("Analyzed in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// Reclaim the space left by deleted rows, after checkpointing and
+    /// truncating the WAL so the rebuilt database isn't held in it. Rewrites
+    /// the whole file and needs as much free disk space again, so use it
+    /// occasionally, such as after prune_changes_older_than().
+    pub fn vacuum(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        let start = Instant::now();
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.execute_batch("VACUUM")?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        log::info!("Vacuumed in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// Run SQLite's PRAGMA optimize, which analyzes only the tables whose
+    /// statistics are likely stale. Cheap enough to call when closing the
+    /// app or periodically.
+    pub fn optimize(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        let start = Instant::now();
+        conn.execute_batch("PRAGMA optimize")?;
+        log::info!("Optimized in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// Like analyze(), for a single table.
+    pub fn analyze_table(&self, table_name: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(&format!("ANALYZE \"{}\"", table_name.replace('"', "\"\"")))?;
+        Ok(())
+    }
+
+    /// The plan SQLite would use to run the query, from EXPLAIN QUERY PLAN.
+    /// The query is not run.
+    pub fn explain_query<P: Params>(&self, sql: &str, params: P) -> Result<QueryPlan> {
+        let conn = self.pool.get()?;
+        query_plan::explain(&conn, sql, params)
+    }
+
+    /// Indexes that would let the query search rather than scan the tables
+    /// it reads in full, as CREATE INDEX statements to add to a migration.
+    /// Each is on a single column the query mentions, and is only suggested
+    /// if SQLite would use it.
+    pub fn suggest_indexes<P: Params + Clone>(&self, sql: &str, params: P) -> Result<Vec<IndexSuggestion>> {
+        let mut conn = self.pool.get()?;
+        query_plan::suggest_indexes(&mut conn, sql, params)
+    }
+
+    /// Create an FTS5 full text index named {table_name}_fts over the
+    /// columns, using the table as its external content, and index the
+    /// existing rows. Triggers keep the index up to date as rows are saved
+    /// and deleted. Search it with search_fts().
+    pub fn create_fts_index(&self, table_name: &str, columns: &[&str]) -> Result<()> {
+        let fts = format!("{}_fts", table_name);
+        let values = |row: &str| columns.iter()
+            .map(|column| format!("{}.{}", row, column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (columns, new, old) = (columns.join(", "), values("new"), values("old"));
+        let sql = format!("
+            CREATE VIRTUAL TABLE {fts} USING fts5({columns}, content='{table_name}', content_rowid='rowid');
+            CREATE TRIGGER {fts}_insert AFTER INSERT ON {table_name} BEGIN
+                INSERT INTO {fts} (rowid, {columns}) VALUES (new.rowid, {new});
+            END;
+            CREATE TRIGGER {fts}_delete AFTER DELETE ON {table_name} BEGIN
+                INSERT INTO {fts} ({fts}, rowid, {columns}) VALUES ('delete', old.rowid, {old});
+            END;
+            CREATE TRIGGER {fts}_update AFTER UPDATE ON {table_name} BEGIN
+                INSERT INTO {fts} ({fts}, rowid, {columns}) VALUES ('delete', old.rowid, {old});
+                INSERT INTO {fts} (rowid, {columns}) VALUES (new.rowid, {new});
+            END;
+            INSERT INTO {fts} ({fts}) VALUES ('rebuild');");
+        self.transaction(|txn| Ok(txn.txn().execute_batch(&sql)?))
+    }
+
+    /// Rebuild the table's full text index from the table. The triggers
+    /// keep it up to date, so this is only needed after changing the table
+    /// with the triggers disabled. import_json() calls it.
+    pub fn rebuild_fts_index(&self, table_name: &str) -> Result<()> {
+        self.execute(&format!("INSERT INTO {table_name}_fts ({table_name}_fts) VALUES ('rebuild')"), [])?;
+        Ok(())
+    }
+
+    /// Drop the table's full text index and its triggers, if it has one.
+    pub fn drop_fts_index(&self, table_name: &str) -> Result<()> {
+        let fts = format!("{}_fts", table_name);
+        self.transaction(|txn| Ok(txn.txn().execute_batch(&format!("
+            DROP TRIGGER IF EXISTS {fts}_insert;
+            DROP TRIGGER IF EXISTS {fts}_delete;
+            DROP TRIGGER IF EXISTS {fts}_update;
+            DROP TABLE IF EXISTS {fts};"))?))
+    }
+
+    /// True if create_fts_index() has been called for the table.
+    pub fn has_fts_index(&self, table_name: &str) -> Result<bool> {
+        self.query_scalar::<bool, _>(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [format!("{}_fts", table_name)])
+    }
+
+    /// Entities of type T whose indexed columns match the FTS5 query, best
+    /// matches first. See create_fts_index() and
+    /// https://www.sqlite.org/fts5.html#full_text_query_syntax
+    pub fn search_fts<T: Entity>(&self, table_name: &str, query: &str) -> Result<Vec<T>> {
+        let sql = format!(
+            "SELECT {table_name}.* FROM {table_name}_fts
+                JOIN {table_name} ON {table_name}.rowid = {table_name}_fts.rowid
+                WHERE {table_name}_fts MATCH ?
+                ORDER BY {table_name}_fts.rank");
+        Ok(self.query(&sql, [query])?)
+    }
+
+    /// Automatically analyze() after every n entity writes, counting saves,
+    /// deletes and merged changes. 0 turns it off, which is the default.
+    /// Applies to all clones of this Db.
+    pub fn set_auto_analyze_after_n_writes(&self, n: u64) {
+        self.analyze_after_writes.store(n, Ordering::Relaxed);
+        self.writes_since_analyze.store(0, Ordering::Relaxed);
+    }
+
+    /// Called after a transaction commits, so the ANALYZE is best effort and
+    /// a failure is only logged.
+    fn count_writes(&self, conn: &Connection, writes: u64) {
+        let analyze_after_writes = self.analyze_after_writes.load(Ordering::Relaxed);
+        if analyze_after_writes == 0 || writes == 0 {
+            return;
+        }
+        let total = self.writes_since_analyze.fetch_add(writes, Ordering::Relaxed) + writes;
+        if total >= analyze_after_writes {
+            self.writes_since_analyze.store(0, Ordering::Relaxed);
+            log::debug!("Analyzing after {} writes", total);
+            if let Err(e) = conn.execute_batch("ANALYZE") {
+                log::warn!("Automatic ANALYZE failed: {}", e);
+            }
+        }
+    }
+
+    /// Subscribe to be notified of any insert, update, or delete to the
+    /// database. Events are received for as long as the returned
+    /// DbEventSubscription is kept, and it unsubscribes when dropped, so
+    /// `let _ = db.subscribe()` receives nothing. The subscription derefs to
+    /// its Receiver:
+    ///
+    /// ```ignore
+    /// let events = db.subscribe();
+    /// while let Ok(event) = events.recv() {
+    ///     println!("{} {} changed", event.entity_type(), event.entity_id());
+    /// }
+    /// ```
+    pub fn subscribe(&self) -> DbEventSubscription {
+        self.subscribe_filtered(EventFilter::default())
+    }
+
+    /// Like subscribe(), but only receives the events matching the filter.
+    /// Events are filtered before they are sent, so unwanted events cost
+    /// the subscriber nothing.
+    /// 
+    /// ```
+    /// use std::collections::HashSet;
+    /// use dimple_db::{db::{EventFilter, EventType}, Db};
+    /// 
+    /// let db = Db::open_memory()?;
+    /// let artist_inserts = db.subscribe_filtered(EventFilter {
+    ///     event_types: Some(HashSet::from([EventType::Insert])),
+    ///     entity_types: Some(HashSet::from(["Artist".to_string()])),
+    ///     ..Default::default()
+    /// });
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn subscribe_filtered(&self, filter: EventFilter) -> DbEventSubscription {
+        let (tx, rx) = mpsc::channel();
+        let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+        
+        // Add to subscriber list
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push((id, filter, tx));
+        }
+        
+        DbEventSubscription {
+            id,
+            subscribers: Arc::downgrade(&self.subscribers),
+            receiver: rx,
+        }
+    }
+
+    /// Like subscribe(), but only receives events for the named table.
+    pub fn subscribe_table(&self, table_name: &str) -> DbEventSubscription {
+        self.subscribe_filtered(EventFilter {
+            entity_types: Some(HashSet::from([table_name.to_string()])),
+            ..Default::default()
+        })
+    }
+
+    /// Like subscribe(), but only receives events for one row of the named
+    /// table.
+    pub fn subscribe_entity(&self, table_name: &str, id: &str) -> DbEventSubscription {
+        self.subscribe_filtered(EventFilter {
+            entity_types: Some(HashSet::from([table_name.to_string()])),
+            entity_ids: Some(HashSet::from([id.to_string()])),
+            ..Default::default()
+        })
+    }
+
+    /// Calls the supplied closure with a database transaction that can be
+    /// used to perform writes to the database. Commits automatically
+    /// if the closure returns Ok, otherwise rolls back. If the closure panics
+    /// the transaction is rolled back and the panic is returned as an error
+    /// with the panic message. The location isn't included, since the panic
+    /// hook, which prints it by default, is left to the application. The
+    /// transaction takes the write lock when it begins, waiting up to
+    /// DbConfig::busy_timeout_ms for other writers.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R>
+        where F: FnOnce(&DbTransaction) -> Result<R> {
+        let mut conn = self.pool.get()?;
+
+        let mut txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        txn.set_drop_behavior(rusqlite::DropBehavior::Rollback);
+        let db_txn = DbTransaction::new(self, &txn);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&db_txn)))
+            .unwrap_or_else(|payload| Err(panic_error(payload)))
+            .and_then(|r| db_txn.flush_pending_changes().map(|_| r));
+        if result.is_ok() {
+            // Collect events before committing
+            let pending_events = db_txn.take_pending_events();
+            txn.commit()?;
+            let writes = pending_events.len() as u64;
+            // Notify subscribers only after successful commit
+            for event in pending_events {
+                self.notify_subscribers(event);
+            }
+            self.count_writes(&conn, writes);
+        }
+        else {
+            txn.rollback()?;
+        }
+        result
+    }
+
+    /// Calls the supplied closure with a transaction for reads that must see
+    /// a consistent snapshot. The transaction is always rolled back.
+    pub(crate) fn read_transaction<F, R>(&self, f: F) -> Result<R>
+        where F: FnOnce(&rusqlite::Transaction) -> Result<R> {
+        let mut conn = self.pool.get()?;
+        let txn = conn.transaction()?;
+        f(&txn)
+    }
+
+    /// Like transaction(), but runs at most once per transaction_id, for
+    /// idempotent replay of replicated operations. The id is recorded in
+    /// ZV_TRANSACTION as part of the transaction, so if a transaction with the
+    /// id has already committed the closure isn't called and None is
+    /// returned. The ids are local to this database and are not synced.
+    pub fn transaction_with_id<F, R>(&self, transaction_id: &str, f: F) -> Result<Option<R>>
+        where F: FnOnce(&DbTransaction) -> Result<R> {
+        self.transaction(|txn| {
+            let inserted = txn.txn().execute(
+                "INSERT OR IGNORE INTO ZV_TRANSACTION (id) VALUES (?)", [transaction_id])?;
+            if inserted == 0 {
+                return Ok(None);
+            }
+            f(txn).map(Some)
+        })
+    }
+
+    /// Async version of transaction(), for calling async code such as HTTP
+    /// requests inside a transaction. The closure returns a boxed future
+    /// that may borrow the transaction:
+    ///
+    /// ```ignore
+    /// db.transaction_async(|t| Box::pin(async move {
+    ///     let name = fetch_name().await?;
+    ///     t.save(&Artist { name, ..Default::default() })
+    /// })).await?;
+    /// ```
+    ///
+    /// The connection is held until the future completes, and since the
+    /// pool has a single connection by default every other database call
+    /// blocks until then. Holding the transaction across await points is safe but limits
+    /// throughput, so keep the awaited work short. If the future is dropped
+    /// before completing the transaction is rolled back, so it can be
+    /// wrapped in a timeout such as tokio::time::timeout(). The future is
+    /// runtime agnostic and not Send, so use it with block_on() or a local
+    /// task set. A panic in the closure or the future rolls back and is
+    /// returned as an error, as in transaction().
+    #[cfg(feature = "async")]
+    pub async fn transaction_async<F, R>(&self, f: F) -> Result<R>
+        where F: for<'t> FnOnce(&'t DbTransaction<'t>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<R>> + 't>> {
+        let mut conn = self.pool.get()?;
+
+        let mut txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        txn.set_drop_behavior(rusqlite::DropBehavior::Rollback);
+        let db_txn = DbTransaction::new(self, &txn);
+        let result = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&db_txn))) {
+            Ok(mut future) => std::future::poll_fn(|cx| {
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx)))
+                    .unwrap_or_else(|payload| std::task::Poll::Ready(Err(panic_error(payload))))
+            }).await,
+            Err(payload) => Err(panic_error(payload)),
+        }
+            .and_then(|r| db_txn.flush_pending_changes().map(|_| r));
+        if result.is_ok() {
+            let pending_events = db_txn.take_pending_events();
+            txn.commit()?;
+            let writes = pending_events.len() as u64;
+            for event in pending_events {
+                self.notify_subscribers(event);
+            }
+            self.count_writes(&conn, writes);
+        }
+        else {
+            txn.rollback()?;
+        }
+        result
+    }
+
+    /// Shortcut to create a transaction and save a single entity.
+    /// See DbTransaction.save()
+    pub fn save<T: Entity>(&self, entity: &T) -> DimpleResult<T> {
+        Ok(self.transaction(|t| t.save(entity))?)
+    }
+
+    /// Shortcut to create a transaction and save a single entity if it
+    /// validates. See DbTransaction.save_validated()
+    pub fn save_validated<T: Entity + Validate>(&self, entity: &T) -> DimpleResult<T> {
+        entity.validate().map_err(DimpleError::ValidationFailed)?;
+        self.save(entity)
+    }
+
+    /// Shortcut to create a transaction and save a single JSON entity.
+    /// See DbTransaction.save_raw()
+    pub fn save_raw(&self, table_name: &str, entity: serde_json::Value) -> DimpleResult<serde_json::Value> {
+        Ok(self.transaction(|t| t.save_raw(table_name, entity))?)
+    }
+
+    /// Shortcut to create a transaction and merge fields into an entity.
+    /// See DbTransaction.merge()
+    pub fn merge<E: Entity>(&self, entity: &E, fields: &[&str]) -> Result<E> {
+        self.transaction(|t| t.merge(entity, fields))
+    }
+
+    /// Saves all the entities in a single transaction, which is much faster
+    /// than saving them one at a time. If any save fails none are saved.
+    /// See DbTransaction.save_many()
+    pub fn save_many<E: Entity>(&self, entities: &[E]) -> Result<Vec<E>> {
+        self.transaction(|t| t.save_many(entities))
+    }
+
+    /// Shortcut to create a transaction and delete a single entity.
+    /// See DbTransaction.delete()
+    pub fn delete<E: Entity>(&self, entity: &E) -> DimpleResult<bool> {
+        Ok(self.transaction(|t| t.delete(entity))?)
+    }
+
+    /// Shortcut to create a transaction and delete a single entity by id.
+    /// See DbTransaction.delete_by_id()
+    pub fn delete_by_id<E: Entity>(&self, id: &str) -> Result<bool> {
+        self.transaction(|t| t.delete_by_id::<E>(id))
+    }
+
+    /// Shortcut to create a transaction and delete matching entities.
+    /// See DbTransaction.delete_where()
+    pub fn delete_where<E: Entity, P: Params>(&self, where_clause: &str, params: P) -> Result<u64> {
+        self.transaction(|t| t.delete_where::<E, P>(where_clause, params))
+    }
+
+    /// Shortcut to create a transaction and delete all entities of a type.
+    /// See DbTransaction.delete_all()
+    pub fn delete_all<E: Entity>(&self) -> Result<u64> {
+        self.transaction(|t| t.delete_all::<E>())
+    }
+
+    /// Set the number of rows above which delete_all() records a single
+    /// bulk delete change instead of one per entity. Defaults to
+    /// DEFAULT_BULK_DELETE_THRESHOLD. Applies to all clones of this Db.
+    pub fn set_bulk_delete_threshold(&self, threshold: u64) {
+        self.bulk_delete_threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bulk_delete_threshold(&self) -> u64 {
+        self.bulk_delete_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Simple query without creating a transaction.
+    ///
+    /// Params can be any rusqlite Params, such as `()` for no params,
+    /// `["Metallica"]`, `("Metallica", 1986)` or
+    /// `rusqlite::params!["Metallica", 1986]`.
+    pub fn query<E: Entity, P: Params>(&self, sql: &str, params: P) -> DimpleResult<Vec<E>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(sql)?;
+        let entities = serde_rusqlite::from_rows::<E>(stmt.query(params)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DimpleError::Other(e.into()))?;
+        Ok(entities)
+    }
+
+    /// Rows from any query as JSON objects keyed by column name, for tables
+    /// whose types aren't known at compile time. Integers and reals become
+    /// numbers, text strings, NULL null, and blobs arrays of bytes, so rows
+    /// can be saved back with save_raw().
+    pub fn query_as_json<P: Params>(&self, sql: &str, params: P) -> Result<Vec<serde_json::Value>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(sql)?;
+        let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+        let rows = stmt.query_map(params, |row| transaction::row_to_json(row, &column_names))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// The first row from query_as_json(), if any.
+    pub fn find_as_json<P: Params>(&self, sql: &str, params: P) -> Result<Option<serde_json::Value>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(sql)?;
+        let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+        Ok(stmt.query_row(params, |row| transaction::row_to_json(row, &column_names)).optional()?)
+    }
+
+    /// Query with named parameters, e.g.
+    /// `db.query_named("SELECT * FROM Artist WHERE name = :name", &[(":name", &name)])`
+    pub fn query_named<E: Entity>(&self, sql: &str, params: &[(&str, &dyn ToSql)]) -> Result<Vec<E>> {
+        Ok(self.query(sql, params)?)
+    }
 
+    /// Query with named parameters and return the first result, if any.
+    pub fn query_one_named<E: Entity>(&self, sql: &str, params: &[(&str, &dyn ToSql)]) -> Result<Option<E>> {
+        Ok(self.query_named(sql, params)?.into_iter().next())
+    }
+
+    /// Get a single entity by id without creating a transaction.
+    pub fn get<E: Entity>(&self, id: &str) -> DimpleResult<Option<E>> {
+        let table_name = self.table_name_for_type::<E>()?;
+        let id_column = self.id_column_for_type::<E>()?;
+        let sql = format!("SELECT * FROM {} WHERE {} = ? LIMIT 1", table_name, id_column);
+        Ok(self.query::<E, _>(&sql, [id])?.into_iter().next())
+    }
+
+    /// Returns true if the query returns any rows. An empty sql checks
+    /// whether the table for the type has any rows.
+    pub fn exists<E: Entity, P: Params>(&self, sql: &str, params: P) -> Result<bool> {
+        let sql = self.sql_or_table_select::<E>(sql)?;
+        self.query_scalar(&format!("SELECT EXISTS({})", sql), params)
+    }
+
+    /// Returns the number of rows the query returns. An empty sql counts the
+    /// rows in the table for the type.
+    pub fn count<E: Entity, P: Params>(&self, sql: &str, params: P) -> Result<i64> {
+        let sql = self.sql_or_table_select::<E>(sql)?;
+        self.query_scalar(&format!("SELECT COUNT(*) FROM ({})", sql), params)
+    }
+
+    fn sql_or_table_select<E: Entity>(&self, sql: &str) -> Result<String> {
+        if sql.trim().is_empty() {
+            Ok(format!("SELECT * FROM {}", self.table_name_for_type::<E>()?))
+        } else {
+            Ok(sql.to_string())
+        }
+    }
+
+    /// Get one zero based page of the query's results, along with the total
+    /// number of rows it returns. See PageQuery.
+    pub fn query_page<E: Entity, P: Params + Clone>(&self, sql: &str, params: P, page_size: usize, page: usize) -> Result<Page<E>> {
+        PageQuery::new(sql, params).page_size(page_size).page(page).fetch(self)
+    }
+
+    /// Get the entities with the given ids in a single query, keyed by id.
+    /// Ids that don't exist are absent from the map, and duplicates are
+    /// ignored.
+    pub fn get_many<E: Entity>(&self, ids: &[&str]) -> Result<HashMap<String, E>> {
+        // Stay well under SQLite's bound parameter limit
+        const IDS_PER_QUERY: usize = 500;
+
+        let mut entities = HashMap::new();
+        if ids.is_empty() {
+            return Ok(entities);
+        }
+        let mut unique_ids = ids.to_vec();
+        unique_ids.sort_unstable();
+        unique_ids.dedup();
+
+        let table_name = self.table_name_for_type::<E>()?;
+        let conn = self.pool.get()?;
+        let id_column = self.table_id_column(&conn, &table_name)?;
+        for chunk in unique_ids.chunks(IDS_PER_QUERY) {
+            let sql = format!("SELECT * FROM {} WHERE {} IN ({})",
+                table_name, id_column, vec!["?"; chunk.len()].join(", "));
+            let mut stmt = conn.prepare(&sql)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(chunk))?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(id_column.as_str())?;
+                entities.insert(id, serde_rusqlite::from_row::<E>(row)?);
+            }
+        }
+        Ok(entities)
+    }
+
+    /// Get the first entity whose field equals the value. The field name is
+    /// checked against the table's columns, so it is never interpolated into
+    /// SQL unchecked.
+    pub fn find_by<E: Entity, V: ToSql>(&self, field_name: &str, value: V) -> Result<Option<E>> {
+        let sql = self.field_query_sql::<E>(field_name)?;
+        Ok(self.query::<E, _>(&format!("{} LIMIT 1", sql), [value])?.into_iter().next())
+    }
+
+    /// Get all entities whose field equals the value. See find_by().
+    pub fn query_by<E: Entity, V: ToSql>(&self, field_name: &str, value: V) -> Result<Vec<E>> {
+        let sql = self.field_query_sql::<E>(field_name)?;
+        Ok(self.query::<E, _>(&sql, [value])?)
+    }
+
+    fn field_query_sql<E: Entity>(&self, field_name: &str) -> Result<String> {
+        let table_name = self.table_name_for_type::<E>()?;
+        let column_names = {
+            let conn = self.pool.get()?;
+            self.table_column_names(&conn, &table_name)?
+        };
+        if !column_names.iter().any(|name| name == field_name) {
+            return Err(anyhow::anyhow!("{} has no column named {}", table_name, field_name));
+        }
+        Ok(format!("SELECT * FROM {} WHERE \"{}\" = ?", table_name, field_name))
+    }
+
+    /// Start building an aggregate query against the named table.
+    /// See Aggregate.
+    pub fn aggregate<T: FromSql>(&self, table_name: &str) -> Aggregate<'_, T> {
+        Aggregate::new(self, table_name)
+    }
+
+    /// Run a single aggregate function over a column of the named table.
+    pub fn aggregate_scalar<T: FromSql>(&self, table_name: &str, func: AggregateFunc, column: &str) -> Result<T> {
+        self.aggregate::<T>(table_name).func(func, column).run()
+    }
+
+    pub(crate) fn query_scalar<T: FromSql, P: Params>(&self, sql: &str, params: P) -> Result<T> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row(sql, params, |row| row.get(0))?)
+    }
+
+    /// Get the database's unique UUIDv7. This is created when the database is
+    /// first initialized and never changes.
+    pub fn get_database_uuid(&self) -> &str {
+        &self.database_uuid
+    }
+
+    /// The author id recorded on changes made by this database, which is the
+    /// database uuid.
+    pub fn author_id(&self) -> &str {
+        &self.database_uuid
+    }
+
+    /// The author set with with_author(), if any.
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// A clone of this Db that records author as the author of the changes
+    /// made through it, such as a user's email when many users share one
+    /// database file. Clones of the returned Db keep the author.
+    ///
+    /// The author is attribution only. It is stored in ZV_CHANGE.author and
+    /// synced with the change, while author_id(), which identifies the
+    /// database in vector clocks, conflict detection and sync manifests,
+    /// stays the database uuid.
+    pub fn with_author(&self, author: &str) -> Db {
+        Db {
+            author: Some(author.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Get a value from the database's metadata, such as "last_sync_at",
+    /// which SyncEngine::sync_when_available() keeps up to date.
+    pub fn get_metadata(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row("SELECT value FROM ZV_METADATA WHERE key = ?", [key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub(crate) fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute("INSERT OR REPLACE INTO ZV_METADATA (key, value) VALUES (?, ?)", [key, value])?;
+        Ok(())
+    }
+
+    /// Replace the source of new entity and change ids, for instance with a
+    /// SequentialUuidSource to get reproducible ids in snapshot tests. Applies
+    /// to all clones of this Db. See UuidSource.
+    pub fn set_uuid_source(&self, uuid_source: Arc<dyn UuidSource>) {
+        if let Ok(mut current) = self.uuid_source.lock() {
+            *current = uuid_source;
+        }
+    }
+
+    pub(crate) fn next_uuid(&self) -> String {
+        match self.uuid_source.lock() {
+            Ok(uuid_source) => uuid_source.next_uuid(),
+            Err(_) => Uuid::now_v7().to_string(),
+        }
+    }
+
+    /// Performs the given query, calling the closure with the results
+    /// immediately and then again any time any table referenced in the query
+    /// changes. Returns a QuerySubscription that automatically unsubscribes the
+    /// query on drop or via QuerySubscription.unsubscribe(). Each
+    /// subscription creates a monitoring thread that uses read-only queries.
+    ///
+    /// The params are re-used for every query so they must be Clone, Send
+    /// and 'static. Use `()` for no params, arrays or tuples of owned or
+    /// 'static values such as `["Metallica".to_string()]` or `("Metallica",)`,
+    /// or `rusqlite::params_from_iter(vec)` for a dynamic list. The borrowed
+    /// slice made by `rusqlite::params!` can't be sent to the monitoring
+    /// thread, so it only works with query() and transactions. The query can
+    /// be changed later with QuerySubscription::update_params() or
+    /// update_sql().
+    pub fn query_subscribe<E, P, F>(&self, sql: &str, params: P, f: F) 
+        -> Result<QuerySubscription> 
+        where 
+            E: Entity + 'static, 
+            P: Params + Clone + Send + 'static, 
+            F: FnMut(Vec<E>) + Send + 'static {
+        QuerySubscription::new(self, sql, params, f)
+    } 
+
+    /// Like query_subscribe(), but for queries with a single integer result
+    /// such as `SELECT COUNT(*) FROM Artist`. A NULL result, as from SUM over
+    /// no rows, is passed as 0. Use `CAST(SUM(x) AS INTEGER)` for REAL
+    /// columns.
+    pub fn aggregate_subscribe<P, F>(&self, sql: &str, params: P, f: F)
+        -> Result<QuerySubscription>
+        where
+            P: Params + Clone + Send + 'static,
+            F: FnMut(i64) + Send + 'static {
+        let query_sql = sql.to_string();
+        QuerySubscription::with_runner(self, sql, move |db| {
+            Ok(db.query_scalar::<Option<i64>, _>(&query_sql, params.clone())?.unwrap_or(0))
+        }, f)
+    }
+
+    fn from_pool(pool: Pool<SqliteConnectionManager>) -> Result<Self> {
+        let conn = pool.get()?;
+        crate::changelog::init_change_tracking_tables(&conn)?;
+        let database_uuid: String = conn.query_row(
+            "SELECT value FROM ZV_METADATA WHERE key = 'database_uuid'",
+            [],
+            |row| row.get(0)
+        )?;
+
+        let db = Db {
+            pool,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            database_uuid,
+            author: None,
+            bundle_id: None,
+            uuid_source: Arc::new(Mutex::new(Arc::new(UuidV7Source))),
+            analyze_after_writes: Arc::new(AtomicU64::new(0)),
+            writes_since_analyze: Arc::new(AtomicU64::new(0)),
+            bulk_delete_threshold: Arc::new(AtomicU64::new(DEFAULT_BULK_DELETE_THRESHOLD)),
+            table_schemas: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        Ok(db)
+    }
+
+    /// Names of the application's tables, excluding SQLite's internal tables
+    /// and the ZV_ change tracking tables.
+    pub fn user_table_names(&self) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")?;
+        let table_names = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(table_names.into_iter()
+            .filter(|name| !INTERNAL_TABLE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+            .collect())
+    }
+
+    /// The table name for the type, either as registered with
+    /// register_entity() or the last path segment of the type name. Generic
+    /// parameters are ignored by the fallback, so register generic types.
+    pub fn table_name_for_type<T: 'static>(&self) -> Result<String> {
+        if let Some(table_name) = super::registered_table_name::<T>() {
+            return Ok(table_name);
+        }
+        let full_name = std::any::type_name::<T>();
+        // Extract just the struct name from the full path, without generics
+        let base_name = full_name.split('<').next().unwrap_or(full_name);
+        Ok(base_name.rsplit("::").next().unwrap_or(base_name).to_string())
+    }
+
+    /// The name of the id column of the table for the type. See
+    /// table_id_column().
+    pub fn id_column_for_type<E: Entity>(&self) -> Result<String> {
+        let table_name = self.table_name_for_type::<E>()?;
+        let conn = self.pool.get()?;
+        self.table_id_column(&conn, &table_name)
+    }
+
+    /// Column names of the table, cached until the next migrate() or
+    /// clear_schema_cache().
+    pub(crate) fn table_column_names(&self, conn: &Connection, table_name: &str) -> Result<Vec<String>> {
+        Ok(self.table_schema(conn, table_name)?.columns)
+    }
+
+    /// The column holding the table's entity ids, and the field holding the
+    /// id on its entities. This is the table's primary key if it has a
+    /// single column primary key with TEXT affinity, since ids are strings,
+    /// otherwise id. Cached along with the columns.
+    pub(crate) fn table_id_column(&self, conn: &Connection, table_name: &str) -> Result<String> {
+        Ok(self.table_schema(conn, table_name)?.id_column)
+    }
+
+    fn table_schema(&self, conn: &Connection, table_name: &str) -> Result<CachedTableSchema> {
+        if let Some(schema) = self.table_schemas.lock().ok()
+            .and_then(|table_schemas| table_schemas.get(table_name).cloned()) {
+            return Ok(schema);
+        }
+
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table_name))?;
+        // Column name is at index 1, declared type at 2, primary key
+        // position at 5
+        let columns = stmt.query_map([], |row| Ok((row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(5)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        if columns.is_empty() {
+            return Err(anyhow::anyhow!("Table '{}' not found or has no columns", table_name));
+        }
+
+        let primary_key = columns.iter().filter(|(_, _, pk)| *pk > 0).collect::<Vec<_>>();
+        let id_column = match primary_key.as_slice() {
+            [(name, decl_type, _)] if has_text_affinity(decl_type) => name.clone(),
+            _ => "id".to_string(),
+        };
+        let schema = CachedTableSchema {
+            columns: columns.into_iter().map(|(name, _, _)| name).collect(),
+            id_column,
+        };
+        
+        if let Ok(mut table_schemas) = self.table_schemas.lock() {
+            table_schemas.insert(table_name.to_string(), schema.clone());
+        }
+        Ok(schema)
+    }
+    
+    pub(crate) fn notify_subscribers(&self, event: DbEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            // Send to all interested subscribers, remove ones that fail
+            subscribers.retain(|(_, filter, tx)| {
+                !filter.matches(&event) || tx.send(event.clone()).is_ok()
+            });
+        }
+    }
+}
+
+/// Saves the elements of a JSON array as they are deserialized, for
+/// Db::import_json(). Save errors are kept so they can be returned as is.
+struct ImportVisitor<'a, 'b> {
+    txn: &'a DbTransaction<'b>,
+    table_name: &'a str,
+    error: Option<anyhow::Error>,
+}
+
+impl<'de> serde::de::Visitor<'de> for &mut ImportVisitor<'_, '_> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a JSON array of objects")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while let Some(entity) = seq.next_element::<serde_json::Value>()? {
+            if let Err(e) = self.txn.save_raw(self.table_name, entity) {
+                let message = e.to_string();
+                self.error = Some(e);
+                return Err(serde::de::Error::custom(message));
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+
+#[derive(Debug)]
+struct DbConnectionCustomizer {
+    config: DbConfig,
+}
+
+impl CustomizeConnection<rusqlite::Connection, rusqlite::Error> for DbConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        self.config.apply(conn)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        functions::register(conn)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde::{Deserialize, Serialize};
+    use rusqlite_migration::{Migrations, M};
+    use std::sync::{mpsc::channel, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::changelog::ChangelogChange;
+    use crate::db::{Db, DbEvent, DbEventSubscription, DimpleError, SequentialUuidSource};
+
+    fn setup_db() -> Result<Db> {
+        let db = Db::open_memory()?;
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+        ]);
+        db.migrate(&migrations)?;
+        Ok(db)
+    }
+
+    // Database Operations
+    #[test]
+    fn can_create_database() -> Result<()> {
+        let _ = Db::open_memory()?;
+        Ok(())
+    }
+
+    #[test]
+    fn file_database_uses_wal() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let db = Db::open(temp_dir.path().join("wal.db"))?;
+        let conn = db.pool.get()?;
+        let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        assert_eq!(journal_mode.to_lowercase(), "wal");
+        Ok(())
+    }
+
+    #[test]
+    fn open_with_config_applies_pragmas() -> Result<()> {
+        use crate::db::{DbConfig, JournalMode, SynchronousMode, TempStore};
+
+        let temp_dir = tempfile::tempdir()?;
+        let db = Db::open_with_config(temp_dir.path().join("config.db"), DbConfig {
+            journal_mode: JournalMode::Truncate,
+            synchronous: SynchronousMode::Normal,
+            cache_size_kb: 4096,
+            max_connections: 2,
+            busy_timeout_ms: 250,
+            temp_store: TempStore::Memory,
+        })?;
+        assert_eq!(db.pool.max_size(), 2);
+        let conn = db.pool.get()?;
+        let pragma = |name: &str| -> Result<String> {
+            Ok(conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get::<_, rusqlite::types::Value>(0))
+                .map(|value| match value {
+                    rusqlite::types::Value::Integer(i) => i.to_string(),
+                    rusqlite::types::Value::Text(s) => s.to_lowercase(),
+                    value => format!("{:?}", value),
+                })?)
+        };
+        assert_eq!(pragma("journal_mode")?, "truncate");
+        assert_eq!(pragma("synchronous")?, "1");
+        assert_eq!(pragma("cache_size")?, "-4096");
+        assert_eq!(pragma("busy_timeout")?, "250");
+        assert_eq!(pragma("temp_store")?, "2");
+        assert_eq!(pragma("foreign_keys")?, "1");
+
+        let db = Db::open_memory_with_config(DbConfig { max_connections: 4, ..Default::default() })?;
+        assert_eq!(db.pool.max_size(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn busy_timeout_waits_for_other_writers() -> Result<()> {
+        use std::{sync::mpsc, thread};
+        use crate::db::DbConfig;
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("busy.db");
+        let db = Db::open(&path)?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+        ]))?;
+        let impatient = Db::open_with_config(&path, DbConfig { busy_timeout_ms: 0, ..Default::default() })?;
+        let patient = Db::open_with_config(&path, DbConfig { busy_timeout_ms: 10_000, ..Default::default() })?;
+
+        // Hold the write lock in another connection for a while
+        let hold_lock = |db: Db| {
+            let (locked_tx, locked_rx) = mpsc::channel();
+            let writer = thread::spawn(move || db.transaction(|txn| {
+                txn.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+                locked_tx.send(())?;
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            }));
+            locked_rx.recv().unwrap();
+            writer
+        };
+
+        let writer = hold_lock(db.clone());
+        let error = impatient.save(&Artist { name: "Megadeth".to_string(), ..Default::default() }).unwrap_err();
+        assert!(matches!(&error, DimpleError::SqliteError(e) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::DatabaseBusy)), "{}", error);
+        writer.join().unwrap()?;
+
+        let writer = hold_lock(db.clone());
+        patient.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        writer.join().unwrap()?;
+        assert_eq!(db.count::<Artist, _>("", ())?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn user_table_names_excludes_internal_tables() -> Result<()> {
+        let db = setup_db()?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let table_names = db.user_table_names()?;
+        assert!(table_names.contains(&"Artist".to_string()));
+        assert!(table_names.iter().all(|name| !name.starts_with("ZV_") && !name.starts_with("sqlite_")));
+        Ok(())
+    }
+
+    #[test]
+    fn execute_runs_untracked_sql() -> Result<()> {
+        let db = setup_db()?;
+        db.execute_batch("CREATE INDEX artist_name ON Artist (name); ALTER TABLE Artist ADD COLUMN country TEXT;")?;
+        let index_count: i64 = db.query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'artist_name'", [])?;
+        assert_eq!(index_count, 1);
+
+        assert_eq!(db.execute("INSERT INTO Artist (id, name) VALUES (uuid7(), ?)", ["Metallica"])?, 1);
+        assert_eq!(db.execute("UPDATE Artist SET country = ?", ["US"])?, 1);
+        let change_count: i64 = db.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE", [])?;
+        assert_eq!(change_count, 0);
+
+        // The schema cache was cleared, so saves see the new column
+        db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        let null_countries: i64 = db.query_scalar("SELECT COUNT(*) FROM Artist WHERE country IS NULL", [])?;
+        assert_eq!(null_countries, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn exported_sql_reproduces_entities() -> Result<()> {
+        let db = setup_db()?;
+        let metallica = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let radiohead = db.save(&Artist { name: "Radiohead".to_string(), summary: Some("It's OK".to_string()), ..Default::default() })?;
+        db.save(&Artist { name: "Nirvana".to_string(), ..Default::default() })?;
+        db.save(&Artist { summary: Some("Thrash".to_string()), ..metallica })?;
+        db.delete_by_id::<Artist>(&radiohead.id)?;
+
+        let mut sql = Vec::new();
+        db.export_changes_as_sql(&mut sql, None)?;
+        let sql = String::from_utf8(sql)?;
+        assert!(sql.contains(db.get_database_uuid()));
+
+        let replayed = setup_db()?;
+        replayed.execute_batch(&sql)?;
+        let query = "SELECT * FROM Artist ORDER BY id";
+        let expected = serde_json::to_value(db.query::<Artist, _>(query, ())?)?;
+        let actual = serde_json::to_value(replayed.query::<Artist, _>(query, ())?)?;
+        assert_eq!(actual, expected);
+        assert_eq!(replayed.count::<Artist, _>("", ())?, 2);
+
+        // Only later changes are exported with since
+        let later_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64 + 60_000;
+        let mut sql = Vec::new();
+        db.export_changes_as_sql(&mut sql, Some(later_ms))?;
+        assert!(!String::from_utf8(sql)?.contains("INSERT"));
+        Ok(())
+    }
+
+    #[test]
+    fn exported_sql_needs_the_not_null_columns() -> Result<()> {
+        let db = setup_db()?;
+        db.execute("INSERT INTO Artist (id, name) VALUES ('untracked', 'Metallica')", ())?;
+        let before_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
+        let artist = db.get::<Artist>("untracked")?.unwrap();
+        db.save(&Artist { summary: Some("Thrash".to_string()), ..artist })?;
+
+        // The only change doesn't set name, so a full script can't insert it
+        let err = db.export_changes_as_sql(&mut Vec::new(), None).unwrap_err();
+        assert!(err.to_string().contains("NOT NULL column name"), "{}", err);
+
+        // A script of later changes expects the entity to exist already
+        let mut sql = Vec::new();
+        db.export_changes_as_sql(&mut sql, Some(before_ms))?;
+        let sql = String::from_utf8(sql)?;
+        assert!(!sql.contains("INSERT"));
+        assert!(sql.contains("UPDATE"));
+        Ok(())
+    }
+
+    #[test]
+    fn changes_are_grouped_by_bundle() -> Result<()> {
+        let db = setup_db()?;
+        db.save(&Artist { name: "Unbundled".to_string(), ..Default::default() })?;
+        let artist = db.with_bundle_id("bundle-1", |db| {
+            let artist = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+            db.with_bundle_id("bundle-2", |db| {
+                Ok(db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?)
+            })?;
+            db.transaction(|txn| {
+                txn.save(&Artist { id: artist.id.clone(), name: "Metallica!".to_string(), ..Default::default() })?;
+                txn.delete_by_id::<Artist>(&artist.id)
+            })?;
+            Ok(artist)
+        })?;
+        db.save(&Artist { name: "Also unbundled".to_string(), ..Default::default() })?;
+
+        let changes: Vec<ChangelogChange> = db.get_changes_for_bundle("bundle-1")?;
+        assert_eq!(changes.len(), 3);
+        assert!(changes.iter().all(|change| change.entity_id == artist.id));
+        assert!(changes.windows(2).all(|pair| pair[0].id < pair[1].id));
+        assert_eq!(db.get_changes_for_bundle("bundle-2")?.len(), 1);
+        assert!(db.get_changes_for_bundle("missing")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_ids_follow_the_db_not_the_thread() -> Result<()> {
+        let db = setup_db()?;
+        let other = Db::open_memory()?;
+        other.execute_batch("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);")?;
+        db.with_bundle_id("bundle", |bundled| {
+            // Other Dbs on this thread aren't bundled
+            other.save(&Artist { name: "Unbundled".to_string(), ..Default::default() })?;
+            // Clones used on other threads are
+            let bundled = bundled.clone();
+            std::thread::spawn(move || bundled.save(&Artist { name: "Metallica".to_string(), ..Default::default() }))
+                .join().unwrap()?;
+            Ok(())
+        })?;
+        assert_eq!(db.get_changes_for_bundle("bundle")?.len(), 1);
+        assert!(other.get_changes_for_bundle("bundle")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn undo_reverts_bundle_and_can_be_redone() -> Result<()> {
+        let db = setup_db()?;
+        let updated = db.save(&Artist { name: "Metallica".to_string(), summary: Some("Thrash".to_string()), ..Default::default() })?;
+        let deleted = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        let inserted = db.with_bundle_id("edit", |db| {
+            db.save(&Artist { name: "Metallica!".to_string(), summary: None, id: updated.id.clone() })?;
+            db.delete_by_id::<Artist>(&deleted.id)?;
+            Ok(db.save(&Artist { name: "Nirvana".to_string(), ..Default::default() })?)
+        })?;
+
+        db.undo("edit")?;
+        let metallica = db.get::<Artist>(&updated.id)?.unwrap();
+        assert_eq!(metallica.name, "Metallica");
+        assert_eq!(metallica.summary.as_deref(), Some("Thrash"));
+        assert_eq!(db.get::<Artist>(&deleted.id)?.map(|a| a.name).as_deref(), Some("Radiohead"));
+        assert!(db.get::<Artist>(&inserted.id)?.is_none());
+        assert_eq!(db.get_changes_for_bundle("undo:edit")?.len(), 3);
+
+        // The original bundle was changed by the undo, so can't be undone again
+        assert!(db.undo("edit").is_err());
+
+        db.undo("undo:edit")?;
+        assert_eq!(db.get::<Artist>(&updated.id)?.map(|a| a.name).as_deref(), Some("Metallica!"));
+        assert!(db.get::<Artist>(&deleted.id)?.is_none());
+        assert!(db.get::<Artist>(&inserted.id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn undo_is_blocked_by_later_changes() -> Result<()> {
+        let db = setup_db()?;
+        let artist = db.with_bundle_id("create", |db| {
+            Ok(db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?)
+        })?;
+        db.save(&Artist { name: "Metallica!".to_string(), ..artist })?;
+
+        let error = db.undo("create").unwrap_err();
+        assert!(error.to_string().contains("changed after"));
+        assert_eq!(db.count::<Artist, _>("", ())?, 1);
+        assert!(db.undo("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn undo_refuses_untracked_entities() -> Result<()> {
+        let db = setup_db()?;
+        let artist = db.transaction(|t| t.save_untracked(&Artist { name: "Metallica".to_string(), ..Default::default() }))?;
+        db.with_bundle_id("rename", |db| {
+            Ok(db.save(&Artist { name: "Metallica!".to_string(), summary: None, id: artist.id.clone() })?)
+        })?;
+
+        let error = db.undo("rename").unwrap_err();
+        assert!(error.to_string().contains("no tracked state"));
+        assert_eq!(db.get::<Artist>(&artist.id)?.unwrap().name, "Metallica!");
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_refuses_removed_migrations() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            M::up("ALTER TABLE Artist ADD COLUMN summary TEXT;"),
+        ]))?;
+        let fewer = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]);
+        db.check_migration_integrity(&fewer)?;
+        let err = db.migrate(&fewer).unwrap_err();
+        assert!(err.to_string().contains("schema version 2 is newer than the 1 migrations"));
+        // Nothing was rolled back
+        db.save(&Artist { name: "Metallica".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+
+        db.transaction(|t| Ok(t.txn().pragma_update(None, "user_version", -1)?))?;
+        assert!(db.check_migration_integrity(&fewer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn open_memory_named_shares_data() -> Result<()> {
+        let db1 = Db::open_memory_named("open_memory_named_shares_data")?;
+        let db2 = Db::open_memory_named("open_memory_named_shares_data")?;
+        let other = Db::open_memory_named("open_memory_named_other")?;
+        db1.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+        ]))?;
+        let artist = db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        assert_eq!(db2.get::<Artist>(&artist.id)?.unwrap().name, "Metallica");
+        assert_eq!(db1.get_database_uuid(), db2.get_database_uuid());
+        assert!(other.user_table_names()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn with_author_records_author_on_changes() -> Result<()> {
+        let db = setup_db()?;
+        let alice = db.with_author("alice@example.com");
+        let bob = db.with_author("bob@example.com");
+        assert_eq!(alice.get_database_uuid(), db.get_database_uuid());
+        assert_eq!(db.author_id(), db.get_database_uuid());
+
+        assert_eq!(alice.author_id(), db.get_database_uuid());
+        assert_eq!((alice.author(), db.author()), (Some("alice@example.com"), None));
+
+        let artist = alice.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        bob.clone().save(&Artist { summary: Some("Thrash".to_string()), ..bob.get::<Artist>(&artist.id)?.unwrap() })?;
+        db.delete(&artist)?;
+
+        let authors: Vec<(String, Option<String>)> = db.pool.get()?
+            .prepare("SELECT author_id, author FROM ZV_CHANGE ORDER BY id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let uuid = db.get_database_uuid().to_string();
+        assert_eq!(authors, vec![
+            (uuid.clone(), Some("alice@example.com".to_string())),
+            (uuid.clone(), Some("bob@example.com".to_string())),
+            (uuid, None),
+        ]);
+
+        // The author syncs with the change
+        use crate::changelog::Changelog as _;
+        let changes = crate::changelog::DbChangelog::new(db.clone()).get_changes(None, None)?;
+        assert_eq!(changes[0].author.as_deref(), Some("alice@example.com"));
+        let db2 = setup_db()?;
+        crate::changelog::DbChangelog::new(db2.clone()).append_changes(changes)?;
+        let author: Option<String> = db2.pool.get()?
+            .query_row("SELECT author FROM ZV_CHANGE ORDER BY id LIMIT 1", [], |row| row.get(0))?;
+        assert_eq!(author.as_deref(), Some("alice@example.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_subscriptions_unsubscribe() -> Result<()> {
+        let db = setup_db()?;
+        let subscriber_count = |db: &Db| db.subscribers.lock().unwrap().len();
+        let _ = db.subscribe();
+        assert_eq!(subscriber_count(&db), 0);
+
+        let first = db.subscribe();
+        let second = db.subscribe();
+        assert_eq!(subscriber_count(&db), 2);
+        drop(first);
+        assert_eq!(subscriber_count(&db), 1);
+
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        assert!(matches!(second.receiver().try_recv()?, DbEvent::Insert { .. }));
+        drop(second);
+        assert_eq!(subscriber_count(&db), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn table_columns_cache_cleared_by_migrate() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            M::up("ALTER TABLE Artist ADD COLUMN summary TEXT;"),
+        ]))?;
+        let artist = db.save(&Artist { name: "Megadeth".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+        assert_eq!(db.get::<Artist>(&artist.id)?.unwrap().summary.as_deref(), Some("Metal"));
+        Ok(())
+    }
+
+    #[test]
+    fn serde_renamed_fields() -> Result<()> {
+        #[derive(Serialize, Deserialize, Default, Debug)]
+        struct Label {
+            id: String,
+            #[serde(rename = "label_name")]
+            name: String,
+            #[serde(rename = "founded_year")]
+            founded: Option<i64>,
+        }
+
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Label (id TEXT PRIMARY KEY, label_name TEXT NOT NULL, founded_year INTEGER);"),
+        ]))?;
+        let label = db.save(&Label { name: "Sub Pop".to_string(), founded: Some(1986), ..Default::default() })?;
+        assert_eq!(label.name, "Sub Pop");
+
+        let label = db.get::<Label>(&label.id)?.unwrap();
+        assert_eq!(label.name, "Sub Pop");
+        assert_eq!(label.founded, Some(1986));
+        let labels: Vec<Label> = db.query("SELECT * FROM Label WHERE label_name = ?", ["Sub Pop"])?;
+        assert_eq!(labels.len(), 1);
+
+        let label = db.save(&Label { name: "Sub Pop Records".to_string(), ..label })?;
+        assert_eq!(db.get::<Label>(&label.id)?.unwrap().name, "Sub Pop Records");
+        let fields: Vec<String> = db.transaction(|t| {
+            let mut stmt = t.txn().prepare("SELECT DISTINCT field_name FROM ZV_CHANGE_FIELD ORDER BY field_name")?;
+            let fields = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
+            Ok(fields)
+        })?;
+        assert_eq!(fields, vec!["founded_year", "label_name"]);
+        Ok(())
+    }
+
+    #[test]
+    fn type_names_map_to_table_names() -> Result<()> {
+        let db = Db::open_memory()?;
+        assert_eq!(db.table_name_for_type::<Artist>()?, "Artist");
+        assert_eq!(db.table_name_for_type::<Album>()?, "Album");
+        assert_eq!(db.table_name_for_type::<AlbumArtist>()?, "AlbumArtist");
+        Ok(())
+    }
+
+    #[test]
+    fn registered_entities_use_registered_table_name() -> Result<()> {
+        #[derive(Serialize, Deserialize, Default)]
+        struct Tagged<T> {
+            id: String,
+            name: String,
+            #[serde(skip)]
+            tag: std::marker::PhantomData<T>,
+        }
+
+        let db = setup_db()?;
+        assert_eq!(db.table_name_for_type::<Tagged<Artist>>()?, "Tagged");
+        crate::db::register_entity::<Tagged<Artist>>("Artist");
+        assert_eq!(db.table_name_for_type::<Tagged<Artist>>()?, "Artist");
+        assert_eq!(db.table_name_for_type::<Tagged<Album>>()?, "Tagged");
+
+        let artist = db.save(&Tagged::<Artist> { name: "Metallica".to_string(), ..Default::default() })?;
+        let loaded = db.get::<Artist>(&artist.id)?.unwrap();
+        assert_eq!(loaded.name, "Metallica");
+        Ok(())
+    }
+
+    #[test]
+    fn save_generates_uuid_for_new_entities() -> Result<()> {
+        let db = setup_db()?;
+        let artist = db.save(&Artist::default())?;
+        assert!(uuid::Uuid::parse_str(&artist.id).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn uuid_source_makes_ids_deterministic() -> Result<()> {
+        let db1 = setup_db()?;
+        let db2 = setup_db()?;
+        db1.set_uuid_source(Arc::new(SequentialUuidSource::new(1_700_000_000_000)));
+        db2.set_uuid_source(Arc::new(SequentialUuidSource::new(1_700_000_000_000)));
+        let artist1 = db1.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
+        let artist2 = db2.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
+        assert_eq!(artist1.id, artist2.id);
+        assert_eq!(uuid::Uuid::parse_str(&artist1.id)?.get_version_num(), 7);
+
+        let change_ids1: Vec<ChangelogChange> = db1.query("SELECT * FROM ZV_CHANGE", [])?;
+        let change_ids2: Vec<ChangelogChange> = db2.query("SELECT * FROM ZV_CHANGE", [])?;
+        assert_eq!(change_ids1[0].id, change_ids2[0].id);
+        assert!(change_ids1[0].id > artist1.id);
+        Ok(())
+    }
+
+    #[test]
+    fn can_retrieve_saved_entities() -> Result<()> {
+        let db = setup_db()?;
+        let saved = db.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
+        let retrieved: Option<Artist> = db.get(&saved.id)?;
+        
+        assert!(retrieved.is_some());
+        assert_eq!(retrieved.unwrap().name, "Beatles");
+        Ok(())
+    }
+
+    #[test]
+    fn returns_none_for_missing_entities() -> Result<()> {
+        let db = setup_db()?;
+        let result: Option<Artist> = db.get("nonexistent")?;
+        assert!(result.is_none());
+        Ok(())
+    }
+
+    // Schema Handling
+    #[test]
+    fn extra_struct_fields_ignored() -> Result<()> {
+        let db = Db::open_memory()?;
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"), // no summary
+        ]);
+        db.migrate(&migrations)?;
+        
+        let _artist = db.save(&Artist {
+            name: "Tool".to_string(),
+            summary: Some("Won't be saved".to_string()),
+            ..Default::default()
+        })?;
+        
+        // Test passes if no error is thrown during save
+        Ok(())
+    }
+
+    #[test]
+    fn retrieved_entities_only_have_table_fields() -> Result<()> {
+        let db = Db::open_memory()?;
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"), // no summary
+        ]);
+        db.migrate(&migrations)?;
+        
+        let saved = db.save(&Artist {
+            name: "Tool".to_string(),
+            summary: Some("Won't be saved".to_string()),
+            ..Default::default()
+        })?;
+        
+        assert_eq!(saved.summary, None);
+        Ok(())
+    }
+
+    // Event System
+    #[test]
+    fn insert_triggers_insert_event() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+        
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        
+        let event = receiver.recv_timeout(Duration::from_millis(100))?;
+        match event {
+            DbEvent::Insert { entity_type, entity_id, data } => {
+                assert_eq!(entity_type, "Artist");
+                assert_eq!(entity_id, artist.id);
+                assert_eq!(data["name"], "Radiohead");
+            }
+            _ => panic!("Expected Insert event"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn update_triggers_update_event() -> Result<()> {
+        let db = setup_db()?;
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        
+        let receiver = db.subscribe();
+        db.save(&Artist {
+            id: artist.id.clone(),
+            name: "Radiohead Updated".to_string(),
+            ..Default::default()
+        })?;
+        
+        let event = receiver.recv_timeout(Duration::from_millis(100))?;
+        match event {
+            DbEvent::Update { entity_type, entity_id, data, old_data } => {
+                assert_eq!(entity_type, "Artist");
+                assert_eq!(entity_id, artist.id);
+                assert_eq!(data["name"], "Radiohead Updated");
+                assert_eq!(old_data.unwrap()["name"], "Radiohead");
+            }
+            _ => panic!("Expected Update event"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn typed_errors() -> Result<()> {
+        let db = setup_db()?;
+        let error = db.query::<Artist, _>("SELECT * FROM Nope", ()).unwrap_err();
+        assert!(matches!(&error, DimpleError::TableNotFound(table_name) if table_name == "Nope"), "{}", error);
+        assert!(matches!(db.query::<Artist, _>("SELEKT", ()), Err(DimpleError::SqliteError(_))));
+        // Only a message that starts with it is a missing table
+        let error = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(1), Some("oops, no such table: Nope".to_string()));
+        assert!(matches!(DimpleError::from(error), DimpleError::SqliteError(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn save_raw_tracks_changes_like_save() -> Result<()> {
+        let typed = setup_db()?;
+        let raw = setup_db()?;
+        let change_fields = |db: &Db| -> Result<Vec<(String, String, Option<String>)>> {
+            db.transaction(|txn| {
+                let mut stmt = txn.txn().prepare(
+                    "SELECT c.entity_type, f.field_name, f.field_value FROM ZV_CHANGE c
+                        JOIN ZV_CHANGE_FIELD f ON f.change_id = c.id
+                        ORDER BY c.id, f.field_name")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rows)
+            })
+        };
+
+        let artist = typed.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let receiver = raw.subscribe();
+        let saved = raw.save_raw("Artist", serde_json::json!({ "id": artist.id, "name": "Metallica", "genre": "Metal" }))?;
+        assert_eq!(saved, serde_json::json!({ "id": artist.id, "name": "Metallica", "summary": null }));
+        assert!(matches!(receiver.try_recv()?, DbEvent::Insert { entity_type, .. } if entity_type == "Artist"));
+
+        typed.save(&Artist { id: artist.id.clone(), name: artist.name, summary: Some("Thrash".to_string()) })?;
+        raw.save_raw("Artist", serde_json::json!({ "id": artist.id, "name": "Metallica", "summary": "Thrash" }))?;
+        assert!(matches!(receiver.try_recv()?, DbEvent::Update { old_data: Some(_), .. }));
+        assert_eq!(change_fields(&raw)?, change_fields(&typed)?);
+        assert_eq!(raw.get::<Artist>(&artist.id)?.unwrap().summary.as_deref(), Some("Thrash"));
+
+        // A missing id is generated
+        let saved = raw.save_raw("Artist", serde_json::json!({ "name": "Megadeth" }))?;
+        let id = saved["id"].as_str().unwrap();
+        assert_eq!(raw.get::<Artist>(id)?.unwrap().name, "Megadeth");
+
+        assert!(raw.save_raw("Artist", serde_json::json!(["Anthrax"])).is_err());
+        assert!(raw.save_raw("Artist", serde_json::json!({ "id": 1, "name": "Anthrax" })).is_err());
+        assert!(raw.save_raw("Label", serde_json::json!({ "name": "Elektra" })).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_as_json_maps_sqlite_types() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Track (id TEXT PRIMARY KEY, number INTEGER, length REAL, title TEXT, artwork BLOB);"),
+        ]))?;
+        db.execute("INSERT INTO Track VALUES ('1', 7, 312.5, 'One', X'00FF10')", [])?;
+        db.execute("INSERT INTO Track VALUES ('2', NULL, NULL, NULL, NULL)", [])?;
+
+        let rows = db.query_as_json("SELECT * FROM Track ORDER BY id", ())?;
+        assert_eq!(rows, vec![
+            serde_json::json!({ "id": "1", "number": 7, "length": 312.5, "title": "One", "artwork": [0, 255, 16] }),
+            serde_json::json!({ "id": "2", "number": null, "length": null, "title": null, "artwork": null }),
+        ]);
+        assert_eq!(db.query_as_json("SELECT COUNT(*) AS count FROM Track WHERE number > ?", [5])?,
+            vec![serde_json::json!({ "count": 1 })]);
+
+        // Rows can be saved back as they are
+        let mut track = db.find_as_json("SELECT * FROM Track WHERE id = ?", ["1"])?.unwrap();
+        track["id"] = "3".into();
+        db.save_raw("Track", track)?;
+        assert_eq!(db.find_as_json("SELECT artwork, length FROM Track WHERE id = '3'", ())?,
+            Some(serde_json::json!({ "artwork": [0, 255, 16], "length": 312.5 })));
+        assert_eq!(db.find_as_json("SELECT * FROM Track WHERE id = ?", ["4"])?, None);
+        assert!(db.query_as_json("SELECT * FROM Album", ()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn json_export_import_round_trip() -> Result<()> {
+        let source = setup_db()?;
+        source.transaction(|txn| {
+            for i in 0..10_000 {
+                txn.save(&Artist { name: format!("Artist {}", i), summary: (i % 2 == 0).then(|| "Even".to_string()), ..Default::default() })?;
+            }
+            Ok(())
+        })?;
+        let mut json = Vec::new();
+        assert_eq!(source.export_json::<Artist, _>("SELECT * FROM Artist ORDER BY id", (), &mut json)?, 10_000);
+
+        let target = setup_db()?;
+        assert_eq!(target.import_json("Artist", &mut json.as_slice())?, 10_000);
+        let artists = |db: &Db| -> Result<Vec<(String, String, Option<String>)>> {
+            Ok(db.query::<Artist, _>("SELECT * FROM Artist ORDER BY id", ())?
+                .into_iter()
+                .map(|artist| (artist.id, artist.name, artist.summary))
+                .collect())
+        };
+        assert_eq!(artists(&target)?, artists(&source)?);
+        assert_eq!(target.query_scalar::<i64, _>("SELECT COUNT(*) FROM ZV_CHANGE", [])?, 10_000);
+
+        // Nothing is imported if any element fails
+        let empty = setup_db()?;
+        assert_eq!(empty.import_json("Artist", &mut "[]".as_bytes())?, 0);
+        assert!(empty.import_json("Artist", &mut r#"[{"name": "Metallica"}, {"id": 1}]"#.as_bytes()).is_err());
+        assert!(empty.import_json("Artist", &mut r#"{"name": "Metallica"}"#.as_bytes()).is_err());
+        assert!(empty.import_json("Artist", &mut r#"[{"name": "Metallica"}] []"#.as_bytes()).is_err());
+        assert_eq!(empty.count::<Artist, _>("", ())?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn suggest_indexes_for_scanned_columns() -> Result<()> {
+        let db = setup_db()?;
+        let sql = "SELECT * FROM Artist WHERE name = ?";
+        let plan = db.explain_query(sql, ["Metallica"])?;
+        assert_eq!(plan.roots().len(), 1);
+        assert_eq!(plan.full_scans()[0].table.as_deref(), Some("Artist"));
+
+        let suggestions = db.suggest_indexes(sql, ["Metallica"])?;
+        assert_eq!(suggestions, vec![crate::db::IndexSuggestion {
+            table: "Artist".to_string(),
+            columns: vec!["name".to_string()],
+            sql: "CREATE INDEX Artist_name ON Artist (name)".to_string(),
+        }]);
+        // The trial indexes are rolled back
+        assert_eq!(db.query_scalar::<i64, _>("SELECT COUNT(*) FROM sqlite_master WHERE name LIKE 'ZV_SUGGESTED%'", [])?, 0);
+        // Columns that are only selected are not suggested, and aliases are resolved
+        assert!(db.suggest_indexes("SELECT name, summary FROM Artist", ())?.is_empty());
+        assert_eq!(db.suggest_indexes("SELECT a.summary FROM Artist AS a WHERE a.name = ?", ["Metallica"])?, suggestions);
+        assert_eq!(db.suggest_indexes("SELECT a.summary FROM Artist a WHERE a.name = ?", ["Metallica"])?, suggestions);
+
+        db.execute(&suggestions[0].sql, [])?;
+        let plan = db.explain_query(sql, ["Metallica"])?;
+        assert!(plan.full_scans().is_empty());
+        assert_eq!(plan.nodes[0].uses_index.as_deref(), Some("Artist_name"));
+        assert!(db.suggest_indexes(sql, ["Metallica"])?.is_empty());
+        assert!(db.explain_query("SELECT * FROM Label", ()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fts_index_searches_across_columns() -> Result<()> {
+        let db = setup_db()?;
+        let metallica = db.save(&Artist { name: "Metallica".to_string(), summary: Some("Thrash metal from Los Angeles".to_string()), ..Default::default() })?;
+        db.save(&Artist { name: "Slayer".to_string(), summary: Some("Thrash band from Huntington Park".to_string()), ..Default::default() })?;
+        db.create_fts_index("Artist", &["name", "summary"])?;
+        assert!(db.has_fts_index("Artist")?);
+
+        let names = |query: &str| -> Result<Vec<String>> {
+            let mut names: Vec<String> = db.search_fts::<Artist>("Artist", query)?
+                .into_iter()
+                .map(|artist| artist.name)
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+        assert_eq!(names("thrash")?, vec!["Metallica", "Slayer"]);
+        assert_eq!(names("metallica")?, vec!["Metallica"]);
+        assert_eq!(names("thrash AND angeles")?, vec!["Metallica"]);
+        assert_eq!(names("hunt*")?, vec!["Slayer"]);
+
+        // Saves and deletes update the index
+        db.save(&Artist { id: metallica.id.clone(), name: "Metallica".to_string(), summary: Some("Heavy metal from Los Angeles".to_string()) })?;
+        db.save(&Artist { name: "Megadeth".to_string(), summary: Some("Thrash".to_string()), ..Default::default() })?;
+        assert_eq!(names("thrash")?, vec!["Megadeth", "Slayer"]);
+        db.delete(&metallica)?;
+        assert!(names("angeles")?.is_empty());
+
+        // Imports are searchable
+        db.import_json("Artist", &mut r#"[{"name": "Anthrax", "summary": "Thrash from New York"}]"#.as_bytes())?;
+        assert_eq!(names("york")?, vec!["Anthrax"]);
+
+        db.drop_fts_index("Artist")?;
+        assert!(!db.has_fts_index("Artist")?);
+        assert!(db.search_fts::<Artist>("Artist", "thrash").is_err());
+        db.save(&Artist { name: "Exodus".to_string(), ..Default::default() })?;
+        Ok(())
+    }
+
+    #[test]
+    fn save_validated_rejects_invalid_entities() -> Result<()> {
+        use crate::db::ValidationError;
+
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+        let error = db.save_validated(&Artist { name: "".to_string(), ..Default::default() }).unwrap_err();
+        match error {
+            DimpleError::ValidationFailed(errors) => assert_eq!(errors, vec![
+                ValidationError::new("name", "must be at least 2 characters"),
+            ]),
+            e => panic!("Expected ValidationFailed, got {}", e),
+        }
+        assert!(db.query::<Artist, _>("SELECT * FROM Artist", ())?.is_empty());
+        assert!(receiver.try_recv().is_err());
+
+        // Inside a transaction the failure rolls back the transaction
+        let result = db.transaction(|txn| {
+            txn.save_validated(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+            txn.save_validated(&Artist { name: "X".to_string(), ..Default::default() })
+        });
+        assert!(matches!(DimpleError::from(result.unwrap_err()), DimpleError::ValidationFailed(_)));
+        assert!(db.query::<Artist, _>("SELECT * FROM Artist", ())?.is_empty());
+
+        db.save_validated(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        Ok(())
+    }
+
+    #[test]
+    fn backup_copies_database() -> Result<()> {
+        let db = setup_db()?;
+        for i in 0..500 {
+            db.save(&Artist { name: format!("Artist {}", i), summary: Some("x".repeat(100)), ..Default::default() })?;
+        }
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("backup.db");
+        let mut progress = Vec::new();
+        db.backup_progress(&path, |fraction| progress.push(fraction))?;
+        assert!(progress.len() > 2, "{:?}", progress);
+        assert_eq!(progress.first(), Some(&0.0));
+        assert_eq!(progress.last(), Some(&1.0));
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+
+        let copy = Db::open(&path)?;
+        assert_eq!(copy.query::<Artist, _>("SELECT * FROM Artist", ())?.len(), 500);
+        assert_eq!(copy.get_database_uuid(), db.get_database_uuid());
+
+        // Backing up again replaces the file
+        db.save(&Artist { name: "One more".to_string(), ..Default::default() })?;
+        drop(copy);
+        db.backup(&path)?;
+        assert_eq!(Db::open(&path)?.query::<Artist, _>("SELECT * FROM Artist", ())?.len(), 501);
+        Ok(())
+    }
+
+    #[test]
+    fn backup_gives_up_on_a_locked_destination() -> Result<()> {
+        let db = setup_db()?;
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("backup.db");
+        let locker = rusqlite::Connection::open(&path)?;
+        locker.execute_batch("CREATE TABLE t (x); BEGIN EXCLUSIVE;")?;
+        let error = db.backup(&path).unwrap_err();
+        assert!(error.to_string().contains("busy or locked"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_up_and_down() -> Result<()> {
+        let db = Db::open_memory()?;
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);")
+                .down("DROP TABLE Artist;"),
+            M::up("ALTER TABLE Artist ADD COLUMN summary TEXT;")
+                .down("ALTER TABLE Artist DROP COLUMN summary;"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT NOT NULL);")
+                .down("DROP TABLE Album;"),
+        ]);
+        let tables = |db: &Db| -> Result<Vec<String>> {
+            Ok(db.introspect()?.tables.into_iter().map(|t| t.name).collect())
+        };
+        assert_eq!(db.current_migration_version()?, 0);
+
+        db.migrate_to(3, &migrations)?;
+        assert_eq!(db.current_migration_version()?, 3);
+        assert_eq!(tables(&db)?, vec!["Album", "Artist"]);
+        db.save(&Artist { name: "Metallica".to_string(), summary: Some("Thrash".to_string()), ..Default::default() })?;
+
+        db.migrate_to(1, &migrations)?;
+        assert_eq!(db.current_migration_version()?, 1);
+        assert_eq!(tables(&db)?, vec!["Artist"]);
+        // The schema cache was cleared, so saves use the reverted columns
+        let artist = db.save(&Artist { name: "Megadeth".to_string(), summary: Some("Thrash".to_string()), ..Default::default() })?;
+        assert_eq!(artist.summary, None);
+
+        db.migrate(&migrations)?;
+        assert_eq!(db.current_migration_version()?, 3);
+        assert_eq!(db.query::<Artist, _>("SELECT * FROM Artist ORDER BY name", ())?.len(), 2);
+
+        assert!(db.migrate_to(4, &migrations).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn update_event_changed_fields() -> Result<()> {
+        let db = setup_db()?;
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+
+        let receiver = db.subscribe();
+        let artist = db.save(&Artist { summary: Some("English rock band".to_string()), ..artist })?;
+        db.delete_by_id::<Artist>(&artist.id)?;
+
+        let update = receiver.recv_timeout(Duration::from_millis(100))?;
+        assert_eq!(update.old_data().unwrap()["summary"], serde_json::Value::Null);
+        assert_eq!(update.data().unwrap()["summary"], "English rock band");
+        assert_eq!(update.changed_fields(), std::collections::BTreeMap::from([
+            ("summary".to_string(), (serde_json::Value::Null, serde_json::json!("English rock band"))),
+        ]));
+
+        let delete = receiver.recv_timeout(Duration::from_millis(100))?;
+        assert_eq!(delete.changed_fields()["name"], (serde_json::json!("Radiohead"), serde_json::Value::Null));
+        Ok(())
+    }
+
+    #[test]
+    fn filtered_subscriptions_receive_matching_events() -> Result<()> {
+        use std::collections::HashSet;
+        use crate::db::{EventFilter, EventType};
+
+        let db = setup_db()?;
+        let artist_inserts = db.subscribe_filtered(EventFilter {
+            event_types: Some(HashSet::from([EventType::Insert])),
+            entity_types: Some(HashSet::from(["Artist".to_string()])),
+            ..Default::default()
+        });
+        let all = db.subscribe();
+
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        let one_artist = db.subscribe_filtered(EventFilter {
+            entity_ids: Some(HashSet::from([artist.id.clone()])),
+            ..Default::default()
+        });
+        db.save(&Artist { name: "Radiohead Updated".to_string(), ..artist })?;
+        let metallica = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.delete_where::<Artist, _>("id = ?", [&metallica.id])?;
+
+        let types = |sub: &DbEventSubscription| sub.try_iter()
+            .map(|event| (event.event_type(), event.entity_type().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(types(&artist_inserts), vec![
+            (EventType::Insert, "Artist".to_string()),
+            (EventType::Insert, "Artist".to_string()),
+        ]);
+        assert_eq!(types(&one_artist), vec![(EventType::Update, "Artist".to_string())]);
+        assert_eq!(types(&all).len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn table_and_entity_subscriptions() -> Result<()> {
+        let db = setup_db()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT NOT NULL);"),
+        ]))?;
+        let artists = db.subscribe_table("Artist");
+        let albums = db.subscribe_table("Album");
+
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        let one_artist = db.subscribe_entity("Artist", &artist.id);
+        db.save(&Album { title: "OK Computer".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Radiohead Updated".to_string(), ..artist })?;
+
+        let names = |sub: &DbEventSubscription| sub.try_iter()
+            .map(|event| event.entity_type().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names(&artists), vec!["Artist", "Artist", "Artist"]);
+        assert_eq!(names(&albums), vec!["Album"]);
+        assert_eq!(names(&one_artist), vec!["Artist"]);
+
+        let metallica = db.query::<Artist, _>("SELECT * FROM Artist WHERE name = 'Metallica'", [])?.remove(0);
+        db.delete(&metallica)?;
+        let events = artists.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], DbEvent::Delete { entity_id, .. } if *entity_id == metallica.id));
+        assert!(albums.try_recv().is_err());
+        assert!(one_artist.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_with_id_runs_once() -> Result<()> {
+        let db = setup_db()?;
+        let save = |name: &str| db.transaction_with_id("txn-1", |txn| {
+            txn.save(&Artist { name: name.to_string(), ..Default::default() })
+        });
+        assert_eq!(save("Radiohead")?.unwrap().name, "Radiohead");
+        assert!(save("Radiohead")?.is_none());
+        assert_eq!(db.query::<Artist, _>("SELECT * FROM Artist", ())?.len(), 1);
+
+        // A rolled back transaction doesn't record its id
+        let failed: Result<Option<()>> = db.transaction_with_id("txn-2", |_| Err(anyhow::anyhow!("rollback")));
+        assert!(failed.is_err());
+        assert_eq!(db.transaction_with_id("txn-2", |_| Ok(2))?, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_events_fire_after_commit_in_order() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+
+        db.transaction(|txn| {
+            let artist = txn.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            txn.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+            txn.save(&Artist { id: artist.id.clone(), name: "Radiohead Updated".to_string(), ..Default::default() })?;
+            txn.delete_where::<Artist, _>("id = ?", [&artist.id])?;
+            assert!(receiver.try_recv().is_err());
+            Ok(())
+        })?;
+
+        let events: Vec<DbEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[0], DbEvent::Insert { .. }));
+        assert!(matches!(events[1], DbEvent::Insert { .. }));
+        assert!(matches!(events[2], DbEvent::Update { .. }));
+        assert!(matches!(events[3], DbEvent::Delete { .. }));
+        assert_eq!(events[0].entity_id(), events[3].entity_id());
+        Ok(())
+    }
+
+    #[test]
+    fn savepoint_rollback_keeps_outer_changes() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+
+        db.transaction(|txn| {
+            txn.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            let result: Result<()> = txn.savepoint("inner", |txn| {
+                txn.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+                Err(anyhow::anyhow!("inner failed"))
+            });
+            assert!(result.is_err());
+            txn.savepoint("released", |txn| {
+                txn.save(&Artist { name: "Nirvana".to_string(), ..Default::default() })
+            })?;
+            assert!(receiver.try_recv().is_err());
+            Ok(())
+        })?;
+
+        let names: Vec<String> = db.query::<Artist, _>("SELECT * FROM Artist ORDER BY name", ())?
+            .into_iter().map(|artist| artist.name).collect();
+        assert_eq!(names, vec!["Nirvana", "Radiohead"]);
+        let change_count: i64 = db.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE", [])?;
+        assert_eq!(change_count, 2);
+        let metallica_fields: i64 = db.query_scalar(
+            "SELECT COUNT(*) FROM ZV_CHANGE_FIELD WHERE field_value = 'Metallica'", [])?;
+        assert_eq!(metallica_fields, 0);
+
+        let events: Vec<DbEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn nested_savepoints_roll_back_independently() -> Result<()> {
+        let db = setup_db()?;
+        db.transaction(|txn| {
+            txn.savepoint("outer", |txn| {
+                txn.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+                let _ = txn.savepoint("inner", |txn| -> Result<()> {
+                    txn.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+                    Err(anyhow::anyhow!("inner failed"))
+                });
+                Ok(())
+            })
+        })?;
+        let artists: Vec<Artist> = db.query("SELECT * FROM Artist", ())?;
+        assert_eq!(artists.len(), 1);
+        assert_eq!(artists[0].name, "Radiohead");
+        Ok(())
+    }
+
+    #[test]
+    fn failed_transaction_fires_no_events() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+
+        let result: Result<()> = db.transaction(|txn| {
+            txn.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            Err(anyhow::anyhow!("rollback"))
+        });
+        assert!(result.is_err());
+        assert!(receiver.try_recv().is_err());
         Ok(())
     }
 
-    /// Subscribe to be notified of any insert, update, or delete to the database.
-    /// Dropped Receivers will be lazily cleaned up on the next event broadcast.
-    pub fn subscribe(&self) -> Receiver<DbEvent> {
-        let (tx, rx) = mpsc::channel();
-        
-        // Add to subscriber list
-        if let Ok(mut subscribers) = self.subscribers.lock() {
-            subscribers.push(tx);
-        }
-        
-        rx
-    }
-
-    /// Calls the supplied closure with a database transaction that can be
-    /// used to perform writes to the database. Commits automatically
-    /// if the closure returns Ok, otherwise rolls back.
-    pub fn transaction<F, R>(&self, f: F) -> Result<R>
-        where F: FnOnce(&DbTransaction) -> Result<R> {
-        let mut conn = self.pool.get()?;
+    #[cfg(feature = "async")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        use std::task::{Context, Poll, Wake, Waker};
 
-        let mut txn = conn.transaction()?;
-        txn.set_drop_behavior(rusqlite::DropBehavior::Rollback);
-        let db_txn = DbTransaction::new(self, &txn);
-        let result = f(&db_txn);
-        if result.is_ok() {
-            // Collect events before committing
-            let pending_events = db_txn.take_pending_events();
-            txn.commit()?;
-            // Notify subscribers only after successful commit
-            for event in pending_events {
-                self.notify_subscribers(event);
+        struct ThreadWaker(thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
             }
         }
-        else {
-            txn.rollback()?;
+
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
         }
-        result
     }
 
-    /// Shortcut to create a transaction and save a single entity.
-    /// See DbTransaction.save()
-    pub fn save<T: Entity>(&self, entity: &T) -> Result<T> {
-        self.transaction(|t| t.save(entity))
+    #[cfg(feature = "async")]
+    #[test]
+    fn transaction_async_commits() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+        let artist = block_on(db.transaction_async(|t| Box::pin(async move {
+            let name = async { "Radiohead".to_string() }.await;
+            t.save(&Artist { name, ..Default::default() })
+        })))?;
+        assert_eq!(db.get::<Artist>(&artist.id)?.unwrap().name, "Radiohead");
+        assert!(matches!(receiver.try_recv()?, DbEvent::Insert { .. }));
+        Ok(())
     }
 
-    /// Simple query without creating a transaction.
-    pub fn query<E: Entity, P: Params>(&self, sql: &str, params: P) -> Result<Vec<E>> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(sql)?;
-        let entities = serde_rusqlite::from_rows::<E>(stmt.query(params)?)
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(entities)
+    #[cfg(feature = "async")]
+    #[test]
+    fn transaction_async_rolls_back_on_error() -> Result<()> {
+        let db = setup_db()?;
+        let result: Result<()> = block_on(db.transaction_async(|t| Box::pin(async move {
+            t.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            Err(anyhow::anyhow!("failed"))
+        })));
+        assert!(result.is_err());
+        let artists: Vec<Artist> = db.query("SELECT * FROM Artist", [])?;
+        assert!(artists.is_empty());
+        Ok(())
     }
 
-    /// Get a single entity by id without creating a transaction.
-    pub fn get<E: Entity>(&self, id: &str) -> Result<Option<E>> {
-        let table_name = self.table_name_for_type::<E>()?;
-        let sql = format!("SELECT * FROM {} WHERE id = ? LIMIT 1", table_name);
-        Ok(self.query::<E, _>(&sql, [id])?.into_iter().next())
+    #[cfg(feature = "async")]
+    #[test]
+    fn transaction_async_panic_rolls_back() -> Result<()> {
+        let db = setup_db()?;
+        let result: Result<()> = block_on(db.transaction_async(|t| Box::pin(async move {
+            t.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            async {}.await;
+            panic!("lost connection to {}", "the band");
+        })));
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("lost connection to the band"), "{}", err);
+        let artists: Vec<Artist> = db.query("SELECT * FROM Artist", [])?;
+        assert!(artists.is_empty());
+        Ok(())
     }
 
-    /// Get the database's unique UUIDv7. This is created when the database is
-    /// first initialized and never changes.
-    pub fn get_database_uuid(&self) -> Result<String> {
-        Ok(self.database_uuid.clone())
-    }
+    #[test]
+    fn transaction_panic_rolls_back() -> Result<()> {
+        let db = setup_db()?;
+        let receiver = db.subscribe();
+        let result: Result<()> = db.transaction(|t| {
+            t.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+            panic!("lost connection to {}", "the band");
+        });
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("lost connection to the band"));
+        assert!(receiver.try_recv().is_err());
 
-    /// Performs the given query, calling the closure with the results
-    /// immediately and then again any time any table referenced in the query
-    /// changes. Returns a QuerySubscription that automatically unsubscribes the
-    /// query on drop or via QuerySubscription.unsubscribe(). Each
-    /// subscription creates a monitoring thread that uses read-only queries.
-    pub fn query_subscribe<E, P, F>(&self, sql: &str, params: P, f: F) 
-        -> Result<QuerySubscription> 
-        where 
-            E: Entity + 'static, 
-            P: Params + Clone + Send + 'static, 
-            F: FnMut(Vec<E>) + Send + 'static {
-        QuerySubscription::new(self, sql, params, f)
-    } 
+        // The connection is usable after the panic
+        let artists: Vec<Artist> = db.query("SELECT * FROM Artist", [])?;
+        assert!(artists.is_empty());
+        db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        Ok(())
+    }
 
-    fn from_pool(pool: Pool<SqliteConnectionManager>) -> Result<Self> {
-        let conn = pool.get()?;
-        crate::changelog::init_change_tracking_tables(&conn)?;
-        let database_uuid: String = conn.query_row(
-            "SELECT value FROM ZV_METADATA WHERE key = 'database_uuid'",
-            [],
-            |row| row.get(0)
-        )?;
+    #[test]
+    fn analyze_collects_statistics() -> Result<()> {
+        let db = setup_db()?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.analyze()?;
+        db.analyze_table("Artist")?;
+        let stats: i64 = db.query_scalar("SELECT COUNT(*) FROM sqlite_stat1", [])?;
+        assert!(stats > 0);
+        Ok(())
+    }
 
-        let db = Db {
-            pool,
-            subscribers: Arc::new(Mutex::new(Vec::new())),
-            database_uuid,
-        };
+    #[test]
+    fn vacuum_truncates_wal() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("vacuum.db");
+        let db = Db::open(&path)?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+        ]))?;
+        for i in 0..200 {
+            db.save(&Artist { name: format!("Artist {}", i), summary: Some("x".repeat(1000)), ..Default::default() })?;
+        }
+        db.delete_all::<Artist>()?;
+        let wal_path = temp_dir.path().join("vacuum.db-wal");
+        assert!(std::fs::metadata(&wal_path)?.len() > 0);
 
-        Ok(db)
+        db.vacuum()?;
+        assert_eq!(std::fs::metadata(&wal_path)?.len(), 0);
+        db.optimize()?;
+        db.analyze()?;
+        assert!(db.query::<Artist, _>("SELECT * FROM Artist", ())?.is_empty());
+        Ok(())
     }
 
-    pub fn table_name_for_type<T>(&self) -> Result<String> {
-        let full_name = std::any::type_name::<T>();
-        // Extract just the struct name from the full path
-        Ok(full_name.split("::").last().unwrap_or(full_name).to_string())
+    #[test]
+    fn auto_analyze_after_n_writes() -> Result<()> {
+        let db = setup_db()?;
+        let stats_exist = |db: &Db| -> Result<bool> {
+            db.query_scalar("SELECT COUNT(*) > 0 FROM sqlite_master WHERE name = 'sqlite_stat1'", [])
+        };
+        db.set_auto_analyze_after_n_writes(3);
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        assert!(!stats_exist(&db)?);
+        db.save(&Artist { name: "Slayer".to_string(), ..Default::default() })?;
+        assert!(stats_exist(&db)?);
+        Ok(())
     }
 
-    pub(crate) fn table_column_names(&self, txn: &Transaction, table_name: &str) -> Result<Vec<String>> {
-        let mut stmt = txn.prepare(&format!("PRAGMA table_info({})", table_name))?;
-        let column_names = stmt.query_map([], |row| {
-            row.get::<_, String>(1) // Column name is at index 1
-        })?
-        .collect::<Result<Vec<_>, _>>()?;
-        
-        if column_names.is_empty() {
-            return Err(anyhow::anyhow!("Table '{}' not found or has no columns", table_name));
+    #[test]
+    fn delete_all_modes() -> Result<()> {
+        let db = setup_db()?;
+        for name in ["Metallica", "Megadeth", "Slayer"] {
+            db.save(&Artist { name: name.to_string(), ..Default::default() })?;
         }
-        
-        Ok(column_names)
-    }
-    
-    pub(crate) fn notify_subscribers(&self, event: DbEvent) {
-        if let Ok(mut subscribers) = self.subscribers.lock() {
-            // Send to all subscribers, remove ones that fail
-            subscribers.retain(|tx| {
-                tx.send(event.clone()).is_ok()
-            });
+        let receiver = db.subscribe();
+        assert_eq!(db.delete_all::<Artist>()?, 3);
+        assert_eq!(receiver.try_iter().count(), 3);
+        let deletes: i64 = db.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE_FIELD WHERE field_name = 'ZV_DELETED'", [])?;
+        assert_eq!(deletes, 3);
+
+        db.set_bulk_delete_threshold(1);
+        let mut ids = vec![];
+        for name in ["Metallica", "Megadeth", "Slayer"] {
+            ids.push(db.save(&Artist { name: name.to_string(), ..Default::default() })?.id);
         }
+        let receiver = db.subscribe();
+        let entity_receiver = db.subscribe_entity("Artist", &ids[0]);
+        assert_eq!(db.delete_all::<Artist>()?, 3);
+        assert!(db.query::<Artist, _>("SELECT * FROM Artist", [])?.is_empty());
+        let events: Vec<DbEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].entity_id(), crate::changelog::ALL_ENTITIES_ID);
+        // The bulk delete deleted the entity too
+        assert_eq!(entity_receiver.try_iter().count(), 1);
+        let deletes: i64 = db.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE_FIELD WHERE field_name = 'ZV_DELETED'", [])?;
+        assert_eq!(deletes, 4);
+        Ok(())
     }
-}
 
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct Setting {
+        key: String,
+        value: String,
+    }
 
-#[derive(Debug)]
-struct DbConnectionCustomizer;
-impl CustomizeConnection<rusqlite::Connection, rusqlite::Error> for DbConnectionCustomizer {
-    fn on_acquire(&self, conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
-        conn.pragma_update(None, "journal_mode", "WAL")?;
-        conn.pragma_update(None, "foreign_keys", "ON")?;
-        conn.create_scalar_function("uuid7", 0, FunctionFlags::SQLITE_UTF8, |_ctx| {
-            Ok(Uuid::now_v7().to_string())
-        })?;
-        Ok(())
+    fn setup_settings_db() -> Result<Db> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Setting (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);"),
+        ]))?;
+        Ok(db)
     }
-}
 
+    #[test]
+    fn primary_key_is_the_id_column() -> Result<()> {
+        let db = setup_settings_db()?;
+        assert_eq!(db.id_column_for_type::<Setting>()?, "key");
+        assert_eq!(setup_db()?.id_column_for_type::<Artist>()?, "id");
 
-#[cfg(test)]
-mod tests {
-    use anyhow::Result;
-    use serde::{Deserialize, Serialize};
-    use rusqlite_migration::{Migrations, M};
-    use std::sync::mpsc::channel;
-    use std::thread;
-    use std::time::Duration;
+        let generated = db.save(&Setting { value: "generated".to_string(), ..Default::default() })?;
+        assert!(!generated.key.is_empty());
+        db.save(&Setting { key: "theme".to_string(), value: "light".to_string() })?;
+        db.save(&Setting { key: "theme".to_string(), value: "dark".to_string() })?;
+        assert_eq!(db.get::<Setting>("theme")?.unwrap().value, "dark");
+        assert_eq!(db.get_many::<Setting>(&["theme", &generated.key])?.len(), 2);
 
-    use crate::db::{Db, DbEvent};
+        // The key is the entity id in change records, not a field
+        let fields: Vec<String> = db.transaction(|t| {
+            let mut stmt = t.txn().prepare("SELECT DISTINCT field_name FROM ZV_CHANGE_FIELD ORDER BY field_name")?;
+            let fields = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
+            Ok(fields)
+        })?;
+        assert_eq!(fields, vec!["value"]);
+        let changes: Vec<ChangelogChange> = db.query("SELECT * FROM ZV_CHANGE WHERE entity_id = 'theme'", [])?;
+        assert_eq!(changes.len(), 2);
 
-    fn setup_db() -> Result<Db> {
-        let db = Db::open_memory()?;
-        let migrations = Migrations::new(vec![
-            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
-        ]);
-        db.migrate(&migrations)?;
-        Ok(db)
+        assert!(db.delete(&generated)?);
+        assert!(db.delete_by_id::<Setting>("theme")?);
+        assert!(db.query::<Setting, _>("SELECT * FROM Setting", [])?.is_empty());
+        Ok(())
+    }
+
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct Track {
+        id: String,
+        number: i64,
+        name: String,
     }
 
-    // Database Operations
     #[test]
-    fn can_create_database() -> Result<()> {
-        let _ = Db::open_memory()?;
+    fn non_text_primary_key_is_not_the_id_column() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Track (number INTEGER PRIMARY KEY, id TEXT NOT NULL UNIQUE, name TEXT NOT NULL);"),
+        ]))?;
+        assert_eq!(db.id_column_for_type::<Track>()?, "id");
+
+        let track = db.save(&Track { number: 1, name: "Battery".to_string(), ..Default::default() })?;
+        db.save(&Track { number: 2, name: "Master of Puppets".to_string(), ..Default::default() })?;
+        assert_eq!(db.get_many::<Track>(&[&track.id])?.len(), 1);
+        assert_eq!(db.transaction(|t| t.delete_where::<Track, _>("number = ?", [2]))?, 1);
+        assert_eq!(db.count::<Track, _>("", ())?, 1);
         Ok(())
     }
 
     #[test]
-    fn type_names_map_to_table_names() -> Result<()> {
-        let db = Db::open_memory()?;
-        assert_eq!(db.table_name_for_type::<Artist>()?, "Artist");
-        assert_eq!(db.table_name_for_type::<Album>()?, "Album");
-        assert_eq!(db.table_name_for_type::<AlbumArtist>()?, "AlbumArtist");
+    fn exists_and_count() -> Result<()> {
+        let db = setup_db()?;
+        let by_name = "SELECT * FROM Artist WHERE name LIKE ?";
+        assert!(!db.exists::<Artist, _>("", [])?);
+        assert_eq!(db.count::<Artist, _>("", [])?, 0);
+        assert!(!db.exists::<Artist, _>(by_name, ["M%"])?);
+
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        assert!(db.exists::<Artist, _>("", [])?);
+        assert_eq!(db.count::<Artist, _>("", [])?, 1);
+        assert!(db.exists::<Artist, _>(by_name, ["M%"])?);
+        assert_eq!(db.count::<Artist, _>(by_name, ["M%"])?, 1);
+
+        db.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Slayer".to_string(), ..Default::default() })?;
+        assert_eq!(db.count::<Artist, _>("", [])?, 3);
+        assert_eq!(db.count::<Artist, _>(by_name, ["M%"])?, 2);
+        assert!(!db.exists::<Artist, _>(by_name, ["X%"])?);
         Ok(())
     }
 
     #[test]
-    fn save_generates_uuid_for_new_entities() -> Result<()> {
+    fn merge_updates_only_named_fields() -> Result<()> {
         let db = setup_db()?;
-        let artist = db.save(&Artist::default())?;
-        assert!(uuid::Uuid::parse_str(&artist.id).is_ok());
+        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        db.save(&Artist { summary: Some("From Abingdon".to_string()), ..db.get::<Artist>(&artist.id)?.unwrap() })?;
+
+        // artist is stale, its summary is None
+        let merged = db.merge(&Artist { name: "Radiohead!".to_string(), ..artist }, &["name"])?;
+        assert_eq!(merged.name, "Radiohead!");
+        assert_eq!(merged.summary.as_deref(), Some("From Abingdon"));
+
+        assert!(db.merge(&Artist { id: "missing".to_string(), ..Default::default() }, &["name"]).is_err());
+        assert!(db.merge(&merged, &["nope"]).is_err());
         Ok(())
     }
 
     #[test]
-    fn can_retrieve_saved_entities() -> Result<()> {
+    fn get_many_returns_existing_entities_by_id() -> Result<()> {
         let db = setup_db()?;
-        let saved = db.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
-        let retrieved: Option<Artist> = db.get(&saved.id)?;
-        
-        assert!(retrieved.is_some());
-        assert_eq!(retrieved.unwrap().name, "Beatles");
+        assert!(db.get_many::<Artist>(&[])?.is_empty());
+
+        let artists = db.save_many(&(0..600)
+            .map(|i| Artist { name: format!("Artist {}", i), ..Default::default() })
+            .collect::<Vec<_>>())?;
+        let ids: Vec<&str> = artists.iter().map(|artist| artist.id.as_str()).collect();
+        let found = db.get_many::<Artist>(&ids)?;
+        assert_eq!(found.len(), 600);
+        assert_eq!(found[&artists[599].id].name, "Artist 599");
+
+        let found = db.get_many::<Artist>(&[&artists[0].id, "missing", &artists[0].id])?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[&artists[0].id].name, "Artist 0");
         Ok(())
     }
 
     #[test]
-    fn returns_none_for_missing_entities() -> Result<()> {
+    fn save_many_saves_in_one_transaction() -> Result<()> {
         let db = setup_db()?;
-        let result: Option<Artist> = db.get("nonexistent")?;
-        assert!(result.is_none());
+        let artists: Vec<Artist> = (0..100)
+            .map(|i| Artist { name: format!("Artist {}", i), ..Default::default() })
+            .collect();
+        let receiver = db.subscribe();
+        let saved = db.save_many(&artists)?;
+        assert_eq!(saved.len(), 100);
+        assert!(saved.iter().all(|artist| !artist.id.is_empty()));
+        assert_eq!(saved[99].name, "Artist 99");
+        assert_eq!(receiver.try_iter().count(), 100);
+
+        // A failure rolls back the whole batch
+        db.transaction(|t| Ok(t.txn().execute_batch("CREATE TRIGGER no_duplicates BEFORE UPDATE ON Artist
+            WHEN NEW.name = 'Duplicate' BEGIN SELECT RAISE(ABORT, 'no'); END")?))?;
+        let invalid = vec![
+            Artist { name: "Valid".to_string(), ..Default::default() },
+            Artist { id: saved[0].id.clone(), name: "Duplicate".to_string(), ..Default::default() },
+        ];
+        assert!(db.save_many(&invalid).is_err());
+        assert_eq!(db.query::<Artist, _>("SELECT * FROM Artist WHERE name = 'Valid'", [])?.len(), 0);
+        assert!(receiver.try_recv().is_err());
         Ok(())
     }
 
-    // Schema Handling
+    /// Timing dependent, run with cargo test --release -- --ignored
     #[test]
-    fn extra_struct_fields_ignored() -> Result<()> {
-        let db = Db::open_memory()?;
-        let migrations = Migrations::new(vec![
-            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"), // no summary
-        ]);
-        db.migrate(&migrations)?;
-        
-        let _artist = db.save(&Artist {
-            name: "Tool".to_string(),
-            summary: Some("Won't be saved".to_string()),
-            ..Default::default()
-        })?;
-        
-        // Test passes if no error is thrown during save
+    #[ignore]
+    fn save_many_is_faster_than_save() -> Result<()> {
+        // On disk, where each transaction's commit has a real cost
+        let temp_dir = tempfile::tempdir()?;
+        let db = Db::open(temp_dir.path().join("test.db"))?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, summary TEXT);"),
+        ]))?;
+        let artists: Vec<Artist> = (0..1000)
+            .map(|i| Artist { name: format!("Artist {}", i), ..Default::default() })
+            .collect();
+
+        let start = std::time::Instant::now();
+        db.save_many(&artists)?;
+        let bulk = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for artist in &artists {
+            db.save(artist)?;
+        }
+        let sequential = start.elapsed();
+        println!("save_many: {:?}, save: {:?}", bulk, sequential);
+        assert!(bulk * 5 < sequential, "save_many {:?}, save {:?}", bulk, sequential);
         Ok(())
     }
 
     #[test]
-    fn retrieved_entities_only_have_table_fields() -> Result<()> {
-        let db = Db::open_memory()?;
-        let migrations = Migrations::new(vec![
-            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"), // no summary
-        ]);
-        db.migrate(&migrations)?;
-        
-        let saved = db.save(&Artist {
-            name: "Tool".to_string(),
-            summary: Some("Won't be saved".to_string()),
-            ..Default::default()
-        })?;
-        
-        assert_eq!(saved.summary, None);
+    fn delete_and_delete_by_id() -> Result<()> {
+        let db = setup_db()?;
+        let beatles = db.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
+        let metallica = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let receiver = db.subscribe();
+
+        assert!(db.delete(&beatles)?);
+        assert!(db.delete_by_id::<Artist>(&metallica.id)?);
+        assert!(db.query::<Artist, _>("SELECT * FROM Artist", [])?.is_empty());
+        let events: Vec<DbEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], DbEvent::Delete { old_data, .. } if old_data["name"] == "Beatles"));
+
+        // Deleting a missing entity records the delete, but fires no event
+        assert!(!db.delete_by_id::<Artist>("missing")?);
+        assert!(receiver.try_recv().is_err());
+        let deletes: i64 = db.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE c JOIN ZV_CHANGE_FIELD cf ON c.id = cf.change_id
+            WHERE c.entity_id = 'missing' AND cf.field_name = 'ZV_DELETED'", [])?;
+        assert_eq!(deletes, 1);
         Ok(())
     }
 
-    // Event System
     #[test]
-    fn insert_triggers_insert_event() -> Result<()> {
+    fn delete_where_deletes_and_notifies() -> Result<()> {
         let db = setup_db()?;
+        let beatles = db.save(&Artist { name: "Beatles".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Bee Gees".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
         let receiver = db.subscribe();
-        
-        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
-        
-        let event = receiver.recv_timeout(Duration::from_millis(100))?;
-        match event {
-            DbEvent::Insert(table_name, entity_id) => {
-                assert_eq!(table_name, "Artist");
-                assert_eq!(entity_id, artist.id);
+
+        let deleted = db.delete_where::<Artist, _>("name LIKE ?", ["B%"])?;
+        assert_eq!(deleted, 2);
+        let remaining: Vec<Artist> = db.query("SELECT * FROM Artist", [])?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "Metallica");
+
+        let events: Vec<DbEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        let beatles_event = events.iter().find(|e| e.entity_id() == beatles.id).unwrap();
+        match beatles_event {
+            DbEvent::Delete { entity_type, old_data, .. } => {
+                assert_eq!(entity_type, "Artist");
+                assert_eq!(old_data["name"], "Beatles");
             }
-            _ => panic!("Expected Insert event"),
+            _ => panic!("Expected Delete event"),
         }
+
+        let changes: Vec<ChangelogChange> = db.query("SELECT * FROM ZV_CHANGE WHERE entity_id = ?", [&beatles.id])?;
+        assert_eq!(changes.len(), 2);
+        assert_eq!(db.delete_where::<Artist, _>("name LIKE ?", ["B%"])?, 0);
         Ok(())
     }
 
     #[test]
-    fn update_triggers_update_event() -> Result<()> {
+    fn param_styles() -> Result<()> {
         let db = setup_db()?;
-        let artist = db.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
-        
-        let receiver = db.subscribe();
-        db.save(&Artist {
-            id: artist.id.clone(),
-            name: "Radiohead Updated".to_string(),
-            ..Default::default()
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let sql = "SELECT * FROM Artist WHERE name = ?";
+        assert_eq!(db.query::<Artist, _>(sql, rusqlite::params!["Metallica"])?.len(), 1);
+        assert_eq!(db.query::<Artist, _>(sql, ["Metallica"])?.len(), 1);
+        assert_eq!(db.query::<Artist, _>(sql, ("Metallica",))?.len(), 1);
+        assert_eq!(db.transaction(|t| t.query::<Artist, _>(sql, rusqlite::params!["Metallica"]))?.len(), 1);
+
+        let (tx, rx) = channel::<usize>();
+        let tx1 = tx.clone();
+        let _sub1 = db.query_subscribe(sql, ["Metallica".to_string()], move |artists: Vec<Artist>| {
+            tx1.send(artists.len()).unwrap();
         })?;
-        
-        let event = receiver.recv_timeout(Duration::from_millis(100))?;
-        match event {
-            DbEvent::Update(table_name, entity_id) => {
-                assert_eq!(table_name, "Artist");
-                assert_eq!(entity_id, artist.id);
-            }
-            _ => panic!("Expected Update event"),
+        let tx2 = tx.clone();
+        let _sub2 = db.query_subscribe(sql, ("Metallica",), move |artists: Vec<Artist>| {
+            tx2.send(artists.len()).unwrap();
+        })?;
+        let _sub3 = db.query_subscribe(sql, rusqlite::params_from_iter(vec!["Metallica".to_string()]), move |artists: Vec<Artist>| {
+            tx.send(artists.len()).unwrap();
+        })?;
+        for _ in 0..3 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(1))?, 1);
         }
         Ok(())
     }
 
+    #[test]
+    fn find_by_and_query_by() -> Result<()> {
+        let db = setup_db()?;
+        db.save(&Artist { name: "Metallica".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+        db.save(&Artist { name: "Megadeth".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+        assert_eq!(db.find_by::<Artist, _>("name", "Megadeth")?.unwrap().name, "Megadeth");
+        assert!(db.find_by::<Artist, _>("name", "Slayer")?.is_none());
+        assert_eq!(db.query_by::<Artist, _>("summary", "Metal")?.len(), 2);
+        assert!(db.query_by::<Artist, _>("name = name OR 1", "Metal").is_err());
+        assert!(db.find_by::<Artist, _>("genre", "Metal").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_named_params() -> Result<()> {
+        let db = setup_db()?;
+        db.save(&Artist { name: "Metallica".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+        db.save(&Artist { name: "Megadeth".to_string(), summary: Some("Metal".to_string()), ..Default::default() })?;
+        let sql = "SELECT * FROM Artist WHERE summary = :summary AND name LIKE :name";
+        let summary = "Metal".to_string();
+        let artists: Vec<Artist> = db.query_named(sql, &[(":summary", &summary), (":name", &"Me%")])?;
+        assert_eq!(artists.len(), 2);
+        let artist: Option<Artist> = db.query_one_named(sql, &[(":name", &"Metal%"), (":summary", &summary)])?;
+        assert_eq!(artist.unwrap().name, "Metallica");
+        let artist: Option<Artist> = db.query_one_named(sql, &[(":name", &"Slayer"), (":summary", &summary)])?;
+        assert!(artist.is_none());
+        Ok(())
+    }
+
     #[test]
     fn query_subscribe() -> Result<()> {
         let db = setup_db()?;
@@ -549,6 +2999,15 @@ mod tests {
         pub summary: Option<String>,
     }
 
+    impl crate::db::Validate for Artist {
+        fn validate(&self) -> std::result::Result<(), Vec<crate::db::ValidationError>> {
+            if self.name.chars().count() < 2 {
+                return Err(vec![crate::db::ValidationError::new("name", "must be at least 2 characters")]);
+            }
+            Ok(())
+        }
+    }
+
     #[derive(Serialize, Deserialize, Default, Debug)]
     pub struct Album {
         pub id: String,
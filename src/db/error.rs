@@ -0,0 +1,173 @@
+use std::fmt;
+
+use crate::{db::ValidationError, storage::StorageHttpError};
+
+/// Errors returned by the main Db and SyncEngine methods, so callers can
+/// match on specific failures. Other methods return anyhow::Error, which a
+/// DimpleError converts to and from, so `?` works in either direction.
+#[derive(Debug)]
+pub enum DimpleError {
+    TableNotFound(String),
+    EntityNotFound { entity_type: String, key: String },
+    SqliteError(rusqlite::Error),
+    SerializationError(serde_json::Error),
+    MigrationError(rusqlite_migration::Error),
+    SyncError(String),
+    StorageError(String),
+    LockError,
+    /// Returned by save_validated() when Validate::validate() fails, before
+    /// anything is written.
+    ValidationFailed(Vec<ValidationError>),
+    /// Any other error, such as failing to get a connection from the pool.
+    Other(anyhow::Error),
+}
+
+pub type DimpleResult<T> = std::result::Result<T, DimpleError>;
+
+impl DimpleError {
+    /// Like From<anyhow::Error>, but errors of unknown types become
+    /// StorageError if they were caused by the network or IO, or by an HTTP
+    /// status that may succeed on a later attempt, such as 503, or SyncError
+    /// otherwise.
+    pub(crate) fn from_sync(e: anyhow::Error) -> Self {
+        match DimpleError::from(e) {
+            DimpleError::Other(e) if e.chain().any(is_transient_cause) => DimpleError::StorageError(format!("{:#}", e)),
+            DimpleError::Other(e) => DimpleError::SyncError(format!("{:#}", e)),
+            e => e,
+        }
+    }
+}
+
+fn is_transient_cause(cause: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = cause.downcast_ref::<StorageHttpError>() {
+        return e.is_transient();
+    }
+    if let Some(s3::error::S3Error::Http(status, _)) = cause.downcast_ref::<s3::error::S3Error>() {
+        return StorageHttpError::new(*status, "").is_transient();
+    }
+    cause.is::<std::io::Error>() || cause.is::<attohttpc::Error>()
+}
+
+impl fmt::Display for DimpleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DimpleError::TableNotFound(table_name) => write!(f, "Table not found: {}", table_name),
+            DimpleError::EntityNotFound { entity_type, key } => write!(f, "{} not found: {}", entity_type, key),
+            DimpleError::SqliteError(e) => write!(f, "SQLite error: {}", e),
+            DimpleError::SerializationError(e) => write!(f, "Serialization error: {}", e),
+            DimpleError::MigrationError(e) => write!(f, "Migration error: {}", e),
+            DimpleError::SyncError(message) => write!(f, "Sync error: {}", message),
+            DimpleError::StorageError(message) => write!(f, "Storage error: {}", message),
+            DimpleError::LockError => write!(f, "Lock poisoned"),
+            DimpleError::ValidationFailed(errors) => write!(f, "Validation failed: {}",
+                errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ")),
+            DimpleError::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for DimpleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DimpleError::SqliteError(e) => Some(e),
+            DimpleError::SerializationError(e) => Some(e),
+            DimpleError::MigrationError(e) => Some(e),
+            DimpleError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// SQLite's "no such table" errors become TableNotFound. SQLite has no
+/// result code for them, so they're recognized by the start of SQLite's
+/// error message.
+impl From<rusqlite::Error> for DimpleError {
+    fn from(e: rusqlite::Error) -> Self {
+        let message = match &e {
+            rusqlite::Error::SqliteFailure(_, Some(msg)) => Some(msg.as_str()),
+            rusqlite::Error::SqlInputError { msg, .. } => Some(msg.as_str()),
+            _ => None,
+        };
+        match message.and_then(|msg| msg.strip_prefix("no such table: ")) {
+            Some(table_name) => DimpleError::TableNotFound(table_name.trim().to_string()),
+            None => DimpleError::SqliteError(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for DimpleError {
+    fn from(e: serde_json::Error) -> Self {
+        DimpleError::SerializationError(e)
+    }
+}
+
+impl From<rusqlite_migration::Error> for DimpleError {
+    fn from(e: rusqlite_migration::Error) -> Self {
+        DimpleError::MigrationError(e)
+    }
+}
+
+impl From<r2d2::Error> for DimpleError {
+    fn from(e: r2d2::Error) -> Self {
+        DimpleError::Other(e.into())
+    }
+}
+
+/// Recovers the typed error if the anyhow::Error wraps one, otherwise Other.
+impl From<anyhow::Error> for DimpleError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<DimpleError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<rusqlite::Error>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<serde_json::Error>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<rusqlite_migration::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => DimpleError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use crate::storage::StorageHttpError;
+
+    use super::DimpleError;
+
+    #[test]
+    fn anyhow_round_trip() {
+        let e: anyhow::Error = DimpleError::EntityNotFound { entity_type: "Artist".to_string(), key: "1".to_string() }.into();
+        assert!(matches!(DimpleError::from(e), DimpleError::EntityNotFound { key, .. } if key == "1"));
+
+        let e: anyhow::Error = rusqlite::Error::QueryReturnedNoRows.into();
+        assert!(matches!(DimpleError::from(e), DimpleError::SqliteError(rusqlite::Error::QueryReturnedNoRows)));
+
+        assert!(matches!(DimpleError::from(anyhow!("boom")), DimpleError::Other(_)));
+        assert!(matches!(DimpleError::from_sync(anyhow!("boom")), DimpleError::SyncError(message) if message == "boom"));
+        let e = anyhow::Error::from(std::io::Error::other("refused")).context("list failed");
+        assert!(matches!(DimpleError::from_sync(e), DimpleError::StorageError(message) if message == "list failed: refused"));
+    }
+
+    #[test]
+    fn http_statuses_are_classified() {
+        for status in [500, 503, 429] {
+            let e = anyhow::Error::from(StorageHttpError::new(status, "unavailable")).context("get failed");
+            assert!(matches!(DimpleError::from_sync(e), DimpleError::StorageError(message) if message == "get failed: unavailable"));
+        }
+        for status in [400, 403, 404] {
+            let e = anyhow::Error::from(StorageHttpError::new(status, "refused"));
+            assert!(matches!(DimpleError::from_sync(e), DimpleError::SyncError(_)));
+        }
+        let e = anyhow::Error::from(s3::error::S3Error::Http(503, "Slow Down".to_string()));
+        assert!(matches!(DimpleError::from_sync(e), DimpleError::StorageError(_)));
+    }
+}
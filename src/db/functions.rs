@@ -0,0 +1,76 @@
+use anyhow::Result;
+use rusqlite::{functions::FunctionFlags, Connection};
+use uuid::Uuid;
+
+/// Register DimpleDb's SQL functions on the connection. Db registers them
+/// on every connection it opens, so this is only needed for connections
+/// opened some other way, such as by a migration tool.
+///
+/// - uuid7() returns a new UUIDv7 string, the format Db uses for ids.
+/// - uuid4() returns a new random UUIDv4 string.
+///
+/// They can be used as column defaults, so rows inserted with plain SQL get
+/// an id:
+///
+/// ```sql
+/// CREATE TABLE Artist (id TEXT PRIMARY KEY DEFAULT (uuid7()), name TEXT NOT NULL);
+/// ```
+///
+/// The functions must be registered on any connection that inserts into
+/// such a table.
+pub fn register_db_functions(conn: &Connection) -> Result<()> {
+    Ok(register(conn)?)
+}
+
+pub(crate) fn register(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function("uuid7", 0, FunctionFlags::SQLITE_UTF8, |_ctx| {
+        Ok(Uuid::now_v7().to_string())
+    })?;
+    conn.create_scalar_function("uuid4", 0, FunctionFlags::SQLITE_UTF8, |_ctx| {
+        Ok(Uuid::new_v4().to_string())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusqlite::Connection;
+    use rusqlite_migration::{Migrations, M};
+    use uuid::Uuid;
+
+    use crate::Db;
+
+    use super::register_db_functions;
+
+    fn parse_uuid(value: &serde_json::Value) -> Uuid {
+        let uuid = Uuid::parse_str(value.as_str().unwrap()).unwrap();
+        assert_eq!(value.as_str().unwrap(), uuid.hyphenated().to_string());
+        uuid
+    }
+
+    #[test]
+    fn uuid_functions() -> Result<()> {
+        let db = Db::open_memory()?;
+        let rows = db.query_as_json("SELECT uuid7() AS v7, uuid4() AS v4, uuid7() AS v7b", ())?;
+        assert_eq!(parse_uuid(&rows[0]["v7"]).get_version_num(), 7);
+        assert_eq!(parse_uuid(&rows[0]["v4"]).get_version_num(), 4);
+        assert_ne!(rows[0]["v7"], rows[0]["v7b"]);
+
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY DEFAULT (uuid7()), name TEXT NOT NULL);"),
+        ]))?;
+        db.execute("INSERT INTO Artist (name) VALUES ('Metallica'), ('Megadeth')", [])?;
+        let ids = db.query_as_json("SELECT id FROM Artist", ())?;
+        assert_eq!(ids.len(), 2);
+        assert!(ids.iter().all(|row| parse_uuid(&row["id"]).get_version_num() == 7));
+        assert_ne!(ids[0], ids[1]);
+
+        let conn = Connection::open_in_memory()?;
+        assert!(conn.query_row("SELECT uuid4()", [], |row| row.get::<_, String>(0)).is_err());
+        register_db_functions(&conn)?;
+        let uuid: String = conn.query_row("SELECT uuid4()", [], |row| row.get(0))?;
+        assert_eq!(Uuid::parse_str(&uuid)?.get_version_num(), 4);
+        Ok(())
+    }
+}
@@ -1,23 +1,191 @@
+pub mod aggregate;
+#[cfg(feature = "async")]
+pub mod async_db;
+pub mod config;
 pub mod core;
+pub mod error;
+pub mod functions;
+pub mod page;
 pub mod query;
+pub mod query_plan;
+pub mod schema;
 pub mod transaction;
+pub mod uuid_source;
+pub mod validate;
 
+pub use aggregate::*;
+#[cfg(feature = "async")]
+pub use async_db::*;
+pub use config::*;
 pub use core::*;
+pub use error::*;
+pub use functions::*;
+pub use page::*;
 pub use query::*;
+pub use query_plan::*;
+pub use schema::*;
+pub use uuid_source::*;
+pub use validate::*;
 pub use rusqlite_migration::*;
 
+use std::{any::TypeId, collections::{BTreeMap, HashMap, HashSet}, sync::{Arc, OnceLock, RwLock}};
+
 use serde::{Serialize, de::DeserializeOwned};
 
-/// Trait for types that can be stored in the database
-pub trait Entity: Serialize + DeserializeOwned {}
+/// Trait for types that can be stored in the database. Fields map to columns
+/// by their serialized name, so #[serde(rename = "column")] can be used to
+/// map a field to a differently named column.
+/// 
+/// The table name defaults to the last path segment of the type name. Use
+/// register_entity() to set it explicitly.
+/// 
+/// The entity's id is the field named for the table's primary key column,
+/// or id if the table has no single column primary key.
+pub trait Entity: Serialize + DeserializeOwned + 'static {}
 
 // Blanket implementation for any type that meets the requirements
-impl<T> Entity for T where T: Serialize + DeserializeOwned {}
+impl<T> Entity for T where T: Serialize + DeserializeOwned + 'static {}
+
+fn entity_registry() -> &'static RwLock<HashMap<TypeId, String>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<TypeId, String>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Registers the table name for an entity type, for all Dbs in the process.
+/// This is the recommended way to name tables, since the type name fallback
+/// is not guaranteed to be stable between compiler versions. Call it at
+/// startup, before the type is used.
+/// 
+/// ```
+/// # #[derive(serde::Serialize, serde::Deserialize)]
+/// # struct Artist { id: String }
+/// dimple_db::db::register_entity::<Artist>("Artist");
+/// ```
+pub fn register_entity<E: Entity>(table_name: &str) {
+    if let Ok(mut registry) = entity_registry().write() {
+        registry.insert(TypeId::of::<E>(), table_name.to_string());
+    }
+}
+
+/// The registered table name for the type, if any.
+pub(crate) fn registered_table_name<T: 'static>() -> Option<String> {
+    entity_registry().read().ok()?.get(&TypeId::of::<T>()).cloned()
+}
 
 /// Sent to subscribers whenever the database is changed. Each variant includes
-/// the entity_type and entity_id.
+/// the entity_type and entity_id, along with the entity data as JSON so that
+/// subscribers don't need to query for it. The data is shared between all
+/// subscribers.
+/// 
+/// For local saves the data is the serialized entity. For changes merged
+/// during sync, and for deletes, the data is the row as stored in the table.
 #[derive(Clone, Debug)]
 pub enum DbEvent {
-    Insert(String, String),
-    Update(String, String),
+    Insert {
+        entity_type: String,
+        entity_id: String,
+        data: Arc<serde_json::Value>,
+    },
+    Update {
+        entity_type: String,
+        entity_id: String,
+        data: Arc<serde_json::Value>,
+        old_data: Option<Arc<serde_json::Value>>,
+    },
+    Delete {
+        entity_type: String,
+        entity_id: String,
+        old_data: Arc<serde_json::Value>,
+    },
+}
+
+impl DbEvent {
+    pub fn entity_type(&self) -> &str {
+        match self {
+            DbEvent::Insert { entity_type, .. } => entity_type,
+            DbEvent::Update { entity_type, .. } => entity_type,
+            DbEvent::Delete { entity_type, .. } => entity_type,
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        match self {
+            DbEvent::Insert { entity_id, .. } => entity_id,
+            DbEvent::Update { entity_id, .. } => entity_id,
+            DbEvent::Delete { entity_id, .. } => entity_id,
+        }
+    }
+
+    /// The entity data after the change, or None for deletes.
+    pub fn data(&self) -> Option<&Arc<serde_json::Value>> {
+        match self {
+            DbEvent::Insert { data, .. } => Some(data),
+            DbEvent::Update { data, .. } => Some(data),
+            DbEvent::Delete { .. } => None,
+        }
+    }
+
+    /// The entity data before the change, or None for inserts and for
+    /// updates where it wasn't known.
+    pub fn old_data(&self) -> Option<&Arc<serde_json::Value>> {
+        match self {
+            DbEvent::Insert { .. } => None,
+            DbEvent::Update { old_data, .. } => old_data.as_ref(),
+            DbEvent::Delete { old_data, .. } => Some(old_data),
+        }
+    }
+
+    /// The fields that differ between old_data() and data(), with their
+    /// values before and after the change. Missing values are Null, so every
+    /// field of an insert or delete is included.
+    pub fn changed_fields(&self) -> BTreeMap<String, (serde_json::Value, serde_json::Value)> {
+        let fields = |data: Option<&Arc<serde_json::Value>>| data
+            .and_then(|data| data.as_object().cloned())
+            .unwrap_or_default();
+        let (before, after) = (fields(self.old_data()), fields(self.data()));
+        before.keys().chain(after.keys())
+            .map(|name| (name.clone(), (
+                before.get(name).cloned().unwrap_or_default(),
+                after.get(name).cloned().unwrap_or_default(),
+            )))
+            .filter(|(_, (before, after))| before != after)
+            .collect()
+    }
+
+    pub fn event_type(&self) -> EventType {
+        match self {
+            DbEvent::Insert { .. } => EventType::Insert,
+            DbEvent::Update { .. } => EventType::Update,
+            DbEvent::Delete { .. } => EventType::Delete,
+        }
+    }
+}
+
+/// The kind of a DbEvent, without its data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventType {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Selects the DbEvents sent to a subscription from Db::subscribe_filtered().
+/// Each field that is Some must contain the event's value for the event to
+/// be sent. The default filter matches every event. entity_ids also matches
+/// bulk deletes, whose entity id is ALL_ENTITIES_ID, since they delete
+/// every entity of their type.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    pub event_types: Option<HashSet<EventType>>,
+    pub entity_types: Option<HashSet<String>>,
+    pub entity_ids: Option<HashSet<String>>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &DbEvent) -> bool {
+        self.event_types.as_ref().map_or(true, |types| types.contains(&event.event_type()))
+            && self.entity_types.as_ref().map_or(true, |types| types.contains(event.entity_type()))
+            && self.entity_ids.as_ref().map_or(true, |ids| ids.contains(event.entity_id())
+                || event.entity_id() == crate::changelog::ALL_ENTITIES_ID)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Result};
+use rusqlite::Params;
+
+use crate::db::{query::QuerySubscription, Db, Entity};
+
+/// One page of results from a PageQuery, along with the total number of rows
+/// the query matches across all pages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub total_rows: i64,
+    /// The zero based page number.
+    pub page: usize,
+    pub page_size: usize,
+}
+
+impl<T> Page<T> {
+    /// The number of pages needed to show all total_rows.
+    pub fn total_pages(&self) -> usize {
+        (self.total_rows.max(0) as usize).div_ceil(self.page_size)
+    }
+}
+
+/// Builder for paginated queries. The base SQL is wrapped as a CTE, and the
+/// requested page and the total row count are read in a single read
+/// transaction, so they are always consistent with each other. Pages are
+/// zero based. Include an ORDER BY in the base SQL so pages are stable.
+///
+/// ```ignore
+/// let page: Page<Artist> = PageQuery::new("SELECT * FROM Artist ORDER BY name", ())
+///     .page_size(20)
+///     .page(2)
+///     .fetch(&db)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct PageQuery<P = ()> {
+    sql: String,
+    params: P,
+    page_size: usize,
+    page: usize,
+}
+
+impl<P: Params + Clone> PageQuery<P> {
+    /// The default number of rows per page.
+    pub const DEFAULT_PAGE_SIZE: usize = 50;
+
+    pub fn new(sql: &str, params: P) -> Self {
+        Self {
+            sql: sql.to_string(),
+            params,
+            page_size: Self::DEFAULT_PAGE_SIZE,
+            page: 0,
+        }
+    }
+
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn page(mut self, page: usize) -> Self {
+        self.page = page;
+        self
+    }
+
+    /// The SQL for the rows in the page.
+    pub fn data_sql(&self) -> String {
+        // page_size and the offset are integers, so they are safe to inline,
+        // and doing so leaves the caller's params the only ones bound
+        format!("WITH page_cte AS ({}) SELECT * FROM page_cte LIMIT {} OFFSET {}",
+            self.sql.trim().trim_end_matches(';'), self.page_size, self.page * self.page_size)
+    }
+
+    /// The SQL for the total number of rows across all pages.
+    pub fn count_sql(&self) -> String {
+        format!("WITH page_cte AS ({}) SELECT COUNT(*) FROM page_cte",
+            self.sql.trim().trim_end_matches(';'))
+    }
+
+    pub fn fetch<T: Entity>(&self, db: &Db) -> Result<Page<T>> {
+        if self.page_size == 0 {
+            return Err(anyhow!("page_size must be greater than zero"));
+        }
+        let (data_sql, count_sql) = (self.data_sql(), self.count_sql());
+        db.read_transaction(|txn| {
+            let mut stmt = txn.prepare_cached(&data_sql)?;
+            let data = serde_rusqlite::from_rows::<T>(stmt.query(self.params.clone())?)
+                .collect::<Result<Vec<_>, _>>()?;
+            let total_rows = txn.query_row(&count_sql, self.params.clone(), |row| row.get(0))?;
+            Ok(Page {
+                data,
+                total_rows,
+                page: self.page,
+                page_size: self.page_size,
+            })
+        })
+    }
+}
+
+impl<P: Params + Clone + Send + 'static> PageQuery<P> {
+    /// Fetches the page, calling the closure with it immediately and then
+    /// again any time any table referenced in the base SQL changes. See
+    /// Db::query_subscribe().
+    pub fn subscribe<T, F>(self, db: &Db, f: F) -> Result<QuerySubscription>
+        where
+            T: Entity,
+            F: FnMut(Page<T>) + Send + 'static {
+        let sql = self.sql.clone();
+        QuerySubscription::with_runner(db, &sql, move |db| self.fetch::<T>(db), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Duration};
+
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::{Db, Page, PageQuery};
+
+    #[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    fn setup_db(count: usize) -> Result<Db> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        let artists: Vec<Artist> = (0..count)
+            .map(|i| Artist { name: format!("Artist {:02}", i), ..Default::default() })
+            .collect();
+        db.save_many(&artists)?;
+        Ok(db)
+    }
+
+    #[test]
+    fn fetch_returns_page_and_total() -> Result<()> {
+        let db = setup_db(25)?;
+        let query = PageQuery::new("SELECT * FROM Artist WHERE name > ? ORDER BY name", ["Artist 04"])
+            .page_size(10);
+
+        let page: Page<Artist> = query.clone().fetch(&db)?;
+        assert_eq!(page.total_rows, 20);
+        assert_eq!(page.total_pages(), 2);
+        assert_eq!(page.data.len(), 10);
+        assert_eq!(page.data[0].name, "Artist 05");
+
+        let page: Page<Artist> = query.clone().page(1).fetch(&db)?;
+        assert_eq!(page.page, 1);
+        assert_eq!(page.data.len(), 10);
+        assert_eq!(page.data[0].name, "Artist 15");
+
+        let page: Page<Artist> = query.page(2).fetch(&db)?;
+        assert!(page.data.is_empty());
+        assert_eq!(page.total_rows, 20);
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_rejects_zero_page_size() -> Result<()> {
+        let db = setup_db(1)?;
+        let result = PageQuery::new("SELECT * FROM Artist", ()).page_size(0).fetch::<Artist>(&db);
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn subscribe_refires_on_change() -> Result<()> {
+        let db = setup_db(3)?;
+        let (tx, rx) = channel::<(usize, i64)>();
+        let _subscription = PageQuery::new("SELECT * FROM Artist ORDER BY name", ())
+            .page_size(2)
+            .subscribe(&db, move |page: Page<Artist>| {
+                let _ = tx.send((page.data.len(), page.total_rows));
+            })?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1))?, (2, 3));
+
+        db.save(&Artist { name: "Artist 99".to_string(), ..Default::default() })?;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1))?, (2, 4));
+        Ok(())
+    }
+}
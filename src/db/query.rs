@@ -2,15 +2,39 @@ use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender};
 use std::thread::{self, JoinHandle};
-use anyhow::Result;
-use rusqlite::Params;
-use crate::db::{Db, Entity, DbEvent};
+use anyhow::{anyhow, Result};
+use rusqlite::{Params, Rows, Statement};
+use crate::db::{Db, Entity};
 
-/// Handle returned to the user for managing a query subscription
+/// Params bound by a closure, so a subscription's params can be replaced by
+/// params of a different type.
+type BoundParams = Box<dyn for<'s> Fn(&'s mut Statement<'_>) -> rusqlite::Result<Rows<'s>> + Send>;
+
+/// The query of a subscription made with new(), which can be replaced while
+/// subscribed.
+struct SubscribedQuery {
+    sql: String,
+    params: BoundParams,
+}
+
+fn bind_params<P: Params + Clone + Send + 'static>(params: P) -> BoundParams {
+    Box::new(move |stmt| stmt.query(params.clone()))
+}
+
+/// Runs the query and calls the callback with the results.
+type Emit = dyn FnMut() -> Result<()> + Send;
+
+/// Handle returned to the user for managing a query subscription. The
+/// monitoring thread holds its own clone of the Db, so the subscription keeps
+/// working after the Db it was created from is dropped. The thread is stopped
+/// and joined by unsubscribe() or on drop.
 pub struct QuerySubscription {
     stop_signal: Option<Sender<()>>,
     refresh_signal: Option<Sender<()>>,
     thread_handle: Option<JoinHandle<()>>,
+    emit: Arc<Mutex<Emit>>,
+    tables: Arc<Mutex<HashSet<String>>>,
+    query: Option<Arc<Mutex<SubscribedQuery>>>,
 }
 
 impl QuerySubscription {
@@ -18,15 +42,51 @@ impl QuerySubscription {
     where 
         F: FnMut(Vec<E>) + Send + 'static
     {        
-        let dependent_tables = QuerySubscription::extract_query_tables(sql)?;
+        let query = Arc::new(Mutex::new(SubscribedQuery {
+            sql: sql.to_string(),
+            params: bind_params(params),
+        }));
+        let run_query = query.clone();
+        let mut subscription = Self::with_runner(db, sql, move |db| {
+            let query = run_query.lock().map_err(|_| anyhow!("Subscription query lock poisoned"))?;
+            db.read_transaction(|txn| {
+                let mut stmt = txn.prepare(&query.sql)?;
+                let entities = serde_rusqlite::from_rows::<E>((query.params)(&mut stmt)?)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(entities)
+            })
+        }, callback)?;
+        subscription.query = Some(query);
+        Ok(subscription)
+    }
+
+    /// Like new(), but the results are produced by calling run with the Db
+    /// instead of running the sql directly. The sql is only used to find the
+    /// tables to monitor.
+    pub(crate) fn with_runner<R, Q, F>(db: &Db, sql: &str, run: Q, mut callback: F) -> Result<Self>
+    where
+        R: 'static,
+        Q: Fn(&Db) -> Result<R> + Send + 'static,
+        F: FnMut(R) + Send + 'static
+    {
+        let tables = Arc::new(Mutex::new(QuerySubscription::extract_query_tables(sql)?));
         
-        // Wrap the callback in Arc<Mutex<>> for thread safety
-        let callback = Arc::new(Mutex::new(callback));
+        // Running and calling back happen under one lock, so results from the
+        // monitoring thread and from update_params() are delivered in the
+        // order they were queried
+        let db_clone = db.clone();
+        let emit: Arc<Mutex<Emit>> = Arc::new(Mutex::new(move || {
+            callback(run(&db_clone)?);
+            Ok(())
+        }));
         
+        // Subscribe before the initial query so no events are missed before
+        // the monitoring thread starts
+        let event_rx = db.subscribe();
+
         // Run the query initially to provide immediate results
-        let initial_results: Vec<E> = db.query(sql, params.clone())?;
-        if let Ok(mut cb) = callback.lock() {
-            cb(initial_results);
+        if let Ok(mut emit) = emit.lock() {
+            emit()?;
         }
         
         // Create stop signal channel
@@ -34,16 +94,11 @@ impl QuerySubscription {
         let (refresh_tx, refresh_rx) = channel::<()>();
         
         // Clone values needed for the thread
-        let db_clone = db.clone();
-        let sql_clone = sql.to_string();
-        let params_clone = params.clone();
-        let tables_clone = dependent_tables.clone();
-        let callback_clone = callback.clone();
-        
+        let tables_clone = tables.clone();
+        let emit_clone = emit.clone();
+
         // Create the monitoring thread
         let thread_handle = thread::spawn(move || {
-            let event_rx = db_clone.subscribe();
-            
             loop {
                 // Check for stop signal
                 if stop_rx.try_recv().is_ok() {
@@ -55,34 +110,31 @@ impl QuerySubscription {
                 // Check for database events (with timeout to allow periodic stop checks)
                 // TODO I think we can drop the timeout by ensuring the sender gets dropped
                 // when the subscription is closed. Probably simplifies a lot of this.
-                match event_rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                let affected = match event_rx.recv_timeout(std::time::Duration::from_millis(100)) {
                     Ok(event) => {
-                        // Check if this event affects our query
-                        let table_name = match &event {
-                            DbEvent::Insert(table, _) => table,
-                            DbEvent::Update(table, _) => table,
-                        };
-                        
-                        if refresh || tables_clone.contains(table_name) {
-                            // Re-run the query
-                            match db_clone.query::<E, _>(sql_clone.as_str(), params_clone.clone()) {
-                                Ok(results) => {
-                                    if let Ok(mut cb) = callback_clone.lock() {
-                                        cb(results);
-                                    }
-                                },
-                                Err(e) => eprintln!("Error re-running query: {}", e),
-                            }
-                        }
+                        // Check if this event, or any already queued behind
+                        // it such as the rest of a transaction's events,
+                        // affects our query. Re-running once covers them all.
+                        let events: Vec<_> = std::iter::once(event).chain(event_rx.try_iter()).collect();
+                        let tables = tables_clone.lock().map(|tables| tables.clone()).unwrap_or_default();
+                        events.iter().any(|event| tables.contains(event.entity_type()))
                     },
                     Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
                         // Timeout is fine, just check stop signal again
-                        continue;
+                        false
                     },
                     Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
                         // Database subscription ended
                         break;
                     }
+                };
+                if refresh || affected {
+                    // Re-run the query
+                    if let Ok(mut emit) = emit_clone.lock() {
+                        if let Err(e) = emit() {
+                            eprintln!("Error re-running query: {}", e);
+                        }
+                    }
                 }
             }
         });
@@ -91,9 +143,61 @@ impl QuerySubscription {
             stop_signal: Some(stop_tx),
             thread_handle: Some(thread_handle),
             refresh_signal: Some(refresh_tx),
+            emit,
+            tables,
+            query: None,
         })
     }
 
+    /// Replaces the params of the query, then re-runs it and calls the
+    /// callback with the results before returning, whether or not any table
+    /// changed. Only subscriptions made by Db::query_subscribe() can be
+    /// updated, and not from within their own callback.
+    pub fn update_params<P: Params + Clone + Send + 'static>(&self, params: P) -> Result<()> {
+        {
+            let query = self.query.as_ref()
+                .ok_or_else(|| anyhow!("This subscription's query can't be updated"))?;
+            let mut query = query.lock().map_err(|_| anyhow!("Subscription query lock poisoned"))?;
+            query.params = bind_params(params);
+        }
+        self.emit_now()
+    }
+
+    /// Like update_params(), but replaces the sql too, and monitors the
+    /// tables of the new sql. The results must still be the subscription's
+    /// entity type. If the new query fails the subscription keeps its old
+    /// query and tables.
+    pub fn update_sql<P: Params + Clone + Send + 'static>(&self, sql: &str, params: P) -> Result<()> {
+        let tables = QuerySubscription::extract_query_tables(sql)?;
+        let query = self.query.as_ref()
+            .ok_or_else(|| anyhow!("This subscription's query can't be updated"))?;
+        let old_query = {
+            let mut query = query.lock().map_err(|_| anyhow!("Subscription query lock poisoned"))?;
+            SubscribedQuery {
+                sql: std::mem::replace(&mut query.sql, sql.to_string()),
+                params: std::mem::replace(&mut query.params, bind_params(params)),
+            }
+        };
+        let old_tables = self.tables.lock()
+            .map(|mut current| std::mem::replace(&mut *current, tables))
+            .ok();
+        let result = self.emit_now();
+        if result.is_err() {
+            if let Ok(mut query) = query.lock() {
+                *query = old_query;
+            }
+            if let (Some(old_tables), Ok(mut current)) = (old_tables, self.tables.lock()) {
+                *current = old_tables;
+            }
+        }
+        result
+    }
+
+    fn emit_now(&self) -> Result<()> {
+        let mut emit = self.emit.lock().map_err(|_| anyhow!("Subscription callback lock poisoned"))?;
+        emit()
+    }
+
     pub fn unsubscribe(&mut self) {
         // Send stop signal to the thread
         if let Some(stop_signal) = self.stop_signal.take() {
@@ -122,12 +226,16 @@ impl QuerySubscription {
         // Normalize the SQL to uppercase for easier parsing
         let sql_upper = sql.to_uppercase();
         
-        // Find FROM clause
-        if let Some(from_start) = sql_upper.find("FROM ") {
+        // Find every FROM clause, including those of sub-selects and CTEs.
+        // CTE names are found too, but no events are sent for them.
+        let mut search_pos = 0;
+        while let Some(from_pos) = sql_upper[search_pos..].find("FROM ") {
+            let from_start = search_pos + from_pos;
+            search_pos = from_start + 5;
             let from_sql = &sql[from_start + 5..];
             
             // Find the end of the FROM clause (before WHERE, JOIN, GROUP BY, etc.)
-            let end_keywords = ["WHERE ", "JOIN ", "LEFT JOIN ", "RIGHT JOIN ", "FULL JOIN ", "CROSS JOIN ", "INNER JOIN ", "GROUP BY ", "ORDER BY ", "HAVING ", "LIMIT "];
+            let end_keywords = ["WHERE ", "JOIN ", "LEFT JOIN ", "RIGHT JOIN ", "FULL JOIN ", "CROSS JOIN ", "INNER JOIN ", "GROUP BY ", "ORDER BY ", "HAVING ", "LIMIT ", ")"];
             let mut end_pos = from_sql.len();
             for keyword in &end_keywords {
                 if let Some(pos) = from_sql.to_uppercase().find(keyword) {
@@ -195,9 +303,108 @@ impl Drop for QuerySubscription {
 mod tests {
     use super::*;
     use rusqlite_migration::{Migrations, M};
-    use serde::{Deserialize, Serialize};
 
 
+    #[test]
+    fn subscription_outlives_db() -> Result<()> {
+        #[derive(serde::Serialize, serde::Deserialize, Default)]
+        struct Artist {
+            id: String,
+            name: String,
+        }
+
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        let writer = db.clone();
+        let (tx, rx) = channel::<usize>();
+        let subscription = db.query_subscribe("SELECT * FROM Artist", (), move |artists: Vec<Artist>| {
+            let _ = tx.send(artists.len());
+        })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, 0);
+
+        drop(db);
+        writer.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, 1);
+
+        drop(writer);
+        let start = std::time::Instant::now();
+        drop(subscription);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        // The callback, and its sender, were dropped with the thread
+        assert!(rx.recv().is_err());
+        Ok(())
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Default)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    fn artists_db() -> Result<Db> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT NOT NULL);"),
+        ]))?;
+        for name in ["Metallica", "Megadeth", "Slayer"] {
+            db.save(&Artist { name: name.to_string(), ..Default::default() })?;
+        }
+        Ok(db)
+    }
+
+    #[test]
+    fn update_params_emits_without_changes() -> Result<()> {
+        let db = artists_db()?;
+        let (tx, rx) = channel::<Vec<String>>();
+        let subscription = db.query_subscribe("SELECT * FROM Artist WHERE name LIKE ? ORDER BY name",
+            ("M%".to_string(),), move |artists: Vec<Artist>| {
+                let _ = tx.send(artists.into_iter().map(|a| a.name).collect());
+            })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, vec!["Megadeth", "Metallica"]);
+
+        // Params of a different type
+        subscription.update_params(["S%"])?;
+        assert_eq!(rx.try_recv()?, vec!["Slayer"]);
+
+        // Later changes are queried with the new params
+        db.save(&Artist { name: "Sepultura".to_string(), ..Default::default() })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, vec!["Sepultura", "Slayer"]);
+        Ok(())
+    }
+
+    #[test]
+    fn update_sql_monitors_new_tables() -> Result<()> {
+        let db = artists_db()?;
+        let (tx, rx) = channel::<usize>();
+        let subscription = db.query_subscribe("SELECT * FROM Artist", (), move |artists: Vec<Artist>| {
+            let _ = tx.send(artists.len());
+        })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, 3);
+
+        subscription.update_sql("SELECT id, title AS name FROM Album WHERE title != ?", ["Kill 'Em All"])?;
+        assert_eq!(rx.try_recv()?, 0);
+
+        db.save(&Artist { name: "Anthrax".to_string(), ..Default::default() })?;
+        #[derive(serde::Serialize, serde::Deserialize, Default)]
+        struct Album {
+            id: String,
+            title: String,
+        }
+        db.save(&Album { title: "Master of Puppets".to_string(), ..Default::default() })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, 1);
+        assert!(rx.recv_timeout(std::time::Duration::from_millis(300)).is_err());
+
+        // A failed update keeps the Album query and its tables
+        assert!(subscription.update_sql("SELECT * FROM Nope", ()).is_err());
+        assert!(subscription.update_sql("SELECT * FROM Artist WHERE nope = ?", [1]).is_err());
+        db.save(&Album { title: "...And Justice for All".to_string(), ..Default::default() })?;
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(1))?, 2);
+        Ok(())
+    }
+
     #[test]
     fn extract_query_tables_simple_select() -> Result<()> {
         let tables = QuerySubscription::extract_query_tables("SELECT * FROM Artist WHERE id = ?")?;
@@ -249,13 +456,16 @@ mod tests {
     
     #[test]
     fn extract_query_tables_subquery() -> Result<()> {
-        // Note: Our simple parser won't handle subqueries perfectly,
-        // but it should at least find the main table
         let tables = QuerySubscription::extract_query_tables(
             "SELECT * FROM Artist WHERE id IN (SELECT artist_id FROM Album WHERE title = ?)"
         )?;
         assert!(tables.contains("Artist"));
-        // Our simple parser might miss the subquery table
+        assert!(tables.contains("Album"));
+
+        let tables = QuerySubscription::extract_query_tables(
+            "WITH recent AS (SELECT * FROM Track WHERE year > ?) SELECT COUNT(*) FROM recent"
+        )?;
+        assert!(tables.contains("Track"));
         Ok(())
     }
 
@@ -426,11 +636,4 @@ mod tests {
         assert!(tables.contains("Track"));
         Ok(())
     }
-    
-    #[derive(Serialize, Deserialize, Default, Debug)]
-    pub struct Artist {
-        pub id: String,
-        pub name: String,
-        pub summary: Option<String>,
-    }
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+use anyhow::Result;
+use rusqlite::{Connection, Params};
+
+/// One row of EXPLAIN QUERY PLAN output. Nodes form a tree through parent,
+/// which is 0 for top level nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryPlanNode {
+    pub id: i32,
+    pub parent: i32,
+    /// SQLite's description of the step, such as "SEARCH Artist USING
+    /// INDEX artist_name (name=?)". The format is not stable between SQLite
+    /// versions, so the fields below are parsed from it on a best effort
+    /// basis.
+    pub detail: String,
+    /// True if the step reads every row of the table or index.
+    pub is_scan: bool,
+    /// The table or alias the step reads, if it reads one.
+    pub table: Option<String>,
+    /// The index the step uses, if any. Automatic indexes, which SQLite
+    /// builds for the query alone, are named "AUTOMATIC".
+    pub uses_index: Option<String>,
+}
+
+impl QueryPlanNode {
+    pub(crate) fn new(id: i32, parent: i32, detail: String) -> Self {
+        let mut words = detail.split_whitespace();
+        let is_scan = detail.starts_with("SCAN ");
+        let table = match words.next() {
+            Some("SCAN") | Some("SEARCH") => words.next()
+                .filter(|table| !table.starts_with('(') && *table != "CONSTANT")
+                .map(|table| table.to_string()),
+            _ => None,
+        };
+        let uses_index = match detail.split_once(" USING ") {
+            Some((_, using)) if using.starts_with("AUTOMATIC ") => Some("AUTOMATIC".to_string()),
+            Some((_, using)) => using.split_once("INDEX ")
+                .and_then(|(_, index)| index.split_whitespace().next())
+                .map(|index| index.to_string()),
+            None => None,
+        };
+        Self { id, parent, detail, is_scan, table, uses_index }
+    }
+
+    /// True if the step reads every row of its table without an index, or
+    /// SQLite had to build an automatic index for it, so an index on the
+    /// table could make the query faster.
+    pub fn is_full_scan(&self) -> bool {
+        self.table.is_some()
+            && ((self.is_scan && self.uses_index.is_none()) || self.uses_index.as_deref() == Some("AUTOMATIC"))
+    }
+}
+
+/// The plan SQLite chose for a query, from Db::explain_query().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// Every node, in the order SQLite reported them.
+    pub nodes: Vec<QueryPlanNode>,
+}
+
+impl QueryPlan {
+    /// The top level nodes.
+    pub fn roots(&self) -> Vec<&QueryPlanNode> {
+        self.children(0)
+    }
+
+    pub fn children(&self, id: i32) -> Vec<&QueryPlanNode> {
+        self.nodes.iter().filter(|node| node.parent == id).collect()
+    }
+
+    /// Nodes that read every row of a table, see QueryPlanNode::is_full_scan().
+    pub fn full_scans(&self) -> Vec<&QueryPlanNode> {
+        self.nodes.iter().filter(|node| node.is_full_scan()).collect()
+    }
+}
+
+/// An index that would let a query search a table rather than scan it,
+/// from Db::suggest_indexes().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexSuggestion {
+    pub table: String,
+    pub columns: Vec<String>,
+    /// The CREATE INDEX statement, for a migration.
+    pub sql: String,
+}
+
+impl IndexSuggestion {
+    pub(crate) fn new(table: &str, columns: &[&str]) -> Self {
+        Self {
+            table: table.to_string(),
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            sql: format!("CREATE INDEX {}_{} ON {} ({})", table, columns.join("_"), table, columns.join(", ")),
+        }
+    }
+}
+
+pub(crate) fn explain<P: Params>(conn: &Connection, sql: &str, params: P) -> Result<QueryPlan> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    let nodes = stmt.query_map(params, |row| Ok(QueryPlanNode::new(row.get(0)?, row.get(1)?, row.get(3)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(QueryPlan { nodes })
+}
+
+/// For each table the query scans, tries an index on each of the table's
+/// columns the query mentions, and suggests the ones SQLite would search
+/// with. The trial indexes are created in a transaction that is rolled
+/// back, so nothing is changed.
+pub(crate) fn suggest_indexes<P: Params + Clone>(conn: &mut Connection, sql: &str, params: P) -> Result<Vec<IndexSuggestion>> {
+    let plan = explain(conn, sql, params.clone())?;
+    let txn = conn.transaction()?;
+    let sql_words = sql.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .map(|word| word.to_lowercase())
+        .collect::<std::collections::HashSet<_>>();
+    let mut suggestions: Vec<IndexSuggestion> = vec![];
+    for node in plan.full_scans() {
+        let Some(table) = node.table.as_deref().and_then(|name| resolve_table(&txn, sql, name)) else {
+            continue;
+        };
+        let mut stmt = txn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+        for column in columns.iter().filter(|column| sql_words.contains(&column.to_lowercase())) {
+            if suggestions.iter().any(|s| s.table == table && s.columns == [column.as_str()]) {
+                continue;
+            }
+            let index_name = format!("ZV_SUGGESTED_{}_{}", table, column);
+            txn.execute_batch(&format!("CREATE INDEX {} ON {} ({})", index_name, table, column))?;
+            let uses_index = explain(&txn, sql, params.clone())?.nodes.iter()
+                .any(|node| !node.is_scan && node.uses_index.as_deref() == Some(index_name.as_str()));
+            txn.execute_batch(&format!("DROP INDEX {}", index_name))?;
+            if uses_index {
+                suggestions.push(IndexSuggestion::new(&table, &[column]));
+            }
+        }
+    }
+    txn.rollback()?;
+    Ok(suggestions)
+}
+
+/// The table a plan node's table name refers to, looking through aliases
+/// such as FROM Artist a.
+fn resolve_table(conn: &Connection, sql: &str, name: &str) -> Option<String> {
+    let exists = |table: &str| conn.query_row(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ? COLLATE NOCASE",
+        [table], |row| row.get::<_, String>(0)).ok();
+    if let Some(table) = exists(name) {
+        return Some(table);
+    }
+    let words = sql.split_whitespace()
+        .filter(|word| !word.eq_ignore_ascii_case("AS"))
+        .collect::<Vec<_>>();
+    words.windows(2)
+        .filter(|pair| pair[1].trim_end_matches([',', ')']).eq_ignore_ascii_case(name))
+        .find_map(|pair| exists(pair[0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryPlanNode;
+
+    #[test]
+    fn parses_plan_details() {
+        let node = QueryPlanNode::new(2, 0, "SCAN Artist".to_string());
+        assert!(node.is_scan && node.is_full_scan());
+        assert_eq!((node.table.as_deref(), node.uses_index), (Some("Artist"), None));
+
+        let node = QueryPlanNode::new(3, 0, "SEARCH Artist USING INDEX artist_name (name=?)".to_string());
+        assert!(!node.is_scan && !node.is_full_scan());
+        assert_eq!(node.uses_index.as_deref(), Some("artist_name"));
+
+        let node = QueryPlanNode::new(4, 0, "SCAN Artist USING COVERING INDEX artist_name".to_string());
+        assert!(node.is_scan && !node.is_full_scan());
+        assert_eq!(node.uses_index.as_deref(), Some("artist_name"));
+
+        let node = QueryPlanNode::new(5, 0, "SEARCH Artist USING INTEGER PRIMARY KEY (rowid=?)".to_string());
+        assert_eq!((node.table.as_deref(), node.uses_index.as_deref()), (Some("Artist"), None));
+        assert!(!node.is_full_scan());
+
+        let node = QueryPlanNode::new(6, 0, "SEARCH a USING AUTOMATIC COVERING INDEX (artist_id=?)".to_string());
+        assert!(node.is_full_scan());
+
+        let node = QueryPlanNode::new(7, 0, "USE TEMP B-TREE FOR ORDER BY".to_string());
+        assert_eq!((node.table.as_deref(), node.uses_index.as_deref(), node.is_full_scan()), (None, None, false));
+        assert_eq!(QueryPlanNode::new(8, 0, "SCAN CONSTANT ROW".to_string()).table, None);
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use rusqlite::Connection;
+
+/// The user tables of a database, from Db::introspect(). The change
+/// tracking tables and SQLite's own tables are left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbSchema {
+    pub tables: Vec<TableSchema>,
+}
+
+impl DbSchema {
+    pub fn table(&self, name: &str) -> Option<&TableSchema> {
+        self.tables.iter().find(|table| table.name == name)
+    }
+
+    /// What changed from this schema to other. Indexes are not compared.
+    pub fn diff(&self, other: &DbSchema) -> SchemaDiff {
+        let added_tables: Vec<String> = other.tables.iter()
+            .filter(|table| self.table(&table.name).is_none())
+            .map(|table| table.name.clone())
+            .collect();
+        let removed_tables: Vec<String> = self.tables.iter()
+            .filter(|table| other.table(&table.name).is_none())
+            .map(|table| table.name.clone())
+            .collect();
+        let modified_tables: Vec<TableDiff> = self.tables.iter()
+            .filter_map(|table| other.table(&table.name).map(|other_table| table.diff(other_table)))
+            .filter(|diff| !diff.is_empty())
+            .collect();
+        let is_compatible = removed_tables.is_empty()
+            && modified_tables.iter().all(|diff| diff.removed_columns.is_empty()
+                && diff.changed_columns.is_empty()
+                && diff.added_columns.iter().all(|column| !column.not_null || column.default_value.is_some()));
+        SchemaDiff { added_tables, removed_tables, modified_tables, is_compatible }
+    }
+}
+
+impl TableSchema {
+    pub fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+
+    fn diff(&self, other: &TableSchema) -> TableDiff {
+        TableDiff {
+            name: self.name.clone(),
+            added_columns: other.columns.iter()
+                .filter(|column| self.column(&column.name).is_none())
+                .cloned()
+                .collect(),
+            removed_columns: self.columns.iter()
+                .filter(|column| other.column(&column.name).is_none())
+                .cloned()
+                .collect(),
+            changed_columns: self.columns.iter()
+                .filter_map(|column| other.column(&column.name)
+                    .filter(|other_column| *other_column != column)
+                    .map(|other_column| (column.clone(), other_column.clone())))
+                .collect(),
+        }
+    }
+}
+
+/// The differences between two schemas, from Db::diff_schemas() or
+/// DbSchema::diff(). Added means present only in the other schema, and
+/// removed means present only in this one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaDiff {
+    pub added_tables: Vec<String>,
+    pub removed_tables: Vec<String>,
+    pub modified_tables: Vec<TableDiff>,
+    /// True if changes synced from a database with this schema can be
+    /// merged into one with the other: no tables or columns were removed,
+    /// no columns changed, and every added column is nullable or has a
+    /// default.
+    pub is_compatible: bool,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_tables.is_empty() && self.removed_tables.is_empty() && self.modified_tables.is_empty()
+    }
+}
+
+/// The column differences of a table in both schemas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableDiff {
+    pub name: String,
+    pub added_columns: Vec<ColumnSchema>,
+    pub removed_columns: Vec<ColumnSchema>,
+    /// Columns whose type, constraints or default differ, as this schema's
+    /// column and the other's.
+    pub changed_columns: Vec<(ColumnSchema, ColumnSchema)>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_columns.is_empty() && self.removed_columns.is_empty() && self.changed_columns.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSchema {
+    pub name: String,
+    pub columns: Vec<ColumnSchema>,
+    pub indexes: Vec<IndexSchema>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// The declared type, such as TEXT, or empty if none was declared.
+    pub data_type: String,
+    pub not_null: bool,
+    /// The default value as SQL, such as 'unknown' or CURRENT_TIMESTAMP.
+    pub default_value: Option<String>,
+    /// The column's 1 based position in the primary key, or 0 if it isn't
+    /// part of it.
+    pub pk: u32,
+}
+
+/// An index, including those SQLite creates for PRIMARY KEY and UNIQUE
+/// constraints.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexSchema {
+    pub name: String,
+    pub unique: bool,
+    /// The indexed columns in order. Expressions are left out.
+    pub columns: Vec<String>,
+}
+
+fn is_internal_table(name: &str) -> bool {
+    name.starts_with("ZV_") || name.starts_with("sqlite_")
+}
+
+pub(crate) fn introspect(conn: &Connection) -> Result<DbSchema> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'table' ORDER BY name")?;
+    let table_names = stmt.query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let tables = table_names.into_iter()
+        .filter(|name| !is_internal_table(name))
+        .map(|name| Ok(TableSchema {
+            columns: columns(conn, &name)?,
+            indexes: indexes(conn, &name)?,
+            name,
+        }))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(DbSchema { tables })
+}
+
+fn columns(conn: &Connection, table_name: &str) -> Result<Vec<ColumnSchema>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?) ORDER BY cid")?;
+    let columns = stmt.query_map([table_name], |row| Ok(ColumnSchema {
+        name: row.get(0)?,
+        data_type: row.get(1)?,
+        not_null: row.get(2)?,
+        default_value: row.get(3)?,
+        pk: row.get(4)?,
+    }))?.collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+fn indexes(conn: &Connection, table_name: &str) -> Result<Vec<IndexSchema>> {
+    let mut stmt = conn.prepare("SELECT name, \"unique\" FROM pragma_index_list(?) ORDER BY name")?;
+    let indexes = stmt.query_map([table_name], |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    indexes.into_iter()
+        .map(|(name, unique)| {
+            let mut stmt = conn.prepare("SELECT name FROM pragma_index_info(?) ORDER BY seqno")?;
+            let columns = stmt.query_map([&name], |row| row.get::<_, Option<String>>(0))?
+                .filter_map(|column| column.transpose())
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(IndexSchema { name, unique, columns })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+
+    use super::{ColumnSchema, IndexSchema, TableDiff};
+    use crate::Db;
+
+    #[test]
+    fn introspect_lists_user_tables() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT DEFAULT 'unknown');
+                CREATE UNIQUE INDEX artist_name ON Artist (name, country);"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT);
+                CREATE INDEX album_title ON Album (lower(title));"),
+        ]))?;
+        let schema = db.introspect()?;
+        assert_eq!(schema.tables.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["Album", "Artist"]);
+
+        let artist = schema.table("Artist").unwrap();
+        assert_eq!(artist.columns, vec![
+            ColumnSchema { name: "id".to_string(), data_type: "TEXT".to_string(), not_null: false, default_value: None, pk: 1 },
+            ColumnSchema { name: "name".to_string(), data_type: "TEXT".to_string(), not_null: true, default_value: None, pk: 0 },
+            ColumnSchema { name: "country".to_string(), data_type: "TEXT".to_string(), not_null: false,
+                default_value: Some("'unknown'".to_string()), pk: 0 },
+        ]);
+        assert!(artist.indexes.contains(&IndexSchema {
+            name: "artist_name".to_string(),
+            unique: true,
+            columns: vec!["name".to_string(), "country".to_string()],
+        }));
+        // The primary key's automatic index
+        assert!(artist.indexes.iter().any(|index| index.unique && index.columns == vec!["id"]));
+
+        let album = schema.table("Album").unwrap();
+        assert!(album.indexes.iter().any(|index| index.name == "album_title" && index.columns.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn diff_schemas_across_migrations() -> Result<()> {
+        let v1 = M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);");
+        let v2 = M::up("ALTER TABLE Artist ADD COLUMN country TEXT;
+            CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT);");
+        let old = Db::open_memory()?;
+        old.migrate(&Migrations::new(vec![v1.clone()]))?;
+        let new = Db::open_memory()?;
+        new.migrate(&Migrations::new(vec![v1, v2]))?;
+
+        assert!(old.diff_schemas(&old)?.is_empty());
+
+        let diff = old.diff_schemas(&new)?;
+        assert_eq!(diff.added_tables, vec!["Album"]);
+        assert!(diff.removed_tables.is_empty());
+        assert_eq!(diff.modified_tables, vec![TableDiff {
+            name: "Artist".to_string(),
+            added_columns: vec![ColumnSchema { name: "country".to_string(), data_type: "TEXT".to_string(),
+                not_null: false, default_value: None, pk: 0 }],
+            removed_columns: vec![],
+            changed_columns: vec![],
+        }]);
+        assert!(diff.is_compatible);
+
+        // Changes from the newer schema would write a column and table the
+        // older one doesn't have
+        let diff = new.diff_schemas(&old)?;
+        assert_eq!(diff.removed_tables, vec!["Album"]);
+        assert_eq!(diff.modified_tables[0].removed_columns[0].name, "country");
+        assert!(!diff.is_compatible);
+        Ok(())
+    }
+
+}
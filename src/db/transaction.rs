@@ -1,15 +1,15 @@
 use anyhow::{anyhow, Result};
-use rusqlite::{Params, ToSql, Transaction};
+use rusqlite::{types::Value, Params, ToSql, Transaction};
 use serde_rusqlite::NamedParamSlice;
-use uuid::Uuid;
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::BTreeMap, sync::Arc};
 
-use crate::db::{Db, DbEvent, Entity};
+use crate::{changelog::PendingChange, db::{Db, DbEvent, DimpleError, Entity, Validate}};
 
 pub struct DbTransaction<'a> {
     db: &'a Db,
     txn: &'a Transaction<'a>,
     pending_events: RefCell<Vec<DbEvent>>,
+    pending_changes: RefCell<Vec<PendingChange>>,
 }
 
 pub type DbValue = NamedParamSlice;
@@ -20,6 +20,7 @@ impl<'a> DbTransaction<'a> {
             db,
             txn,
             pending_events: RefCell::new(Vec::new()),
+            pending_changes: RefCell::new(Vec::new()),
         }
     }
 
@@ -27,7 +28,7 @@ impl<'a> DbTransaction<'a> {
         self.db
     }
     
-    pub fn txn(&self) -> &rusqlite::Transaction {
+    pub fn txn(&self) -> &rusqlite::Transaction<'_> {
         self.txn
     }
 
@@ -41,12 +42,82 @@ impl<'a> DbTransaction<'a> {
     /// A diff between the old entity, if any, and the new is created and
     /// saved in the change tracking tables. Subscribers are then notified
     /// of the changes and the newly inserted or updated entity is returned.
+    /// The change records for every save in the transaction are written
+    /// together when it commits.
     /// 
     /// Note that only fields present in both the table and entity are mapped.
     pub fn save<E: Entity>(&self, entity: &E) -> Result<E> {
         self.save_internal(entity, true)
     }
 
+    /// Like save(), but fails with DimpleError::ValidationFailed, without
+    /// writing anything, if the entity doesn't validate.
+    pub fn save_validated<E: Entity + Validate>(&self, entity: &E) -> Result<E> {
+        entity.validate().map_err(DimpleError::ValidationFailed)?;
+        self.save(entity)
+    }
+
+    /// Updates only the named fields of an existing entity from the given
+    /// entity, leaving the rest as they are in the database, and saves it.
+    /// Use this instead of save() when the entity may be stale, so that
+    /// fields changed since it was read, such as by a sync, aren't
+    /// overwritten. Returns an error if the entity doesn't exist or a field
+    /// isn't a column of its table.
+    pub fn merge<E: Entity>(&self, entity: &E, fields: &[&str]) -> Result<E> {
+        let table_name = self.db.table_name_for_type::<E>()?;
+        let column_names = self.db.table_column_names(self.txn, &table_name)?;
+        let id_column = self.db.table_id_column(self.txn, &table_name)?;
+
+        let new_json = serde_json::to_value(entity)?;
+        let id = new_json.get(&id_column)
+            .and_then(|id| id.as_str())
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| anyhow!("no {} on entity", id_column))?;
+        let current = self.get::<E>(id)?
+            .ok_or_else(|| anyhow!("{} {} does not exist", table_name, id))?;
+
+        let mut merged_json = serde_json::to_value(&current)?;
+        for field in fields {
+            if !column_names.iter().any(|column| column == field) {
+                return Err(anyhow!("{} has no column named {}", table_name, field));
+            }
+            let value = new_json.get(*field).cloned()
+                .ok_or_else(|| anyhow!("entity has no field named {}", field))?;
+            merged_json[*field] = value;
+        }
+        self.save(&serde_json::from_value::<E>(merged_json)?)
+    }
+
+    /// Saves each entity as with save(), returning the saved entities in
+    /// order.
+    pub fn save_many<E: Entity>(&self, entities: &[E]) -> Result<Vec<E>> {
+        entities.iter().map(|entity| self.save(entity)).collect()
+    }
+
+    /// Like save(), for tables with no Rust type, such as ones defined at
+    /// runtime. The entity is a JSON object whose keys are column names.
+    /// Keys that aren't columns are ignored and columns without a key are
+    /// set to NULL. The table's primary key column, or id if it has none,
+    /// gets a new uuidv7 if it's missing, null or empty. Returns the saved
+    /// row, with values as stored, so booleans are 0 or 1.
+    pub fn save_raw(&self, table_name: &str, entity: serde_json::Value) -> Result<serde_json::Value> {
+        let id_column = self.db.table_id_column(self.txn, table_name)?;
+        let serde_json::Value::Object(fields) = entity else {
+            return Err(anyhow!("{} entity must be a JSON object", table_name));
+        };
+        let id = match fields.get(&id_column) {
+            Some(serde_json::Value::String(id)) if !id.is_empty() => id.clone(),
+            Some(serde_json::Value::String(_)) | Some(serde_json::Value::Null) | None => self.db.next_uuid(),
+            Some(id) => return Err(anyhow!("{} {} must be a string, not {}", table_name, id_column, id)),
+        };
+        let values = fields.iter()
+            .map(|(name, value)| (name.clone(), crate::sync::sync_engine::json_to_sql_value(value)))
+            .collect();
+        self.save_row(table_name, &id, &values)?;
+        Ok(self.get_row_json(table_name, &id)?
+            .ok_or_else(|| DimpleError::EntityNotFound { entity_type: table_name.to_string(), key: id })?)
+    }
+
     pub fn save_untracked<E: Entity>(&self, entity: &E) -> Result<E> {
         self.save_internal(entity, false)
     }
@@ -60,16 +131,18 @@ impl<'a> DbTransaction<'a> {
     fn save_internal<E: Entity>(&self, entity: &E, track_changes: bool) -> Result<E> {
         let table_name = self.db.table_name_for_type::<E>()?;
         let column_names = self.db.table_column_names(self.txn, &table_name)?;
+        let id_column = self.db.table_id_column(self.txn, &table_name)?;
 
         let mut new_value = Self::entity_to_value(entity, &column_names)?;
-        let id = self.ensure_entity_id(&mut new_value)?;
-        let old_value = self.get::<E>(&id)?
-            .and_then(|e| Self::entity_to_value(&e, &column_names).ok());
+        let id = self.ensure_entity_id(&mut new_value, &id_column)?;
+        let old_entity = self.get::<E>(&id)?;
+        let old_value = old_entity.as_ref()
+            .and_then(|e| Self::entity_to_value(e, &column_names).ok());
 
         let exists = old_value.is_some();
         
         if exists {
-            self.update_entity(&table_name, &column_names, &new_value)?;
+            self.update_entity(&table_name, &id_column, &column_names, &new_value)?;
         } else {
             self.insert_entity(&table_name, &column_names, &new_value)?;
         }
@@ -77,23 +150,144 @@ impl<'a> DbTransaction<'a> {
         // Track changes
         if track_changes {
             crate::changelog::track_changes(self, &table_name, &id, old_value.as_ref(), 
-                &new_value, &column_names)?;
+                &new_value, &column_names, &id_column)?;
         }
         
+        let saved = self.get::<E>(&id)?
+            .ok_or_else(|| DimpleError::EntityNotFound { entity_type: table_name.clone(), key: id.clone() })?;
+
         // Queue event for notification after commit
+        let data = Arc::new(serde_json::to_value(&saved)?);
         let event = if exists {
-            DbEvent::Update(table_name.clone(), id.clone())
+            let old_data = old_entity.as_ref()
+                .map(serde_json::to_value)
+                .transpose()?
+                .map(Arc::new);
+            DbEvent::Update { entity_type: table_name, entity_id: id, data, old_data }
         } else {
-            DbEvent::Insert(table_name.clone(), id.clone())
+            DbEvent::Insert { entity_type: table_name, entity_id: id, data }
         };
         self.pending_events.borrow_mut().push(event);
         
-        self.get::<E>(&id)?
-            .ok_or_else(|| anyhow::anyhow!("Failed to retrieve saved entity"))    
+        Ok(saved)
+    }
+
+    /// Deletes the entity by its id. See delete_by_id().
+    pub fn delete<E: Entity>(&self, entity: &E) -> Result<bool> {
+        let table_name = self.db.table_name_for_type::<E>()?;
+        let id_column = self.db.table_id_column(self.txn, &table_name)?;
+        let value = serde_json::to_value(entity)?;
+        let id = value.get(&id_column)
+            .and_then(|id| id.as_str())
+            .ok_or_else(|| anyhow!("no {} on entity", id_column))?;
+        self.delete_by_id::<E>(id)
+    }
+
+    /// Deletes the entity of type E with the id, recording a delete change
+    /// and queueing a DbEvent::Delete. Returns false if there is no such
+    /// entity. The delete change is recorded either way, so that replicas
+    /// which do have the entity delete it when they sync.
+    pub fn delete_by_id<E: Entity>(&self, id: &str) -> Result<bool> {
+        let table_name = self.db.table_name_for_type::<E>()?;
+        if self.delete_internal(&table_name, id, true)? {
+            return Ok(true);
+        }
+        crate::changelog::track_delete(self, &table_name, id)?;
+        Ok(false)
+    }
+
+    /// Deletes every entity of type E matching the where clause, which is
+    /// used as is after WHERE with the params bound to it. A delete change is
+    /// recorded and a DbEvent::Delete is queued for each deleted entity.
+    /// Returns the number of entities deleted.
+    pub fn delete_where<E: Entity, P: Params>(&self, where_clause: &str, params: P) -> Result<u64> {
+        let table_name = self.db.table_name_for_type::<E>()?;
+        let id_column = self.db.table_id_column(self.txn, &table_name)?;
+        let sql = format!("SELECT {} FROM {} WHERE {}", id_column, table_name, where_clause);
+        let ids = {
+            let mut stmt = self.txn.prepare(&sql)?;
+            let ids = stmt.query_map(params, |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            ids
+        };
+        let mut count = 0;
+        for id in ids {
+            if self.delete_internal(&table_name, &id, true)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Deletes every entity of type E and returns the number deleted.
+    ///
+    /// Up to Db's bulk delete threshold, each entity is deleted as with
+    /// delete_where(), recording a delete change and queueing a
+    /// DbEvent::Delete per entity. Above the threshold the table is emptied
+    /// with a single statement and a single bulk delete change is recorded,
+    /// which deletes every entity of the type that has no newer change when
+    /// merged on other replicas. A single DbEvent::Delete with entity_id
+    /// ALL_ENTITIES_ID and null old_data is queued.
+    pub fn delete_all<E: Entity>(&self) -> Result<u64> {
+        let table_name = self.db.table_name_for_type::<E>()?;
+        let count: u64 = self.txn.query_row(&format!("SELECT COUNT(*) FROM {}", table_name), [], |row| row.get(0))?;
+        if count <= self.db.bulk_delete_threshold() {
+            return self.delete_where::<E, _>("1 = 1", []);
+        }
+        self.txn.execute(&format!("DELETE FROM {}", table_name), [])?;
+        crate::changelog::track_delete(self, &table_name, crate::changelog::ALL_ENTITIES_ID)?;
+        self.pending_events.borrow_mut().push(DbEvent::Delete {
+            entity_type: table_name,
+            entity_id: crate::changelog::ALL_ENTITIES_ID.to_string(),
+            old_data: Arc::new(serde_json::Value::Null),
+        });
+        Ok(count)
+    }
+
+    pub(crate) fn delete_internal(&self, table_name: &str, id: &str, track_changes: bool) -> Result<bool> {
+        let Some(old_data) = self.get_row_json(table_name, id)? else {
+            return Ok(false);
+        };
+        let id_column = self.db.table_id_column(self.txn, table_name)?;
+        self.txn.execute(&format!("DELETE FROM {} WHERE {} = ?", table_name, id_column), [id])?;
+        if track_changes {
+            crate::changelog::track_delete(self, table_name, id)?;
+        }
+        self.pending_events.borrow_mut().push(DbEvent::Delete {
+            entity_type: table_name.to_string(),
+            entity_id: id.to_string(),
+            old_data: Arc::new(old_data),
+        });
+        Ok(true)
+    }
+
+    /// Runs the closure within a savepoint nested in this transaction. If the
+    /// closure returns Ok the savepoint is released and its work becomes part
+    /// of the transaction. Otherwise only the savepoint's work is rolled
+    /// back, including its change records and events, and the error is
+    /// returned. Events from released savepoints are sent when the outermost
+    /// transaction commits. Savepoints can be nested.
+    pub fn savepoint<F, R>(&self, name: &str, f: F) -> Result<R>
+        where F: FnOnce(&DbTransaction) -> Result<R> {
+        let name = format!("\"{}\"", name.replace('"', "\"\""));
+        let events_len = self.pending_events.borrow().len();
+        let changes_len = self.pending_changes.borrow().len();
+
+        self.txn.execute_batch(&format!("SAVEPOINT {}", name))?;
+        let result = f(self);
+        if result.is_ok() {
+            self.txn.execute_batch(&format!("RELEASE {}", name))?;
+        } else {
+            // ROLLBACK TO leaves the savepoint open, so release it too
+            self.txn.execute_batch(&format!("ROLLBACK TO {}; RELEASE {}", name, name))?;
+            self.pending_events.borrow_mut().truncate(events_len);
+            self.pending_changes.borrow_mut().truncate(changes_len);
+        }
+        result
     }
 
     pub fn query<E: Entity, P: Params>(&self, sql: &str, params: P) -> Result<Vec<E>> {
-        let mut stmt = self.txn.prepare(sql)?;
+        let mut stmt = self.txn.prepare_cached(sql)?;
         let entities = serde_rusqlite::from_rows::<E>(stmt.query(params)?)
             .collect::<Result<Vec<_>, _>>()?;
         Ok(entities)
@@ -101,26 +295,102 @@ impl<'a> DbTransaction<'a> {
 
     pub fn get<E: Entity>(&self, id: &str) -> Result<Option<E>> {
         let table_name = self.db.table_name_for_type::<E>()?;
-        let sql = format!("SELECT * FROM {} WHERE id = ? LIMIT 1", table_name);
+        let id_column = self.db.table_id_column(self.txn, &table_name)?;
+        let sql = format!("SELECT * FROM {} WHERE {} = ? LIMIT 1", table_name, id_column);
         Ok(self.query::<E, _>(&sql, [id])?.into_iter().next())
     }
 
-    fn ensure_entity_id(&self, entity_value: &mut DbValue) -> Result<String> {
+    /// Saves a row given as column values, for when the entity type is not
+    /// known. Columns missing from values are set to NULL. As with save(),
+    /// the change is tracked and an event is queued.
+    pub(crate) fn save_row(&self, table_name: &str, id: &str, values: &BTreeMap<String, Value>) -> Result<()> {
+        let column_names = self.db.table_column_names(self.txn, table_name)?;
+        let id_column = self.db.table_id_column(self.txn, table_name)?;
+        let to_db_value = |values: &BTreeMap<String, Value>| -> DbValue {
+            column_names.iter()
+                .map(|column| {
+                    let value = if *column == id_column {
+                        Value::Text(id.to_string())
+                    } else {
+                        values.get(column).cloned().unwrap_or(Value::Null)
+                    };
+                    (format!(":{}", column), Box::new(value) as Box<dyn ToSql>)
+                })
+                .collect::<Vec<_>>()
+                .into()
+        };
+
+        let new_value = to_db_value(values);
+        let old_data = self.get_row_json(table_name, id)?;
+        let old_value = self.get_row_values(table_name, id)?.map(|old| to_db_value(&old));
+        if old_value.is_some() {
+            self.update_entity(table_name, &id_column, &column_names, &new_value)?;
+        } else {
+            self.insert_entity(table_name, &column_names, &new_value)?;
+        }
+        crate::changelog::track_changes(self, table_name, id, old_value.as_ref(),
+            &new_value, &column_names, &id_column)?;
+
+        let data = Arc::new(self.get_row_json(table_name, id)?.unwrap_or_default());
+        let event = match old_data {
+            Some(old_data) => DbEvent::Update {
+                entity_type: table_name.to_string(),
+                entity_id: id.to_string(),
+                data,
+                old_data: Some(Arc::new(old_data)),
+            },
+            None => DbEvent::Insert { entity_type: table_name.to_string(), entity_id: id.to_string(), data },
+        };
+        self.pending_events.borrow_mut().push(event);
+        Ok(())
+    }
+
+    /// Read a single row as SQL values keyed by column name.
+    pub(crate) fn get_row_values(&self, table_name: &str, id: &str) -> Result<Option<BTreeMap<String, Value>>> {
+        use rusqlite::OptionalExtension as _;
+
+        let id_column = self.db.table_id_column(self.txn, table_name)?;
+        let sql = format!("SELECT * FROM {} WHERE {} = ? LIMIT 1", table_name, id_column);
+        let mut stmt = self.txn.prepare(&sql)?;
+        let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+        let row = stmt.query_row([id], |row| {
+            column_names.iter().enumerate()
+                .map(|(i, name)| Ok((name.clone(), row.get::<_, Value>(i)?)))
+                .collect::<rusqlite::Result<BTreeMap<_, _>>>()
+        }).optional()?;
+        Ok(row)
+    }
+
+    /// Read a single row as a JSON object keyed by column name, for when the
+    /// entity type is not known.
+    pub(crate) fn get_row_json(&self, table_name: &str, id: &str) -> Result<Option<serde_json::Value>> {
+        use rusqlite::OptionalExtension as _;
+
+        let id_column = self.db.table_id_column(self.txn, table_name)?;
+        let sql = format!("SELECT * FROM {} WHERE {} = ? LIMIT 1", table_name, id_column);
+        let mut stmt = self.txn.prepare(&sql)?;
+        let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+        let row = stmt.query_row([id], |row| row_to_json(row, &column_names)).optional()?;
+        Ok(row)
+    }
+
+    fn ensure_entity_id(&self, entity_value: &mut DbValue, id_column: &str) -> Result<String> {
+        let id_param_name = format!(":{}", id_column);
         let id_param = entity_value.iter_mut()
-            .find(|(name, _)| name == &":id")
-            .ok_or_else(|| anyhow!("no id column on entity"))?;
+            .find(|(name, _)| *name == id_param_name)
+            .ok_or_else(|| anyhow!("no {} column on entity", id_column))?;
         
-        match Self::extract_id(&id_param.1).filter(|s| !s.is_empty()) {
+        match Self::extract_id(id_param.1.as_ref()).filter(|s| !s.is_empty()) {
             Some(id) => Ok(id),
             None => {
-                let id = Uuid::now_v7().to_string();
+                let id = self.db.next_uuid();
                 id_param.1 = Box::new(id.clone());
                 Ok(id)
             }
         }
     }
 
-    fn extract_id(val: &Box<dyn ToSql>) -> Option<String> {
+    fn extract_id(val: &dyn ToSql) -> Option<String> {
         use rusqlite::types::{ToSqlOutput, Value, ValueRef};
         
         val.to_sql().ok().and_then(|output| match output {
@@ -131,15 +401,15 @@ impl<'a> DbTransaction<'a> {
         })
     }
 
-    fn update_entity(&self, table_name: &str, column_names: &[String], entity_value: &DbValue) -> Result<()> {
+    fn update_entity(&self, table_name: &str, id_column: &str, column_names: &[String], entity_value: &DbValue) -> Result<()> {
         let set_clause = column_names
             .iter()
-            .filter(|col| *col != "id")
+            .filter(|col| *col != id_column)
             .map(|col| format!("{} = :{}", col, col))
             .collect::<Vec<_>>()
             .join(", ");
         
-        let sql = format!("UPDATE {} SET {} WHERE id = :id", table_name, set_clause);
+        let sql = format!("UPDATE {} SET {} WHERE {} = :{}", table_name, set_clause, id_column, id_column);
 
         self.execute_with_named_params(&sql, entity_value)
     }
@@ -161,11 +431,14 @@ impl<'a> DbTransaction<'a> {
     }
     
     fn execute_with_named_params(&self, sql: &str, entity_value: &DbValue) -> Result<()> {
-        let mut stmt = self.txn.prepare(sql)?;
+        let mut stmt = self.txn.prepare_cached(sql)?;
         stmt.execute(entity_value.to_slice().as_slice())?;
         Ok(())
     }
     
+    /// Events queued by every save and delete in the transaction, in the
+    /// order the operations were performed. Db sends them to subscribers
+    /// only once the transaction has committed.
     pub(crate) fn take_pending_events(&self) -> Vec<DbEvent> {
         std::mem::take(&mut *self.pending_events.borrow_mut())
     }
@@ -173,4 +446,33 @@ impl<'a> DbTransaction<'a> {
     pub(crate) fn add_pending_event(&self, event: DbEvent) {
         self.pending_events.borrow_mut().push(event);
     }
+
+    pub(crate) fn add_pending_change(&self, change: PendingChange) {
+        self.pending_changes.borrow_mut().push(change);
+    }
+
+    /// Write any buffered change records to the change tracking tables.
+    pub(crate) fn flush_pending_changes(&self) -> Result<()> {
+        let pending_changes = std::mem::take(&mut *self.pending_changes.borrow_mut());
+        crate::changelog::write_changes(self, &pending_changes)
+    }
+}
+
+/// A row as a JSON object keyed by column name. Integers and reals become
+/// numbers, and blobs arrays of bytes, as save_raw() expects.
+pub(crate) fn row_to_json(row: &rusqlite::Row, column_names: &[String]) -> rusqlite::Result<serde_json::Value> {
+    use rusqlite::types::ValueRef;
+
+    let mut map = serde_json::Map::new();
+    for (i, name) in column_names.iter().enumerate() {
+        let value = match row.get_ref(i)? {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Integer(i) => i.into(),
+            ValueRef::Real(f) => f.into(),
+            ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+            ValueRef::Blob(b) => b.to_vec().into(),
+        };
+        map.insert(name.clone(), value);
+    }
+    Ok(serde_json::Value::Object(map))
 }
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use uuid::{Builder, Uuid};
+
+/// Source of the UUIDs used for new entity ids and change ids. The default
+/// is UuidV7Source. Tests that need reproducible ids can install a
+/// SequentialUuidSource, or their own implementation, with
+/// Db::set_uuid_source().
+/// 
+/// Ids must sort in creation order and be unique across every replica that
+/// syncs together, so anything other than UuidV7Source should only be used
+/// for testing.
+pub trait UuidSource: Send + Sync {
+    fn next_uuid(&self) -> String;
+}
+
+/// Generates random, time ordered UUIDv7s.
+#[derive(Debug, Default)]
+pub struct UuidV7Source;
+
+impl UuidSource for UuidV7Source {
+    fn next_uuid(&self) -> String {
+        Uuid::now_v7().to_string()
+    }
+}
+
+/// Generates deterministic UUIDv7s with a fixed timestamp and an increasing
+/// counter in place of the random bits.
+#[derive(Debug)]
+pub struct SequentialUuidSource {
+    timestamp_ms: u64,
+    counter: AtomicU64,
+}
+
+impl SequentialUuidSource {
+    pub fn new(timestamp_ms: u64) -> Self {
+        Self {
+            timestamp_ms,
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl UuidSource for SequentialUuidSource {
+    fn next_uuid(&self) -> String {
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        let mut bytes = [0u8; 10];
+        bytes[2..].copy_from_slice(&counter.to_be_bytes());
+        Builder::from_unix_timestamp_millis(self.timestamp_ms, &bytes)
+            .into_uuid()
+            .to_string()
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+
+/// Implemented by entities that check their own fields before being saved
+/// with Db::save_validated() or DbTransaction::save_validated().
+///
+/// ```
+/// use dimple_db::db::{Validate, ValidationError};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Artist {
+///     id: String,
+///     name: String,
+/// }
+///
+/// impl Validate for Artist {
+///     fn validate(&self) -> Result<(), Vec<ValidationError>> {
+///         if self.name.is_empty() {
+///             return Err(vec![ValidationError::new("name", "must not be empty")]);
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait Validate {
+    /// Returns every problem found, not just the first.
+    fn validate(&self) -> Result<(), Vec<ValidationError>>;
+}
+
+/// A problem with one field of an entity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
@@ -1,3 +1,6 @@
+// Option::is_none_or() needs Rust 1.82, so map_or(true, ..) is used instead
+#![allow(clippy::unnecessary_map_or)]
+
 pub mod db;
 pub mod sync;
 pub mod changelog;
@@ -0,0 +1,489 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{events::Event, Reader};
+
+use super::{s3_storage::parse_last_modified, StorageHttpError, StorageObject, SyncStorage};
+
+const API_VERSION: &str = "2021-08-06";
+
+/// Everything but unreserved characters and slashes, which separate the
+/// virtual directories in blob names.
+const ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'/');
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+/// Azure Blob Storage using the REST API, authenticated with a storage
+/// account Shared Key.
+pub struct AzureBlobStorage {
+    endpoint: String,
+    account: String,
+    access_key: Vec<u8>,
+    container: String,
+}
+
+impl AzureBlobStorage {
+    /// Create storage for the container, where access_key is one of the
+    /// base64 storage account keys.
+    pub fn new(account: &str, access_key: &str, container: &str) -> Result<Self> {
+        let endpoint = format!("https://{}.blob.core.windows.net", account);
+        Self::new_with_endpoint(&endpoint, account, access_key, container)
+    }
+
+    /// Like new(), but using a different endpoint, such as the Azurite
+    /// emulator at http://127.0.0.1:10000/devstoreaccount1.
+    pub fn new_with_endpoint(endpoint: &str, account: &str, access_key: &str, container: &str) -> Result<Self> {
+        let access_key = STANDARD.decode(access_key)
+            .map_err(|e| anyhow!("Azure access key is not valid base64: {}", e))?;
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            account: account.to_string(),
+            access_key,
+            container: container.to_string(),
+        })
+    }
+
+    fn container_url(&self) -> String {
+        format!("{}/{}", self.endpoint, utf8_percent_encode(&self.container, ENCODE_SET))
+    }
+
+    fn blob_url(&self, path: &str) -> String {
+        format!("{}/{}", self.container_url(), utf8_percent_encode(path, ENCODE_SET))
+    }
+
+    /// Signs and sends a request. headers are the x-ms-* headers other than
+    /// the date and version, which are added here.
+    fn send(&self, method: &str, url: &str, query: &[(&str, &str)], headers: &[(&'static str, &str)],
+            if_none_match: Option<&str>, content: Option<&[u8]>) -> Result<attohttpc::Response> {
+        let date = http_date(SystemTime::now())?;
+        let mut ms_headers = vec![("x-ms-date", date.as_str()), ("x-ms-version", API_VERSION)];
+        ms_headers.extend_from_slice(headers);
+        // Content-Length is signed as an empty string when it's zero
+        let content_length = content.filter(|c| !c.is_empty())
+            .map(|c| c.len().to_string()).unwrap_or_default();
+        let content_type = if content.is_some() { "application/octet-stream" } else { "" };
+
+        let url_path = url::Url::parse(url)?.path().to_string();
+        let string_to_sign = string_to_sign(method, &content_length, content_type,
+            if_none_match.unwrap_or_default(), &ms_headers, &self.account, &url_path, query);
+        let signature = hmac_sha256(&self.access_key, &string_to_sign)?;
+
+        let mut request = attohttpc::RequestBuilder::new(method.parse()?, url)
+            .params(query)
+            .header("Authorization", format!("SharedKey {}:{}", self.account, signature));
+        for (name, value) in &ms_headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(if_none_match) = if_none_match {
+            request = request.header("If-None-Match", if_none_match);
+        }
+        let response = match content {
+            Some(content) => request.header("Content-Type", content_type).bytes(content).send(),
+            None => request.send(),
+        };
+        // Kept as the source so the error can be recognized as transient
+        response.context("Azure network error")
+    }
+
+    fn put_blob(&self, path: &str, content: &[u8], if_none_match: Option<&str>) -> Result<attohttpc::Response> {
+        self.send("PUT", &self.blob_url(path), &[], &[("x-ms-blob-type", "BlockBlob")],
+            if_none_match, Some(content))
+    }
+}
+
+/// Turns an unsuccessful response into an error, calling out authentication
+/// failures separately since they usually mean a bad account or key.
+fn check_status(response: attohttpc::Response, context: &str) -> Result<attohttpc::Response> {
+    match response.status().as_u16() {
+        200..=299 => Ok(response),
+        status @ (401 | 403) => Err(StorageHttpError::new(status,
+            format!("Azure authentication failed with status {} {}", status, context)).into()),
+        status => Err(StorageHttpError::new(status, format!("Azure returned error status {} {}", status, context)).into()),
+    }
+}
+
+/// The Shared Key string to sign. See
+/// https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+#[allow(clippy::too_many_arguments)]
+fn string_to_sign(method: &str, content_length: &str, content_type: &str, if_none_match: &str,
+        ms_headers: &[(&str, &str)], account: &str, url_path: &str, query: &[(&str, &str)]) -> String {
+    let mut ms_headers = ms_headers.iter()
+        .map(|(name, value)| (name.to_lowercase(), value.trim()))
+        .collect::<Vec<_>>();
+    ms_headers.sort();
+    let canonical_headers = ms_headers.iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect::<String>();
+
+    let mut query = query.iter()
+        .map(|(name, value)| (name.to_lowercase(), *value))
+        .collect::<Vec<_>>();
+    query.sort();
+    let canonical_resource = query.iter()
+        .fold(format!("/{}{}", account, url_path), |resource, (name, value)| {
+            format!("{}\n{}:{}", resource, name, value)
+        });
+
+    // Content-Encoding, Content-Language, Content-Length, Content-MD5,
+    // Content-Type, Date, If-Modified-Since, If-Match, If-None-Match,
+    // If-Unmodified-Since and Range
+    format!("{}\n\n\n{}\n\n{}\n\n\n\n{}\n\n\n{}{}",
+        method, content_length, content_type, if_none_match, canonical_headers, canonical_resource)
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Result<String> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data.as_bytes())?;
+    Ok(STANDARD.encode(signer.sign_to_vec()?))
+}
+
+/// Formats a time as an RFC 1123 date, such as "Sun, 06 Nov 1994 08:49:37 GMT".
+fn http_date(time: SystemTime) -> Result<String> {
+    let secs = time.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Proleptic Gregorian date from days since the epoch
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    Ok(format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize], day, MONTHS[month as usize - 1], year,
+        secs / 3600, secs % 3600 / 60, secs % 60))
+}
+
+/// Parses an RFC 1123 date, as used for Last-Modified in blob listings.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split_whitespace().skip(1);
+    let (day, month, year, time) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    parse_last_modified(&format!("{}-{:02}-{}T{}Z", year, month, day, time))
+}
+
+/// Parses a List Blobs response into its blobs and the marker for the next
+/// page, if any. BlobPrefix entries, the virtual directories, are skipped.
+fn parse_list_response(xml: &str) -> Result<(Vec<StorageObject>, Option<String>)> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut objects = Vec::new();
+    let mut next_marker = None;
+    let mut elements: Vec<String> = Vec::new();
+    let (mut name, mut size, mut modified) = (String::new(), 0, None);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => elements.push(String::from_utf8_lossy(e.name().as_ref()).to_string()),
+            Event::End(_) if elements.pop().as_deref() == Some("Blob") => {
+                objects.push(StorageObject {
+                    path: std::mem::take(&mut name),
+                    size: std::mem::take(&mut size),
+                    modified: modified.take(),
+                });
+            },
+            Event::Text(text) => {
+                let text = text.unescape()?.to_string();
+                let parent = elements.len().checked_sub(2).and_then(|i| elements.get(i)).map(String::as_str);
+                match (parent, elements.last().map(String::as_str)) {
+                    (Some("Blob"), Some("Name")) => name = text,
+                    (Some("Properties"), Some("Content-Length")) => size = text.parse()?,
+                    (Some("Properties"), Some("Last-Modified")) => modified = parse_http_date(&text),
+                    (Some("EnumerationResults"), Some("NextMarker")) => next_marker = Some(text),
+                    _ => {},
+                }
+            },
+            Event::Eof => break,
+            _ => {},
+        }
+    }
+    Ok((objects, next_marker.filter(|m| !m.is_empty())))
+}
+
+impl SyncStorage for AzureBlobStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        log::debug!("STORAGE LIST: prefix='{}'", prefix);
+        let url = self.container_url();
+        let mut objects = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let mut query = vec![("comp", "list"), ("delimiter", "/"), ("prefix", prefix), ("restype", "container")];
+            if let Some(marker) = &marker {
+                query.push(("marker", marker));
+            }
+            let response = self.send("GET", &url, &query, &[], None, None)?;
+            let response = check_status(response, &format!("listing prefix: {}", prefix))?;
+            let (page, next_marker) = parse_list_response(&response.text()?)?;
+            objects.extend(page);
+            match next_marker {
+                Some(next_marker) => marker = Some(next_marker),
+                None => break,
+            }
+        }
+        log::debug!("STORAGE LIST RESULT: {} items", objects.len());
+        Ok(objects)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        log::debug!("STORAGE GET: path='{}'", path);
+        let response = self.send("GET", &self.blob_url(path), &[], &[], None, None)?;
+        let bytes = check_status(response, &format!("for path: {}", path))?.bytes()?;
+        log::debug!("STORAGE GET RESULT: {} bytes", bytes.len());
+        Ok(bytes)
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        log::debug!("STORAGE PUT: path='{}', size={} bytes", path, content.len());
+        let response = self.put_blob(path, content, None)?;
+        check_status(response, &format!("for path: {}", path))?;
+        Ok(())
+    }
+
+    /// Uses If-None-Match: *, which Azure rejects with 409 Conflict if the
+    /// blob exists.
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes", path, content.len());
+        let response = self.put_blob(path, content, Some("*"))?;
+        match response.status().as_u16() {
+            409 | 412 => Ok(false),
+            _ => check_status(response, &format!("for path: {}", path)).map(|_| true),
+        }
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("STORAGE DELETE: path='{}'", path);
+        let response = self.send("DELETE", &self.blob_url(path), &[], &[], None, None)?;
+        match response.status().as_u16() {
+            404 => Ok(()),
+            _ => check_status(response, &format!("deleting path: {}", path)).map(|_| ()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let response = self.send("HEAD", &self.blob_url(path), &[], &[], None, None)?;
+        match response.status().as_u16() {
+            404 => Ok(false),
+            _ => check_status(response, &format!("for path: {}", path)).map(|_| true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, Mutex}, time::{Duration, UNIX_EPOCH}};
+
+    use anyhow::Result;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    use super::{hmac_sha256, http_date, parse_http_date, string_to_sign, AzureBlobStorage};
+    use crate::storage::{mock_http::{decode, start_mock_server, MockRequest, MockResponse}, SyncStorage};
+
+    const ACCOUNT: &str = "devstoreaccount1";
+    const CONTAINER: &str = "sync";
+    const KEY: &[u8] = b"test storage account key";
+
+    /// A minimal Blob service, handling one request per connection. The
+    /// endpoint is path style, like Azurite.
+    struct MockAzure {
+        endpoint: String,
+        blobs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    }
+
+    impl MockAzure {
+        fn start() -> Result<Self> {
+            let blobs = Arc::new(Mutex::new(BTreeMap::new()));
+            let server_blobs = blobs.clone();
+            let endpoint = start_mock_server(move |request| {
+                let (status, body) = handle(request, &server_blobs);
+                MockResponse::new(status, body)
+            })?;
+            Ok(Self { endpoint: format!("{}/{}", endpoint, ACCOUNT), blobs })
+        }
+    }
+
+    /// Rebuilds the Shared Key signature from the request as received.
+    fn is_authorized(method: &str, path: &str, params: &BTreeMap<String, String>, headers: &BTreeMap<String, String>) -> bool {
+        let header = |name: &str| headers.get(name).cloned().unwrap_or_default();
+        let content_length = header("content-length");
+        let mut string_to_sign = format!("{}\n\n\n{}\n\n{}\n\n\n\n{}\n\n\n", method,
+            if content_length == "0" { "" } else { &content_length }, header("content-type"), header("if-none-match"));
+        for (name, value) in headers.iter().filter(|(name, _)| name.starts_with("x-ms-")) {
+            string_to_sign += &format!("{}:{}\n", name, value);
+        }
+        string_to_sign += &format!("/{}{}", ACCOUNT, path);
+        for (name, value) in params {
+            string_to_sign += &format!("\n{}:{}", name, value);
+        }
+        let expected = format!("SharedKey {}:{}", ACCOUNT, hmac_sha256(KEY, &string_to_sign).unwrap());
+        headers.get("authorization") == Some(&expected)
+    }
+
+    fn handle(request: &MockRequest, blobs: &Mutex<BTreeMap<String, Vec<u8>>>) -> (&'static str, Vec<u8>) {
+        let (method, path, params) = (request.method.as_str(), request.path(), request.params());
+        let (headers, body) = (&request.headers, &request.body);
+        if !is_authorized(method, path, &params, headers) {
+            return ("403 Forbidden", Vec::new());
+        }
+
+        let mut blobs = blobs.lock().unwrap();
+        let container_path = format!("/{}/{}", ACCOUNT, CONTAINER);
+        if method == "GET" && path == container_path && params.get("comp").map(String::as_str) == Some("list") {
+            // Two blobs per page to exercise paging
+            let prefix = params.get("prefix").cloned().unwrap_or_default();
+            let start: usize = params.get("marker").map(|m| m.parse().unwrap()).unwrap_or(0);
+            let names = blobs.keys()
+                .filter(|name| name.starts_with(&prefix) && !name[prefix.len()..].contains('/'))
+                .collect::<Vec<_>>();
+            let entries = names.iter().skip(start).take(2)
+                .map(|name| format!("<Blob><Name>{}</Name><Properties>\
+                    <Last-Modified>Thu, 29 Feb 2024 00:00:00 GMT</Last-Modified>\
+                    <Content-Length>{}</Content-Length></Properties></Blob>", name, blobs[*name].len()))
+                .collect::<String>();
+            let next_marker = if start + 2 < names.len() { (start + 2).to_string() } else { String::new() };
+            let response = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                <EnumerationResults ContainerName=\"{}\"><Prefix>{}</Prefix><Blobs>\
+                <BlobPrefix><Name>{}nested/</Name></BlobPrefix>{}</Blobs>\
+                <NextMarker>{}</NextMarker></EnumerationResults>", CONTAINER, prefix, prefix, entries, next_marker);
+            return ("200 OK", response.into_bytes());
+        }
+        let Some(name) = path.strip_prefix(&format!("{}/", container_path)).map(decode) else {
+            return ("404 Not Found", Vec::new());
+        };
+        if method == "GET" {
+            return match blobs.get(&name) {
+                Some(content) => ("200 OK", content.clone()),
+                None => ("404 Not Found", Vec::new()),
+            };
+        }
+        if method == "HEAD" {
+            return match blobs.contains_key(&name) {
+                true => ("200 OK", Vec::new()),
+                false => ("404 Not Found", Vec::new()),
+            };
+        }
+        if method == "DELETE" {
+            return match blobs.remove(&name) {
+                Some(_) => ("202 Accepted", Vec::new()),
+                None => ("404 Not Found", Vec::new()),
+            };
+        }
+        if method == "PUT" && headers.get("x-ms-blob-type").map(String::as_str) == Some("BlockBlob") {
+            if headers.get("if-none-match").map(String::as_str) == Some("*") && blobs.contains_key(&name) {
+                return ("409 Conflict", Vec::new());
+            }
+            blobs.insert(name, body.to_vec());
+            return ("201 Created", Vec::new());
+        }
+        ("404 Not Found", Vec::new())
+    }
+
+    fn setup() -> Result<(MockAzure, AzureBlobStorage)> {
+        let server = MockAzure::start()?;
+        let storage = AzureBlobStorage::new_with_endpoint(&server.endpoint, ACCOUNT, &STANDARD.encode(KEY), CONTAINER)?;
+        Ok((server, storage))
+    }
+
+    #[test]
+    fn put_get_and_list() -> Result<()> {
+        let (server, storage) = setup()?;
+        storage.put("sync/changes/a.msgpack", b"a")?;
+        storage.put("sync/changes/b.msgpack", b"bb")?;
+        storage.put("sync/changes/c.msgpack", b"ccc")?;
+        storage.put("sync/other/d.msgpack", b"d")?;
+        assert_eq!(server.blobs.lock().unwrap().len(), 4);
+
+        assert_eq!(storage.get("sync/changes/b.msgpack")?, b"bb");
+        assert!(storage.get("sync/changes/missing.msgpack").is_err());
+
+        let objects = storage.list("sync/changes/")?;
+        let paths = objects.iter().map(|o| o.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["sync/changes/a.msgpack", "sync/changes/b.msgpack", "sync/changes/c.msgpack"]);
+        assert_eq!(objects[2].size, 3);
+        assert_eq!(objects[0].modified, Some(UNIX_EPOCH + Duration::from_secs(1709164800)));
+        Ok(())
+    }
+
+    #[test]
+    fn put_empty_blob() -> Result<()> {
+        let (server, storage) = setup()?;
+        storage.put("sync/empty.msgpack", b"")?;
+        assert!(server.blobs.lock().unwrap()["sync/empty.msgpack"].is_empty());
+        assert!(storage.get("sync/empty.msgpack")?.is_empty());
+        Ok(())
+    }
+
+    /// The Get Container Metadata example from the Shared Key documentation,
+    /// signed with the emulator's well known account key.
+    #[test]
+    fn shared_key_known_answer() -> Result<()> {
+        let string_to_sign = string_to_sign("GET", "", "", "",
+            &[("x-ms-date", "Sun, 11 Oct 2009 21:49:13 GMT"), ("x-ms-version", "2009-09-19")],
+            "myaccount", "/mycontainer", &[("restype", "container"), ("comp", "metadata"), ("timeout", "20")]);
+        assert_eq!(string_to_sign, "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+            x-ms-date:Sun, 11 Oct 2009 21:49:13 GMT\nx-ms-version:2009-09-19\n\
+            /myaccount/mycontainer\ncomp:metadata\nrestype:container\ntimeout:20");
+        let key = STANDARD.decode("Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==")?;
+        assert_eq!(hmac_sha256(&key, &string_to_sign)?, "m649E40iEJ3QQyCg9/WI2Fa9zS+RB/2rEBcLJb0CKs0=");
+        Ok(())
+    }
+
+    #[test]
+    fn list_returns_empty() -> Result<()> {
+        let (_server, storage) = setup()?;
+        assert!(storage.list("sync/changes/")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn put_if_not_exists() -> Result<()> {
+        let (server, storage) = setup()?;
+        assert!(storage.put_if_not_exists("changes/a.msgpack", b"first")?);
+        assert!(!storage.put_if_not_exists("changes/a.msgpack", b"second")?);
+        assert_eq!(server.blobs.lock().unwrap()["changes/a.msgpack"], b"first");
+        Ok(())
+    }
+
+    #[test]
+    fn delete_and_exists() -> Result<()> {
+        let (server, storage) = setup()?;
+        storage.put("changes/a.msgpack", b"a")?;
+        assert!(storage.exists("changes/a.msgpack")?);
+        storage.delete("changes/a.msgpack")?;
+        assert!(!storage.exists("changes/a.msgpack")?);
+        assert!(server.blobs.lock().unwrap().is_empty());
+        // Deleting a missing blob succeeds
+        storage.delete("changes/a.msgpack")?;
+        Ok(())
+    }
+
+    #[test]
+    fn errors_distinguish_auth_from_network() -> Result<()> {
+        let (server, _storage) = setup()?;
+        let storage = AzureBlobStorage::new_with_endpoint(&server.endpoint, ACCOUNT, &STANDARD.encode("wrong key"), CONTAINER)?;
+        let error = storage.list("sync/").unwrap_err().to_string();
+        assert!(error.contains("authentication failed"), "{}", error);
+
+        let storage = AzureBlobStorage::new_with_endpoint("http://127.0.0.1:1/account", ACCOUNT, &STANDARD.encode(KEY), CONTAINER)?;
+        let error = storage.list("sync/").unwrap_err().to_string();
+        assert!(error.contains("network error"), "{}", error);
+
+        assert!(AzureBlobStorage::new(ACCOUNT, "not base64!", CONTAINER).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn http_dates() -> Result<()> {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time)?, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        Ok(())
+    }
+}
@@ -3,7 +3,21 @@ use std::sync::{Arc};
 use age::secrecy::SecretString;
 use anyhow::Result;
 
-use super::{ArcStorage, SyncStorage};
+use super::{ArcStorage, StorageObject, SyncStorage};
+
+/// Magic bytes at the start of every binary age file.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// Result of EncryptedStorage::verify_all() or verify_decryptable(), listing
+/// object paths by what was found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerificationReport {
+    pub valid: Vec<String>,
+    /// Objects that look like age files but couldn't be read.
+    pub invalid: Vec<String>,
+    /// Objects that aren't age files at all.
+    pub plaintext: Vec<String>,
+}
 
 /// EncryptedStorage transparently encrypts another Storage using age with
 /// passphrase-derived keys
@@ -34,12 +48,77 @@ impl EncryptedStorage {
         let decrypted = age::decrypt(&self.identity, encrypted)?;
         Ok(decrypted)
     }
+
+    /// Checks that every object under the prefix in the underlying storage
+    /// is an age encrypted file, by its magic bytes and header. Doesn't need
+    /// the passphrase, so it can't detect corrupted payloads.
+    pub fn verify_all(storage: &dyn SyncStorage, prefix: &str) -> Result<VerificationReport> {
+        Self::verify(storage, prefix, |content| age::Decryptor::new(content).is_ok())
+    }
+
+    /// Like verify_all(), but decrypts each object with the passphrase,
+    /// which also checks the header MAC and payload.
+    pub fn verify_decryptable(passphrase: &str, storage: &dyn SyncStorage, prefix: &str) -> Result<VerificationReport> {
+        let identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+        Self::verify(storage, prefix, |content| age::decrypt(&identity, content).is_ok())
+    }
+
+    /// Re-encrypts every object under the prefix in the underlying storage
+    /// from the old passphrase to the new one, returning the number of
+    /// objects rotated. Every object is decrypted before any is written, so a
+    /// wrong old passphrase or corrupted object fails without changing
+    /// anything. Objects are overwritten in place, and those that already
+    /// use the new passphrase are skipped, so an interrupted rotation can
+    /// simply be run again. Plaintext objects are left alone.
+    pub fn rotate_key(old_passphrase: &str, new_passphrase: &str, storage: &dyn SyncStorage, prefix: &str) -> Result<usize> {
+        let new_passphrase = SecretString::from(new_passphrase.to_string());
+        Self::rotate(storage, prefix,
+            &age::scrypt::Identity::new(SecretString::from(old_passphrase.to_string())),
+            &age::scrypt::Identity::new(new_passphrase.clone()),
+            &age::scrypt::Recipient::new(new_passphrase))
+    }
+
+    fn rotate(storage: &dyn SyncStorage, prefix: &str, old_identity: &age::scrypt::Identity,
+            new_identity: &age::scrypt::Identity, new_recipient: &age::scrypt::Recipient) -> Result<usize> {
+        let mut decrypted = Vec::new();
+        for path in storage.list_paths_only(prefix)? {
+            let content = storage.get(&path)?;
+            if !content.starts_with(AGE_MAGIC) {
+                continue;
+            }
+            match age::decrypt(old_identity, &content) {
+                Ok(plaintext) => decrypted.push((path, plaintext)),
+                Err(_) if age::decrypt(new_identity, &content).is_ok() => continue,
+                Err(e) => return Err(anyhow::anyhow!("Failed to decrypt {} with the old passphrase: {}", path, e)),
+            }
+        }
+        for (path, plaintext) in &decrypted {
+            storage.put(path, &age::encrypt(new_recipient, plaintext)?)?;
+        }
+        Ok(decrypted.len())
+    }
+
+    fn verify(storage: &dyn SyncStorage, prefix: &str, is_valid: impl Fn(&[u8]) -> bool) -> Result<VerificationReport> {
+        let mut report = VerificationReport::default();
+        for path in storage.list_paths_only(prefix)? {
+            let content = storage.get(&path)?;
+            if !content.starts_with(AGE_MAGIC) {
+                report.plaintext.push(path);
+            } else if is_valid(&content) {
+                report.valid.push(path);
+            } else {
+                report.invalid.push(path);
+            }
+        }
+        Ok(report)
+    }
 }
 
 impl SyncStorage for EncryptedStorage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         log::debug!("ENCRYPTED STORAGE LIST: prefix='{}'", prefix);        
-        // Pass through to underlying storage - paths are not encrypted
+        // Pass through to underlying storage - paths are not encrypted, sizes
+        // are of the encrypted content
         self.inner.list(prefix)
     }
     
@@ -58,6 +137,19 @@ impl SyncStorage for EncryptedStorage {
         log::debug!("ENCRYPTED STORAGE PUT RESULT: success");
         Ok(())
     }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        let encrypted_content = self.encrypt_bytes(content)?;
+        self.inner.put_if_not_exists(path, &encrypted_content)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path)
+    }
 }
 
 #[cfg(test)]
@@ -66,6 +158,79 @@ mod tests {
 
     use super::*;
 
+    /// Encrypts with a low scrypt work factor, so the tests run quickly.
+    fn encrypt_fast(passphrase: &str, data: &[u8]) -> Vec<u8> {
+        let mut recipient = age::scrypt::Recipient::new(SecretString::from(passphrase.to_string()));
+        recipient.set_work_factor(2);
+        age::encrypt(&recipient, data).unwrap()
+    }
+
+    #[test]
+    fn verify_all_classifies_objects() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let encrypted = encrypt_fast("passphrase", b"data");
+        storage.put("sync/encrypted", &encrypted)?;
+        storage.put("sync/plaintext", b"data")?;
+        storage.put("sync/truncated", &encrypted[..AGE_MAGIC.len() + 4])?;
+        storage.put("other/plaintext", b"data")?;
+
+        let report = EncryptedStorage::verify_all(&storage, "sync/")?;
+        assert_eq!(report, VerificationReport {
+            valid: vec!["sync/encrypted".to_string()],
+            invalid: vec!["sync/truncated".to_string()],
+            plaintext: vec!["sync/plaintext".to_string()],
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn verify_decryptable_checks_passphrase_and_payload() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let encrypted = encrypt_fast("passphrase", b"data");
+        let mut corrupted = encrypted.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        storage.put("a", &encrypted)?;
+        storage.put("b", &encrypt_fast("other passphrase", b"data"))?;
+        storage.put("c", &corrupted)?;
+
+        let report = EncryptedStorage::verify_decryptable("passphrase", &storage, "")?;
+        assert_eq!(report.valid, vec!["a"]);
+        assert_eq!(report.invalid, vec!["b", "c"]);
+        assert!(report.plaintext.is_empty());
+        // Without the passphrase, corruption can't be detected
+        assert_eq!(EncryptedStorage::verify_all(&storage, "")?.valid.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_key_reencrypts_objects() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        storage.put("sync/a", &encrypt_fast("old", b"a"))?;
+        storage.put("sync/b", &encrypt_fast("old", b"b"))?;
+        storage.put("sync/plaintext", b"c")?;
+        storage.put("other/d", &encrypt_fast("old", b"d"))?;
+
+        // A wrong old passphrase changes nothing
+        let new_identity = age::scrypt::Identity::new(SecretString::from("new".to_string()));
+        let mut new_recipient = age::scrypt::Recipient::new(SecretString::from("new".to_string()));
+        new_recipient.set_work_factor(2);
+        let wrong_identity = age::scrypt::Identity::new(SecretString::from("wrong".to_string()));
+        assert!(EncryptedStorage::rotate(&storage, "sync/", &wrong_identity, &new_identity, &new_recipient).is_err());
+        assert_eq!(EncryptedStorage::verify_decryptable("old", &storage, "sync/")?.valid.len(), 2);
+
+        let old_identity = age::scrypt::Identity::new(SecretString::from("old".to_string()));
+        assert_eq!(EncryptedStorage::rotate(&storage, "sync/", &old_identity, &new_identity, &new_recipient)?, 2);
+        let encrypted = EncryptedStorage::new(Box::new(storage.clone()), "new".to_string());
+        assert_eq!(encrypted.get("sync/a")?, b"a");
+        assert_eq!(encrypted.get("sync/b")?, b"b");
+        assert_eq!(storage.get("sync/plaintext")?, b"c");
+        assert!(encrypted.get("other/d").is_err());
+
+        // Running it again finds nothing left to rotate
+        assert_eq!(EncryptedStorage::rotate(&storage, "sync/", &old_identity, &new_identity, &new_recipient)?, 0);
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn encrypt_decrypt_roundtrip() -> Result<()> {
@@ -130,7 +295,7 @@ mod tests {
         storage.put("other/file3.txt", b"data3")?;
         
         // List should work normally (paths are not encrypted)
-        let files = storage.list("test/")?;
+        let files = storage.list_paths_only("test/")?;
         assert_eq!(files.len(), 2);
         assert!(files.contains(&"test/file1.txt".to_string()));
         assert!(files.contains(&"test/file2.txt".to_string()));
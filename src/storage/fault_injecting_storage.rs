@@ -0,0 +1,157 @@
+use std::{collections::HashMap, io::ErrorKind, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use anyhow::Result;
+
+use super::{StorageObject, SyncStorage};
+
+/// A failure for FaultInjectingStorage to return. Each is an io::Error of
+/// the matching kind, like a real network or filesystem failure would be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageFault {
+    Timeout,
+    PermissionDenied,
+    NotFound,
+    ConnectionReset,
+}
+
+impl StorageFault {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            StorageFault::Timeout => ErrorKind::TimedOut,
+            StorageFault::PermissionDenied => ErrorKind::PermissionDenied,
+            StorageFault::NotFound => ErrorKind::NotFound,
+            StorageFault::ConnectionReset => ErrorKind::ConnectionReset,
+        }
+    }
+}
+
+/// Passes calls through to another Storage, except the calls programmed to
+/// fail, for testing how failures at exact points are handled. Calls of
+/// every kind are numbered together, starting at 1, and a failed call does
+/// not reach the inner storage.
+///
+/// ```ignore
+/// let storage = FaultInjectingStorage::new(Box::new(InMemoryStorage::new()),
+///     vec![(3, StorageFault::Timeout), (7, StorageFault::PermissionDenied)]);
+/// storage.put("a", b"1")?;
+/// storage.get("a")?;
+/// assert!(storage.list("").is_err());
+/// ```
+pub struct FaultInjectingStorage {
+    inner: Box<dyn SyncStorage>,
+    faults: Mutex<HashMap<u64, StorageFault>>,
+    call_count: AtomicU64,
+}
+
+impl FaultInjectingStorage {
+    pub fn new(inner: Box<dyn SyncStorage>, faults: Vec<(u64, StorageFault)>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(faults.into_iter().collect()),
+            call_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Make the call numbered call fail, counting the calls already made.
+    pub fn add_fault(&self, call: u64, fault: StorageFault) {
+        self.faults.lock().unwrap_or_else(|e| e.into_inner()).insert(call, fault);
+    }
+
+    /// The number of calls made so far, including failed ones.
+    pub fn call_count(&self) -> u64 {
+        self.call_count.load(Ordering::SeqCst)
+    }
+
+    /// Counts the call and fails it if it was programmed to.
+    fn call(&self, name: &str, path: &str) -> Result<()> {
+        let call = self.call_count.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.faults.lock().unwrap_or_else(|e| e.into_inner()).remove(&call) {
+            Some(fault) => Err(std::io::Error::new(fault.kind(),
+                format!("Injected {:?} for {} call {} on path: {}", fault, name, call, path)).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SyncStorage for FaultInjectingStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        self.call("list", prefix)?;
+        self.inner.list(prefix)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        self.call("get", path)?;
+        self.inner.get(path)
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.call("put", path)?;
+        self.inner.put(path, content)
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        self.call("put", path)?;
+        self.inner.put_if_not_exists(path, content)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.call("delete", path)?;
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.call("exists", path)?;
+        self.inner.exists(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{db::DimpleError, storage::InMemoryStorage, sync::SyncEngine, Db};
+
+    use super::*;
+
+    #[test]
+    fn fails_the_programmed_calls() -> Result<()> {
+        let storage = FaultInjectingStorage::new(Box::new(InMemoryStorage::new()),
+            vec![(3, StorageFault::Timeout), (5, StorageFault::PermissionDenied)]);
+        storage.put("a", b"1")?;
+        storage.get("a")?;
+        let error = storage.list("").unwrap_err();
+        assert_eq!(error.downcast_ref::<std::io::Error>().unwrap().kind(), ErrorKind::TimedOut);
+        storage.list("")?;
+        let error = storage.put("b", b"2").unwrap_err();
+        assert_eq!(error.downcast_ref::<std::io::Error>().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(!storage.exists("b")?);
+
+        storage.add_fault(storage.call_count() + 1, StorageFault::NotFound);
+        assert!(storage.get("a").is_err());
+        assert_eq!(storage.get("a")?, b"1");
+        assert_eq!(storage.call_count(), 8);
+        Ok(())
+    }
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    #[test]
+    fn sync_reports_injected_faults_as_storage_errors() -> Result<()> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let storage = FaultInjectingStorage::new(Box::new(InMemoryStorage::new()),
+            vec![(1, StorageFault::ConnectionReset)]);
+        let engine = SyncEngine::builder().storage(Box::new(storage)).build()?;
+        assert!(matches!(engine.sync(&db), Err(DimpleError::StorageError(_))));
+        engine.sync(&db)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+use std::{sync::Mutex, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use openssl::{hash::MessageDigest, pkey::{PKey, Private}, sign::Signer};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+
+use super::{s3_storage::parse_last_modified, StorageHttpError, StorageObject, SyncStorage};
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Everything but unreserved characters, so that slashes in object names
+/// are encoded as the JSON API requires.
+const ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The fields of a service account key file that are needed to get an
+/// access token.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse {
+    #[serde(default)]
+    items: Vec<ListItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListItem {
+    name: String,
+    size: String,
+    updated: Option<String>,
+}
+
+/// Google Cloud Storage using the JSON API, authenticated with a service
+/// account key.
+pub struct GcsStorage {
+    endpoint: String,
+    bucket: String,
+    client_email: String,
+    private_key: PKey<Private>,
+    token_uri: String,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcsStorage {
+    /// Create storage for the bucket, where service_account_json is the
+    /// contents of a service account key file.
+    pub fn new(bucket: &str, service_account_json: &str) -> Result<Self> {
+        Self::new_with_endpoint(DEFAULT_ENDPOINT, bucket, service_account_json)
+    }
+
+    /// Like new(), but using a different endpoint, such as an emulator.
+    pub fn new_with_endpoint(endpoint: &str, bucket: &str, service_account_json: &str) -> Result<Self> {
+        let key: ServiceAccountKey = serde_json::from_str(service_account_json)?;
+        let private_key = PKey::private_key_from_pem(key.private_key.as_bytes())?;
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            client_email: key.client_email,
+            private_key,
+            token_uri: key.token_uri.unwrap_or_else(|| DEFAULT_TOKEN_URI.to_string()),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Returns a cached access token, or exchanges a newly signed JWT for
+    /// one when it is missing or about to expire.
+    fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().map_err(|_| anyhow!("GCS token lock poisoned"))?;
+        if let Some((access_token, expires_at)) = token.as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let assertion = self.signed_jwt()?;
+        let body = format!("grant_type={}&assertion={}",
+            utf8_percent_encode("urn:ietf:params:oauth:grant-type:jwt-bearer", ENCODE_SET),
+            utf8_percent_encode(&assertion, ENCODE_SET));
+        let response = attohttpc::post(&self.token_uri)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .text(body)
+            .send()?;
+        if !response.is_success() {
+            let status = response.status().as_u16();
+            return Err(StorageHttpError::new(status, format!("GCS token request returned status {}", status)).into());
+        }
+        let response: TokenResponse = response.json()?;
+        // Refresh a minute early so a token doesn't expire mid request
+        let expires_at = Instant::now() + Duration::from_secs(response.expires_in.saturating_sub(60));
+        *token = Some((response.access_token.clone(), expires_at));
+        Ok(response.access_token)
+    }
+
+    fn signed_jwt(&self) -> Result<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = serde_json::json!({ "alg": "RS256", "typ": "JWT" });
+        let claims = serde_json::json!({
+            "iss": self.client_email,
+            "scope": SCOPE,
+            "aud": self.token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        });
+        let unsigned = format!("{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?));
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        signer.update(unsigned.as_bytes())?;
+        Ok(format!("{}.{}", unsigned, URL_SAFE_NO_PAD.encode(signer.sign_to_vec()?)))
+    }
+
+    fn object_url(&self, path: &str) -> String {
+        format!("{}/storage/v1/b/{}/o/{}", self.endpoint,
+            utf8_percent_encode(&self.bucket, ENCODE_SET),
+            utf8_percent_encode(path, ENCODE_SET))
+    }
+
+    fn upload(&self, path: &str, content: &[u8], if_generation_match: Option<&str>) -> Result<attohttpc::Response> {
+        let url = format!("{}/upload/storage/v1/b/{}/o", self.endpoint,
+            utf8_percent_encode(&self.bucket, ENCODE_SET));
+        let mut request = attohttpc::post(url)
+            .bearer_auth(self.access_token()?)
+            .param("uploadType", "media")
+            .param("name", path);
+        if let Some(generation) = if_generation_match {
+            request = request.param("ifGenerationMatch", generation);
+        }
+        Ok(request
+            .header("Content-Type", "application/octet-stream")
+            .bytes(content)
+            .send()?)
+    }
+}
+
+impl SyncStorage for GcsStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        log::debug!("STORAGE LIST: prefix='{}'", prefix);
+        let url = format!("{}/storage/v1/b/{}/o", self.endpoint,
+            utf8_percent_encode(&self.bucket, ENCODE_SET));
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = attohttpc::get(&url)
+                .bearer_auth(self.access_token()?)
+                .param("prefix", prefix)
+                .param("delimiter", "/");
+            if let Some(page_token) = &page_token {
+                request = request.param("pageToken", page_token);
+            }
+            let response = request.send()?;
+            if !response.is_success() {
+                let status = response.status().as_u16();
+                return Err(StorageHttpError::new(status, format!("GCS returned error status {} listing prefix: {}", status, prefix)).into());
+            }
+            let response: ListResponse = response.json()?;
+            for item in response.items {
+                objects.push(StorageObject {
+                    size: item.size.parse()?,
+                    modified: item.updated.as_deref().and_then(parse_last_modified),
+                    path: item.name,
+                });
+            }
+            match response.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        log::debug!("STORAGE LIST RESULT: {} items", objects.len());
+        Ok(objects)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        log::debug!("STORAGE GET: path='{}'", path);
+        let response = attohttpc::get(self.object_url(path))
+            .bearer_auth(self.access_token()?)
+            .param("alt", "media")
+            .send()?;
+        if !response.is_success() {
+            let status = response.status().as_u16();
+            return Err(StorageHttpError::new(status, format!("GCS returned error status {} for path: {}", status, path)).into());
+        }
+        let bytes = response.bytes()?;
+        log::debug!("STORAGE GET RESULT: {} bytes", bytes.len());
+        Ok(bytes)
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        log::debug!("STORAGE PUT: path='{}', size={} bytes", path, content.len());
+        let response = self.upload(path, content, None)?;
+        if !response.is_success() {
+            let status = response.status().as_u16();
+            return Err(StorageHttpError::new(status, format!("GCS returned error status {} for path: {}", status, path)).into());
+        }
+        Ok(())
+    }
+
+    /// Uses ifGenerationMatch=0, which GCS rejects with 412 Precondition
+    /// Failed if the object exists.
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes", path, content.len());
+        let response = self.upload(path, content, Some("0"))?;
+        match response.status().as_u16() {
+            200..=299 => Ok(true),
+            412 => Ok(false),
+            status => Err(StorageHttpError::new(status, format!("GCS returned error status {} for path: {}", status, path)).into()),
+        }
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("STORAGE DELETE: path='{}'", path);
+        let response = attohttpc::delete(self.object_url(path))
+            .bearer_auth(self.access_token()?)
+            .send()?;
+        match response.status().as_u16() {
+            200..=299 | 404 => Ok(()),
+            status => Err(StorageHttpError::new(status, format!("GCS returned error status {} deleting path: {}", status, path)).into()),
+        }
+    }
+
+    /// Gets the object's metadata rather than its content.
+    fn exists(&self, path: &str) -> Result<bool> {
+        let response = attohttpc::get(self.object_url(path))
+            .bearer_auth(self.access_token()?)
+            .send()?;
+        match response.status().as_u16() {
+            200..=299 => Ok(true),
+            404 => Ok(false),
+            status => Err(StorageHttpError::new(status, format!("GCS returned error status {} for path: {}", status, path)).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+
+    use anyhow::Result;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use openssl::{hash::MessageDigest, pkey::{PKey, Public}, rsa::Rsa, sign::Verifier};
+
+    use super::GcsStorage;
+    use crate::storage::{mock_http::{decode, form_params, start_mock_server, MockRequest, MockResponse}, SyncStorage};
+
+    const BUCKET: &str = "test-bucket";
+    const TOKEN: &str = "test-token";
+
+    /// A minimal GCS JSON API server, handling one request per connection.
+    struct MockGcs {
+        endpoint: String,
+        objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+        token_requests: Arc<Mutex<usize>>,
+    }
+
+    impl MockGcs {
+        fn start(public_key: PKey<Public>) -> Result<Self> {
+            let objects = Arc::new(Mutex::new(BTreeMap::new()));
+            let token_requests = Arc::new(Mutex::new(0));
+            let (server_objects, server_token_requests) = (objects.clone(), token_requests.clone());
+            let endpoint = start_mock_server(move |request| {
+                let (status, body) = handle(request, &public_key, &server_objects, &server_token_requests);
+                MockResponse::new(status, body)
+            })?;
+            Ok(Self { endpoint, objects, token_requests })
+        }
+    }
+
+    fn handle(request: &MockRequest, public_key: &PKey<Public>,
+            objects: &Mutex<BTreeMap<String, Vec<u8>>>, token_requests: &Mutex<usize>) -> (&'static str, Vec<u8>) {
+        let (method, path, params, body) = (request.method.as_str(), request.path(), request.params(), &request.body);
+
+        if method == "POST" && path == "/token" {
+            *token_requests.lock().unwrap() += 1;
+            let form = form_params(&String::from_utf8_lossy(body));
+            let assertion = form.get("assertion").cloned().unwrap_or_default();
+            let (unsigned, signature) = assertion.rsplit_once('.').unwrap_or_default();
+            let signature = URL_SAFE_NO_PAD.decode(signature).unwrap_or_default();
+            let mut verifier = Verifier::new(MessageDigest::sha256(), public_key).unwrap();
+            verifier.update(unsigned.as_bytes()).unwrap();
+            if !verifier.verify(&signature).unwrap_or(false) {
+                return ("401 Unauthorized", Vec::new());
+            }
+            let response = serde_json::json!({ "access_token": TOKEN, "expires_in": 3600 });
+            return ("200 OK", response.to_string().into_bytes());
+        }
+
+        let authorized = request.header("authorization") == Some(format!("Bearer {}", TOKEN).as_str());
+        if !authorized {
+            return ("401 Unauthorized", Vec::new());
+        }
+
+        let mut objects = objects.lock().unwrap();
+        let list_path = format!("/storage/v1/b/{}/o", BUCKET);
+        let upload_path = format!("/upload/storage/v1/b/{}/o", BUCKET);
+        if method == "GET" && path == list_path {
+            // Two items per page to exercise paging
+            let prefix = params.get("prefix").cloned().unwrap_or_default();
+            let start: usize = params.get("pageToken").map(|t| t.parse().unwrap()).unwrap_or(0);
+            let names = objects.keys()
+                .filter(|name| name.starts_with(&prefix) && !name[prefix.len()..].contains('/'))
+                .collect::<Vec<_>>();
+            let items = names.iter().skip(start).take(2)
+                .map(|name| serde_json::json!({
+                    "name": name,
+                    "size": objects[*name].len().to_string(),
+                    "updated": "2024-02-29T00:00:00.500Z",
+                }))
+                .collect::<Vec<_>>();
+            let mut response = serde_json::json!({ "items": items });
+            if start + 2 < names.len() {
+                response["nextPageToken"] = (start + 2).to_string().into();
+            }
+            return ("200 OK", response.to_string().into_bytes());
+        }
+        if method == "GET" && path.starts_with(&list_path) {
+            let name = decode(&path[list_path.len() + 1..]);
+            return match objects.get(&name) {
+                Some(content) if params.get("alt").map(String::as_str) == Some("media") => ("200 OK", content.clone()),
+                Some(content) => ("200 OK", serde_json::json!({ "name": name, "size": content.len().to_string() })
+                    .to_string().into_bytes()),
+                None => ("404 Not Found", Vec::new()),
+            };
+        }
+        if method == "DELETE" && path.starts_with(&list_path) {
+            let name = decode(&path[list_path.len() + 1..]);
+            return match objects.remove(&name) {
+                Some(_) => ("204 No Content", Vec::new()),
+                None => ("404 Not Found", Vec::new()),
+            };
+        }
+        if method == "POST" && path == upload_path {
+            let name = params.get("name").cloned().unwrap_or_default();
+            if params.get("ifGenerationMatch").map(String::as_str) == Some("0") && objects.contains_key(&name) {
+                return ("412 Precondition Failed", Vec::new());
+            }
+            objects.insert(name, body.to_vec());
+            return ("200 OK", b"{}".to_vec());
+        }
+        ("404 Not Found", Vec::new())
+    }
+
+    fn setup() -> Result<(MockGcs, GcsStorage)> {
+        let rsa = Rsa::generate(2048)?;
+        let public_key = PKey::public_key_from_pem(&rsa.public_key_to_pem()?)?;
+        let private_key = String::from_utf8(PKey::from_rsa(rsa)?.private_key_to_pem_pkcs8()?)?;
+        let server = MockGcs::start(public_key)?;
+        let service_account_json = serde_json::json!({
+            "type": "service_account",
+            "client_email": "sync@example.iam.gserviceaccount.com",
+            "private_key": private_key,
+            "token_uri": format!("{}/token", server.endpoint),
+        }).to_string();
+        let storage = GcsStorage::new_with_endpoint(&server.endpoint, BUCKET, &service_account_json)?;
+        Ok((server, storage))
+    }
+
+    #[test]
+    fn put_get_and_list() -> Result<()> {
+        let (server, storage) = setup()?;
+        storage.put("sync/changes/a.msgpack", b"a")?;
+        storage.put("sync/changes/b.msgpack", b"bb")?;
+        storage.put("sync/changes/c.msgpack", b"ccc")?;
+        storage.put("sync/other/d.msgpack", b"d")?;
+        assert_eq!(server.objects.lock().unwrap().len(), 4);
+
+        assert_eq!(storage.get("sync/changes/b.msgpack")?, b"bb");
+        assert!(storage.get("sync/changes/missing.msgpack").is_err());
+
+        let objects = storage.list("sync/changes/")?;
+        let paths = objects.iter().map(|o| o.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["sync/changes/a.msgpack", "sync/changes/b.msgpack", "sync/changes/c.msgpack"]);
+        assert_eq!(objects[2].size, 3);
+        assert!(objects[0].modified.is_some());
+
+        // The access token is reused between requests
+        assert_eq!(*server.token_requests.lock().unwrap(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn put_if_not_exists() -> Result<()> {
+        let (server, storage) = setup()?;
+        assert!(storage.put_if_not_exists("changes/a.msgpack", b"first")?);
+        assert!(!storage.put_if_not_exists("changes/a.msgpack", b"second")?);
+        assert_eq!(server.objects.lock().unwrap()["changes/a.msgpack"], b"first");
+        Ok(())
+    }
+
+    #[test]
+    fn delete_and_exists() -> Result<()> {
+        let (server, storage) = setup()?;
+        storage.put("changes/a.msgpack", b"a")?;
+        assert!(storage.exists("changes/a.msgpack")?);
+        storage.delete("changes/a.msgpack")?;
+        assert!(!storage.exists("changes/a.msgpack")?);
+        assert!(server.objects.lock().unwrap().is_empty());
+        // Deleting a missing object succeeds
+        storage.delete("changes/a.msgpack")?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_service_account_is_rejected() {
+        assert!(GcsStorage::new(BUCKET, "{}").is_err());
+        assert!(GcsStorage::new(BUCKET, r#"{"client_email": "a", "private_key": "not a key"}"#).is_err());
+    }
+}
@@ -1,23 +1,78 @@
-use std::{fs, path::Path};
+use std::{fs, io::Write as _, path::Path};
 
 use anyhow::Result;
 
-use super::SyncStorage;
+use super::{StorageObject, SyncStorage};
 
 pub struct LocalStorage {
     base_path: String,
 }
 
 impl LocalStorage {
+    /// Store files under base_path. A leading ~ is expanded to the home
+    /// directory, and $VAR and ${VAR} are expanded from the environment, as
+    /// is %VAR% on Windows. Unset variables are left as is.
     pub fn new(base_path: &str) -> Self {
         Self {
-            base_path: base_path.to_string(),
+            base_path: expand_path(base_path, cfg!(windows), |name| std::env::var(name).ok()),
         }
     }
 }
 
+fn home_dir(env: &impl Fn(&str) -> Option<String>) -> Option<String> {
+    env("HOME").or_else(|| env("USERPROFILE"))
+}
+
+/// Expands ~ and environment variables in path, including %VAR% if
+/// percent_vars is set, since % is an ordinary character in Unix paths.
+fn expand_path(path: &str, percent_vars: bool, env: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        if let Some(home) = home_dir(&env) {
+            expanded.push_str(home.trim_end_matches(['/', '\\']));
+            rest = &rest[1..];
+        }
+    }
+
+    let markers: &[char] = if percent_vars { &['$', '%'] } else { &['$'] };
+    while let Some(i) = rest.find(markers) {
+        expanded.push_str(&rest[..i]);
+        let (name, len) = if let Some(braced) = rest[i..].strip_prefix("${") {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 3),
+                None => ("", 0),
+            }
+        }
+        else if rest[i..].starts_with('%') {
+            match rest[i + 1..].find('%') {
+                Some(end) => (&rest[i + 1..i + 1 + end], end + 2),
+                None => ("", 0),
+            }
+        }
+        else {
+            let end = rest[i + 1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len() - i - 1);
+            (&rest[i + 1..i + 1 + end], end + 1)
+        };
+        match env(name) {
+            Some(value) if !name.is_empty() => {
+                expanded.push_str(&value);
+                rest = &rest[i + len..];
+            },
+            _ => {
+                expanded.push_str(&rest[i..i + 1]);
+                rest = &rest[i + 1..];
+            },
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
 impl SyncStorage for LocalStorage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         log::debug!("STORAGE LIST: prefix='{}'", prefix);
         // Remove trailing slash from prefix to normalize it
         let normalized_prefix = prefix.trim_end_matches('/');
@@ -33,12 +88,18 @@ impl SyncStorage for LocalStorage {
         for entry in fs::read_dir(path)? {
             let entry = entry?;
             let file_name = entry.file_name().to_string_lossy().to_string();
+            let metadata = entry.metadata()?;
             // Handle empty prefix case
-            if normalized_prefix.is_empty() {
-                results.push(format!("/{}", file_name));
+            let path = if normalized_prefix.is_empty() {
+                format!("/{}", file_name)
             } else {
-                results.push(format!("{}/{}", normalized_prefix, file_name));
-            }
+                format!("{}/{}", normalized_prefix, file_name)
+            };
+            results.push(StorageObject {
+                path,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
         }
 
         log::debug!("STORAGE LIST RESULT: {} items", results.len());
@@ -63,6 +124,36 @@ impl SyncStorage for LocalStorage {
         log::debug!("STORAGE PUT RESULT: success");
         Ok(())
     }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes", path, content.len());
+        let full_path = format!("{}/{}", self.base_path, path);
+        if let Some(parent) = Path::new(&full_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // create_new fails if the file exists, atomically
+        let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(&full_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(content)?;
+        Ok(true)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("STORAGE DELETE: path='{}'", path);
+        let full_path = format!("{}/{}", self.base_path, path);
+        match fs::remove_file(full_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let full_path = format!("{}/{}", self.base_path, path);
+        Ok(Path::new(&full_path).is_file())
+    }
 }
 
 #[cfg(test)]
@@ -80,19 +171,52 @@ mod tests {
         storage.put("file2.txt", b"content2").unwrap();
         
         // List with empty prefix should return all files in root
-        let files = storage.list("").unwrap();
+        let files = storage.list_paths_only("").unwrap();
         assert_eq!(files.len(), 2);
         assert!(files.contains(&"/file1.txt".to_string()));
         assert!(files.contains(&"/file2.txt".to_string()));
     }
 
+    #[test]
+    fn test_put_if_not_exists() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path().to_str().unwrap());
+        assert!(storage.put_if_not_exists("dir/file.txt", b"first").unwrap());
+        assert!(!storage.put_if_not_exists("dir/file.txt", b"second").unwrap());
+        assert_eq!(storage.get("dir/file.txt").unwrap(), b"first");
+    }
+
+    #[test]
+    fn test_delete_and_exists() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path().to_str().unwrap());
+        storage.put("dir/file.txt", b"content").unwrap();
+        assert!(storage.exists("dir/file.txt").unwrap());
+        assert!(!storage.exists("dir").unwrap());
+        storage.delete("dir/file.txt").unwrap();
+        assert!(!storage.exists("dir/file.txt").unwrap());
+        storage.delete("dir/file.txt").unwrap();
+    }
+
+    #[test]
+    fn test_list_returns_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path().to_str().unwrap());
+        storage.put("dir/file.txt", b"content").unwrap();
+        let objects = storage.list("dir").unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].path, "dir/file.txt");
+        assert_eq!(objects[0].size, 7);
+        assert!(objects[0].modified.is_some());
+    }
+
     #[test]
     fn test_list_with_non_existent_prefix() {
         let temp_dir = TempDir::new().unwrap();
         let storage = LocalStorage::new(temp_dir.path().to_str().unwrap());
         
         // List a non-existent directory
-        let files = storage.list("non_existent_dir").unwrap();
+        let files = storage.list_paths_only("non_existent_dir").unwrap();
         assert_eq!(files.len(), 0);
     }
 
@@ -108,18 +232,73 @@ mod tests {
         storage.put("dir2/file4.txt", b"content4").unwrap();
         
         // List dir1 - should only show immediate children
-        let files = storage.list("dir1").unwrap();
+        let files = storage.list_paths_only("dir1").unwrap();
         assert_eq!(files.len(), 3); // file1.txt, file2.txt, and subdir
         assert!(files.contains(&"dir1/file1.txt".to_string()));
         assert!(files.contains(&"dir1/file2.txt".to_string()));
         assert!(files.contains(&"dir1/subdir".to_string()));
         
         // List dir1/subdir
-        let files = storage.list("dir1/subdir").unwrap();
+        let files = storage.list_paths_only("dir1/subdir").unwrap();
         assert_eq!(files.len(), 1);
         assert!(files.contains(&"dir1/subdir/file3.txt".to_string()));
     }
 
+    fn test_env(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/dimple".to_string()),
+            "DIMPLE_SYNC_DIR" => Some("/var/sync".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_path() {
+        assert_eq!(expand_path("~", false, test_env), "/home/dimple");
+        assert_eq!(expand_path("~/dimple-sync", false, test_env), "/home/dimple/dimple-sync");
+        assert_eq!(expand_path("$DIMPLE_SYNC_DIR/todos", false, test_env), "/var/sync/todos");
+        assert_eq!(expand_path("${DIMPLE_SYNC_DIR}-todos", false, test_env), "/var/sync-todos");
+        assert_eq!(expand_path("/data/~user/$UNSET/100%", false, test_env), "/data/~user/$UNSET/100%");
+        assert_eq!(expand_path("~other/sync", false, test_env), "~other/sync");
+    }
+
+    #[test]
+    fn test_expand_percent_vars() {
+        assert_eq!(expand_path("%DIMPLE_SYNC_DIR%\\todos", true, test_env), "/var/sync\\todos");
+        assert_eq!(expand_path("C:\\100%\\$DIMPLE_SYNC_DIR", true, test_env), "C:\\100%\\/var/sync");
+        // % is an ordinary character outside of Windows
+        assert_eq!(expand_path("/sync/%DIMPLE_SYNC_DIR%", false, test_env), "/sync/%DIMPLE_SYNC_DIR%");
+    }
+
+    #[test]
+    fn test_expand_windows_home() {
+        let env = |name: &str| (name == "USERPROFILE").then(|| "C:\\Users\\dimple\\".to_string());
+        assert_eq!(expand_path("~\\sync", true, env), "C:\\Users\\dimple\\sync");
+    }
+
+    #[test]
+    fn test_new_expands_environment() {
+        const NAME: &str = "DIMPLE_LOCAL_STORAGE_TEST_DIR";
+
+        /// Restores the variable when the test ends, even if it fails.
+        struct RestoreVar(Option<std::ffi::OsString>);
+        impl Drop for RestoreVar {
+            fn drop(&mut self) {
+                match self.0.take() {
+                    Some(value) => std::env::set_var(NAME, value),
+                    None => std::env::remove_var(NAME),
+                }
+            }
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+        let _restore = RestoreVar(std::env::var_os(NAME));
+        std::env::set_var(NAME, temp_dir.path());
+        let storage = LocalStorage::new("$DIMPLE_LOCAL_STORAGE_TEST_DIR/sync");
+        storage.put("file.txt", b"content").unwrap();
+        assert!(temp_dir.path().join("sync/file.txt").exists());
+    }
+
     #[test]
     fn test_list_with_trailing_slash() {
         let temp_dir = TempDir::new().unwrap();
@@ -129,8 +308,8 @@ mod tests {
         storage.put("dir/file.txt", b"content").unwrap();
         
         // Both "dir" and "dir/" should work the same after normalization
-        let files1 = storage.list("dir").unwrap();
-        let files2 = storage.list("dir/").unwrap();
+        let files1 = storage.list_paths_only("dir").unwrap();
+        let files2 = storage.list_paths_only("dir/").unwrap();
         
         assert_eq!(files1.len(), 1);
         assert_eq!(files2.len(), 1); // Should be the same after fixing trailing slash handling
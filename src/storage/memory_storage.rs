@@ -1,17 +1,101 @@
-use std::{collections::HashMap, sync::{Arc, RwLock}};
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, RwLock}, time::SystemTime};
 
 use anyhow::Result;
 
-use super::SyncStorage;
+use super::{StorageObject, SyncStorage};
 
+#[derive(Clone)]
+struct MemoryObject {
+    content: Vec<u8>,
+    modified: SystemTime,
+}
+
+/// Storage backed by a HashMap in memory, for testing and prototyping.
+/// 
+/// Clones share the same underlying data, so a clone can be handed to a
+/// SyncEngine while the original is kept for inspection. Use fork() to get
+/// an independent copy.
 pub struct InMemoryStorage {
-    data: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    data: Arc<RwLock<HashMap<String, MemoryObject>>>,
+}
+
+/// The exact set of objects in an InMemoryStorage at a point in time. See
+/// InMemoryStorage::snapshot().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InMemoryStorageSnapshot {
+    pub objects: BTreeMap<String, Vec<u8>>,
+}
+
+impl InMemoryStorageSnapshot {
+    pub fn paths(&self) -> Vec<&str> {
+        self.objects.keys().map(String::as_str).collect()
+    }
 }
 
 impl InMemoryStorage {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Create a deep copy of this storage. Unlike clone(), changes to the
+    /// fork are not visible in the original and vice versa.
+    pub fn fork(&self) -> Result<Self> {
+        let data = self
+            .data
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
+        Ok(Self {
+            data: Arc::new(RwLock::new(data.clone())),
+        })
+    }
+
+    /// Record the current contents for later comparison.
+    pub fn snapshot(&self) -> Result<InMemoryStorageSnapshot> {
+        let data = self
+            .data
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
+        Ok(InMemoryStorageSnapshot {
+            objects: data.iter().map(|(k, v)| (k.clone(), v.content.clone())).collect(),
+        })
+    }
+
+    /// Write the contents to a single MessagePack file, as a map from path
+    /// to content, replacing the file only once it's fully written. Load it
+    /// with load().
+    pub fn persist(&self, path: &str) -> Result<()> {
+        let objects: HashMap<String, Vec<u8>> = {
+            let data = self
+                .data
+                .read()
+                .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
+            data.iter().map(|(k, v)| (k.clone(), v.content.clone())).collect()
+        };
+        let temp_path = format!("{}.tmp", path);
+        std::fs::write(&temp_path, rmp_serde::to_vec(&objects)?)?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Read a file written by persist(). Modified times are not persisted,
+    /// so every object is modified as of now.
+    pub fn load(path: &str) -> Result<Self> {
+        let objects: HashMap<String, Vec<u8>> = rmp_serde::from_slice(&std::fs::read(path)?)?;
+        let modified = SystemTime::now();
+        Ok(Self {
+            data: Arc::new(RwLock::new(objects.into_iter()
+                .map(|(path, content)| (path, MemoryObject { content, modified }))
+                .collect())),
+        })
+    }
+
+    /// Like load(), but empty if the file doesn't exist.
+    pub fn load_or_new(path: &str) -> Result<Self> {
+        match std::path::Path::new(path).exists() {
+            true => Self::load(path),
+            false => Ok(Self::new()),
+        }
+    }
 }
 
 impl Default for InMemoryStorage {
@@ -23,7 +107,7 @@ impl Default for InMemoryStorage {
 }
 
 impl SyncStorage for InMemoryStorage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         log::debug!("STORAGE LIST: prefix='{}'", prefix);
         let data = self
             .data
@@ -31,13 +115,17 @@ impl SyncStorage for InMemoryStorage {
             .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
         let mut results = Vec::new();
 
-        for key in data.keys() {
+        for (key, object) in data.iter() {
             if key.starts_with(prefix) {
-                results.push(key.clone());
+                results.push(StorageObject {
+                    path: key.clone(),
+                    size: object.content.len() as u64,
+                    modified: Some(object.modified),
+                });
             }
         }
 
-        results.sort();
+        results.sort_by(|a, b| a.path.cmp(&b.path));
         log::debug!("STORAGE LIST RESULT: {} items", results.len());
         Ok(results)
     }
@@ -50,7 +138,7 @@ impl SyncStorage for InMemoryStorage {
             .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
         let content = data
             .get(path)
-            .cloned()
+            .map(|object| object.content.clone())
             .ok_or_else(|| anyhow::anyhow!("Path not found: {}", path))?;
         log::debug!("STORAGE GET RESULT: {} bytes", content.len());
         Ok(content)
@@ -62,10 +150,47 @@ impl SyncStorage for InMemoryStorage {
             .data
             .write()
             .map_err(|_| anyhow::anyhow!("Failed to acquire write lock"))?;
-        data.insert(path.to_string(), content.to_vec());
+        data.insert(path.to_string(), MemoryObject {
+            content: content.to_vec(),
+            modified: SystemTime::now(),
+        });
         log::debug!("STORAGE PUT RESULT: success");
         Ok(())
     }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes", path, content.len());
+        let mut data = self
+            .data
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire write lock"))?;
+        if data.contains_key(path) {
+            return Ok(false);
+        }
+        data.insert(path.to_string(), MemoryObject {
+            content: content.to_vec(),
+            modified: SystemTime::now(),
+        });
+        Ok(true)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("STORAGE DELETE: path='{}'", path);
+        let mut data = self
+            .data
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire write lock"))?;
+        data.remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let data = self
+            .data
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
+        Ok(data.contains_key(path))
+    }
 }
 
 impl Clone for InMemoryStorage {
@@ -75,3 +200,101 @@ impl Clone for InMemoryStorage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_data() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let clone = storage.clone();
+        clone.put("a", b"1")?;
+        assert_eq!(storage.get("a")?, b"1");
+        Ok(())
+    }
+
+    #[test]
+    fn put_if_not_exists_keeps_existing() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        assert!(storage.put_if_not_exists("a", b"first")?);
+        assert!(!storage.put_if_not_exists("a", b"second")?);
+        assert_eq!(storage.get("a")?, b"first");
+        Ok(())
+    }
+
+    #[test]
+    fn persist_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("storage.msgpack");
+        let path = path.to_str().unwrap();
+        assert!(InMemoryStorage::load(path).is_err());
+        assert!(InMemoryStorage::load_or_new(path)?.list("")?.is_empty());
+
+        let storage = InMemoryStorage::new();
+        storage.put("changes/a", b"1")?;
+        storage.put("changes/b", &[0, 255, 7])?;
+        storage.put("empty", b"")?;
+        storage.persist(path)?;
+        let loaded = InMemoryStorage::load(path)?;
+        assert_eq!(loaded.snapshot()?, storage.snapshot()?);
+
+        loaded.delete("empty")?;
+        loaded.persist(path)?;
+        assert_eq!(InMemoryStorage::load_or_new(path)?.snapshot()?.paths(), vec!["changes/a", "changes/b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_and_exists() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        storage.put("a", b"1")?;
+        assert!(storage.exists("a")?);
+        storage.delete("a")?;
+        assert!(!storage.exists("a")?);
+        storage.delete("a")?;
+        Ok(())
+    }
+
+    #[test]
+    fn list_returns_metadata() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        let before = SystemTime::now();
+        storage.put("dir/a", b"12345")?;
+        storage.put("dir/b", b"")?;
+        storage.put("other", b"1")?;
+        let objects = storage.list("dir/")?;
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].path, "dir/a");
+        assert_eq!(objects[0].size, 5);
+        assert!(objects[0].modified.unwrap() >= before);
+        assert_eq!(objects[1].size, 0);
+        assert_eq!(storage.list_paths_only("dir/")?, vec!["dir/a", "dir/b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn forks_diverge() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        storage.put("a", b"1")?;
+        let fork = storage.fork()?;
+        fork.put("b", b"2")?;
+        storage.put("a", b"changed")?;
+        assert!(storage.get("b").is_err());
+        assert_eq!(fork.get("a")?, b"1");
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_compare_contents() -> Result<()> {
+        let storage = InMemoryStorage::new();
+        storage.put("b", b"2")?;
+        storage.put("a", b"1")?;
+        let before = storage.snapshot()?;
+        assert_eq!(before.paths(), vec!["a", "b"]);
+        assert_eq!(before, storage.snapshot()?);
+        storage.put("c", b"3")?;
+        assert_ne!(before, storage.snapshot()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use anyhow::Result;
+
+use super::{StorageObject, SyncStorage};
+
+/// Totals of the calls made to a MetricsStorage. put_if_not_exists() counts
+/// as a put, and its bytes are only counted if it wrote. Failed calls only
+/// count toward error_count.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageMetrics {
+    pub list_calls: u64,
+    pub get_calls: u64,
+    pub put_calls: u64,
+    pub delete_calls: u64,
+    pub list_duration_ms: u64,
+    pub get_duration_ms: u64,
+    pub put_duration_ms: u64,
+    pub get_bytes: u64,
+    pub put_bytes: u64,
+    pub error_count: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    metrics: StorageMetrics,
+    list_duration: Duration,
+    get_duration: Duration,
+    put_duration: Duration,
+}
+
+/// MetricsStorage passes every call through to another Storage and counts
+/// the calls, bytes transferred and time taken. See
+/// SyncEngineBuilder::with_metrics().
+pub struct MetricsStorage {
+    inner: Box<dyn SyncStorage>,
+    counters: Mutex<Counters>,
+}
+
+impl MetricsStorage {
+    pub fn new(inner: Box<dyn SyncStorage>) -> Self {
+        Self {
+            inner,
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    /// The totals since the storage was created or last reset().
+    pub fn snapshot(&self) -> StorageMetrics {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        StorageMetrics {
+            list_duration_ms: counters.list_duration.as_millis() as u64,
+            get_duration_ms: counters.get_duration.as_millis() as u64,
+            put_duration_ms: counters.put_duration.as_millis() as u64,
+            ..counters.metrics.clone()
+        }
+    }
+
+    pub fn reset(&self) {
+        *self.counters.lock().unwrap_or_else(|e| e.into_inner()) = Counters::default();
+    }
+
+    /// Run the call, then count it with f, and count it as an error if it
+    /// failed.
+    fn measure<T>(&self, call: impl FnOnce() -> Result<T>, f: impl FnOnce(&mut Counters, &T, Duration)) -> Result<T> {
+        let start = Instant::now();
+        let result = call();
+        let elapsed = start.elapsed();
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Ok(value) => f(&mut counters, value, elapsed),
+            Err(_) => counters.metrics.error_count += 1,
+        }
+        result
+    }
+}
+
+impl SyncStorage for MetricsStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        self.measure(|| self.inner.list(prefix), |counters, _, elapsed| {
+            counters.metrics.list_calls += 1;
+            counters.list_duration += elapsed;
+        })
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        self.measure(|| self.inner.get(path), |counters, content, elapsed| {
+            counters.metrics.get_calls += 1;
+            counters.metrics.get_bytes += content.len() as u64;
+            counters.get_duration += elapsed;
+        })
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.measure(|| self.inner.put(path, content), |counters, _, elapsed| {
+            counters.metrics.put_calls += 1;
+            counters.metrics.put_bytes += content.len() as u64;
+            counters.put_duration += elapsed;
+        })
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        self.measure(|| self.inner.put_if_not_exists(path, content), |counters, written, elapsed| {
+            counters.metrics.put_calls += 1;
+            if *written {
+                counters.metrics.put_bytes += content.len() as u64;
+            }
+            counters.put_duration += elapsed;
+        })
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.measure(|| self.inner.delete(path), |counters, _, _| counters.metrics.delete_calls += 1)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.measure(|| self.inner.exists(path), |_, _, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::InMemoryStorage;
+
+    use super::*;
+
+    #[test]
+    fn counts_calls_and_bytes() -> Result<()> {
+        let storage = MetricsStorage::new(Box::new(InMemoryStorage::new()));
+        storage.put("a/1", b"one")?;
+        assert!(!storage.put_if_not_exists("a/1", b"again")?);
+        storage.list("a/")?;
+        storage.get("a/1")?;
+        assert!(storage.get("a/2").is_err());
+        storage.delete("a/1")?;
+
+        let metrics = StorageMetrics { list_duration_ms: 0, get_duration_ms: 0, put_duration_ms: 0, ..storage.snapshot() };
+        assert_eq!(metrics, StorageMetrics {
+            list_calls: 1,
+            get_calls: 1,
+            put_calls: 2,
+            delete_calls: 1,
+            get_bytes: 3,
+            put_bytes: 3,
+            error_count: 1,
+            ..Default::default()
+        });
+        storage.reset();
+        assert_eq!(storage.snapshot(), StorageMetrics::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, thread};
+
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+
+/// A request received by a server from start_mock_server().
+pub(crate) struct MockRequest {
+    pub method: String,
+    /// The path and query, as sent.
+    pub target: String,
+    /// Headers by lowercase name.
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl MockRequest {
+    /// The target without the query.
+    pub fn path(&self) -> &str {
+        self.target.split_once('?').map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    /// The decoded query params.
+    pub fn params(&self) -> BTreeMap<String, String> {
+        self.target.split_once('?').map(|(_, query)| form_params(query)).unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+}
+
+/// A response from a start_mock_server() handler.
+pub(crate) struct MockResponse {
+    pub status: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self { status, headers: Vec::new(), body: body.into() }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Starts a minimal HTTP server on localhost that answers one request per
+/// connection with the handler's response. Returns the server's base url,
+/// such as http://127.0.0.1:1234. The server runs until the test process
+/// exits.
+pub(crate) fn start_mock_server(mut handler: impl FnMut(&MockRequest) -> MockResponse + Send + 'static) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { break };
+            let Ok(request) = read_request(&mut stream) else { continue };
+            let response = handler(&request);
+            let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+            for (name, value) in &response.headers {
+                head += &format!("{}: {}\r\n", name, value);
+            }
+            head += &format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&response.body);
+        }
+    });
+    Ok(endpoint)
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default().to_string(), parts.next().unwrap_or_default().to_string());
+    let mut headers = BTreeMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length = headers.get("content-length").map(|l| l.parse()).transpose()?.unwrap_or(0);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(MockRequest { method, target, headers, body })
+}
+
+/// Parses a query string or form body into decoded params.
+pub(crate) fn form_params(query: &str) -> BTreeMap<String, String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .collect()
+}
+
+/// Percent decodes s, with + as a space.
+pub(crate) fn decode(s: &str) -> String {
+    percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().to_string()
+}
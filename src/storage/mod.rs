@@ -1,13 +1,31 @@
 mod sync_storage;
+mod azure_storage;
 mod encrypted_storage;
+#[cfg(any(test, feature = "test-utils"))]
+mod fault_injecting_storage;
+mod gcs_storage;
 mod local_storage;
 mod memory_storage;
+mod metrics_storage;
+#[cfg(test)]
+mod mock_http;
+mod obfuscated_path_storage;
+mod recording_storage;
+#[cfg(any(test, feature = "test-utils"))]
 mod slow_memory_storage;
 mod s3_storage;
 
-pub use sync_storage::{ArcStorage, SyncStorage};
-pub use encrypted_storage::EncryptedStorage;
+pub use sync_storage::{ArcStorage, StorageHttpError, StorageObject, SyncStorage};
+pub use azure_storage::AzureBlobStorage;
+pub use encrypted_storage::{EncryptedStorage, VerificationReport};
+#[cfg(any(test, feature = "test-utils"))]
+pub use fault_injecting_storage::{FaultInjectingStorage, StorageFault};
+pub use gcs_storage::GcsStorage;
 pub use local_storage::LocalStorage;
-pub use memory_storage::InMemoryStorage;
+pub use memory_storage::{InMemoryStorage, InMemoryStorageSnapshot};
+pub use metrics_storage::{MetricsStorage, StorageMetrics};
+pub use obfuscated_path_storage::ObfuscatedPathStorage;
+pub use recording_storage::{RecordingStorage, StorageOpKind, StorageOperation};
+#[cfg(any(test, feature = "test-utils"))]
 pub use slow_memory_storage::SlowInMemoryStorage;
-pub use s3_storage::S3Storage;
\ No newline at end of file
+pub use s3_storage::{S3Storage, S3StorageBuilder, S3StorageConfig};
\ No newline at end of file
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use openssl::{hash::MessageDigest, pkcs5::pbkdf2_hmac, pkey::PKey, sign::Signer, symm::{self, Cipher}};
+
+use super::{StorageObject, SyncStorage};
+
+const KEY_SALT: &[u8] = b"dimple_db obfuscated paths";
+const KEY_ITERATIONS: usize = 100_000;
+const SIV_LEN: usize = 16;
+
+/// ObfuscatedPathStorage hides the paths of another Storage, so an observer
+/// can't infer the schema from names like dimple-sync/buckets/Artist. Each
+/// segment of a path is encrypted deterministically: the IV is the
+/// HMAC-SHA256 of the segment, which is then encrypted with AES-256-CTR.
+/// Encrypting segments rather than whole paths keeps prefix listing working,
+/// and since the stored paths can be decrypted, list() needs no mapping
+/// table, so every device with the passphrase sees the same plain paths.
+/// Use with EncryptedStorage to also encrypt the content.
+pub struct ObfuscatedPathStorage {
+    inner: Box<dyn SyncStorage>,
+    mac_key: PKey<openssl::pkey::Private>,
+    encryption_key: Vec<u8>,
+}
+
+impl ObfuscatedPathStorage {
+    pub fn new(inner: Box<dyn SyncStorage>, passphrase: &str) -> Result<Self> {
+        let mut keys = [0; 64];
+        pbkdf2_hmac(passphrase.as_bytes(), KEY_SALT, KEY_ITERATIONS, MessageDigest::sha256(), &mut keys)?;
+        Ok(Self {
+            inner,
+            mac_key: PKey::hmac(&keys[..32])?,
+            encryption_key: keys[32..].to_vec(),
+        })
+    }
+
+    fn siv(&self, segment: &str) -> Result<Vec<u8>> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.mac_key)?;
+        signer.update(segment.as_bytes())?;
+        Ok(signer.sign_to_vec()?[..SIV_LEN].to_vec())
+    }
+
+    /// The stored path for a plain path. Empty segments, such as after a
+    /// trailing slash, stay empty.
+    pub fn obfuscate(&self, path: &str) -> Result<String> {
+        let segments = path.split('/')
+            .map(|segment| {
+                if segment.is_empty() {
+                    return Ok(String::new());
+                }
+                let siv = self.siv(segment)?;
+                let ciphertext = symm::encrypt(Cipher::aes_256_ctr(), &self.encryption_key, Some(&siv), segment.as_bytes())?;
+                Ok(URL_SAFE_NO_PAD.encode([siv, ciphertext].concat()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(segments.join("/"))
+    }
+
+    /// The plain path for a stored path, or an error if it wasn't obfuscated
+    /// with this passphrase.
+    pub fn deobfuscate(&self, path: &str) -> Result<String> {
+        let segments = path.split('/')
+            .map(|segment| {
+                if segment.is_empty() {
+                    return Ok(String::new());
+                }
+                let bytes = URL_SAFE_NO_PAD.decode(segment)?;
+                if bytes.len() < SIV_LEN {
+                    return Err(anyhow!("Path segment is too short to be obfuscated: {}", segment));
+                }
+                let (siv, ciphertext) = bytes.split_at(SIV_LEN);
+                let plain = String::from_utf8(symm::decrypt(Cipher::aes_256_ctr(), &self.encryption_key, Some(siv), ciphertext)?)?;
+                if self.siv(&plain)? != siv {
+                    return Err(anyhow!("Path segment wasn't obfuscated with this passphrase: {}", segment));
+                }
+                Ok(plain)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(segments.join("/"))
+    }
+}
+
+impl SyncStorage for ObfuscatedPathStorage {
+    /// Lists the obfuscated directory containing the prefix, since a partial
+    /// segment can't be matched once encrypted, and filters the plain paths.
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        let directory = &prefix[..prefix.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        let objects = self.inner.list(&self.obfuscate(directory)?)?;
+        Ok(objects.into_iter()
+            .filter_map(|object| match self.deobfuscate(&object.path) {
+                Ok(path) => Some(StorageObject { path, ..object }),
+                Err(e) => {
+                    log::warn!("OBFUSCATED STORAGE LIST: skipping {}: {}", object.path, e);
+                    None
+                },
+            })
+            .filter(|object| object.path.starts_with(prefix))
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        self.inner.get(&self.obfuscate(path)?)
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.inner.put(&self.obfuscate(path)?, content)
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        self.inner.put_if_not_exists(&self.obfuscate(path)?, content)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.inner.delete(&self.obfuscate(path)?)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(&self.obfuscate(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::ObfuscatedPathStorage;
+    use crate::storage::{InMemoryStorage, SyncStorage};
+
+    #[test]
+    fn list_returns_plain_paths() -> Result<()> {
+        let inner = InMemoryStorage::new();
+        let storage = ObfuscatedPathStorage::new(Box::new(inner.clone()), "passphrase")?;
+        storage.put("dimple-sync/manifests/a.msgpack", b"a")?;
+        storage.put("dimple-sync/manifests/b.msgpack", b"b")?;
+        storage.put("dimple-sync/batches/c.msgpack", b"c")?;
+
+        let stored = inner.snapshot()?;
+        assert_eq!(stored.objects.len(), 3);
+        for path in stored.paths() {
+            assert!(!path.contains("dimple-sync") && !path.contains("manifests") && !path.contains("msgpack"), "{}", path);
+        }
+
+        let mut paths = storage.list_paths_only("dimple-sync/manifests/")?;
+        paths.sort();
+        assert_eq!(paths, vec!["dimple-sync/manifests/a.msgpack", "dimple-sync/manifests/b.msgpack"]);
+        assert_eq!(storage.list_paths_only("dimple-sync/manifests/b")?, vec!["dimple-sync/manifests/b.msgpack"]);
+        assert_eq!(storage.get("dimple-sync/batches/c.msgpack")?, b"c");
+
+        // Another device with the passphrase sees the same paths, without
+        // any local state
+        let other = ObfuscatedPathStorage::new(Box::new(inner.clone()), "passphrase")?;
+        assert_eq!(other.list_paths_only("dimple-sync/batches/")?, vec!["dimple-sync/batches/c.msgpack"]);
+        let wrong = ObfuscatedPathStorage::new(Box::new(inner), "wrong")?;
+        assert!(wrong.list_paths_only("dimple-sync/batches/")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn obfuscation_is_deterministic_per_segment() -> Result<()> {
+        let storage = ObfuscatedPathStorage::new(Box::new(InMemoryStorage::new()), "passphrase")?;
+        let a = storage.obfuscate("dimple-sync/manifests/a.msgpack")?;
+        let b = storage.obfuscate("dimple-sync/manifests/b.msgpack")?;
+        assert_eq!(a, storage.obfuscate("dimple-sync/manifests/a.msgpack")?);
+        assert_eq!(a.rsplit_once('/').unwrap().0, b.rsplit_once('/').unwrap().0);
+        assert_ne!(a, b);
+        assert!(storage.obfuscate("dimple-sync/manifests/")?.ends_with('/'));
+        assert_eq!(storage.deobfuscate(&a)?, "dimple-sync/manifests/a.msgpack");
+        assert!(storage.deobfuscate("dimple-sync/manifests").is_err());
+        Ok(())
+    }
+}
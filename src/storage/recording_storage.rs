@@ -0,0 +1,127 @@
+use std::{sync::{Arc, Mutex}, time::Instant};
+
+use anyhow::Result;
+
+use super::{StorageObject, SyncStorage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StorageOpKind {
+    List,
+    Get,
+    Put,
+    PutIfNotExists,
+    Delete,
+    Exists,
+}
+
+/// A single call made to a RecordingStorage.
+#[derive(Clone, Debug)]
+pub struct StorageOperation {
+    pub kind: StorageOpKind,
+    /// The path, or the prefix for lists.
+    pub path: String,
+    /// The content read or written, None for lists and failed gets.
+    pub bytes: Option<Vec<u8>>,
+    pub timestamp: Instant,
+}
+
+/// RecordingStorage passes every call through to another Storage and records
+/// it, so that tests can assert on the exact storage access pattern.
+pub struct RecordingStorage {
+    inner: Box<dyn SyncStorage>,
+    operations: Arc<Mutex<Vec<StorageOperation>>>,
+}
+
+impl RecordingStorage {
+    pub fn new(inner: Box<dyn SyncStorage>) -> Self {
+        Self {
+            inner,
+            operations: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The shared list of recorded operations, which outlives the storage.
+    pub fn operations_handle(&self) -> Arc<Mutex<Vec<StorageOperation>>> {
+        self.operations.clone()
+    }
+
+    /// A copy of the operations recorded so far, oldest first.
+    pub fn operations(&self) -> Vec<StorageOperation> {
+        self.operations.lock().map(|ops| ops.clone()).unwrap_or_default()
+    }
+
+    fn record(&self, kind: StorageOpKind, path: &str, bytes: Option<Vec<u8>>) {
+        if let Ok(mut operations) = self.operations.lock() {
+            operations.push(StorageOperation {
+                kind,
+                path: path.to_string(),
+                bytes,
+                timestamp: Instant::now(),
+            });
+        }
+    }
+}
+
+impl SyncStorage for RecordingStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        self.record(StorageOpKind::List, prefix, None);
+        self.inner.list(prefix)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let result = self.inner.get(path);
+        self.record(StorageOpKind::Get, path, result.as_ref().ok().cloned());
+        result
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.record(StorageOpKind::Put, path, Some(content.to_vec()));
+        self.inner.put(path, content)
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        self.record(StorageOpKind::PutIfNotExists, path, Some(content.to_vec()));
+        self.inner.put_if_not_exists(path, content)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.record(StorageOpKind::Delete, path, None);
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.record(StorageOpKind::Exists, path, None);
+        self.inner.exists(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::InMemoryStorage;
+
+    use super::*;
+
+    #[test]
+    fn records_operations_in_order() -> Result<()> {
+        let storage = RecordingStorage::new(Box::new(InMemoryStorage::new()));
+        storage.put("a/1", b"one")?;
+        storage.list("a/")?;
+        storage.get("a/1")?;
+        assert!(storage.get("a/2").is_err());
+
+        let operations = storage.operations();
+        let kinds: Vec<_> = operations.iter().map(|op| (op.kind, op.path.as_str())).collect();
+        assert_eq!(kinds, vec![
+            (StorageOpKind::Put, "a/1"),
+            (StorageOpKind::List, "a/"),
+            (StorageOpKind::Get, "a/1"),
+            (StorageOpKind::Get, "a/2"),
+        ]);
+        assert_eq!(operations[0].bytes.as_deref(), Some(&b"one"[..]));
+        assert_eq!(operations[1].bytes, None);
+        assert_eq!(operations[2].bytes.as_deref(), Some(&b"one"[..]));
+        assert_eq!(operations[3].bytes, None);
+        assert!(operations[0].timestamp <= operations[3].timestamp);
+        Ok(())
+    }
+}
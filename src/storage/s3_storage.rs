@@ -1,10 +1,158 @@
+use std::{collections::HashSet, fmt, thread, time::{Duration, SystemTime}};
+
 use anyhow::Result;
 use s3::{creds::Credentials, Bucket, Region};
 
-use super::SyncStorage;
+use super::{StorageHttpError, StorageObject, SyncStorage};
+
+/// Tuning for S3Storage uploads and listings.
+#[derive(Clone, Debug)]
+pub struct S3StorageConfig {
+    /// Puts larger than this many bytes use a multipart upload.
+    pub multipart_threshold: usize,
+    /// The size of each part of a multipart upload. S3 requires at least
+    /// 5 MB for every part but the last.
+    pub part_size: usize,
+    /// For services where LIST is eventually consistent, list again up to
+    /// this many times, list_retry_delay apart, until a listing finds no
+    /// objects the earlier ones missed. Each list() makes at least one
+    /// extra request when this is not 0.
+    pub list_retries: u32,
+    /// How long to wait between the listings of list_retries.
+    pub list_retry_delay: Duration,
+}
+
+impl Default for S3StorageConfig {
+    fn default() -> Self {
+        Self {
+            multipart_threshold: 8 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+            list_retries: 0,
+            list_retry_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Builder for S3Storage, for S3 compatible services that need more than
+/// S3Storage::new(), such as path style bucket URLs.
+#[derive(Clone, Default)]
+pub struct S3StorageBuilder {
+    endpoint: String,
+    bucket_name: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    path_style: bool,
+    config: S3StorageConfig,
+}
+
+/// Written by hand so that the secret key is not logged.
+impl fmt::Debug for S3StorageBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3StorageBuilder")
+            .field("endpoint", &self.endpoint)
+            .field("bucket_name", &self.bucket_name)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<redacted>")
+            .field("path_style", &self.path_style)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl S3StorageBuilder {
+    pub fn new(endpoint: &str, bucket_name: &str, region: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            bucket_name: bucket_name.to_string(),
+            region: region.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Cloudflare R2, at https://<account_id>.r2.cloudflarestorage.com with
+    /// path style URLs. R2 does not support object versioning.
+    ///
+    /// R2 has been eventually consistent for LIST, so a sync may
+    /// occasionally miss blobs uploaded just before it. They are picked up
+    /// by the next sync, and the listing is retried once by default to make
+    /// that less likely. Set S3StorageConfig::list_retries with
+    /// S3StorageBuilder to change it.
+    pub fn cloudflare_r2(account_id: &str, access_key: &str, secret_key: &str, bucket_name: &str) -> Result<S3Storage> {
+        Self::r2(account_id, bucket_name)
+            .credentials(access_key, secret_key)
+            .build()
+    }
+
+    /// The builder cloudflare_r2() uses, to change its config before
+    /// building.
+    pub fn r2(account_id: &str, bucket_name: &str) -> Self {
+        Self::new(&format!("https://{}.r2.cloudflarestorage.com", account_id), bucket_name, "auto")
+            .path_style(true)
+            .config(S3StorageConfig { list_retries: 1, ..Default::default() })
+    }
+
+    /// Backblaze B2, at https://s3.<region>.backblazeb2.com, where region is
+    /// the bucket's region such as us-west-004, and key_id and
+    /// application_key are a B2 application key, which B2's S3 API accepts
+    /// as the access key and secret key. B2 requires a Content-Type on
+    /// uploads, and S3Storage always sends application/octet-stream.
+    ///
+    /// B2 puts take at least around 10ms, and B2 bills by the transaction,
+    /// so syncing less often is cheaper: each sync writes its changes as a
+    /// single batch, however many there are.
+    pub fn backblaze_b2(key_id: &str, application_key: &str, bucket_name: &str, region: &str) -> Result<S3Storage> {
+        Self::b2(region, bucket_name)
+            .credentials(key_id, application_key)
+            .build()
+    }
+
+    /// The builder backblaze_b2() uses, to change its config before
+    /// building.
+    pub fn b2(region: &str, bucket_name: &str) -> Self {
+        Self::new(&format!("https://s3.{}.backblazeb2.com", region), bucket_name, region)
+            .path_style(true)
+    }
+
+    /// Replace the endpoint, such as to use a proxy or a local mock.
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
+    pub fn credentials(mut self, access_key: &str, secret_key: &str) -> Self {
+        self.access_key = access_key.to_string();
+        self.secret_key = secret_key.to_string();
+        self
+    }
+
+    /// Address the bucket as endpoint/bucket rather than as a subdomain of
+    /// the endpoint.
+    pub fn path_style(mut self, path_style: bool) -> Self {
+        self.path_style = path_style;
+        self
+    }
+
+    pub fn config(mut self, config: S3StorageConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<S3Storage> {
+        let storage = S3Storage::new(&self.endpoint, &self.bucket_name, &self.region,
+            &self.access_key, &self.secret_key)?;
+        let bucket = match self.path_style {
+            true => storage.bucket.with_path_style(),
+            false => storage.bucket,
+        };
+        Ok(S3Storage { bucket, config: self.config })
+    }
+}
 
 pub struct S3Storage {
     bucket: Bucket,
+    config: S3StorageConfig,
 }
 
 impl S3Storage {
@@ -21,26 +169,83 @@ impl S3Storage {
             endpoint: endpoint.to_string(),
         };
         let bucket = Bucket::new(bucket_name, region, credentials)?;
-        Ok(Self { bucket })
+        Ok(Self { bucket, config: S3StorageConfig::default() })
     }
-}
 
-impl SyncStorage for S3Storage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
-        log::debug!("STORAGE LIST: prefix='{}'", prefix);
+    pub fn with_config(mut self, config: S3StorageConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn list_once(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         let results = self
             .bucket
             .list(prefix.to_string(), Some("/".to_string()))?;
-        let mut keys = Vec::new();
+        let mut objects = Vec::new();
 
         for list_bucket_result in results {
             for object in list_bucket_result.contents {
-                keys.push(object.key);
+                objects.push(StorageObject {
+                    modified: parse_last_modified(&object.last_modified),
+                    path: object.key,
+                    size: object.size,
+                });
             }
         }
+        Ok(objects)
+    }
 
-        log::debug!("STORAGE LIST RESULT: {} items", keys.len());
-        Ok(keys)
+    /// Uploads content in parts of config.part_size, aborting the upload if
+    /// any part or the completion fails so no orphaned parts are billed.
+    fn put_multipart(&self, path: &str, content: &[u8]) -> Result<()> {
+        let content_type = "application/octet-stream";
+        let upload = self.bucket.initiate_multipart_upload(path, content_type)?;
+        log::debug!("STORAGE PUT MULTIPART: path='{}', upload_id='{}'", path, upload.upload_id);
+        let result = (|| -> Result<()> {
+            let mut parts = Vec::new();
+            for (i, chunk) in content.chunks(self.config.part_size.max(1)).enumerate() {
+                let part_number = i as u32 + 1;
+                parts.push(self.bucket.put_multipart_chunk(chunk.to_vec(), path,
+                    part_number, &upload.upload_id, content_type)?);
+            }
+            let response = self.bucket.complete_multipart_upload(path, &upload.upload_id, parts)?;
+            // S3 can report a failed completion with a 200 and an Error body
+            if !(200..300).contains(&response.status_code())
+                || String::from_utf8_lossy(response.as_slice()).contains("<Error>") {
+                return Err(StorageHttpError::new(response.status_code(), format!(
+                    "S3 returned error status {} completing multipart upload for path: {}", response.status_code(), path)).into());
+            }
+            Ok(())
+        })();
+        if let Err(e) = &result {
+            log::error!("STORAGE PUT MULTIPART ERROR: path='{}': {}", path, e);
+            if let Err(e) = self.bucket.abort_upload(path, &upload.upload_id) {
+                log::warn!("STORAGE PUT MULTIPART: failed to abort upload '{}': {}", upload.upload_id, e);
+            }
+        }
+        result
+    }
+}
+
+impl SyncStorage for S3Storage {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        log::debug!("STORAGE LIST: prefix='{}'", prefix);
+        let mut objects = self.list_once(prefix)?;
+        for _ in 0..self.config.list_retries {
+            thread::sleep(self.config.list_retry_delay);
+            let paths = objects.iter().map(|object| object.path.clone()).collect::<HashSet<_>>();
+            let missed = self.list_once(prefix)?.into_iter()
+                .filter(|object| !paths.contains(&object.path))
+                .collect::<Vec<_>>();
+            if missed.is_empty() {
+                break;
+            }
+            log::debug!("STORAGE LIST RETRY: found {} more items", missed.len());
+            objects.extend(missed);
+        }
+
+        log::debug!("STORAGE LIST RESULT: {} items", objects.len());
+        Ok(objects)
     }
 
     fn get(&self, path: &str) -> Result<Vec<u8>> {
@@ -50,13 +255,14 @@ impl SyncStorage for S3Storage {
         // Check response status
         if response.status_code() != 200 {
             log::error!("STORAGE GET ERROR: S3 returned status {} for path '{}'", response.status_code(), path);
-            return Err(anyhow::anyhow!("S3 returned error status {} for path: {}", response.status_code(), path));
+            return Err(StorageHttpError::new(response.status_code(), format!(
+                "S3 returned error status {} for path: {}", response.status_code(), path)).into());
         }
         
         let bytes = response.bytes().to_vec();
         
         // Check if we got an HTML error page instead of actual content
-        if bytes.len() > 0 && bytes[0] == b'<' {
+        if !bytes.is_empty() && bytes[0] == b'<' {
             log::error!("STORAGE GET ERROR: Received HTML response (likely an error page) for path '{}'. Content starts with: {:?}", 
                        path, String::from_utf8_lossy(&bytes[..std::cmp::min(100, bytes.len())]));
             return Err(anyhow::anyhow!("S3 returned HTML error page instead of file content for path: {}", path));
@@ -75,10 +281,73 @@ impl SyncStorage for S3Storage {
 
     fn put(&self, path: &str, content: &[u8]) -> Result<()> {
         log::debug!("STORAGE PUT: path='{}', size={} bytes", path, content.len());
-        self.bucket.put_object(path, content)?;
+        if content.len() > self.config.multipart_threshold {
+            return self.put_multipart(path, content);
+        }
+        let response = self.bucket.put_object(path, content)?;
+        if !(200..300).contains(&response.status_code()) {
+            return Err(StorageHttpError::new(response.status_code(), format!(
+                "S3 returned error status {} for path: {}", response.status_code(), path)).into());
+        }
         log::debug!("STORAGE PUT RESULT: success");
         Ok(())
     }
+
+    /// Uses a conditional PUT with If-None-Match: *, which S3 rejects with
+    /// 412 Precondition Failed if the object exists.
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes", path, content.len());
+        let mut bucket = self.bucket.clone();
+        bucket.add_header("If-None-Match", "*");
+        let response = bucket.put_object(path, content)?;
+        match response.status_code() {
+            200..=299 => Ok(true),
+            412 => Ok(false),
+            status => Err(StorageHttpError::new(status, format!("S3 returned error status {} for path: {}", status, path)).into()),
+        }
+    }
+
+    /// S3 returns 204 No Content whether or not the object existed.
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("STORAGE DELETE: path='{}'", path);
+        let response = self.bucket.delete_object(path)?;
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(StorageHttpError::new(status, format!("S3 returned error status {} deleting path: {}", status, path)).into()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let (_, status) = self.bucket.head_object(path)?;
+        match status {
+            200..=299 => Ok(true),
+            404 => Ok(false),
+            status => Err(StorageHttpError::new(status, format!("S3 returned error status {} for path: {}", status, path)).into()),
+        }
+    }
+}
+
+/// Parse an ISO 8601 UTC timestamp as used by ListObjects and GCS, such as
+/// 2009-10-12T17:50:30.000Z.
+pub(super) fn parse_last_modified(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.trim_end_matches('Z').split_once('T')?;
+    let mut date_parts = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date_parts.next()??, date_parts.next()??, date_parts.next()??);
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time_parts = hms.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (time_parts.next()??, time_parts.next()??, time_parts.next()??);
+    let millis = format!("{:0<3}", fraction).get(..3)?.parse::<u64>().ok()?;
+
+    // Days since the epoch for a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?) + Duration::from_millis(millis))
 }
 
 #[cfg(test)]
@@ -86,6 +355,160 @@ mod tests {
     use super::*;
     use std::env;
 
+    #[test]
+    fn builder_debug_redacts_secret_key() {
+        let builder = S3StorageBuilder::new("https://s3.example.com", "bucket", "us-east-1")
+            .credentials("access_key", "super secret");
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("access_key"), "{}", debug);
+        assert!(!debug.contains("super secret"), "{}", debug);
+    }
+
+    #[test]
+    fn test_parse_last_modified() {
+        let parsed = parse_last_modified("2009-10-12T17:50:30.000Z").unwrap();
+        assert_eq!(parsed.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(), 1255369830);
+        let parsed = parse_last_modified("2024-02-29T00:00:00.5Z").unwrap();
+        assert_eq!(parsed.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(), 1709164800500);
+        assert_eq!(parse_last_modified("1970-01-01T00:00:00Z"), Some(SystemTime::UNIX_EPOCH));
+        assert!(parse_last_modified("yesterday").is_none());
+    }
+
+    type Requests = std::sync::Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// A minimal S3 server, handling one request per connection and
+    /// recording each request as "METHOD target". Uploading the part
+    /// numbered fail_part returns a 500. Returns the endpoint.
+    fn mock_s3_server(fail_part: Option<u32>) -> Result<(String, Requests)> {
+        use crate::storage::mock_http::{start_mock_server, MockResponse};
+
+        let requests = Requests::default();
+        let server_requests = requests.clone();
+        let endpoint = start_mock_server(move |request| {
+            let (method, target) = (request.method.as_str(), request.target.as_str());
+            server_requests.lock().unwrap().push(format!("{} {}", method, target));
+
+            let part_number = request.params().get("partNumber").and_then(|n| n.parse::<u32>().ok());
+            let lists = server_requests.lock().unwrap().iter().filter(|r| r.contains("list-type=")).count();
+            match (method, part_number) {
+                // Each listing includes one more object, up to two, as
+                // an eventually consistent LIST might
+                ("GET", _) if target.contains("list-type=") => MockResponse::new("200 OK", format!(
+                    "<ListBucketResult><Name>bucket</Name><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                    (1..=lists.min(2)).map(|i| format!(
+                        "<Contents><Key>changes/{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified><Size>{}</Size></Contents>", i, i))
+                        .collect::<String>())),
+                ("POST", _) if target.ends_with("?uploads") => MockResponse::new("200 OK",
+                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>big</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>"),
+                ("PUT", Some(n)) if Some(n) == fail_part => MockResponse::new("500 Internal Server Error",
+                    "<Error><Code>InternalError</Code></Error>"),
+                ("PUT", Some(n)) => MockResponse::new("200 OK", "").with_header("ETag", &format!("\"etag-{}\"", n)),
+                ("POST", _) => MockResponse::new("200 OK",
+                    "<CompleteMultipartUploadResult><Key>big</Key><ETag>\"done\"</ETag></CompleteMultipartUploadResult>"),
+                ("DELETE", _) => MockResponse::new("204 No Content", ""),
+                ("GET", _) => MockResponse::new("200 OK", format!("contents of {}", target)),
+                _ => MockResponse::new("200 OK", ""),
+            }
+        })?;
+
+        Ok((endpoint, requests))
+    }
+
+    /// An S3Storage using mock_s3_server(), with small multipart sizes.
+    fn mock_s3(fail_part: Option<u32>) -> Result<(S3Storage, Requests)> {
+        let (endpoint, requests) = mock_s3_server(fail_part)?;
+        let credentials = Credentials::new(Some("access"), Some("secret"), None, None, None)?;
+        let region = Region::Custom { region: "us-east-1".to_string(), endpoint };
+        let storage = S3Storage {
+            bucket: Bucket::new("bucket", region, credentials)?.with_path_style(),
+            config: S3StorageConfig::default(),
+        }.with_config(S3StorageConfig { multipart_threshold: 10, part_size: 8, ..Default::default() });
+        Ok((storage, requests))
+    }
+
+    #[test]
+    fn test_multipart_upload() -> Result<()> {
+        let (storage, requests) = mock_s3(None)?;
+        storage.put("big", &[7; 20])?;
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests, vec![
+            "POST /bucket/big?uploads",
+            "PUT /bucket/big?partNumber=1&uploadId=upload-1",
+            "PUT /bucket/big?partNumber=2&uploadId=upload-1",
+            "PUT /bucket/big?partNumber=3&uploadId=upload-1",
+            "POST /bucket/big?uploadId=upload-1",
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multipart_upload_aborts_on_part_failure() -> Result<()> {
+        let (storage, requests) = mock_s3(Some(2))?;
+        assert!(storage.put("big", &[7; 20]).is_err());
+        let requests = requests.lock().unwrap().clone();
+        assert!(requests.iter().any(|r| r == "DELETE /bucket/big?uploadId=upload-1"), "{:?}", requests);
+        assert!(!requests.iter().any(|r| r == "POST /bucket/big?uploadId=upload-1"), "{:?}", requests);
+        assert!(!requests.iter().any(|r| r.contains("partNumber=3")), "{:?}", requests);
+        Ok(())
+    }
+
+    #[test]
+    fn test_small_put_is_single_request() -> Result<()> {
+        let (storage, requests) = mock_s3(None)?;
+        storage.put("small", &[7; 10])?;
+        assert_eq!(*requests.lock().unwrap(), vec!["PUT /bucket/small"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_retries_until_nothing_new() -> Result<()> {
+        let (storage, requests) = mock_s3(None)?;
+        assert_eq!(storage.list("changes/")?.len(), 1);
+
+        let (storage, requests_with_retries) = mock_s3(None)?;
+        let storage = storage.with_config(S3StorageConfig { list_retries: 5, list_retry_delay: Duration::ZERO, ..Default::default() });
+        let paths = storage.list("changes/")?.into_iter().map(|object| object.path).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["changes/1", "changes/2"]);
+        // The third listing found nothing new
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(requests_with_retries.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cloudflare_r2() -> Result<()> {
+        let storage = S3StorageBuilder::cloudflare_r2("account", "access", "secret", "bucket")?;
+        assert_eq!(storage.bucket.url(), "https://account.r2.cloudflarestorage.com/bucket");
+        assert_eq!(storage.bucket.region().to_string(), "auto");
+        assert_eq!(storage.config.list_retries, 1);
+
+        let storage = S3StorageBuilder::new("https://s3.example.com", "bucket", "us-east-1").build()?;
+        assert_eq!(storage.bucket.url(), "https://bucket.s3.example.com");
+        Ok(())
+    }
+
+    #[test]
+    fn test_backblaze_b2() -> Result<()> {
+        let storage = S3StorageBuilder::backblaze_b2("key-id", "application-key", "bucket", "us-west-004")?;
+        assert_eq!(storage.bucket.url(), "https://s3.us-west-004.backblazeb2.com/bucket");
+        assert_eq!(storage.bucket.region().to_string(), "us-west-004");
+
+        let (endpoint, requests) = mock_s3_server(None)?;
+        let storage = S3StorageBuilder::b2("us-west-004", "bucket")
+            .endpoint(&endpoint)
+            .credentials("key-id", "application-key")
+            .build()?;
+        let objects = storage.list("changes/")?;
+        assert_eq!(objects.len(), 1);
+        assert_eq!((objects[0].path.as_str(), objects[0].size), ("changes/1", 1));
+        assert_eq!(storage.get(&objects[0].path)?, b"contents of /bucket/changes/1");
+        assert_eq!(*requests.lock().unwrap(), vec![
+            "GET /bucket/?delimiter=%2F&prefix=changes%2F&list-type=2",
+            "GET /bucket/changes/1",
+        ]);
+        Ok(())
+    }
+
     // Helper function to get test credentials from environment
     fn get_test_config() -> Option<(String, String, String, String, String, String)> {
         let endpoint = env::var("DIMPLE_TEST_S3_ENDPOINT").ok()?;
@@ -142,7 +565,7 @@ mod tests {
         storage.put(&format!("{}file3.txt", test_prefix), b"content3")?;
 
         // List files with prefix
-        let files = storage.list(&test_prefix)?;
+        let files = storage.list_paths_only(&test_prefix)?;
 
         // Should find our test files
         println!("Found {} files: {:?}", files.len(), files);
@@ -1,24 +1,38 @@
-use std::{collections::HashMap, sync::{Arc, RwLock}, time::Duration};
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use anyhow::Result;
 
-use super::SyncStorage;
+use super::{InMemoryStorage, StorageObject, SyncStorage};
 
-/// In-memory storage with artificial delays to simulate high-latency storage like S3
+/// In-memory storage with artificial delays to simulate high-latency storage
+/// like S3, and optionally random failures. Counts every operation. Clones
+/// share their data and counts.
 pub struct SlowInMemoryStorage {
-    data: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    data: InMemoryStorage,
     get_delay_ms: u64,
     put_delay_ms: u64,
     list_delay_ms: u64,
+    /// The f64 bits of the failure rate, shared so it can be changed
+    /// through any clone.
+    failure_rate: Arc<AtomicU64>,
+    /// xorshift state for deciding failures
+    rng: Arc<AtomicU64>,
+    operation_count: Arc<AtomicU64>,
 }
 
 impl SlowInMemoryStorage {
     pub fn new(get_delay_ms: u64, put_delay_ms: u64, list_delay_ms: u64) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
         Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            data: InMemoryStorage::new(),
             get_delay_ms,
             put_delay_ms,
             list_delay_ms,
+            failure_rate: Arc::new(AtomicU64::new(0.0f64.to_bits())),
+            rng: Arc::new(AtomicU64::new(seed | 1)),
+            operation_count: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -26,6 +40,59 @@ impl SlowInMemoryStorage {
     pub fn s3_like() -> Self {
         Self::new(25, 250, 50) // GET: 25ms, PUT: 250ms, LIST: 50ms
     }
+
+    /// Create a storage without delays where each operation fails with the
+    /// given probability, from 0.0 to 1.0.
+    pub fn new_with_failures(failure_rate: f64) -> Self {
+        let storage = Self::new(0, 0, 0);
+        storage.set_failure_rate(failure_rate);
+        storage
+    }
+
+    /// Change the failure rate of this storage and all of its clones, e.g.
+    /// to simulate storage going offline and coming back.
+    pub fn set_failure_rate(&self, failure_rate: f64) {
+        self.failure_rate.store(failure_rate.to_bits(), Ordering::SeqCst);
+    }
+
+    /// Create a storage without delays, for counting operations with
+    /// operation_count().
+    pub fn new_counting() -> Self {
+        Self::new(0, 0, 0)
+    }
+
+    /// The number of list, get and put calls made so far, including failed
+    /// ones.
+    pub fn operation_count(&self) -> u64 {
+        self.operation_count.load(Ordering::SeqCst)
+    }
+
+    /// Counts the operation, sleeps, and then decides if it fails.
+    fn operation(&self, name: &str, path: &str, delay_ms: u64) -> Result<()> {
+        self.operation_count.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(delay_ms));
+        // An io::Error, like a real network failure would be
+        let failure_rate = f64::from_bits(self.failure_rate.load(Ordering::SeqCst));
+        if failure_rate > 0.0 && self.next_random() < failure_rate {
+            return Err(std::io::Error::other(format!("Simulated {} failure for path: {}", name, path)).into());
+        }
+        Ok(())
+    }
+
+    /// A uniformly distributed value in [0, 1).
+    fn next_random(&self) -> f64 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        loop {
+            let mut next = x;
+            next ^= next << 13;
+            next ^= next >> 7;
+            next ^= next << 17;
+            match self.rng.compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return (next >> 11) as f64 / (1u64 << 53) as f64,
+                Err(current) => x = current,
+            }
+        }
+    }
 }
 
 impl Default for SlowInMemoryStorage {
@@ -35,54 +102,40 @@ impl Default for SlowInMemoryStorage {
 }
 
 impl SyncStorage for SlowInMemoryStorage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         log::debug!("SLOW STORAGE LIST: prefix='{}' (delay: {}ms)", prefix, self.list_delay_ms);
-        std::thread::sleep(Duration::from_millis(self.list_delay_ms));
-        
-        let data = self
-            .data
-            .read()
-            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
-        let mut results = Vec::new();
-
-        for key in data.keys() {
-            if key.starts_with(prefix) {
-                results.push(key.clone());
-            }
-        }
-
-        results.sort();
-        log::debug!("SLOW STORAGE LIST RESULT: {} items", results.len());
-        Ok(results)
+        self.operation("list", prefix, self.list_delay_ms)?;
+        self.data.list(prefix)
     }
 
     fn get(&self, path: &str) -> Result<Vec<u8>> {
         log::debug!("SLOW STORAGE GET: path='{}' (delay: {}ms)", path, self.get_delay_ms);
-        std::thread::sleep(Duration::from_millis(self.get_delay_ms));
-        
-        let data = self
-            .data
-            .read()
-            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock"))?;
-        let content = data
-            .get(path)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Path not found: {}", path))?;
-        log::debug!("SLOW STORAGE GET RESULT: {} bytes", content.len());
-        Ok(content)
+        self.operation("get", path, self.get_delay_ms)?;
+        self.data.get(path)
     }
 
     fn put(&self, path: &str, content: &[u8]) -> Result<()> {
         log::debug!("SLOW STORAGE PUT: path='{}', size={} bytes (delay: {}ms)", path, content.len(), self.put_delay_ms);
-        std::thread::sleep(Duration::from_millis(self.put_delay_ms));
-        
-        let mut data = self
-            .data
-            .write()
-            .map_err(|_| anyhow::anyhow!("Failed to acquire write lock"))?;
-        data.insert(path.to_string(), content.to_vec());
-        log::debug!("SLOW STORAGE PUT RESULT: success");
-        Ok(())
+        self.operation("put", path, self.put_delay_ms)?;
+        self.data.put(path, content)
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        log::debug!("SLOW STORAGE PUT IF NOT EXISTS: path='{}', size={} bytes (delay: {}ms)", path, content.len(), self.put_delay_ms);
+        self.operation("put", path, self.put_delay_ms)?;
+        self.data.put_if_not_exists(path, content)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        log::debug!("SLOW STORAGE DELETE: path='{}' (delay: {}ms)", path, self.put_delay_ms);
+        self.operation("delete", path, self.put_delay_ms)?;
+        self.data.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        log::debug!("SLOW STORAGE EXISTS: path='{}' (delay: {}ms)", path, self.get_delay_ms);
+        self.operation("exists", path, self.get_delay_ms)?;
+        self.data.exists(path)
     }
 }
 
@@ -93,6 +146,35 @@ impl Clone for SlowInMemoryStorage {
             get_delay_ms: self.get_delay_ms,
             put_delay_ms: self.put_delay_ms,
             list_delay_ms: self.list_delay_ms,
+            failure_rate: self.failure_rate.clone(),
+            rng: self.rng.clone(),
+            operation_count: self.operation_count.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_operations() -> Result<()> {
+        let storage = SlowInMemoryStorage::new_counting();
+        storage.put("a", b"1")?;
+        storage.get("a")?;
+        storage.list("")?;
+        assert!(storage.get("missing").is_err());
+        assert_eq!(storage.clone().operation_count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_at_the_given_rate() {
+        assert!(SlowInMemoryStorage::new_with_failures(1.0).put("a", b"1").is_err());
+        assert!(SlowInMemoryStorage::new_with_failures(0.0).put("a", b"1").is_ok());
+
+        let storage = SlowInMemoryStorage::new_with_failures(0.5);
+        let failures = (0..1000).filter(|_| storage.put("a", b"1").is_err()).count();
+        assert!((300..700).contains(&failures), "{} failures", failures);
+    }
+}
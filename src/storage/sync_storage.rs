@@ -1,11 +1,80 @@
-use std::sync::{Arc};
+use std::{fmt, sync::Arc, time::SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+/// An error status returned by an HTTP storage service, such as S3, so sync
+/// can tell failures worth retrying from permanent ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageHttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl StorageHttpError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+
+    /// True for server errors and 429 Too Many Requests, which may succeed
+    /// on a later attempt.
+    pub fn is_transient(&self) -> bool {
+        self.status >= 500 || self.status == 429
+    }
+}
+
+impl fmt::Display for StorageHttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StorageHttpError {}
+
+/// An object returned by SyncStorage::list().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageObject {
+    pub path: String,
+    /// Size in bytes as stored.
+    pub size: u64,
+    /// Last modified time, if the storage tracks it.
+    pub modified: Option<SystemTime>,
+}
 
 pub trait SyncStorage: Sync + Send {
-    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>>;
     fn get(&self, path: &str) -> Result<Vec<u8>>;
     fn put(&self, path: &str, content: &[u8]) -> Result<()>;
+
+    /// Deletes the object at the path. Like S3, deleting a path that
+    /// doesn't exist succeeds, so a failed cleanup can simply be retried.
+    /// The default implementation returns an error, for storage that can't
+    /// delete. Only compaction needs it.
+    fn delete(&self, path: &str) -> Result<()> {
+        Err(anyhow!("delete is not supported by this storage: {}", path))
+    }
+
+    /// True if an object exists at the path. The default implementation
+    /// lists the path as a prefix and looks for an exact match.
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.list(path)?.iter().any(|object| object.path == path))
+    }
+
+    /// Puts the content only if nothing exists at the path, returning true if
+    /// it was written. The default implementation checks with get() first,
+    /// so it is not atomic, implementations should override it when the
+    /// underlying storage supports conditional writes.
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        if self.get(path).is_ok() {
+            return Ok(false);
+        }
+        self.put(path, content)?;
+        Ok(true)
+    }
+
+    /// Like list(), returning only the paths.
+    fn list_paths_only(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self.list(prefix)?.into_iter().map(|object| object.path).collect())
+    }
 }
 
 // SyncStorage trait wrapper to allow Arc<dyn SyncStorage> to implement SyncStorage
@@ -21,7 +90,7 @@ impl ArcStorage {
 }
 
 impl SyncStorage for ArcStorage {
-    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
         self.inner.list(prefix)
     }
 
@@ -32,5 +101,47 @@ impl SyncStorage for ArcStorage {
     fn put(&self, path: &str, content: &[u8]) -> Result<()> {
         self.inner.put(path, content)
     }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path)
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        self.inner.put_if_not_exists(path, content)
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{StorageObject, SyncStorage};
+
+    /// Storage that only implements the required methods.
+    struct ReadOnlyStorage;
+
+    impl SyncStorage for ReadOnlyStorage {
+        fn list(&self, _prefix: &str) -> Result<Vec<StorageObject>> {
+            Ok(vec![])
+        }
+
+        fn get(&self, path: &str) -> Result<Vec<u8>> {
+            Err(anyhow::anyhow!("not found: {}", path))
+        }
+
+        fn put(&self, _path: &str, _content: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delete_is_unsupported_by_default() {
+        let error = ReadOnlyStorage.delete("changes/a.msgpack").unwrap_err();
+        assert!(error.to_string().contains("not supported"), "{}", error);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, mpsc::{channel, RecvTimeoutError, Sender}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use anyhow::Result;
+
+use crate::{db::DimpleError, sync::SyncEngine, Db};
+
+/// Metadata key holding the time of the last successful background sync, in
+/// milliseconds since the epoch. See Db::get_metadata().
+pub const LAST_SYNC_AT_KEY: &str = "last_sync_at";
+
+/// Handle to the thread started by SyncEngine::sync_when_available() or
+/// SyncEngine::start_background_sync(). The handle can be dropped to detach
+/// the thread, which then keeps syncing until it gives up, or explicitly
+/// stopped or joined.
+pub struct BackgroundSync {
+    stop_signal: Sender<()>,
+    thread_handle: JoinHandle<()>,
+    last_result: Arc<Mutex<Option<Result<(), String>>>>,
+    sync_count: Arc<AtomicUsize>,
+}
+
+impl BackgroundSync {
+    /// Signal the thread to stop after the current sync, if any, and wait
+    /// for it to finish.
+    pub fn stop(self) {
+        let _ = self.stop_signal.send(());
+        let _ = self.thread_handle.join();
+    }
+
+    /// Wait for the thread to give up, without signalling it to stop.
+    pub fn join(self) {
+        let _ = self.thread_handle.join();
+    }
+
+    /// True once the thread has stopped or given up.
+    pub fn is_finished(&self) -> bool {
+        self.thread_handle.is_finished()
+    }
+
+    /// The result of the most recent sync, with the error's message if it
+    /// failed, or None before the first sync finishes.
+    pub fn last_result(&self) -> Option<Result<(), String>> {
+        self.last_result.lock().ok().and_then(|last_result| last_result.clone())
+    }
+
+    /// The number of syncs finished, successful or not.
+    pub fn sync_count(&self) -> usize {
+        self.sync_count.load(Ordering::SeqCst)
+    }
+}
+
+impl SyncEngine {
+    /// Start a thread that syncs immediately and then every retry_interval,
+    /// recording each success under LAST_SYNC_AT_KEY in the db's metadata.
+    /// Changes made while storage is unreachable stay in the local changelog
+    /// and are pushed by the first sync that succeeds.
+    ///
+    /// Network and IO errors, and HTTP 5xx and 429 responses from storage,
+    /// are treated as transient and retried, giving up after max_retries
+    /// consecutive failures if given. Any other error,
+    /// such as a wrong passphrase, is permanent and the thread gives up
+    /// immediately.
+    pub fn sync_when_available(self: &Arc<Self>, db: &Db, retry_interval: Duration,
+            max_retries: Option<usize>) -> Result<BackgroundSync> {
+        let mut failures = 0;
+        spawn_sync_thread(self.clone(), db.clone(), retry_interval, move |result| match result {
+            Ok(()) => {
+                failures = 0;
+                true
+            },
+            Err(e) if is_transient(e) => {
+                failures += 1;
+                log::warn!("Sync: Storage unavailable, attempt {} failed: {:#}", failures, e);
+                if max_retries.is_some_and(|max_retries| failures > max_retries) {
+                    log::error!("Sync: Giving up after {} attempts.", failures);
+                    return false;
+                }
+                true
+            },
+            Err(e) => {
+                log::error!("Sync: Giving up on permanent error: {:#}", e);
+                false
+            },
+        })
+    }
+
+    /// Start a thread that syncs immediately and then every interval until
+    /// stopped, whether or not the syncs succeed. Each success is recorded
+    /// under LAST_SYNC_AT_KEY in the db's metadata. Use
+    /// sync_when_available() instead to give up on permanent errors.
+    pub fn start_background_sync(self: &Arc<Self>, db: &Db, interval: Duration) -> Result<BackgroundSync> {
+        spawn_sync_thread(self.clone(), db.clone(), interval, |result| {
+            if let Err(e) = result {
+                log::warn!("Sync: Background sync failed: {:#}", e);
+            }
+            true
+        })
+    }
+}
+
+/// Sync immediately and then every interval, until keep_going returns false
+/// for a result or the handle is stopped. Dropping the handle detaches the
+/// thread.
+fn spawn_sync_thread(engine: Arc<SyncEngine>, db: Db, interval: Duration,
+        mut keep_going: impl FnMut(&Result<(), DimpleError>) -> bool + Send + 'static) -> Result<BackgroundSync> {
+    let (stop_tx, stop_rx) = channel::<()>();
+    let last_result = Arc::new(Mutex::new(None));
+    let sync_count = Arc::new(AtomicUsize::new(0));
+    let thread_last_result = last_result.clone();
+    let thread_sync_count = sync_count.clone();
+    let thread_handle = thread::Builder::new()
+        .name("dimple-sync".to_string())
+        .spawn(move || loop {
+            let result = engine.sync(&db);
+            if result.is_ok() {
+                if let Err(e) = record_sync_time(&db) {
+                    log::warn!("Sync: Failed to record sync time: {}", e);
+                }
+            }
+            let keep_going = keep_going(&result);
+            if let Ok(mut last_result) = thread_last_result.lock() {
+                *last_result = Some(result.map_err(|e| e.to_string()));
+            }
+            thread_sync_count.fetch_add(1, Ordering::SeqCst);
+            if !keep_going {
+                break;
+            }
+            // recv_timeout() returns Disconnected immediately once the
+            // handle is dropped, so sleep instead to keep the interval
+            match stop_rx.recv_timeout(interval) {
+                Ok(()) => break,
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(interval),
+            }
+        })?;
+    Ok(BackgroundSync {
+        stop_signal: stop_tx,
+        thread_handle,
+        last_result,
+        sync_count,
+    })
+}
+
+fn record_sync_time(db: &Db) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    db.set_metadata(LAST_SYNC_AT_KEY, &now.to_string())
+}
+
+/// True if the error was caused by the network, IO or a transient HTTP
+/// status, and so may succeed on a later attempt. See DimpleError::from_sync().
+fn is_transient(error: &DimpleError) -> bool {
+    matches!(error, DimpleError::StorageError(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::{Duration, Instant}};
+
+    use anyhow::Result;
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{storage::{SlowInMemoryStorage, SyncStorage}, sync::{SyncEngine, LAST_SYNC_AT_KEY}, Db};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    fn setup_db() -> Result<Db> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        Ok(db)
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while !condition() {
+            if start.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn syncs_once_storage_is_available() -> Result<()> {
+        let db = setup_db()?;
+        let storage = SlowInMemoryStorage::new_with_failures(1.0);
+        let engine = Arc::new(SyncEngine::builder().storage(Box::new(storage.clone())).build()?);
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+
+        let background = engine.sync_when_available(&db, Duration::from_millis(10), None)?;
+        assert!(wait_for(|| storage.operation_count() >= 3));
+        assert_eq!(db.get_metadata(LAST_SYNC_AT_KEY)?, None);
+
+        storage.set_failure_rate(0.0);
+        assert!(wait_for(|| db.get_metadata(LAST_SYNC_AT_KEY).unwrap().is_some()));
+        background.stop();
+
+        let db2 = setup_db()?;
+        SyncEngine::builder().storage(Box::new(storage)).build()?.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn background_sync_runs_on_interval() -> Result<()> {
+        let db = setup_db()?;
+        let storage = SlowInMemoryStorage::new_with_failures(1.0);
+        let engine = Arc::new(SyncEngine::builder().storage(Box::new(storage.clone())).build()?);
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+
+        let interval = Duration::from_millis(20);
+        let start = Instant::now();
+        let handle = engine.start_background_sync(&db, interval)?;
+        assert!(wait_for(|| handle.sync_count() >= 3));
+        // The first sync is immediate, each one after waits an interval
+        assert!(start.elapsed() >= interval * 2);
+        assert!(matches!(handle.last_result(), Some(Err(message)) if message.starts_with("Storage error")));
+
+        // Failures don't stop it
+        storage.set_failure_rate(0.0);
+        assert!(wait_for(|| handle.last_result() == Some(Ok(()))));
+        assert!(db.get_metadata(LAST_SYNC_AT_KEY)?.is_some());
+        handle.stop();
+
+        let db2 = setup_db()?;
+        SyncEngine::builder().storage(Box::new(storage)).build()?.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_the_handle_detaches_the_thread() -> Result<()> {
+        let db = setup_db()?;
+        let storage = SlowInMemoryStorage::new_with_failures(1.0);
+        let engine = Arc::new(SyncEngine::builder().storage(Box::new(storage.clone())).build()?);
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+
+        // Fire and forget: the dropped handle keeps retrying until storage
+        // is available, then keeps syncing on the interval
+        drop(engine.sync_when_available(&db, Duration::from_millis(10), None)?);
+        assert!(wait_for(|| storage.operation_count() >= 3));
+        storage.set_failure_rate(0.0);
+        assert!(wait_for(|| db.get_metadata(LAST_SYNC_AT_KEY).unwrap().is_some()));
+
+        let db2 = setup_db()?;
+        SyncEngine::builder().storage(Box::new(storage)).build()?.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() -> Result<()> {
+        let db = setup_db()?;
+        let storage = SlowInMemoryStorage::new_with_failures(1.0);
+        let engine = Arc::new(SyncEngine::builder().storage(Box::new(storage.clone())).build()?);
+
+        let background = engine.sync_when_available(&db, Duration::from_millis(1), Some(2))?;
+        background.join();
+        // The first attempt and two retries, each failing on its first
+        // storage operation
+        assert_eq!(storage.operation_count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_on_permanent_error() -> Result<()> {
+        let db = setup_db()?;
+        let storage = SlowInMemoryStorage::new_counting();
+        storage.put("dimple-sync/manifests/corrupt.msgpack", b"not msgpack")?;
+        let engine = Arc::new(SyncEngine::builder().storage(Box::new(storage.clone())).build()?);
+
+        let background = engine.sync_when_available(&db, Duration::from_millis(1), Some(100))?;
+        background.join();
+        // The put, and one attempt's list and get of the manifest
+        assert_eq!(storage.operation_count(), 3);
+        assert_eq!(db.get_metadata(LAST_SYNC_AT_KEY)?, None);
+        Ok(())
+    }
+}
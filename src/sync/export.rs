@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Builder;
+
+use crate::{changelog::{Changelog, ChangelogChangeWithFields, DbChangelog}, sync::SyncEngine, Db};
+
+const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// Self-contained MessagePack envelope for changes exported with
+/// SyncEngine::export_changes_since().
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedChanges {
+    format_version: u32,
+    schema_version: i64,
+    author_id: String,
+    checksum: Vec<u8>,
+    changes: Vec<u8>,
+}
+
+/// Result of SyncEngine::import_exported_changes().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApplyResult {
+    /// Changes that were new to the database and were applied.
+    pub applied: usize,
+    /// Changes the database already had.
+    pub skipped: usize,
+}
+
+impl SyncEngine {
+    /// Export all changes in the database's changelog made at or after
+    /// since_ms (milliseconds since the Unix epoch) as a single MessagePack
+    /// blob. The blob can be moved to another device by any means and
+    /// applied there with import_exported_changes(). This does not touch
+    /// the sync storage.
+    pub fn export_changes_since(&self, db: &Db, since_ms: i64) -> Result<Vec<u8>> {
+        // Change ids are UUIDv7 so the lowest possible id for the timestamp
+        // is the start of the range.
+        let from_id = Builder::from_unix_timestamp_millis(since_ms.max(0) as u64, &[0; 10])
+            .into_uuid()
+            .to_string();
+        let changes = DbChangelog::new(db.clone()).get_changes(Some(&from_id), None)?;
+        let changes = rmp_serde::to_vec(&changes)?;
+        let exported = ExportedChanges {
+            format_version: EXPORT_FORMAT_VERSION,
+            schema_version: db.query_scalar("PRAGMA user_version", [])?,
+            author_id: db.author_id().to_string(),
+            checksum: Sha256::digest(&changes).to_vec(),
+            changes,
+        };
+        Ok(rmp_serde::to_vec(&exported)?)
+    }
+
+    /// Apply a blob created by export_changes_since(). Changes the database
+    /// already has are skipped and the rest are merged just as they would be
+    /// during a sync.
+    pub fn import_exported_changes(&self, db: &Db, data: &[u8]) -> Result<ApplyResult> {
+        let exported: ExportedChanges = rmp_serde::from_slice(data)?;
+        if exported.format_version != EXPORT_FORMAT_VERSION {
+            return Err(anyhow!("Unsupported export format version {}", exported.format_version));
+        }
+        if Sha256::digest(&exported.changes).as_slice() != exported.checksum.as_slice() {
+            return Err(anyhow!("Export checksum mismatch, the data is corrupt"));
+        }
+        let schema_version: i64 = db.query_scalar("PRAGMA user_version", [])?;
+        if schema_version != exported.schema_version {
+            log::warn!("Importing changes from {} exported at schema version {} into schema version {}.",
+                exported.author_id, exported.schema_version, schema_version);
+        }
+
+        let changes: Vec<ChangelogChangeWithFields> = rmp_serde::from_slice(&exported.changes)?;
+        let local_changelog = DbChangelog::new(db.clone());
+        let local_change_ids = local_changelog.get_all_change_ids()?
+            .into_iter().collect::<HashSet<_>>();
+        let total = changes.len();
+        let new_changes = changes.into_iter()
+            .filter(|c| !local_change_ids.contains(&c.change.id))
+            .collect::<Vec<_>>();
+        let result = ApplyResult {
+            applied: new_changes.len(),
+            skipped: total - new_changes.len(),
+        };
+        local_changelog.append_changes(new_changes)?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite_migration::{Migrations, M};
+    use serde::{Deserialize, Serialize};
+
+    use crate::{sync::{ApplyResult, SyncEngine}, Db};
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    struct Artist {
+        id: String,
+        name: String,
+    }
+
+    fn setup_db() -> anyhow::Result<Db> {
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL);"),
+        ]))?;
+        Ok(db)
+    }
+
+    #[test]
+    fn export_import_roundtrip() -> anyhow::Result<()> {
+        let db1 = setup_db()?;
+        let db2 = setup_db()?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+        let artist = db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db1.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+
+        let data = sync_engine.export_changes_since(&db1, 0)?;
+        let result = sync_engine.import_exported_changes(&db2, &data)?;
+        assert_eq!(result, ApplyResult { applied: 2, skipped: 0 });
+        let imported: Artist = db2.get(&artist.id)?.unwrap();
+        assert_eq!(imported.name, "Metallica");
+
+        // Importing again is a no-op
+        let result = sync_engine.import_exported_changes(&db2, &data)?;
+        assert_eq!(result, ApplyResult { applied: 0, skipped: 2 });
+        Ok(())
+    }
+
+    #[test]
+    fn export_since_excludes_older_changes() -> anyhow::Result<()> {
+        let db = setup_db()?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let since_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
+        db.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+
+        let data = sync_engine.export_changes_since(&db, since_ms)?;
+        let result = sync_engine.import_exported_changes(&setup_db()?, &data)?;
+        assert_eq!(result.applied, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn import_rejects_corrupt_data() -> anyhow::Result<()> {
+        let db = setup_db()?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        let mut data = sync_engine.export_changes_since(&db, 0)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(sync_engine.import_exported_changes(&setup_db()?, &data).is_err());
+        Ok(())
+    }
+}
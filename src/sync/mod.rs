@@ -1,3 +1,8 @@
+mod background_sync;
+pub mod export;
 pub mod sync_engine;
+mod sync_url;
 
+pub use background_sync::{BackgroundSync, LAST_SYNC_AT_KEY};
+pub use export::*;
 pub use sync_engine::*;
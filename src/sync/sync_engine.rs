@@ -1,13 +1,93 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, ops::Deref, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rmpv::Value as MsgPackValue;
 
-use crate::{changelog::{BatchingStorageChangelog, Changelog}, storage::{EncryptedStorage, InMemoryStorage, LocalStorage, S3Storage, SyncStorage}, Db};
+use crate::{changelog::{BatchingStorageChangelog, Changelog, ConflictContext, ConflictResolution, ConflictResolver}, storage::{ArcStorage, AzureBlobStorage, EncryptedStorage, GcsStorage, InMemoryStorage, LocalStorage, MetricsStorage, ObfuscatedPathStorage, RecordingStorage, S3Storage, S3StorageBuilder, StorageObject, StorageOperation, SyncStorage}, db::{DimpleError, DimpleResult}, Db};
+
+const SCHEMA_VERSION_PATH: &str = "schema_version.msgpack";
+const REMOTE_SCHEMA_VERSION_KEY: &str = "remote_schema_version";
+
+/// Result of SyncEngine::sync_with_schema_version().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncResult {
+    Synced,
+    /// The remote was written by a newer schema version, nothing was synced.
+    SchemaMismatch { local: i32, remote: i32 },
+}
+
+/// The step a sync is on, reported through SyncProgress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncPhase {
+    /// Getting the local and remote change ids.
+    Listing,
+    /// Changes pulled so far, of the total to pull.
+    Pulling(usize, usize),
+    /// Applying a batch of pulled changes to the local database.
+    Merging,
+    /// Changes pushed so far, of the total to push.
+    Pushing(usize, usize),
+    Done,
+}
+
+/// Progress of a sync, passed to the callback given to
+/// SyncEngine::sync_with_progress() or SyncEngineBuilder::on_progress().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncProgress {
+    pub phase: SyncPhase,
+    pub changes_pulled: usize,
+    pub changes_pushed: usize,
+    /// Bytes read from and written to storage so far. Always 0 when
+    /// reported by GenericSyncEngine, which doesn't see storage.
+    pub bytes_transferred: usize,
+}
+
+/// What a sync would do, from SyncEngine::dry_run().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncDiff {
+    /// Ids of the remote changes that would be pulled, in order.
+    pub would_pull: Vec<String>,
+    /// Ids of the local changes that would be pushed, in order.
+    pub would_push: Vec<String>,
+    /// The MessagePack encoded size of the changes that would be pulled.
+    /// Storage overhead such as batching and encryption isn't included.
+    pub would_pull_bytes: usize,
+    /// The MessagePack encoded size of the changes that would be pushed.
+    pub would_push_bytes: usize,
+}
+
+/// Which way changes flow in a sync. See SyncEngineBuilder::pull_only() and
+/// SyncEngineBuilder::push_only().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncDirection {
+    #[default]
+    Both,
+    /// Remote changes are pulled, local changes are never pushed.
+    PullOnly,
+    /// Local changes are pushed, remote changes are never pulled.
+    PushOnly,
+}
+
+/// Options for GenericSyncEngine::sync_changes().
+#[derive(Clone, Copy, Default)]
+struct SyncOptions<'a> {
+    entity_types: Option<&'a [&'a str]>,
+    direction: SyncDirection,
+    /// Only report what would change, in the returned SyncDiff, which is
+    /// left empty otherwise.
+    dry_run: bool,
+}
+
+pub type SyncProgressCallback = Box<dyn FnMut(SyncProgress) + Send>;
 
 pub struct SyncEngine {
     storage: Box<dyn SyncStorage>,
     prefix: String,
+    entity_types: Option<Vec<String>>,
+    direction: SyncDirection,
+    on_progress: Mutex<Option<SyncProgressCallback>>,
+    conflict_resolver: Option<Arc<ConflictResolver>>,
+    vector_clocks: bool,
 }
 
 pub struct GenericSyncEngine;
@@ -21,13 +101,66 @@ impl GenericSyncEngine {
     /// 
     /// 1. Get the sets of local and remote change_ids.
     /// 2. For any remote change_id not in the local set, download and insert
-    /// it, setting merged = false. 
+    ///    it, setting merged = false.
     /// 3. For any local change_id not in the remote set, upload it.
     /// 
     /// Call changelogs to merge entity updates.
     pub fn sync(local: &dyn Changelog, remote: &dyn Changelog) -> Result<()> {
+        Self::sync_changes(local, remote, SyncOptions::default(), &mut |_| {})?;
+        Ok(())
+    }
+
+    /// Like sync(), but only pulling or only pushing, or both.
+    pub fn sync_direction(local: &dyn Changelog, remote: &dyn Changelog, direction: SyncDirection) -> Result<()> {
+        let options = SyncOptions { direction, ..Default::default() };
+        Self::sync_changes(local, remote, options, &mut |_| {})?;
+        Ok(())
+    }
+
+    /// Compare the changelogs as sync() does, reading the changes that
+    /// would be pulled and pushed, but without appending them to either.
+    pub fn dry_run(local: &dyn Changelog, remote: &dyn Changelog) -> Result<SyncDiff> {
+        let options = SyncOptions { dry_run: true, ..Default::default() };
+        Self::sync_changes(local, remote, options, &mut |_| {})
+    }
+
+    /// Like sync(), calling progress as each phase starts and after each
+    /// batch of changes is pulled or pushed.
+    pub fn sync_with_progress(local: &dyn Changelog, remote: &dyn Changelog,
+            progress: &mut dyn FnMut(SyncProgress)) -> Result<()> {
+        Self::sync_changes(local, remote, SyncOptions::default(), progress)?;
+        Ok(())
+    }
+
+    /// Like sync(), but only changes to the given entity types are pulled
+    /// and pushed. Other changes stay where they are. Since change ids don't
+    /// include the entity type, remote changes of other types are still
+    /// downloaded to check them on every sync.
+    pub fn sync_entity_types(local: &dyn Changelog, remote: &dyn Changelog, entity_types: &[&str]) -> Result<()> {
+        let options = SyncOptions { entity_types: Some(entity_types), ..Default::default() };
+        Self::sync_changes(local, remote, options, &mut |_| {})?;
+        Ok(())
+    }
+
+    fn sync_changes(local: &dyn Changelog, remote: &dyn Changelog, options: SyncOptions,
+            progress: &mut dyn FnMut(SyncProgress)) -> Result<SyncDiff> {
+        let SyncOptions { entity_types, direction, dry_run } = options;
+        let get_changes = |changelog: &dyn Changelog, from_id, to_id| match entity_types {
+            Some(entity_types) => changelog.get_changes_for_types(entity_types, from_id, to_id),
+            None => changelog.get_changes(from_id, to_id),
+        };
+        let mut diff = SyncDiff::default();
+        let (mut changes_pulled, mut changes_pushed) = (0, 0);
+        let mut report = |phase, changes_pulled, changes_pushed| progress(SyncProgress {
+            phase,
+            changes_pulled,
+            changes_pushed,
+            bytes_transferred: 0,
+        });
+
         // 1. Get the sets of local and remote change_ids.
         log::info!("Sync: Getting change lists.");
+        report(SyncPhase::Listing, 0, 0);
         let local_change_ids = local.get_all_change_ids()?
             .into_iter().collect::<HashSet<_>>();
         let remote_change_ids = remote.get_all_change_ids()?
@@ -40,34 +173,83 @@ impl GenericSyncEngine {
         let mut change_ids_to_pull = remote_change_ids.iter()
             .filter(|id| !local_change_ids.contains(*id))
             .collect::<Vec<_>>();
+        if direction == SyncDirection::PushOnly {
+            log::info!("Sync: Push only, not pulling {} new changes.", change_ids_to_pull.len());
+            change_ids_to_pull.clear();
+        }
         log::info!("Sync: Pulling {} new changes.", change_ids_to_pull.len());
         if !change_ids_to_pull.is_empty() {
             change_ids_to_pull.sort();
+            let pull_total = change_ids_to_pull.len();
             for change_ids_to_pull in change_ids_to_pull.chunks(100) {
                 let pull_min = change_ids_to_pull.iter().min().cloned().map(|s| s.as_str());
                 let pull_max = change_ids_to_pull.iter().max().cloned().map(|s| s.as_str());
-                let pulled_changes = remote.get_changes(pull_min, pull_max)?;
-                local.append_changes(pulled_changes)?;
+                // The range may include changes local already has, so
+                // filter those out before appending.
+                let pulled_changes = get_changes(remote, pull_min, pull_max)?
+                    .into_iter()
+                    .filter(|change| !local_change_ids.contains(&change.change.id))
+                    .collect::<Vec<_>>();
+                changes_pulled += change_ids_to_pull.len();
+                report(SyncPhase::Pulling(changes_pulled, pull_total), changes_pulled, changes_pushed);
+                if dry_run {
+                    for change in &pulled_changes {
+                        diff.would_pull.push(change.change.id.clone());
+                        diff.would_pull_bytes += rmp_serde::to_vec(change)?.len();
+                    }
+                } else {
+                    report(SyncPhase::Merging, changes_pulled, changes_pushed);
+                    local.append_changes(pulled_changes)?;
+                }
             }
         }
         
+        // Merging can create local changes, such as conflict resolutions,
+        // so they are pushed by this sync too
+        let local_change_ids = if changes_pulled > 0 && !dry_run {
+            local.get_all_change_ids()?.into_iter().collect::<HashSet<_>>()
+        } else {
+            local_change_ids
+        };
+
         // 3. For any local change_id not in the remote set, upload it
         let mut change_ids_to_push = local_change_ids.iter()
             .filter(|id| !remote_change_ids.contains(*id))
             .collect::<Vec<_>>();
+        if direction == SyncDirection::PullOnly {
+            log::info!("Sync: Pull only, not pushing {} new changes.", change_ids_to_push.len());
+            change_ids_to_push.clear();
+        }
         log::info!("Sync: Pushing {} new changes.", change_ids_to_push.len());
         if !change_ids_to_push.is_empty() {
             change_ids_to_push.sort();
+            let push_total = change_ids_to_push.len();
             for change_ids_to_push in change_ids_to_push.chunks(100) {
                 let push_min = change_ids_to_push.iter().min().cloned().map(|s| s.as_str());
                 let push_max = change_ids_to_push.iter().max().cloned().map(|s| s.as_str());
-                let changes_to_push = local.get_changes(push_min, push_max)?;
-                remote.append_changes(changes_to_push)?;
+                // As with pulling, skip changes remote already has. Pruned
+                // changes have lost their fields, so they are never pushed.
+                let changes_to_push = get_changes(local, push_min, push_max)?
+                    .into_iter()
+                    .filter(|change| !remote_change_ids.contains(&change.change.id))
+                    .filter(|change| !change.fields.is_empty())
+                    .collect::<Vec<_>>();
+                if dry_run {
+                    for change in &changes_to_push {
+                        diff.would_push.push(change.change.id.clone());
+                        diff.would_push_bytes += rmp_serde::to_vec(change)?.len();
+                    }
+                } else {
+                    remote.append_changes(changes_to_push)?;
+                }
+                changes_pushed += change_ids_to_push.len();
+                report(SyncPhase::Pushing(changes_pushed, push_total), changes_pulled, changes_pushed);
             }
         }
 
         log::info!("Sync: Done. =============");
-        Ok(())
+        report(SyncPhase::Done, changes_pulled, changes_pushed);
+        Ok(diff)
     }
 }
 
@@ -76,6 +258,11 @@ impl SyncEngine {
         Ok(SyncEngine {
             storage,
             prefix,
+            entity_types: None,
+            direction: SyncDirection::Both,
+            on_progress: Mutex::new(None),
+            conflict_resolver: None,
+            vector_clocks: false,
         })
     }
 
@@ -84,15 +271,157 @@ impl SyncEngine {
     }
 
 
-    /// Sync using the generic sync algorithm with DbChangelog and BatchingStorageChangelog
-    pub fn sync(&self, db: &Db) -> Result<()> {
-        use crate::changelog::{DbChangelog};
-        
+    /// Sync using the generic sync algorithm with DbChangelog and
+    /// BatchingStorageChangelog. If the engine was built with entity_types()
+    /// only changes to those types are synced, and if it was built with
+    /// on_progress() progress is reported to it.
+    ///
+    /// The callback is taken out of the engine while it runs, so it may use
+    /// the engine, but progress from a sync it starts, or from another
+    /// thread's sync at the same moment, isn't reported.
+    pub fn sync(&self, db: &Db) -> DimpleResult<()> {
+        self.sync_with_progress(db, |progress| {
+            let Some(mut callback) = self.on_progress.lock().ok().and_then(|mut cb| cb.take()) else {
+                return;
+            };
+            callback(progress);
+            if let Ok(mut on_progress) = self.on_progress.lock() {
+                *on_progress = Some(callback);
+            }
+        }).map_err(DimpleError::from_sync)
+    }
+
+    /// Like sync(), for async applications. The sync runs on its own
+    /// thread, as AsyncDb calls do, so it doesn't block the executor.
+    #[cfg(feature = "async")]
+    pub async fn sync_async(self: &Arc<Self>, db: &crate::db::AsyncDb) -> DimpleResult<()> {
+        let engine = self.clone();
+        db.spawn(move |db| engine.sync(&db)).await
+    }
+
+    /// Like sync(), calling the callback as each phase starts and after
+    /// each batch of changes is pulled or pushed.
+    pub fn sync_with_progress(&self, db: &Db, mut callback: impl FnMut(SyncProgress)) -> Result<()> {
+        match &self.entity_types {
+            Some(entity_types) => {
+                let entity_types = entity_types.iter().map(String::as_str).collect::<Vec<_>>();
+                self.sync_changes(db, Some(&entity_types), &mut callback)
+            },
+            None => self.sync_changes(db, None, &mut callback),
+        }
+    }
+
+    /// Report which changes sync() would pull and push, without changing the
+    /// database or storage. Only the engine's entity_types are considered,
+    /// if set. See GenericSyncEngine::dry_run().
+    pub fn dry_run(&self, db: &Db) -> Result<SyncDiff> {
+        use crate::changelog::DbChangelog;
+
         let local_changelog = DbChangelog::new(db.clone());
         let remote_changelog = BatchingStorageChangelog::new(self.storage.as_ref(), self.prefix.clone());
+        let entity_types = self.entity_types.as_ref()
+            .map(|entity_types| entity_types.iter().map(String::as_str).collect::<Vec<_>>());
+        let options = SyncOptions {
+            entity_types: entity_types.as_deref(),
+            direction: self.direction,
+            dry_run: true,
+        };
+        GenericSyncEngine::sync_changes(&local_changelog, &remote_changelog, options, &mut |_| {})
+    }
+
+    /// Sync only changes to the given entity types, leaving changes to other
+    /// types local, or on the remote. See GenericSyncEngine::sync_entity_types().
+    pub fn sync_entity_types(&self, db: &Db, entity_types: &[&str]) -> Result<()> {
+        self.sync_changes(db, Some(entity_types), &mut |_| {})
+    }
+
+    fn sync_changes(&self, db: &Db, entity_types: Option<&[&str]>, progress: &mut dyn FnMut(SyncProgress)) -> Result<()> {
+        use crate::changelog::{DbChangelog};
         
+        let storage = CountingStorage { inner: self.storage.as_ref(), bytes: AtomicUsize::new(0) };
+        let mut local_changelog = DbChangelog::new(db.clone());
+        if let Some(conflict_resolver) = &self.conflict_resolver {
+            local_changelog = local_changelog.with_conflict_resolver(conflict_resolver.clone());
+        }
+        if self.vector_clocks {
+            local_changelog = local_changelog.with_vector_clocks();
+            if db.get_metadata(crate::changelog::vector_clock::VECTOR_CLOCK_KEY)?.is_none() {
+                db.transaction(|txn| crate::changelog::vector_clock::enable_clocks(txn.txn(), txn.db().author_id()))?;
+            }
+        }
+        let remote_changelog = BatchingStorageChangelog::new(&storage, self.prefix.clone());
+        let mut progress = |progress_update: SyncProgress| progress(SyncProgress {
+            bytes_transferred: storage.bytes.load(Ordering::Relaxed),
+            ..progress_update
+        });
+        
+        // Every change that exists now will be in remote storage once the
+        // sync succeeds, which makes them candidates for pruning. That's
+        // not the case for changes a filtered or pull only sync leaves local.
+        let synced_through_id: Option<String> = match (entity_types, self.direction) {
+            (Some(_), _) | (_, SyncDirection::PullOnly) => None,
+            _ => db.query_scalar("SELECT MAX(id) FROM ZV_CHANGE", [])?,
+        };
+
         // Use the generic sync algorithm
-        Ok(GenericSyncEngine::sync(&local_changelog, &remote_changelog)?)
+        let options = SyncOptions {
+            entity_types,
+            direction: self.direction,
+            dry_run: false,
+        };
+        GenericSyncEngine::sync_changes(&local_changelog, &remote_changelog, options, &mut progress)?;
+
+        if let Some(synced_through_id) = synced_through_id {
+            db.set_metadata(crate::changelog::SYNCED_THROUGH_KEY, &synced_through_id)?;
+        }
+        Ok(())
+    }
+
+    /// Sync, first checking that the remote wasn't written by a newer
+    /// schema than the app's current migration version. A remote written by
+    /// a newer version may contain columns this version doesn't know about,
+    /// which would be silently dropped, so SchemaMismatch is returned
+    /// without syncing. Otherwise the remote's version is raised to
+    /// schema_version after syncing. The remote's last seen version is kept
+    /// in the local database's metadata.
+    pub fn sync_with_schema_version(&self, db: &Db, schema_version: i32) -> Result<SyncResult> {
+        let path = self.prefixed_path(SCHEMA_VERSION_PATH);
+        // Only a missing version means the remote has none, any other
+        // failure must not let this sync overwrite it
+        let remote_version = match self.storage.exists(&path)? {
+            true => Some(rmp_serde::from_slice::<i32>(&self.storage.get(&path)?)?),
+            false => None,
+        };
+        if let Some(remote_version) = remote_version {
+            db.set_metadata(REMOTE_SCHEMA_VERSION_KEY, &remote_version.to_string())?;
+            if remote_version > schema_version {
+                log::warn!("Sync: Remote schema version {} is newer than local {}, not syncing.",
+                    remote_version, schema_version);
+                return Ok(SyncResult::SchemaMismatch { local: schema_version, remote: remote_version });
+            }
+        }
+
+        self.sync(db)?;
+
+        if remote_version.map_or(true, |remote_version| remote_version < schema_version) {
+            self.storage.put(&path, &rmp_serde::to_vec(&schema_version)?)?;
+            db.set_metadata(REMOTE_SCHEMA_VERSION_KEY, &schema_version.to_string())?;
+        }
+        Ok(SyncResult::Synced)
+    }
+
+    /// The remote's schema version as of the last sync_with_schema_version(),
+    /// e.g. to prompt the user to update the app after a SchemaMismatch.
+    pub fn last_remote_schema_version(&self, db: &Db) -> Result<Option<i32>> {
+        Ok(db.get_metadata(REMOTE_SCHEMA_VERSION_KEY)?.map(|v| v.parse()).transpose()?)
+    }
+
+    fn prefixed_path(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
     }
 
 }
@@ -136,11 +465,162 @@ pub fn msgpack_to_sql_value(value: &MsgPackValue) -> rusqlite::types::Value {
     }
 }
 
+/// Convert a rusqlite::Value to the JSON used for field values. Blobs become
+/// arrays of bytes, which is unambiguous since SQLite has no array type.
+pub fn sql_value_to_json(value: &rusqlite::types::Value) -> serde_json::Value {
+    match value {
+        rusqlite::types::Value::Null => serde_json::Value::Null,
+        rusqlite::types::Value::Integer(i) => (*i).into(),
+        rusqlite::types::Value::Real(f) => serde_json::Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        rusqlite::types::Value::Text(s) => serde_json::Value::String(s.clone()),
+        rusqlite::types::Value::Blob(b) => b.iter().map(|byte| serde_json::Value::from(*byte)).collect(),
+    }
+}
+
+/// Convert a JSON field value back to a rusqlite::Value
+pub fn json_to_sql_value(value: &serde_json::Value) -> rusqlite::types::Value {
+    match value {
+        serde_json::Value::Null => rusqlite::types::Value::Null,
+        serde_json::Value::Bool(b) => rusqlite::types::Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => {
+            if let Some(i64_val) = n.as_i64() {
+                rusqlite::types::Value::Integer(i64_val)
+            } else if let Some(u64_val) = n.as_u64() {
+                rusqlite::types::Value::Integer(u64_val as i64)
+            } else {
+                rusqlite::types::Value::Real(n.as_f64().unwrap_or_default())
+            }
+        },
+        serde_json::Value::String(s) => rusqlite::types::Value::Text(s.clone()),
+        serde_json::Value::Array(_) => match json_to_bytes(value) {
+            Some(bytes) => rusqlite::types::Value::Blob(bytes),
+            None => rusqlite::types::Value::Text(value.to_string()),
+        },
+        serde_json::Value::Object(_) => rusqlite::types::Value::Text(value.to_string()),
+    }
+}
+
+/// Convert a MessagePack field value from the wire encoding to JSON
+pub fn field_value_to_json(value: &MsgPackValue) -> serde_json::Value {
+    match value {
+        MsgPackValue::Nil => serde_json::Value::Null,
+        MsgPackValue::Boolean(b) => serde_json::Value::Bool(*b),
+        MsgPackValue::Integer(i) => {
+            if let Some(i64_val) = i.as_i64() {
+                i64_val.into()
+            } else if let Some(u64_val) = i.as_u64() {
+                u64_val.into()
+            } else {
+                serde_json::Value::Null
+            }
+        },
+        MsgPackValue::F32(f) => serde_json::Number::from_f64(*f as f64)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        MsgPackValue::F64(f) => serde_json::Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        MsgPackValue::String(s) => match s.as_str() {
+            Some(string) => serde_json::Value::String(string.to_string()),
+            None => serde_json::Value::Null,
+        },
+        MsgPackValue::Binary(b) => b.iter().map(|byte| serde_json::Value::from(*byte)).collect(),
+        MsgPackValue::Array(values) => values.iter().map(field_value_to_json).collect(),
+        MsgPackValue::Map(entries) => serde_json::Value::Object(entries.iter()
+            .map(|(key, value)| {
+                let key = key.as_str().map(str::to_string).unwrap_or_else(|| key.to_string());
+                (key, field_value_to_json(value))
+            })
+            .collect()),
+        MsgPackValue::Ext(_, _) => serde_json::Value::Null,
+    }
+}
+
+/// Convert a JSON field value to the MessagePack wire encoding. Arrays of
+/// bytes are encoded as binary so blobs stay compact.
+pub fn field_value_from_json(value: &serde_json::Value) -> MsgPackValue {
+    match value {
+        serde_json::Value::Null => MsgPackValue::Nil,
+        serde_json::Value::Bool(b) => MsgPackValue::Boolean(*b),
+        serde_json::Value::Number(n) => {
+            if let Some(i64_val) = n.as_i64() {
+                MsgPackValue::Integer(i64_val.into())
+            } else if let Some(u64_val) = n.as_u64() {
+                MsgPackValue::Integer(u64_val.into())
+            } else {
+                MsgPackValue::F64(n.as_f64().unwrap_or_default())
+            }
+        },
+        serde_json::Value::String(s) => MsgPackValue::String(s.clone().into()),
+        serde_json::Value::Array(values) => match json_to_bytes(value) {
+            Some(bytes) => MsgPackValue::Binary(bytes),
+            None => MsgPackValue::Array(values.iter().map(field_value_from_json).collect()),
+        },
+        serde_json::Value::Object(entries) => MsgPackValue::Map(entries.iter()
+            .map(|(key, value)| (MsgPackValue::String(key.clone().into()), field_value_from_json(value)))
+            .collect()),
+    }
+}
+
+fn json_to_bytes(value: &serde_json::Value) -> Option<Vec<u8>> {
+    value.as_array()?.iter()
+        .map(|v| v.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+        .collect()
+}
+
+/// Counts the bytes read and written through it, for SyncProgress.
+struct CountingStorage<'a> {
+    inner: &'a dyn SyncStorage,
+    bytes: AtomicUsize,
+}
+
+impl SyncStorage for CountingStorage<'_> {
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>> {
+        self.inner.list(prefix)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let content = self.inner.get(path)?;
+        self.bytes.fetch_add(content.len(), Ordering::Relaxed);
+        Ok(content)
+    }
+
+    fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        self.inner.put(path, content)?;
+        self.bytes.fetch_add(content.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn put_if_not_exists(&self, path: &str, content: &[u8]) -> Result<bool> {
+        let written = self.inner.put_if_not_exists(path, content)?;
+        if written {
+            self.bytes.fetch_add(content.len(), Ordering::Relaxed);
+        }
+        Ok(written)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path)
+    }
+}
+
 #[derive(Default)]
 pub struct SyncEngineBuilder {
     storage: Option<Box<dyn SyncStorage>>,
     passphrase: Option<String>,
+    obfuscate_paths: bool,
     prefix: Option<String>,
+    entity_types: Option<Vec<String>>,
+    direction: SyncDirection,
+    on_progress: Option<SyncProgressCallback>,
+    conflict_resolver: Option<Arc<ConflictResolver>>,
+    vector_clocks: bool,
 }
 
 impl SyncEngineBuilder {
@@ -164,34 +644,203 @@ impl SyncEngineBuilder {
         Ok(self)
     }
 
+    /// Cloudflare R2, see S3StorageBuilder::cloudflare_r2() for its
+    /// consistency caveats.
+    pub fn cloudflare_r2(mut self, account_id: &str,
+        access_key: &str,
+        secret_key: &str,
+        bucket_name: &str) -> Result<Self> {
+        self.storage = Some(Box::new(S3StorageBuilder::cloudflare_r2(account_id, access_key,
+            secret_key, bucket_name)?));
+        Ok(self)
+    }
+
+    /// Backblaze B2, see S3StorageBuilder::backblaze_b2().
+    pub fn backblaze_b2(mut self, key_id: &str,
+        application_key: &str,
+        bucket_name: &str,
+        region: &str) -> Result<Self> {
+        self.storage = Some(Box::new(S3StorageBuilder::backblaze_b2(key_id, application_key,
+            bucket_name, region)?));
+        Ok(self)
+    }
+
+    /// Google Cloud Storage, where service_account_json is the contents of a
+    /// service account key file.
+    pub fn gcs(mut self, bucket: &str, service_account_json: &str) -> Result<Self> {
+        self.storage = Some(Box::new(GcsStorage::new(bucket, service_account_json)?));
+        Ok(self)
+    }
+
+    /// Azure Blob Storage, where access_key is one of the base64 storage
+    /// account keys.
+    pub fn azure(mut self, account: &str, access_key: &str, container: &str) -> Result<Self> {
+        self.storage = Some(Box::new(AzureBlobStorage::new(account, access_key, container)?));
+        Ok(self)
+    }
+
+    /// Use any SyncStorage, such as one shared with another engine.
+    pub fn storage(mut self, storage: Box<dyn SyncStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
     pub fn encrypted(mut self, passphrase: &str) -> Self {
         self.passphrase = Some(passphrase.to_string());
         self
     }
 
+    /// Also hide the storage paths, using the encrypted() passphrase. See
+    /// ObfuscatedPathStorage.
+    pub fn obfuscate_paths(mut self) -> Self {
+        self.obfuscate_paths = true;
+        self
+    }
+
     pub fn prefix(mut self, prefix: &str) -> Self {
         self.prefix = Some(prefix.to_string());
         self
     }
 
-    pub fn build(self) -> Result<SyncEngine> {
+    /// Only sync changes to these entity types. By default every type is
+    /// synced.
+    pub fn entity_types(mut self, entity_types: &[&str]) -> Self {
+        self.entity_types = Some(entity_types.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    /// Only pull remote changes, never pushing local ones. For read only
+    /// subscribers.
+    pub fn pull_only(mut self) -> Self {
+        self.direction = SyncDirection::PullOnly;
+        self
+    }
+
+    /// Only push local changes, never pulling remote ones. For write only
+    /// publishers.
+    pub fn push_only(mut self) -> Self {
+        self.direction = SyncDirection::PushOnly;
+        self
+    }
+
+    /// Decide fields changed both locally and remotely with the resolver,
+    /// instead of by last write wins. The resolver is called once for each
+    /// conflicting field while pulled changes are merged. See
+    /// ConflictContext.
+    pub fn on_conflict(mut self, resolver: impl Fn(ConflictContext) -> ConflictResolution + Send + Sync + 'static) -> Self {
+        self.conflict_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Push each change's VectorClock with it, so that on_conflict() is
+    /// only called for changes that are really concurrent. Only enable it
+    /// once every replica has been updated to a version that can read
+    /// clocks. Changes without one are still ordered as before.
+    ///
+    /// The Db only starts recording clocks at the first sync with this set,
+    /// which gives the local changes made before it one clock between them.
+    pub fn vector_clocks(mut self) -> Self {
+        self.vector_clocks = true;
+        self
+    }
+
+    /// Report progress to the callback on every sync(). See
+    /// SyncEngine::sync_with_progress().
+    pub fn on_progress(mut self, callback: impl FnMut(SyncProgress) + Send + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Wraps the storage in the requested path obfuscation and encryption.
+    fn wrap_storage(&mut self, storage: Box<dyn SyncStorage>) -> Result<Box<dyn SyncStorage>> {
+        let Some(passphrase) = self.passphrase.take() else {
+            if self.obfuscate_paths {
+                return Err(anyhow!("obfuscate_paths() requires a passphrase, see encrypted()"));
+            }
+            return Ok(storage);
+        };
+        let storage: Box<dyn SyncStorage> = match self.obfuscate_paths {
+            true => Box::new(ObfuscatedPathStorage::new(storage, &passphrase)?),
+            false => storage,
+        };
+        Ok(Box::new(EncryptedStorage::new(storage, passphrase)))
+    }
+
+    pub fn build(mut self) -> Result<SyncEngine> {
+        let storage = self.storage.take().ok_or_else(|| anyhow!("No storage configured"))?;
+        let storage = self.wrap_storage(storage)?;
+        self.build_with_storage(storage)
+    }
+
+    /// Build a SyncEngine whose storage calls are counted by the returned
+    /// MetricsStorage. If encrypted, the bytes counted are the ciphertext.
+    pub fn with_metrics(mut self) -> Result<(SyncEngine, Arc<MetricsStorage>)> {
+        let storage = self.storage.take().ok_or_else(|| anyhow!("No storage configured"))?;
+        let metrics = Arc::new(MetricsStorage::new(self.wrap_storage(storage)?));
+        let engine = self.build_with_storage(Box::new(ArcStorage::new(metrics.clone())))?;
+        Ok((engine, metrics))
+    }
+
+    /// Build a TestSyncEngine which records every storage operation made by
+    /// the engine. Storage defaults to in memory. If encrypted, the
+    /// recorded content is the plaintext.
+    pub fn test_mode(mut self) -> Result<TestSyncEngine> {
+        let storage = self.storage.take().unwrap_or_else(|| Box::new(InMemoryStorage::new()));
+        let storage = RecordingStorage::new(self.wrap_storage(storage)?);
+        let operations = storage.operations_handle();
+        let engine = self.build_with_storage(Box::new(storage))?;
+        Ok(TestSyncEngine {
+            engine,
+            operations,
+        })
+    }
+
+    fn build_with_storage(self, storage: Box<dyn SyncStorage>) -> Result<SyncEngine> {
         let prefix = self.prefix.unwrap_or_else(|| "dimple-sync".to_string());
-        
-        if let Some(passphrase) = self.passphrase {
-            let storage = EncryptedStorage::new(self.storage.unwrap(), passphrase);
-            SyncEngine::new_with_storage(Box::new(storage), prefix)
-        }
-        else {
-            SyncEngine::new_with_storage(self.storage.unwrap(), prefix)
+        let mut engine = SyncEngine::new_with_storage(storage, prefix)?;
+        engine.entity_types = self.entity_types;
+        engine.direction = self.direction;
+        engine.on_progress = Mutex::new(self.on_progress);
+        engine.conflict_resolver = self.conflict_resolver;
+        engine.vector_clocks = self.vector_clocks;
+        Ok(engine)
+    }
+}
+
+/// A SyncEngine that records its storage operations, for testing. Created
+/// with SyncEngineBuilder::test_mode().
+pub struct TestSyncEngine {
+    engine: SyncEngine,
+    operations: Arc<Mutex<Vec<StorageOperation>>>,
+}
+
+impl TestSyncEngine {
+    /// A copy of the storage operations recorded so far, oldest first.
+    pub fn operations(&self) -> Vec<StorageOperation> {
+        self.operations.lock().map(|ops| ops.clone()).unwrap_or_default()
+    }
+
+    /// Forget the operations recorded so far.
+    pub fn clear_operations(&self) {
+        if let Ok(mut operations) = self.operations.lock() {
+            operations.clear();
         }
     }
 }
 
+impl Deref for TestSyncEngine {
+    type Target = SyncEngine;
+
+    fn deref(&self) -> &Self::Target {
+        &self.engine
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rusqlite_migration::{Migrations, M};
     use serde::{Deserialize, Serialize};
-    use crate::{changelog::ChangelogChange, db::DbEvent, sync::SyncEngine, Db};
+    use crate::{changelog::ChangelogChange, db::DbEvent, storage::StorageOpKind, sync::SyncEngine, Db};
 
     #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
     struct Artist {
@@ -246,6 +895,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_mode_records_storage_operations() -> anyhow::Result<()> {
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (name TEXT NOT NULL, country TEXT, id TEXT NOT NULL PRIMARY KEY);"),
+        ]);
+        let db = Db::open_memory()?;
+        db.migrate(&migrations)?;
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+
+        let sync_engine = SyncEngine::builder().prefix("test").test_mode()?;
+        sync_engine.sync(&db)?;
+        let puts: Vec<_> = sync_engine.operations().into_iter()
+            .filter(|op| op.kind == StorageOpKind::Put)
+            .map(|op| op.path)
+            .collect();
+        assert_eq!(puts.len(), 2);
+        assert!(puts[0].starts_with("test/batches/"));
+        assert_eq!(puts[1], format!("test/manifests/{}.msgpack", db.author_id()));
+
+        // Nothing new to push, so the second sync only reads
+        sync_engine.clear_operations();
+        sync_engine.sync(&db)?;
+        let operations = sync_engine.operations();
+        assert!(!operations.is_empty());
+        assert!(operations.iter().all(|op| op.kind != StorageOpKind::Put));
+        Ok(())
+    }
+
     /// Ensures replicas will "catch up" when A has synced multiple times since
     /// B.
     #[test]
@@ -581,9 +1260,10 @@ mod tests {
         // Check that we received an insert notification
         let event = receiver.recv_timeout(Duration::from_secs(1))?;
         match event {
-            DbEvent::Insert(entity_type, entity_id) => {
+            DbEvent::Insert { entity_type, entity_id, data } => {
                 assert_eq!(entity_type, "Artist");
                 assert_eq!(entity_id, artist.id);
+                assert_eq!(data["country"], "UK");
             }
             _ => panic!("Expected Insert event, got {:?}", event),
         }
@@ -602,9 +1282,11 @@ mod tests {
         // Check for update notification
         let event = receiver.recv_timeout(Duration::from_secs(1))?;
         match event {
-            DbEvent::Update(entity_type, entity_id) => {
+            DbEvent::Update { entity_type, entity_id, data, old_data } => {
                 assert_eq!(entity_type, "Artist");
                 assert_eq!(entity_id, artist.id);
+                assert_eq!(data["country"], "United Kingdom");
+                assert_eq!(old_data.unwrap()["country"], "UK");
             }
             _ => panic!("Expected Update event, got {:?}", event),
         }
@@ -613,72 +1295,881 @@ mod tests {
     }
 
     #[test]
-    fn test_generic_sync_engine() -> anyhow::Result<()> {
-        use crate::{changelog::{DbChangelog, BatchingStorageChangelog}, storage::InMemoryStorage};
-        use super::GenericSyncEngine;
-        
+    fn schema_version_mismatch() -> anyhow::Result<()> {
+        use crate::sync::SyncResult;
+
         let migrations = Migrations::new(vec![
             M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
         ]);
-        
-        let db1 = Db::open_memory()?;
-        let db2 = Db::open_memory()?;
-        db1.migrate(&migrations)?;
-        db2.migrate(&migrations)?;
-        
-        let storage = InMemoryStorage::new();
-        
-        // Create changelogs
-        let db1_changelog = DbChangelog::new(db1.clone());
-        let db2_changelog = DbChangelog::new(db2.clone());
-        let storage_changelog = BatchingStorageChangelog::new(&storage, "test".to_string());
-        
-        // Add data to db1
-        let _artist1 = db1.save(&Artist {
-            name: "The Beatles".to_string(),
-            ..Default::default()
-        })?;
-        
-        // Add data to db2
-        let _artist2 = db2.save(&Artist {
-            name: "Pink Floyd".to_string(),
-            ..Default::default()
-        })?;
-        
-        // Sync db1 to storage
-        GenericSyncEngine::sync(&db1_changelog, &storage_changelog)?;
-        
-        // Sync db2 to storage  
-        GenericSyncEngine::sync(&db2_changelog, &storage_changelog)?;
-        
-        // Sync storage back to db1 (should get Pink Floyd)
-        GenericSyncEngine::sync(&storage_changelog, &db1_changelog)?;
-        crate::changelog::merge_unmerged_changes(&db1)?;
-        
-        // Sync storage back to db2 (should get The Beatles)
-        GenericSyncEngine::sync(&storage_changelog, &db2_changelog)?;
-        crate::changelog::merge_unmerged_changes(&db2)?;
-        
-        // Both databases should now have both artists
-        let artists1: Vec<Artist> = db1.query("SELECT * FROM Artist ORDER BY name", ())?;
-        let artists2: Vec<Artist> = db2.query("SELECT * FROM Artist ORDER BY name", ())?;
-        
-        assert_eq!(artists1.len(), 2);
-        assert_eq!(artists2.len(), 2);
-        assert_eq!(artists1[0].name, "Pink Floyd");
-        assert_eq!(artists1[1].name, "The Beatles");
-        assert_eq!(artists2[0].name, "Pink Floyd");
-        assert_eq!(artists2[1].name, "The Beatles");
-        
+        let old_app = Db::open_memory()?;
+        let new_app = Db::open_memory()?;
+        old_app.migrate(&migrations)?;
+        new_app.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        old_app.save(&Artist { name: "Pink Floyd".to_string(), ..Default::default() })?;
+        assert_eq!(sync_engine.sync_with_schema_version(&old_app, 1)?, SyncResult::Synced);
+        assert_eq!(sync_engine.sync_with_schema_version(&new_app, 2)?, SyncResult::Synced);
+        assert_eq!(new_app.query::<Artist, _>("SELECT * FROM Artist", [])?.len(), 1);
+
+        new_app.save(&Artist { name: "Genesis".to_string(), ..Default::default() })?;
+        assert_eq!(sync_engine.sync_with_schema_version(&new_app, 2)?, SyncResult::Synced);
+        assert_eq!(sync_engine.sync_with_schema_version(&old_app, 1)?,
+            SyncResult::SchemaMismatch { local: 1, remote: 2 });
+        assert_eq!(old_app.query::<Artist, _>("SELECT * FROM Artist", [])?.len(), 1);
+        assert_eq!(sync_engine.last_remote_schema_version(&old_app)?, Some(2));
         Ok(())
     }
 
     #[test]
-    fn performance_comparison_small_dataset() -> anyhow::Result<()> {
-        use crate::{changelog::{Changelog, DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::SlowInMemoryStorage};
-        use super::GenericSyncEngine;
-        use std::time::Instant;
-        
+    fn schema_version_read_failure_is_an_error() -> anyhow::Result<()> {
+        use std::sync::Arc;
+        use crate::{storage::{ArcStorage, FaultInjectingStorage, InMemoryStorage, StorageFault}, sync::SyncResult};
+
+        let db = Db::open_memory()?;
+        let storage = Arc::new(FaultInjectingStorage::new(Box::new(InMemoryStorage::new()), vec![]));
+        let sync_engine = SyncEngine::builder().storage(Box::new(ArcStorage::new(storage.clone()))).build()?;
+        assert_eq!(sync_engine.sync_with_schema_version(&db, 2)?, SyncResult::Synced);
+
+        // A failed read of the version doesn't count as there being none,
+        // which would let an older app overwrite it
+        storage.add_fault(storage.call_count() + 1, StorageFault::Timeout);
+        assert!(sync_engine.sync_with_schema_version(&db, 1).is_err());
+        assert_eq!(sync_engine.sync_with_schema_version(&db, 1)?, SyncResult::SchemaMismatch { local: 1, remote: 2 });
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_deletes_sync() -> anyhow::Result<()> {
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        db1.save(&Artist { name: "Pink Floyd".to_string(), ..Default::default() })?;
+        db2.save(&Artist { name: "Genesis".to_string(), ..Default::default() })?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        sync_engine.sync(&db1)?;
+
+        db1.set_bulk_delete_threshold(0);
+        assert_eq!(db1.delete_all::<Artist>()?, 2);
+        // Saved after the bulk delete, so it survives
+        let yes = db2.save(&Artist { name: "Yes".to_string(), ..Default::default() })?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        sync_engine.sync(&db1)?;
+
+        for db in [&db1, &db2] {
+            let artists: Vec<Artist> = db.query("SELECT * FROM Artist", [])?;
+            assert_eq!(artists.len(), 1);
+            assert_eq!(artists[0].id, yes.id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn field_values_round_trip_as_msgpack() -> anyhow::Result<()> {
+        use crate::changelog::RemoteFieldRecord;
+        use rusqlite::types::Value;
+
+        let values = vec![
+            Value::Null,
+            Value::Integer(-42),
+            Value::Real(1.5),
+            Value::Text("Pink Floyd".to_string()),
+            Value::Blob(vec![0, 1, 255]),
+        ];
+        for value in values {
+            let record = RemoteFieldRecord {
+                field_name: "field".to_string(),
+                field_value: super::sql_value_to_json(&value),
+            };
+            let bytes = rmp_serde::to_vec(&record)?;
+            let decoded: RemoteFieldRecord = rmp_serde::from_slice(&bytes)?;
+            assert_eq!(super::json_to_sql_value(&decoded.field_value), value);
+        }
+
+        // Blobs are MessagePack binary on the wire
+        let record = RemoteFieldRecord {
+            field_name: "field".to_string(),
+            field_value: super::sql_value_to_json(&Value::Blob(vec![1, 2])),
+        };
+        let wire: rmpv::Value = rmp_serde::from_slice(&rmp_serde::to_vec(&record)?)?;
+        assert!(wire.as_array().unwrap().iter().any(|v| v.is_bin()));
+        Ok(())
+    }
+
+    #[test]
+    fn pruned_changes_stay_pruned_and_merge_the_same() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        let artist = db1.save(&Artist { name: "Pink Floyd".to_string(), country: Some("UK".to_string()), ..Default::default() })?;
+        db1.save(&Artist { name: "The Pink Floyd".to_string(), ..artist.clone() })?;
+        let artist = db1.save(&Artist { name: "Floyd".to_string(), ..artist })?;
+        std::thread::sleep(Duration::from_millis(5));
+
+        // Nothing is pruned until it has been synced
+        assert_eq!(db1.prune_changes_older_than(Duration::ZERO)?, 0);
+        sync_engine.sync(&db1)?;
+
+        // The first change set country, which no later change did
+        assert_eq!(db1.prune_changes_keep_last_n(2)?, 0);
+        assert_eq!(db1.prune_changes_older_than(Duration::from_secs(3600))?, 0);
+        assert_eq!(db1.prune_changes_older_than(Duration::ZERO)?, 1);
+        assert_eq!(db1.prune_changes_older_than(Duration::ZERO)?, 0);
+
+        // Pruned changes aren't pulled again, and replicas still agree
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        let field_count: i64 = db1.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE_FIELD", [])?;
+        assert_eq!(field_count, 3);
+        assert_eq!(db2.get::<Artist>(&artist.id)?.map(|a| a.name), Some("Floyd".to_string()));
+        assert_eq!(db1.get::<Artist>(&artist.id)?, db2.get::<Artist>(&artist.id)?);
+
+        // Nor are they pushed to storage that never had them
+        let storage = crate::storage::InMemoryStorage::new();
+        let new_engine = SyncEngine::builder().storage(Box::new(storage.clone())).build()?;
+        new_engine.sync(&db1)?;
+        let remote = crate::changelog::BatchingStorageChangelog::new(&storage, "dimple-sync".to_string());
+        let pushed = crate::changelog::Changelog::get_changes(&remote, None, None)?;
+        assert_eq!(pushed.len(), 2);
+        assert!(pushed.iter().all(|change| !change.fields.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn only_selected_entity_types_sync() -> anyhow::Result<()> {
+        #[derive(Serialize, Deserialize, Default)]
+        struct Album {
+            id: String,
+            title: String,
+        }
+
+        #[derive(Serialize, Deserialize, Default)]
+        struct Track {
+            id: String,
+            title: String,
+        }
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+            M::up("CREATE TABLE Album (id TEXT PRIMARY KEY, title TEXT NOT NULL);"),
+            M::up("CREATE TABLE Track (id TEXT PRIMARY KEY, title TEXT NOT NULL);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let filtered = SyncEngine::builder()
+            .storage(Box::new(storage.clone()))
+            .entity_types(&["Artist", "Album"])
+            .build()?;
+        let unfiltered = SyncEngine::builder().storage(Box::new(storage)).build()?;
+
+        db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db1.save(&Album { title: "Master of Puppets".to_string(), ..Default::default() })?;
+        db1.save(&Track { title: "Battery".to_string(), ..Default::default() })?;
+        filtered.sync(&db1)?;
+        unfiltered.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 1);
+        assert_eq!(db2.count::<Album, _>("", ())?, 1);
+        assert_eq!(db2.count::<Track, _>("", ())?, 0);
+
+        // Tracks on the remote aren't pulled either
+        db2.save(&Track { title: "Orion".to_string(), ..Default::default() })?;
+        db2.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        unfiltered.sync(&db2)?;
+        filtered.sync(&db1)?;
+        assert_eq!(db1.count::<Artist, _>("", ())?, 2);
+        let tracks: Vec<Track> = db1.query("SELECT * FROM Track", ())?;
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].title, "Battery");
+
+        // The filter can also be given per sync
+        unfiltered.sync_entity_types(&db1, &["Track"])?;
+        unfiltered.sync(&db2)?;
+        assert_eq!(db2.count::<Track, _>("", ())?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn progress_reports_phases() -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex};
+        use crate::sync::{SyncPhase, SyncProgress};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let progress = Arc::new(Mutex::new(Vec::<SyncProgress>::new()));
+        let recorded = progress.clone();
+        let engine1 = SyncEngine::builder()
+            .storage(Box::new(storage.clone()))
+            .on_progress(move |p| recorded.lock().unwrap().push(p))
+            .build()?;
+        let engine2 = SyncEngine::builder().storage(Box::new(storage)).build()?;
+
+        db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db1.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        engine1.sync(&db1)?;
+        let phases = progress.lock().unwrap().iter().map(|p| p.phase.clone()).collect::<Vec<_>>();
+        assert_eq!(phases, vec![SyncPhase::Listing, SyncPhase::Pushing(2, 2), SyncPhase::Done]);
+        let done = progress.lock().unwrap().last().cloned().unwrap();
+        assert_eq!((done.changes_pulled, done.changes_pushed), (0, 2));
+        assert!(done.bytes_transferred > 0);
+
+        db2.save(&Artist { name: "Anthrax".to_string(), ..Default::default() })?;
+        let mut phases = vec![];
+        engine2.sync_with_progress(&db2, |p| phases.push(p.phase))?;
+        assert_eq!(phases, vec![SyncPhase::Listing, SyncPhase::Pulling(2, 2), SyncPhase::Merging,
+            SyncPhase::Pushing(1, 1), SyncPhase::Done]);
+        assert_eq!(db2.count::<Artist, _>("", ())?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn progress_callback_can_use_the_engine() -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex, OnceLock};
+        use crate::sync::SyncPhase;
+
+        let db = Db::open_memory()?;
+        db.migrate(&Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]))?;
+        let engine_cell = Arc::new(OnceLock::<Arc<SyncEngine>>::new());
+        let callback_engine = engine_cell.clone();
+        let callback_db = db.clone();
+        let nested = Arc::new(Mutex::new(Vec::new()));
+        let recorded = nested.clone();
+        let engine = Arc::new(SyncEngine::builder()
+            .in_memory()
+            .on_progress(move |p| {
+                if p.phase == SyncPhase::Done {
+                    let engine = callback_engine.get().unwrap();
+                    recorded.lock().unwrap().push(engine.dry_run(&callback_db).map(|diff| diff.would_push.len()).ok());
+                    recorded.lock().unwrap().push(engine.sync(&callback_db).ok().map(|_| 0));
+                }
+            })
+            .build()?);
+        let _ = engine_cell.set(engine.clone());
+
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        engine.sync(&db)?;
+        assert_eq!(*nested.lock().unwrap(), vec![Some(0), Some(0)]);
+        // The callback is back in place for the next sync
+        engine.sync(&db)?;
+        assert_eq!(nested.lock().unwrap().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() -> anyhow::Result<()> {
+        use crate::changelog::{BatchingStorageChangelog, Changelog, DbChangelog};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let engine = SyncEngine::builder().storage(Box::new(storage.clone())).build()?;
+
+        db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        engine.sync(&db1)?;
+        db2.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        db2.save(&Artist { name: "Anthrax".to_string(), ..Default::default() })?;
+
+        let local = DbChangelog::new(db2.clone());
+        let remote = BatchingStorageChangelog::new(&storage, "dimple-sync".to_string());
+        let (local_ids, remote_ids) = (local.get_all_change_ids()?, remote.get_all_change_ids()?);
+
+        let diff = engine.dry_run(&db2)?;
+        assert_eq!(diff.would_pull, remote_ids);
+        assert_eq!(diff.would_push, local_ids);
+        assert!(diff.would_pull_bytes > 0 && diff.would_push_bytes > diff.would_pull_bytes);
+        assert_eq!(local.get_all_change_ids()?, local_ids);
+        assert_eq!(remote.get_all_change_ids()?, remote_ids);
+        assert_eq!(db2.count::<Artist, _>("", ())?, 2);
+
+        // The real sync does what the dry run said it would
+        engine.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 3);
+        assert_eq!(engine.dry_run(&db2)?, Default::default());
+        Ok(())
+    }
+
+    #[test]
+    fn pull_only_and_push_only() -> anyhow::Result<()> {
+        use crate::{changelog::{BasicStorageChangelog, Changelog, DbChangelog}, storage::InMemoryStorage};
+        use super::{GenericSyncEngine, SyncDirection};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let publisher = Db::open_memory()?;
+        let subscriber = Db::open_memory()?;
+        publisher.migrate(&migrations)?;
+        subscriber.migrate(&migrations)?;
+        let storage = InMemoryStorage::new();
+        let remote = BasicStorageChangelog::new(&storage, "shared".to_string());
+        let publisher_changelog = DbChangelog::new(publisher.clone());
+        let subscriber_changelog = DbChangelog::new(subscriber.clone());
+
+        // Pushing with no local changes is fine
+        GenericSyncEngine::sync_direction(&publisher_changelog, &remote, SyncDirection::PushOnly)?;
+        assert!(remote.get_all_change_ids()?.is_empty());
+
+        publisher.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        subscriber.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        GenericSyncEngine::sync_direction(&publisher_changelog, &remote, SyncDirection::PushOnly)?;
+        GenericSyncEngine::sync_direction(&subscriber_changelog, &remote, SyncDirection::PullOnly)?;
+        assert_eq!(subscriber.count::<Artist, _>("", ())?, 2);
+        assert_eq!(remote.get_all_change_ids()?, publisher_changelog.get_all_change_ids()?);
+
+        // The subscriber's change never reaches the publisher
+        GenericSyncEngine::sync_direction(&publisher_changelog, &remote, SyncDirection::PushOnly)?;
+        assert_eq!(publisher.count::<Artist, _>("", ())?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn with_metrics_counts_sync_storage_calls() -> anyhow::Result<()> {
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let (engine1, metrics1) = SyncEngine::builder().storage(Box::new(storage.clone())).with_metrics()?;
+        let (engine2, metrics2) = SyncEngine::builder().storage(Box::new(storage)).with_metrics()?;
+
+        db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        engine1.sync(&db1)?;
+        let pushed = metrics1.snapshot();
+        assert!(pushed.put_bytes > 0);
+        assert!(pushed.list_calls > 0);
+
+        engine2.sync(&db2)?;
+        let pulled = metrics2.snapshot();
+        assert!(pulled.get_calls > 0);
+        assert!(pulled.get_bytes >= pushed.put_bytes);
+        assert_eq!(pulled.error_count, 0);
+
+        metrics1.reset();
+        assert_eq!(metrics1.snapshot(), crate::storage::StorageMetrics::default());
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_changelog_syncs_to_fresh_db() -> anyhow::Result<()> {
+        use crate::{changelog::{BasicStorageChangelog, Changelog, CompactionStats, DbChangelog}, storage::{InMemoryStorage, SyncStorage}};
+        use super::GenericSyncEngine;
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        let storage = InMemoryStorage::new();
+        let remote = BasicStorageChangelog::new(&storage, "shared".to_string());
+        let local1 = DbChangelog::new(db1.clone());
+
+        let metallica = db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        db1.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+        GenericSyncEngine::sync(&local1, &remote)?;
+        let change_ids = remote.get_all_change_ids()?;
+        assert_eq!(storage.list_paths_only("shared/changes/")?.len(), change_ids.len());
+
+        let stats = remote.compact()?;
+        assert_eq!(stats.files_merged, change_ids.len());
+        assert_eq!(stats.files_deleted, change_ids.len());
+        assert!(stats.bytes_after > 0);
+        assert!(storage.list_paths_only("shared/changes/")?.is_empty());
+        assert_eq!(storage.list_paths_only("shared/compacted/")?,
+            vec![format!("shared/compacted/{}.msgpack", change_ids.last().unwrap())]);
+        assert_eq!(remote.get_all_change_ids()?, change_ids);
+        assert_eq!(remote.compact()?, CompactionStats::default());
+
+        // Changes pushed after compaction are merged with the compacted file
+        db1.save(&Artist { country: Some("USA".to_string()), ..metallica.clone() })?;
+        GenericSyncEngine::sync(&local1, &remote)?;
+        let stats = remote.compact()?;
+        assert_eq!(stats.files_merged, 2);
+        assert_eq!(stats.files_deleted, 2);
+        assert_eq!(storage.list_paths_only("shared/compacted/")?.len(), 1);
+
+        let db2 = Db::open_memory()?;
+        db2.migrate(&migrations)?;
+        GenericSyncEngine::sync(&DbChangelog::new(db2.clone()), &remote)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 2);
+        let artist: Artist = db2.get(&metallica.id)?.unwrap();
+        assert_eq!(artist.country.as_deref(), Some("USA"));
+        Ok(())
+    }
+
+    #[test]
+    fn pull_only_engine_keeps_changes_local() -> anyhow::Result<()> {
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db = Db::open_memory()?;
+        db.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let pull_only = SyncEngine::builder().storage(Box::new(storage.clone())).pull_only().build()?;
+        let push_only = SyncEngine::builder().storage(Box::new(storage)).push_only().build()?;
+
+        db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        pull_only.sync(&db)?;
+        assert!(pull_only.dry_run(&db)?.would_push.is_empty());
+        assert_eq!(push_only.dry_run(&db)?.would_push.len(), 1);
+        // Unpushed changes must not be pruned
+        assert_eq!(db.get_metadata(crate::changelog::SYNCED_THROUGH_KEY)?, None);
+
+        push_only.sync(&db)?;
+        assert!(push_only.dry_run(&db)?.would_push.is_empty());
+        assert!(db.get_metadata(crate::changelog::SYNCED_THROUGH_KEY)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_resolver_overrides_last_write_wins() -> anyhow::Result<()> {
+        use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+        use crate::changelog::{ConflictContext, ConflictResolution};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver_calls = calls.clone();
+        // Keep the most detailed description
+        let engine1 = SyncEngine::builder()
+            .storage(Box::new(storage.clone()))
+            .on_conflict(move |context: ConflictContext| {
+                resolver_calls.fetch_add(1, Ordering::SeqCst);
+                assert_eq!((context.entity_type.as_str(), context.field_name.as_str()), ("Artist", "country"));
+                assert!(context.local_change_id > context.remote_change_id);
+                let len = |value: &serde_json::Value| value.as_str().map(str::len).unwrap_or_default();
+                if len(&context.local_field_value) >= len(&context.remote_field_value) {
+                    ConflictResolution::TakeLocal
+                } else {
+                    ConflictResolution::TakeRemote
+                }
+            })
+            .build()?;
+        let engine2 = SyncEngine::builder().storage(Box::new(storage)).build()?;
+
+        let artist = db1.save(&Artist { name: "Metallica".to_string(), country: Some("US".to_string()), ..Default::default() })?;
+        engine1.sync(&db1)?;
+        engine2.sync(&db2)?;
+
+        // db2's change is older, so by default db1's would win
+        db2.save(&Artist { country: Some("United States of America".to_string()), ..artist.clone() })?;
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        db1.save(&Artist { name: "Metallica!".to_string(), country: Some("USA".to_string()), ..artist.clone() })?;
+        engine2.sync(&db2)?;
+        engine1.sync(&db1)?;
+        engine2.sync(&db2)?;
+
+        // Only the country conflicts, name was only changed on db1
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for db in [&db1, &db2] {
+            let artist: Artist = db.get(&artist.id)?.unwrap();
+            assert_eq!(artist.name, "Metallica!");
+            assert_eq!(artist.country.as_deref(), Some("United States of America"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vector_clocks_only_resolve_concurrent_changes() -> anyhow::Result<()> {
+        use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+        use crate::changelog::{BatchingStorageChangelog, Changelog, ConflictResolution};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let storage = crate::storage::InMemoryStorage::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver_calls = calls.clone();
+        // A filtered sync never records what has been synced, so without
+        // clocks every local change would look concurrent
+        let engine1 = SyncEngine::builder()
+            .storage(Box::new(storage.clone()))
+            .entity_types(&["Artist"])
+            .vector_clocks()
+            .on_conflict(move |_| {
+                resolver_calls.fetch_add(1, Ordering::SeqCst);
+                ConflictResolution::TakeLocal
+            })
+            .build()?;
+        let engine2 = SyncEngine::builder().storage(Box::new(storage.clone())).vector_clocks().build()?;
+
+        let artist = db1.save(&Artist { name: "Metallica".to_string(), country: Some("US".to_string()), ..Default::default() })?;
+        engine1.sync(&db1)?;
+        engine2.sync(&db2)?;
+
+        // db2 has seen db1's change, so its change wins without a conflict
+        db2.save(&Artist { country: Some("USA".to_string()), ..artist.clone() })?;
+        engine2.sync(&db2)?;
+        engine1.sync(&db1)?;
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(db1.get::<Artist>(&artist.id)?.unwrap().country.as_deref(), Some("USA"));
+
+        // Neither has seen the other's change
+        db2.save(&Artist { country: Some("United States".to_string()), ..artist.clone() })?;
+        db1.save(&Artist { country: Some("America".to_string()), ..artist.clone() })?;
+        engine2.sync(&db2)?;
+        engine1.sync(&db1)?;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(db1.get::<Artist>(&artist.id)?.unwrap().country.as_deref(), Some("America"));
+
+        let remote = BatchingStorageChangelog::new(&storage, "dimple-sync".to_string());
+        assert!(remote.get_changes(None, None)?.iter().all(|change| change.clock.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn clocks_are_recorded_once_per_transaction_when_enabled() -> anyhow::Result<()> {
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db = Db::open_memory()?;
+        db.migrate(&migrations)?;
+        let clock_count = |db: &Db| db.query_scalar::<i64, _>("SELECT COUNT(*) FROM ZV_CLOCK", ());
+
+        // Nothing is recorded without vector clocks
+        let artist = db.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        SyncEngine::builder().in_memory().build()?.sync(&db)?;
+        assert_eq!(clock_count(&db)?, 0);
+        assert!(db.get_metadata(crate::changelog::vector_clock::VECTOR_CLOCK_KEY)?.is_none());
+
+        // Enabling them gives the earlier changes one clock
+        let engine = SyncEngine::builder().in_memory().vector_clocks().build()?;
+        engine.sync(&db)?;
+        assert_eq!(clock_count(&db)?, 1);
+
+        db.transaction(|txn| {
+            txn.save(&Artist { name: "Megadeth".to_string(), ..Default::default() })?;
+            txn.save(&Artist { name: "Anthrax".to_string(), ..Default::default() })?;
+            Ok(())
+        })?;
+        assert_eq!(clock_count(&db)?, 2);
+
+        // A change's clock goes when it is pruned
+        db.save(&Artist { name: "Metallica!".to_string(), ..artist.clone() })?;
+        db.save(&Artist { name: "Metallica!!".to_string(), ..artist.clone() })?;
+        engine.sync(&db)?;
+        assert_eq!(clock_count(&db)?, 4);
+        assert_eq!(db.prune_changes_keep_last_n(1)?, 1);
+        assert_eq!(clock_count(&db)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_through_obfuscated_paths() -> anyhow::Result<()> {
+        use crate::storage::{InMemoryStorage, ObfuscatedPathStorage};
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let inner = InMemoryStorage::new();
+        let engine = |inner: &InMemoryStorage| -> anyhow::Result<SyncEngine> {
+            let storage = ObfuscatedPathStorage::new(Box::new(inner.clone()), "passphrase")?;
+            SyncEngine::builder().storage(Box::new(storage)).build()
+        };
+
+        db1.save(&Artist { name: "Metallica".to_string(), ..Default::default() })?;
+        engine(&inner)?.sync(&db1)?;
+        engine(&inner)?.sync(&db2)?;
+        assert_eq!(db2.count::<Artist, _>("", ())?, 1);
+        assert!(inner.snapshot()?.paths().iter().all(|path| !path.contains("dimple-sync")));
+
+        // Obfuscation is keyed by the encryption passphrase
+        assert!(SyncEngine::builder().in_memory().obfuscate_paths().build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_sync() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        let artist = db1.save(&Artist { name: "Pink Floyd".to_string(), ..Default::default() })?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        assert!(db2.get::<Artist>(&artist.id)?.is_some());
+
+        // db2 updates the artist before db1 deletes it
+        db2.save(&Artist { country: Some("UK".to_string()), ..artist.clone() })?;
+        db1.delete_where::<Artist, _>("id = ?", [&artist.id])?;
+        let receiver = db2.subscribe();
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        assert!(db2.get::<Artist>(&artist.id)?.is_none());
+        match receiver.recv_timeout(Duration::from_secs(1))? {
+            DbEvent::Delete { entity_id, .. } => assert_eq!(entity_id, artist.id),
+            event => panic!("Expected Delete event, got {:?}", event),
+        }
+
+        // db2's older update doesn't resurrect it on db1
+        sync_engine.sync(&db1)?;
+        let change_count: i64 = db1.query_scalar("SELECT COUNT(*) FROM ZV_CHANGE WHERE entity_id = ?", [&artist.id])?;
+        assert_eq!(change_count, 3);
+        assert!(db1.get::<Artist>(&artist.id)?.is_none());
+
+        // Deleting an entity a replica hasn't received yet still syncs
+        let artist = db1.save(&Artist { name: "Genesis".to_string(), ..Default::default() })?;
+        assert!(!db2.delete_by_id::<Artist>(&artist.id)?);
+        sync_engine.sync(&db2)?;
+        sync_engine.sync(&db1)?;
+        assert!(db1.get::<Artist>(&artist.id)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn merges_of_different_fields_sync() -> anyhow::Result<()> {
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        let artist = db1.save(&Artist { name: "Radiohead".to_string(), ..Default::default() })?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+
+        // db2's copy goes stale when db1's rename arrives
+        let stale = db2.get::<Artist>(&artist.id)?.unwrap();
+        db1.merge(&Artist { name: "Radiohead!".to_string(), ..artist.clone() }, &["name"])?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        db2.merge(&Artist { country: Some("UK".to_string()), ..stale }, &["country"])?;
+        sync_engine.sync(&db2)?;
+        sync_engine.sync(&db1)?;
+
+        for db in [&db1, &db2] {
+            let artist = db.get::<Artist>(&artist.id)?.unwrap();
+            assert_eq!(artist.name, "Radiohead!");
+            assert_eq!(artist.country.as_deref(), Some("UK"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn entities_keyed_by_primary_key_sync() -> anyhow::Result<()> {
+        #[derive(Serialize, Deserialize, Default, Debug)]
+        struct Setting {
+            key: String,
+            value: String,
+        }
+
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Setting (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);"),
+        ]);
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        let sync_engine = SyncEngine::builder().in_memory().build()?;
+
+        db1.save(&Setting { key: "theme".to_string(), value: "dark".to_string() })?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        assert_eq!(db2.get::<Setting>("theme")?.unwrap().value, "dark");
+
+        db2.save(&Setting { key: "theme".to_string(), value: "light".to_string() })?;
+        sync_engine.sync(&db2)?;
+        sync_engine.sync(&db1)?;
+        assert_eq!(db1.get::<Setting>("theme")?.unwrap().value, "light");
+
+        db1.delete_by_id::<Setting>("theme")?;
+        sync_engine.sync(&db1)?;
+        sync_engine.sync(&db2)?;
+        assert!(db2.get::<Setting>("theme")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generic_sync_engine() -> anyhow::Result<()> {
+        use crate::{changelog::{DbChangelog, BatchingStorageChangelog}, storage::InMemoryStorage};
+        use super::GenericSyncEngine;
+        
+        let migrations = Migrations::new(vec![
+            M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
+        ]);
+        
+        let db1 = Db::open_memory()?;
+        let db2 = Db::open_memory()?;
+        db1.migrate(&migrations)?;
+        db2.migrate(&migrations)?;
+        
+        let storage = InMemoryStorage::new();
+        
+        // Create changelogs
+        let db1_changelog = DbChangelog::new(db1.clone());
+        let db2_changelog = DbChangelog::new(db2.clone());
+        let storage_changelog = BatchingStorageChangelog::new(&storage, "test".to_string());
+        
+        // Add data to db1
+        let _artist1 = db1.save(&Artist {
+            name: "The Beatles".to_string(),
+            ..Default::default()
+        })?;
+        
+        // Add data to db2
+        let _artist2 = db2.save(&Artist {
+            name: "Pink Floyd".to_string(),
+            ..Default::default()
+        })?;
+        
+        // Sync db1 to storage
+        GenericSyncEngine::sync(&db1_changelog, &storage_changelog)?;
+        
+        // Sync db2 to storage  
+        GenericSyncEngine::sync(&db2_changelog, &storage_changelog)?;
+        
+        // Sync storage back to db1 (should get Pink Floyd)
+        GenericSyncEngine::sync(&storage_changelog, &db1_changelog)?;
+        crate::changelog::merge_unmerged_changes(&db1, None)?;
+        
+        // Sync storage back to db2 (should get The Beatles)
+        GenericSyncEngine::sync(&storage_changelog, &db2_changelog)?;
+        crate::changelog::merge_unmerged_changes(&db2, None)?;
+        
+        // Both databases should now have both artists
+        let artists1: Vec<Artist> = db1.query("SELECT * FROM Artist ORDER BY name", ())?;
+        let artists2: Vec<Artist> = db2.query("SELECT * FROM Artist ORDER BY name", ())?;
+        
+        assert_eq!(artists1.len(), 2);
+        assert_eq!(artists2.len(), 2);
+        assert_eq!(artists1[0].name, "Pink Floyd");
+        assert_eq!(artists1[1].name, "The Beatles");
+        assert_eq!(artists2[0].name, "Pink Floyd");
+        assert_eq!(artists2[1].name, "The Beatles");
+        
+        Ok(())
+    }
+
+    #[test]
+    fn pull_skips_changes_already_local() -> anyhow::Result<()> {
+        use std::sync::Mutex;
+        use crate::changelog::{Changelog, ChangelogChangeWithFields};
+        use super::GenericSyncEngine;
+
+        struct VecChangelog {
+            changes: Mutex<Vec<ChangelogChangeWithFields>>,
+            appended: Mutex<Vec<String>>,
+        }
+
+        impl VecChangelog {
+            fn new(ids: &[&str]) -> Self {
+                let changes = ids.iter().map(|id| ChangelogChangeWithFields {
+                    change: ChangelogChange {
+                        id: id.to_string(),
+                        author_id: "author".to_string(),
+                        entity_type: "Artist".to_string(),
+                        entity_id: id.to_string(),
+                        merged: false,
+                    },
+                    fields: vec![],
+                    clock: None,
+                    author: None,
+                }).collect();
+                Self { changes: Mutex::new(changes), appended: Mutex::new(vec![]) }
+            }
+        }
+
+        impl Changelog for VecChangelog {
+            fn get_all_change_ids(&self) -> anyhow::Result<Vec<String>> {
+                Ok(self.changes.lock().unwrap().iter().map(|c| c.change.id.clone()).collect())
+            }
+
+            fn get_changes(&self, from_id: Option<&str>, to_id: Option<&str>) -> anyhow::Result<Vec<ChangelogChangeWithFields>> {
+                Ok(self.changes.lock().unwrap().iter()
+                    .filter(|c| from_id.map_or(true, |from| c.change.id.as_str() >= from))
+                    .filter(|c| to_id.map_or(true, |to| c.change.id.as_str() <= to))
+                    .cloned()
+                    .collect())
+            }
+
+            fn append_changes(&self, changes: Vec<ChangelogChangeWithFields>) -> anyhow::Result<()> {
+                self.appended.lock().unwrap().extend(changes.iter().map(|c| c.change.id.clone()));
+                self.changes.lock().unwrap().extend(changes);
+                Ok(())
+            }
+        }
+
+        // Remote range b..=d overlaps the local c
+        let local = VecChangelog::new(&["a", "c"]);
+        let remote = VecChangelog::new(&["a", "b", "c", "d"]);
+        GenericSyncEngine::sync(&local, &remote)?;
+        assert_eq!(*local.appended.lock().unwrap(), vec!["b", "d"]);
+        Ok(())
+    }
+
+    /// Number of puts under the prefix recorded after the first `since`
+    /// operations.
+    fn count_puts(storage: &crate::storage::RecordingStorage, prefix: &str, since: usize) -> usize {
+        storage.operations().iter()
+            .skip(since)
+            .filter(|op| matches!(op.kind, StorageOpKind::Put | StorageOpKind::PutIfNotExists))
+            .filter(|op| op.path.starts_with(prefix))
+            .count()
+    }
+
+    #[test]
+    fn performance_comparison_small_dataset() -> anyhow::Result<()> {
+        use crate::{changelog::{Changelog, DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::{RecordingStorage, SlowInMemoryStorage}};
+        use super::GenericSyncEngine;
+        use std::time::Instant;
+        
         let migrations = Migrations::new(vec![
             M::up("CREATE TABLE Artist (id TEXT PRIMARY KEY, name TEXT NOT NULL, country TEXT);"),
         ]);
@@ -699,7 +2190,7 @@ mod tests {
             })?;
         }
         
-        let storage = SlowInMemoryStorage::new(1, 10, 2); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
+        let storage = RecordingStorage::new(Box::new(SlowInMemoryStorage::new(1, 10, 2))); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
         let db_changelog = DbChangelog::new(db1.clone());
         
         // Test BatchingStorageChangelog
@@ -719,6 +2210,13 @@ mod tests {
         println!("  Basic approach:    {:?}", basic_duration);
         println!("  Ratio: {:.2}x", basic_duration.as_nanos() as f64 / batching_duration.as_nanos() as f64);
         
+        // Basic writes a file per change, batching a batch and a manifest
+        let batching_puts = count_puts(&storage, "batching", 0);
+        let basic_puts = count_puts(&storage, "basic", 0);
+        println!("  Puts: batching {}, basic {}", batching_puts, basic_puts);
+        assert_eq!(basic_puts, num_records);
+        assert!(batching_puts < basic_puts);
+        
         // Verify both approaches stored the same number of changes
         assert_eq!(batching_changelog.get_all_change_ids()?.len(), num_records);
         assert_eq!(basic_changelog.get_all_change_ids()?.len(), num_records);
@@ -728,7 +2226,7 @@ mod tests {
 
     #[test]
     fn performance_comparison_large_dataset() -> anyhow::Result<()> {
-        use crate::{changelog::{Changelog, DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::SlowInMemoryStorage};
+        use crate::{changelog::{Changelog, DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::{RecordingStorage, SlowInMemoryStorage}};
         use super::GenericSyncEngine;
         use std::time::Instant;
         
@@ -750,7 +2248,7 @@ mod tests {
             })?;
         }
         
-        let storage = SlowInMemoryStorage::new(1, 10, 2); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
+        let storage = RecordingStorage::new(Box::new(SlowInMemoryStorage::new(1, 10, 2))); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
         let db_changelog = DbChangelog::new(db1.clone());
         
         // Test BatchingStorageChangelog
@@ -770,6 +2268,13 @@ mod tests {
         println!("  Basic approach:    {:?}", basic_duration);
         println!("  Ratio: {:.2}x", basic_duration.as_nanos() as f64 / batching_duration.as_nanos() as f64);
         
+        // Basic writes a file per change, batching a batch and a manifest
+        let batching_puts = count_puts(&storage, "batching", 0);
+        let basic_puts = count_puts(&storage, "basic", 0);
+        println!("  Puts: batching {}, basic {}", batching_puts, basic_puts);
+        assert_eq!(basic_puts, num_records);
+        assert!(batching_puts < basic_puts);
+        
         // Verify both approaches stored the same number of changes
         assert_eq!(batching_changelog.get_all_change_ids()?.len(), num_records);
         assert_eq!(basic_changelog.get_all_change_ids()?.len(), num_records);
@@ -779,7 +2284,7 @@ mod tests {
 
     #[test]
     fn performance_comparison_incremental_sync() -> anyhow::Result<()> {
-        use crate::{changelog::{DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::SlowInMemoryStorage};
+        use crate::{changelog::{DbChangelog, BatchingStorageChangelog, BasicStorageChangelog}, storage::{RecordingStorage, SlowInMemoryStorage}};
         use super::GenericSyncEngine;
         use std::time::Instant;
         
@@ -806,7 +2311,7 @@ mod tests {
             })?;
         }
         
-        let storage = SlowInMemoryStorage::new(1, 10, 2); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
+        let storage = RecordingStorage::new(Box::new(SlowInMemoryStorage::new(1, 10, 2))); // Fast testing: GET: 1ms, PUT: 10ms, LIST: 2ms
         let db1_changelog = DbChangelog::new(db1.clone());
         let db2_changelog = DbChangelog::new(db2.clone());
         
@@ -828,6 +2333,7 @@ mod tests {
         }
         
         // Test incremental sync performance
+        let since = storage.operations().len();
         let start = Instant::now();
         GenericSyncEngine::sync(&db2_changelog, &batching_changelog)?;
         let batching_incremental = start.elapsed();
@@ -841,6 +2347,13 @@ mod tests {
         println!("  Basic approach:    {:?}", basic_incremental);
         println!("  Ratio: {:.2}x", basic_incremental.as_nanos() as f64 / batching_incremental.as_nanos() as f64);
         
+        // db2's initial records are distinct from db1's, so all are new
+        let batching_puts = count_puts(&storage, "batching", since);
+        let basic_puts = count_puts(&storage, "basic", since);
+        println!("  Puts: batching {}, basic {}", batching_puts, basic_puts);
+        assert_eq!(basic_puts, initial_records + incremental_records);
+        assert!(batching_puts < basic_puts);
+        
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, Context, Result};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::sync::SyncEngine;
+
+/// Storage and prefix parsed from a sync url. See SyncEngine::from_url().
+#[derive(Debug, PartialEq, Eq)]
+struct SyncUrl {
+    storage: SyncUrlStorage,
+    prefix: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SyncUrlStorage {
+    Memory,
+    Local {
+        base_path: String,
+    },
+    S3 {
+        endpoint: String,
+        bucket_name: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+    Gcs {
+        bucket: String,
+        credentials_path: String,
+    },
+    Azure {
+        account: String,
+        access_key: String,
+        container: String,
+    },
+}
+
+impl SyncEngine {
+    /// Create a SyncEngine from a url in one of the following formats:
+    /// - memory://prefix
+    /// - file://base_path, e.g. file:///var/sync or file://~/sync
+    /// - s3://access_key:secret_key@endpoint/bucket/prefix?region=us-east-1
+    /// - gcs://bucket/prefix?credentials=/path/to/service_account.json
+    /// - az://account:access_key@container/prefix
+    ///
+    /// Credentials in s3 and az urls are percent decoded. If a gcs url has no
+    /// credentials path, GOOGLE_APPLICATION_CREDENTIALS is used.
+    pub fn from_url(url: &str) -> Result<SyncEngine> {
+        Self::build_from_url(url, None)
+    }
+
+    /// Like from_url(), with the storage encrypted using the passphrase.
+    pub fn from_url_with_passphrase(url: &str, passphrase: &str) -> Result<SyncEngine> {
+        Self::build_from_url(url, Some(passphrase))
+    }
+
+    fn build_from_url(url: &str, passphrase: Option<&str>) -> Result<SyncEngine> {
+        let sync_url = parse_sync_url(url)?;
+        let mut builder = match sync_url.storage {
+            SyncUrlStorage::Memory => SyncEngine::builder().in_memory(),
+            SyncUrlStorage::Local { base_path } => SyncEngine::builder().local(&base_path),
+            SyncUrlStorage::S3 { endpoint, bucket_name, region, access_key, secret_key } => 
+                SyncEngine::builder().s3(&endpoint, &bucket_name, &region, &access_key, &secret_key)?,
+            SyncUrlStorage::Gcs { bucket, credentials_path } => {
+                let service_account_json = std::fs::read_to_string(&credentials_path)
+                    .with_context(|| format!("reading GCS credentials {}", credentials_path))?;
+                SyncEngine::builder().gcs(&bucket, &service_account_json)?
+            },
+            SyncUrlStorage::Azure { account, access_key, container } =>
+                SyncEngine::builder().azure(&account, &access_key, &container)?,
+        };
+        if let Some(prefix) = sync_url.prefix {
+            builder = builder.prefix(&prefix);
+        }
+        if let Some(passphrase) = passphrase {
+            builder = builder.encrypted(passphrase);
+        }
+        builder.build()
+    }
+}
+
+fn parse_sync_url(url: &str) -> Result<SyncUrl> {
+    let url = Url::parse(url)?;
+    match url.scheme() {
+        "memory" => {
+            let prefix = format!("{}{}", url.host_str().unwrap_or_default(), url.path());
+            let prefix = prefix.trim_matches('/');
+            Ok(SyncUrl {
+                storage: SyncUrlStorage::Memory,
+                prefix: (!prefix.is_empty()).then(|| prefix.to_string()),
+            })
+        },
+        "file" => {
+            let base_path = format!("{}{}", url.host_str().unwrap_or_default(), url.path());
+            let base_path = base_path.trim_end_matches('/');
+            if base_path.is_empty() {
+                return Err(anyhow!("base path is required"));
+            }
+            Ok(SyncUrl {
+                storage: SyncUrlStorage::Local { base_path: decode(base_path)? },
+                prefix: None,
+            })
+        },
+        "s3" => {
+            let secret_key = url.password().ok_or_else(|| anyhow!("secret key is required"))?;
+            let host = url.host_str().ok_or_else(|| anyhow!("endpoint is required"))?;
+            let endpoint = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            let path_segs = url.path_segments()
+                .ok_or_else(|| anyhow!("bucket is required"))?
+                .filter(|seg| !seg.is_empty())
+                .map(decode)
+                .collect::<Result<Vec<_>>>()?;
+            let bucket_name = path_segs.first().ok_or_else(|| anyhow!("bucket name is required"))?;
+            let prefix = path_segs[1..].join("/");
+            let region = url.query_pairs().find(|qp| qp.0 == "region")
+                .map(|qp| qp.1.to_string()).unwrap_or_default();
+            Ok(SyncUrl {
+                storage: SyncUrlStorage::S3 {
+                    endpoint,
+                    bucket_name: bucket_name.clone(),
+                    region,
+                    access_key: decode(url.username())?,
+                    secret_key: decode(secret_key)?,
+                },
+                prefix: (!prefix.is_empty()).then_some(prefix),
+            })
+        },
+        "gcs" => {
+            let bucket = url.host_str().ok_or_else(|| anyhow!("bucket is required"))?;
+            let credentials_path = match url.query_pairs().find(|qp| qp.0 == "credentials") {
+                Some(qp) => qp.1.to_string(),
+                None => std::env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                    .map_err(|_| anyhow!("credentials path is required"))?,
+            };
+            let prefix = url.path().trim_matches('/');
+            Ok(SyncUrl {
+                storage: SyncUrlStorage::Gcs {
+                    bucket: bucket.to_string(),
+                    credentials_path,
+                },
+                prefix: (!prefix.is_empty()).then(|| decode(prefix)).transpose()?,
+            })
+        },
+        "az" => {
+            let access_key = url.password().ok_or_else(|| anyhow!("access key is required"))?;
+            if url.username().is_empty() {
+                return Err(anyhow!("account is required"));
+            }
+            let container = url.host_str().ok_or_else(|| anyhow!("container is required"))?;
+            let prefix = url.path().trim_matches('/');
+            Ok(SyncUrl {
+                storage: SyncUrlStorage::Azure {
+                    account: decode(url.username())?,
+                    access_key: decode(access_key)?,
+                    container: container.to_string(),
+                },
+                prefix: (!prefix.is_empty()).then(|| decode(prefix)).transpose()?,
+            })
+        },
+        scheme => Err(anyhow!("invalid sync url scheme: {}", scheme)),
+    }
+}
+
+fn decode(s: &str) -> Result<String> {
+    Ok(percent_decode_str(s).decode_utf8()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::SyncEngine;
+
+    use super::{parse_sync_url, SyncUrl, SyncUrlStorage};
+
+    #[test]
+    fn parse_memory_urls() {
+        assert_eq!(parse_sync_url("memory://").unwrap(), SyncUrl { storage: SyncUrlStorage::Memory, prefix: None });
+        assert_eq!(parse_sync_url("memory://todos/v1").unwrap().prefix.as_deref(), Some("todos/v1"));
+    }
+
+    #[test]
+    fn parse_file_urls() {
+        let base_path = |url: &str| match parse_sync_url(url).unwrap().storage {
+            SyncUrlStorage::Local { base_path } => base_path,
+            storage => panic!("unexpected storage {:?}", storage),
+        };
+        assert_eq!(base_path("file:///var/sync"), "/var/sync");
+        assert_eq!(base_path("file://~/sync"), "~/sync");
+        assert_eq!(base_path("file://base_path"), "base_path");
+        assert_eq!(base_path("file:///my%20sync"), "/my sync");
+        assert!(parse_sync_url("file:///").is_err());
+    }
+
+    #[test]
+    fn parse_s3_urls() {
+        let sync_url = parse_sync_url("s3://access_key:secret%2Fkey@endpoint:9000/bucket/prefix1/prefix2?region=us-east-1").unwrap();
+        assert_eq!(sync_url, SyncUrl {
+            storage: SyncUrlStorage::S3 {
+                endpoint: "endpoint:9000".to_string(),
+                bucket_name: "bucket".to_string(),
+                region: "us-east-1".to_string(),
+                access_key: "access_key".to_string(),
+                secret_key: "secret/key".to_string(),
+            },
+            prefix: Some("prefix1/prefix2".to_string()),
+        });
+        assert_eq!(parse_sync_url("s3://access_key:secret_key@endpoint/bucket").unwrap().prefix, None);
+        let sync_url = parse_sync_url("s3://access_key:secret_key@endpoint/my%20bucket/my%20prefix").unwrap();
+        assert!(matches!(sync_url.storage, SyncUrlStorage::S3 { bucket_name, .. } if bucket_name == "my bucket"));
+        assert_eq!(sync_url.prefix, Some("my prefix".to_string()));
+        assert!(parse_sync_url("s3://access_key:secret_key@endpoint/").is_err());
+        assert!(parse_sync_url("s3://access_key@endpoint/bucket").is_err());
+    }
+
+    #[test]
+    fn parse_gcs_urls() {
+        let sync_url = parse_sync_url("gcs://bucket/prefix1/prefix2?credentials=/etc/my%20key.json").unwrap();
+        assert_eq!(sync_url, SyncUrl {
+            storage: SyncUrlStorage::Gcs {
+                bucket: "bucket".to_string(),
+                credentials_path: "/etc/my key.json".to_string(),
+            },
+            prefix: Some("prefix1/prefix2".to_string()),
+        });
+        assert_eq!(parse_sync_url("gcs://bucket?credentials=key.json").unwrap().prefix, None);
+        assert!(parse_sync_url("gcs:///prefix?credentials=key.json").is_err());
+    }
+
+    #[test]
+    fn parse_az_urls() {
+        let sync_url = parse_sync_url("az://account:a2V5%2Bkey%2F%3D%3D@container/prefix1/prefix2").unwrap();
+        assert_eq!(sync_url, SyncUrl {
+            storage: SyncUrlStorage::Azure {
+                account: "account".to_string(),
+                access_key: "a2V5+key/==".to_string(),
+                container: "container".to_string(),
+            },
+            prefix: Some("prefix1/prefix2".to_string()),
+        });
+        assert_eq!(parse_sync_url("az://account:key@container").unwrap().prefix, None);
+    }
+
+    #[test]
+    fn parse_invalid_urls() {
+        assert!(parse_sync_url("").is_err());
+        assert!(parse_sync_url("http://example.com").is_err());
+        assert!(parse_sync_url("az://container/prefix").is_err());
+        assert!(parse_sync_url("az://:key@container/prefix").is_err());
+    }
+
+    #[test]
+    fn from_url_builds_engines() {
+        assert!(SyncEngine::from_url("memory://prefix").is_ok());
+        assert!(SyncEngine::from_url("s3://access_key:secret_key@endpoint/bucket?region=us-east-1").is_ok());
+        assert!(SyncEngine::from_url("az://account:a2V5@container/prefix").is_ok());
+        assert!(SyncEngine::from_url("az://account:not%20base64!@container/prefix").is_err());
+        assert!(SyncEngine::from_url_with_passphrase("memory://prefix", "correct horse battery staple").is_ok());
+        assert!(SyncEngine::from_url("https://example.com").is_err());
+    }
+
+    #[test]
+    fn from_url_reads_gcs_credentials() -> anyhow::Result<()> {
+        let rsa = openssl::rsa::Rsa::generate(2048)?;
+        let private_key = String::from_utf8(openssl::pkey::PKey::from_rsa(rsa)?.private_key_to_pem_pkcs8()?)?;
+        let key = serde_json::json!({ "client_email": "sync@example.iam.gserviceaccount.com", "private_key": private_key });
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("key.json");
+        std::fs::write(&path, key.to_string())?;
+        assert!(SyncEngine::from_url(&format!("gcs://bucket/prefix?credentials={}", path.display())).is_ok());
+        assert!(SyncEngine::from_url(&format!("gcs://bucket/prefix?credentials={}", dir.path().join("missing.json").display())).is_err());
+        Ok(())
+    }
+}